@@ -0,0 +1,156 @@
+//! The `audio-report` subcommand: analyze the audio of the library and show what an encode would
+//! do with each audio stream, to help tune `--audio-copy-threshold` and `--audio-bitrate`.
+
+use std::fmt::Display;
+
+#[allow(unused_imports)]
+use log::*;
+
+use crate::{
+    format_size, get_file_size, input_too_large, input_too_small, AudioCopyPolicy, AudioReportOpts,
+    Encoder, JiffyResult, StreamInfo,
+};
+
+/// What an encode would do with an audio stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioAction {
+    Copy,
+    /// Reencode with this ffmpeg encoder, or with ffmpeg's default one if it's None.
+    Reencode(Option<String>),
+    Drop,
+}
+
+impl Display for AudioAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Copy => write!(f, "copy"),
+            Self::Reencode(Some(codec)) => write!(f, "reencode as {codec}"),
+            Self::Reencode(None) => write!(f, "reencode"),
+            Self::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// An audio stream in the audio report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioStreamReport {
+    /// The index of the stream among all streams in the file.
+    pub index: u32,
+    pub codec: Option<String>,
+    pub channels: Option<u32>,
+    /// The bitrate in kb/s, if it could be measured.
+    pub bitrate: Option<f32>,
+    pub action: AudioAction,
+}
+
+/// A video in the audio report.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioFileReport {
+    pub path: String,
+    pub size: u64,
+    /// The integrated loudness in LUFS, if it was measured.
+    pub loudness: Option<f64>,
+    pub streams: Vec<AudioStreamReport>,
+}
+
+/// Print the audio report: each video and its audio streams, then how many streams would be
+/// copied and reencoded.
+pub fn render_audio_report(files: &[AudioFileReport], copy_policy: &AudioCopyPolicy) -> String {
+    let mut report = String::new();
+    let (mut copied, mut reencoded, mut dropped) = (0, 0, 0);
+    for file in files {
+        report += &format!("{} ({})", file.path, format_size(file.size));
+        if let Some(loudness) = file.loudness {
+            report += &format!(", {loudness:.1} LUFS");
+        }
+        report += "\n";
+        if file.streams.is_empty() {
+            report += "  No audio\n";
+        }
+        for stream in &file.streams {
+            let codec = stream.codec.as_deref().unwrap_or("unknown codec");
+            let channels = stream
+                .channels
+                .map_or("? channels".to_string(), |channels| {
+                    format!("{channels} channels")
+                });
+            let bitrate = stream
+                .bitrate
+                .map_or("? kb/s".to_string(), |bitrate| format!("{bitrate:.0} kb/s"));
+            report += &format!(
+                "  Stream {}: {codec}, {channels}, {bitrate}: {}\n",
+                stream.index, stream.action
+            );
+            match stream.action {
+                AudioAction::Copy => copied += 1,
+                AudioAction::Reencode(_) => reencoded += 1,
+                AudioAction::Drop => dropped += 1,
+            }
+        }
+    }
+    report += &format!(
+        "{copied} audio streams would be copied, {reencoded} reencoded and {dropped} dropped. {copy_policy}.\n"
+    );
+    report
+}
+
+impl Encoder {
+    /// Analyze the audio of the videos an encode would find, and print what it would do with
+    /// each audio stream. Nothing is encoded.
+    pub async fn audio_report(&self, opts: &AudioReportOpts) -> JiffyResult<()> {
+        let mut files = Vec::new();
+        for input in self.get_video_paths().await? {
+            let size = get_file_size(&input.path).unwrap_or_default();
+            // An encode checks the size limits when it starts, so they're checked here too:
+            if input_too_small(size, &self.cli.minimum_size)?
+                || input_too_large(size, &self.cli.maximum_size)?
+            {
+                continue;
+            }
+            log::info!("Analyzing the audio of {:?}", input.path);
+            let streams: Vec<StreamInfo> = match self.get_output_audio_streams(&input).await {
+                Some(streams) => streams,
+                None => input.get_audio_streams().await.unwrap_or_default(),
+            };
+            let bitrates = input.get_audio_bitrates().await.unwrap_or_default();
+            let audio_args = self.get_audio_args(&input).await;
+            let actions = audio_args.actions.for_streams(streams.len());
+            // --normalize-audio already measured the loudness:
+            let measured = audio_args.loudness.first().map(|loudness| loudness.input_i);
+            let loudness = match streams.first() {
+                _ if measured.is_some() => measured,
+                Some(stream) if !opts.no_loudness => {
+                    match input.measure_loudness(stream.index).await {
+                        Ok(loudness) => Some(loudness.input_i),
+                        Err(err) => {
+                            log::warn!("Could not measure the loudness of {:?}: {err}", input.path);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            files.push(AudioFileReport {
+                path: input.path.to_string_lossy().into_owned(),
+                size,
+                loudness,
+                streams: streams
+                    .into_iter()
+                    .zip(actions)
+                    .map(|(stream, action)| AudioStreamReport {
+                        index: stream.index,
+                        bitrate: bitrates.get(&stream.index).copied(),
+                        codec: stream.codec,
+                        channels: stream.channels,
+                        action,
+                    })
+                    .collect(),
+            });
+        }
+        print!(
+            "{}",
+            render_audio_report(&files, &AudioCopyPolicy::new(&self.cli))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Chapters for `--auto-chapters`. They are written to an ffmetadata file, which is given to
+//! ffmpeg as another input so its chapters can be mapped to the output.
+
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+
+/// The scene change score, from 0 to 1, for a cut to count as a major scene change.
+pub const SCENE_THRESHOLD: f64 = 0.4;
+/// Scene changes closer together than this don't start another chapter.
+pub const MIN_SCENE_CHAPTER_SECONDS: f64 = 120.0;
+
+/// Where `--auto-chapters` puts the chapters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoChapters {
+    /// A chapter every this many minutes.
+    Interval(f64),
+    /// A chapter at each major scene change.
+    Scene,
+}
+
+impl AutoChapters {
+    /// Parse "scene", or a number of minutes like "10".
+    pub fn parse(input: &str) -> Result<Self> {
+        let msg = "The chapters must be \"scene\" or a number of minutes, like \"10\"";
+        if input.trim().eq_ignore_ascii_case("scene") {
+            return Ok(Self::Scene);
+        }
+        let minutes = input.trim().parse::<f64>().context(msg)?;
+        if !minutes.is_finite() || minutes <= 0.0 {
+            bail!(msg);
+        }
+        Ok(Self::Interval(minutes))
+    }
+}
+
+/// The chapter start times, in seconds, for a chapter every `minutes` minutes.
+pub fn interval_chapters(duration: f64, minutes: f64) -> Vec<f64> {
+    let interval = minutes * 60.0;
+    (0..)
+        .map(|i| i as f64 * interval)
+        .take_while(|&start| start == 0.0 || start < duration)
+        .collect()
+}
+
+/// Get the times of the scene changes from the `showinfo` lines ffmpeg prints to stderr.
+pub fn parse_scene_times(output: &str) -> Vec<f64> {
+    output
+        .lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| line.split_once("pts_time:"))
+        .filter_map(|(_, rest)| rest.split_whitespace().next()?.parse().ok())
+        .collect()
+}
+
+/// The chapter start times, in seconds, for these scene changes. The first chapter starts at
+/// the beginning, and scene changes too soon after the last chapter start are skipped.
+pub fn scene_chapters(scene_times: &[f64], duration: f64) -> Vec<f64> {
+    let mut starts = vec![0.0];
+    for &time in scene_times {
+        if time - starts.last().unwrap() >= MIN_SCENE_CHAPTER_SECONDS
+            && duration - time >= MIN_SCENE_CHAPTER_SECONDS
+        {
+            starts.push(time);
+        }
+    }
+    starts
+}
+
+/// Write the chapters in ffmpeg's metadata file format. Each chapter ends where the next starts,
+/// and the last at the end of the video.
+pub fn to_ffmetadata(starts: &[f64], duration: f64) -> String {
+    let mut metadata = String::from(";FFMETADATA1\n");
+    let to_ms = |seconds: f64| (seconds * 1000.0).round() as u64;
+    for (i, start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(duration);
+        let _ = write!(
+            metadata,
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle=Chapter {}\n",
+            to_ms(*start),
+            to_ms(end),
+            i + 1
+        );
+    }
+    metadata
+}
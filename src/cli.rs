@@ -0,0 +1,1246 @@
+//! The command line options.
+
+use std::{
+    cmp::max,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use rand::{rngs::StdRng, SeedableRng};
+use regex::Regex;
+
+use crate::{
+    parse_bitrate, parse_fraction, parse_pattern_file, parse_since, parse_size, parse_speed,
+    parse_timestamp, video_extensions_regex, AudioCopyThreshold, AudioPolicy, AutoChapters,
+    Denoise, EditionPolicy, HwDecode, InputUrl, ListFormat, LosslessAudioPolicy, OutputCollision,
+    OutputUrl, QueueOrder, ReportFormat, SubHandling, SubtitleSelector, SyncTool, TooLargePolicy,
+    Trim, DEFAULT_VIDEO_EXTENSIONS,
+};
+
+pub const ENCODED: &str = "encoded";
+
+#[derive(PartialEq, std::fmt::Debug)]
+pub enum Codec {
+    Av1,
+    H265,
+    H264,
+    ProRes,
+    Dnxhr,
+    Ffv1,
+    Copy,
+}
+
+impl Codec {
+    /// The highest (worst quality) CRF the encoder accepts.
+    pub fn max_crf(&self) -> u8 {
+        match self {
+            Codec::Av1 => 63,
+            _ => 51,
+        }
+    }
+
+    /// The name of the ffmpeg encoder used for this codec, if any.
+    pub fn encoder_name(&self) -> Option<&'static str> {
+        match self {
+            Codec::Av1 => Some("libaom-av1"),
+            Codec::H265 => Some("libx265"),
+            Codec::H264 => Some("libx264"),
+            Codec::ProRes => Some("prores_ks"),
+            Codec::Dnxhr => Some("dnxhd"),
+            Codec::Ffv1 => Some("ffv1"),
+            Codec::Copy => None,
+        }
+    }
+
+    /// Whether the quality is set by a CRF. The mezzanine codecs are set by a profile instead,
+    /// and FFV1 is always lossless.
+    pub fn uses_crf(&self) -> bool {
+        matches!(self, Codec::Av1 | Codec::H265 | Codec::H264)
+    }
+}
+
+/// A high bitrate, intra-frame format for `--mezzanine`, for video that will be edited.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Mezzanine {
+    /// Apple ProRes 422 HQ.
+    Prores,
+    /// Avid DNxHR HQX, which is 10-bit 4:2:2 like ProRes 422 HQ.
+    Dnxhr,
+}
+
+/// A table of CRFs by resolution, for `--crf-map`, from the highest resolution to the lowest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrfMap(pub Vec<(u32, u8)>);
+
+impl CrfMap {
+    /// Parse a table like "2160=26,1080=22,720=20".
+    pub fn parse(input: &str) -> Result<Self> {
+        let msg =
+            "The CRF map must be a list of resolution=CRF pairs, like \"2160=26,1080=22,720=20\"";
+        let mut entries = Vec::new();
+        for entry in input
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (resolution, crf) = entry.split_once('=').context(msg)?;
+            let resolution = resolution
+                .trim()
+                .trim_end_matches(['p', 'P'])
+                .parse::<u32>()
+                .context(msg)?;
+            entries.push((resolution, crf.trim().parse::<u8>().context(msg)?));
+        }
+        if entries.is_empty() {
+            bail!(msg);
+        }
+        entries.sort_by_key(|&(resolution, _)| std::cmp::Reverse(resolution));
+        Ok(Self(entries))
+    }
+
+    /// Get the CRF for a video of this size: the entry for the highest resolution it reaches, or
+    /// else the lowest one. A video reaches a resolution if its shorter side is at least that
+    /// much, or its longer side is as wide as 16:9 video of that resolution, so letterboxed
+    /// 1920x800 video counts as 1080p.
+    pub fn get_crf(&self, width: u32, height: u32) -> u8 {
+        let (long_side, short_side) = (max(width, height), width.min(height));
+        self.0
+            .iter()
+            .find(|(resolution, _)| short_side >= *resolution || long_side >= resolution * 16 / 9)
+            .or(self.0.last())
+            .map(|(_, crf)| *crf)
+            .expect("The CRF map is not empty")
+    }
+}
+
+/// The CRF of `--near-lossless`, unless `--crf` is given.
+pub const NEAR_LOSSLESS_CRF: u8 = 12;
+
+/// The `--crf-offsets` used by default. Sources in efficient codecs have little redundancy left,
+/// so they need a better CRF to keep their quality.
+pub const DEFAULT_CRF_OFFSETS: &str = "hevc:10=-2,hevc=-1,av1=-2,vp9=-1";
+
+/// Adjustments to the inferred CRF by the source's codec and bit depth, for `--crf-offsets`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrfOffsets(pub Vec<(String, Option<u32>, i8)>);
+
+impl CrfOffsets {
+    /// Parse a table like "hevc:10=-2,hevc=-1,h264=0". The keys are ffprobe's codec names, with
+    /// an optional bit depth.
+    pub fn parse(input: &str) -> Result<Self> {
+        let msg = "The CRF offsets must be a list of codec=offset or codec:bits=offset pairs, like \"hevc:10=-2,vp9=-1\"";
+        let mut entries = Vec::new();
+        for entry in input
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (key, offset) = entry.split_once('=').context(msg)?;
+            let (codec, bits) = match key.split_once(':') {
+                Some((codec, bits)) => (codec, Some(bits.trim().parse::<u32>().context(msg)?)),
+                None => (key, None),
+            };
+            entries.push((
+                codec.trim().to_lowercase(),
+                bits,
+                offset.trim().parse::<i8>().context(msg)?,
+            ));
+        }
+        Ok(Self(entries))
+    }
+
+    /// Get the offset for a source codec and bit depth. An entry with the bit depth is preferred
+    /// over one without it.
+    pub fn get_offset(&self, codec: &str, bits: u32) -> i8 {
+        let matching = |with_bits: bool| {
+            self.0.iter().find(|(entry_codec, entry_bits, _)| {
+                entry_codec == codec
+                    && entry_bits.is_some() == with_bits
+                    && entry_bits.is_none_or(|b| b == bits)
+            })
+        };
+        matching(true)
+            .or_else(|| matching(false))
+            .map_or(0, |(_, _, offset)| *offset)
+    }
+}
+
+// TODO: the encode dir is unnecessary if both --include and -o are specified
+#[derive(Parser, Clone, Default)]
+pub struct Cli {
+    /// Set the quality level (for either encoded). The default is 24 for AV1 and 22 for H265, but
+    /// if unspecified, a better CRF may be used for small videos, or a lower quality CRF may be
+    /// used for animation.
+    #[clap(long)]
+    pub crf: Option<u8>,
+
+    /// Choose the CRF by the resolution of each video, from a table like "2160=26,1080=22,720=20".
+    /// Each video uses the CRF of the highest resolution it reaches, or else the lowest one. The
+    /// CRFs are used as they are, without adjusting for small videos or animation.
+    #[clap(long, value_parser = CrfMap::parse, conflicts_with_all = ["crf", "copy_streams"])]
+    pub crf_map: Option<CrfMap>,
+
+    /// Skip videos that ffprobe can't read the dimensions or video stream of, instead of choosing
+    /// their CRF without them. Either way, the videos that couldn't be probed are counted in the
+    /// summary.
+    #[clap(long)]
+    pub strict_probe: bool,
+
+    /// Adjust the inferred CRF by the source's codec and bit depth, with a table like
+    /// "hevc:10=-2,hevc=-1,h264=0". The codecs are named as ffprobe shows them. The default is
+    /// "hevc:10=-2,hevc=-1,av1=-2,vp9=-1". This isn't used with `--crf` or `--crf-map`.
+    #[clap(long, value_parser = CrfOffsets::parse, conflicts_with_all = ["crf", "crf_map"])]
+    pub crf_offsets: Option<CrfOffsets>,
+
+    /// Use x265 instead of aom-av1. This is the default.
+    #[clap(long, alias = "h265", conflicts_with_all = ["av1", "reference"])]
+    pub x265: bool,
+
+    /// Use x264 to make a high quality (high disk space) fast encode.
+    #[clap(long, conflicts_with_all = ["av1", "x265"])]
+    pub reference: bool,
+
+    /// Use libaom-av1 for encoding.
+    #[clap(long = "av1", aliases = ["aom", "libaom", "aom-av1"])]
+    pub av1: bool,
+
+    /// Make an intra-frame ProRes or DNxHR video in a .mov file, for editing rather than
+    /// archiving. The outputs are much larger than the inputs. The audio is stored as PCM, and
+    /// the subtitles are dropped.
+    #[clap(long, value_enum, conflicts_with_all = ["av1", "x265", "reference", "for_tv", "copy_streams", "crf",
+        "crf_map", "maxrate", "tune", "expected_size"])]
+    pub mezzanine: Option<Mezzanine>,
+
+    /// Encode without any loss, for archiving masters like digitized home videos. This uses
+    /// x265's lossless mode, or FFV1 with `--ffv1`. The video isn't scaled or converted to
+    /// another pixel format, and the audio is copied. The outputs are usually larger than lossy
+    /// inputs. Filters that change the picture, like `--autocrop` or `--speed`, can't be used.
+    #[clap(long, default_value_if("ffv1", "true", "true"), conflicts_with_all = ["av1", "reference", "for_tv",
+        "mezzanine", "crf", "crf_map", "height_720p", "eight_bit", "expected_size", "autocrop", "denoise",
+        "grain_prefilter", "tonemap_sdr", "speed", "max_fps", "label_overlay", "stabilize", "burn_sub"])]
+    pub lossless: bool,
+
+    /// Encode losslessly with FFV1, in a .mkv file. Implies `--lossless`.
+    // The conflicts of --lossless aren't checked when it's implied, so they're repeated here:
+    #[clap(long, conflicts_with_all = ["x265", "autocrop", "denoise", "grain_prefilter", "tonemap_sdr", "speed",
+        "max_fps", "label_overlay", "stabilize", "burn_sub"])]
+    pub ffv1: bool,
+
+    /// Encode with x265 at a very low CRF (12 unless `--crf` is given), at the source resolution,
+    /// for archiving masters with less space than `--lossless` needs. Like with `--lossless`,
+    /// filters that change the picture can't be used.
+    #[clap(long, conflicts_with_all = ["lossless", "av1", "reference", "for_tv", "mezzanine", "crf_map",
+        "height_720p", "expected_size", "autocrop", "denoise", "grain_prefilter", "tonemap_sdr", "speed", "max_fps",
+        "label_overlay", "stabilize", "burn_sub"])]
+    pub near_lossless: bool,
+
+    /// Use settings that work well for anime or animation.
+    #[clap(long = "animation", alias = "anime",
+        default_value_ifs = [
+            ("anime_slow_well_lit", "true", "true"),
+            ("anime_mixed_dark_battle", "true", "true"),
+        ],
+    )]
+    pub anime: bool,
+
+    /// Use this setting for slow well lit anime, like slice of life:
+    #[clap(long, conflicts_with_all = ["av1", "anime_mixed_dark_battle", "reference"])]
+    pub anime_slow_well_lit: bool,
+
+    /// Use this setting for anime with some dark scenes, some battle scenes (shonen, historical, etc.)
+    #[clap(long, conflicts_with_all = ["av1", "anime_slow_well_lit", "reference"])]
+    pub anime_mixed_dark_battle: bool,
+
+    /// Encode this many videos in parallel. The default varies per encoder.
+    #[clap(long, short, alias = "max-jobs")]
+    pub jobs: Option<usize>,
+
+    /// Encode as 720p. Otherwise the video will be 1080p. The source size is taken into
+    /// consideration; in no case is a video scaled up.
+    #[clap(long = "720p")]
+    pub height_720p: bool,
+
+    /// Encode as 8-bit.  Otherwise the video will be 10-bit, except if creating
+    /// a file as reference or for TV. However, this depends on the compilation
+    /// options of the encoder.
+    #[clap(
+        long = "8-bit",
+        alias = "8bit",
+        default_value_if("for_tv", "true", "true"),
+        default_value_if("reference", "true", "true")
+    )]
+    pub eight_bit: bool,
+
+    /// Fail if the encoder can't do what was asked, instead of falling back. For example, if the
+    /// encoder was built without 10-bit support, the videos are normally encoded as 8-bit with a
+    /// warning.
+    #[clap(long)]
+    pub strict_capabilities: bool,
+
+    /// Limit the peak video bitrate, for example "10M" or "5000k", so the output streams well on
+    /// constrained devices or networks. This applies to any codec. The default is 10M with
+    /// `--for-tv`.
+    #[clap(long, value_parser = parse_bitrate, default_value_if("for_tv", "true", "10M"))]
+    pub maxrate: Option<u64>,
+
+    /// The rate control buffer size used with `--maxrate`, for example "16M". The default is
+    /// twice the max rate.
+    #[clap(long, value_parser = parse_bitrate, requires = "maxrate")]
+    pub bufsize: Option<u64>,
+
+    /// Speed up (or slow down) the video and audio by this factor, for example "2x" to make a
+    /// sped-up copy of a lecture or dashcam recording. Soft subtitles are dropped since their
+    /// timing would no longer match. The output name can include it with {speed}.
+    #[clap(long, value_parser = parse_speed, conflicts_with_all = ["copy_streams", "copy_audio"])]
+    pub speed: Option<f64>,
+
+    /// Start encoding at this time in each video, like "90", "1:30" or "1:02:03.5".
+    #[clap(long, value_parser = parse_timestamp)]
+    pub start: Option<f64>,
+
+    /// Stop encoding at this time in each video. This is a time in the input, not a length.
+    #[clap(long, value_parser = parse_timestamp)]
+    pub end: Option<f64>,
+
+    /// Encode only this much of each video, from `--start` or the beginning.
+    #[clap(long, value_parser = parse_timestamp, conflicts_with = "end")]
+    pub duration: Option<f64>,
+
+    /// Stabilize shaky footage. This analyzes each video in a separate pass before encoding, so
+    /// it roughly doubles the time taken.
+    #[clap(long, conflicts_with = "copy_streams")]
+    pub stabilize: bool,
+
+    /// Keep surround sound audio streams as surround when reencoding them, instead of mixing them
+    /// down to stereo. Their bitrate is increased accordingly.
+    #[clap(long)]
+    pub keep_surround: bool,
+
+    /// The bitrate for reencoded stereo audio, like "160k". Surround audio gets proportionally
+    /// more. Audio streams are copied instead of reencoded if their bitrate is not much higher
+    /// than this. The default is 128k, or 192k with `--for-tv`.
+    #[clap(long, value_parser = parse_bitrate)]
+    pub audio_bitrate: Option<u64>,
+
+    /// Copy audio streams whose bitrate is at most this, like "256k", instead of reencoding them.
+    /// A threshold per channel, like "96k/ch", suits sources with various channel counts. The
+    /// default scales with `--audio-bitrate`: 200k for the default 128k. `jiffy audio-report`
+    /// shows which streams this copies.
+    #[clap(long, value_parser = AudioCopyThreshold::parse)]
+    pub audio_copy_threshold: Option<AudioCopyThreshold>,
+
+    /// Which audio streams to copy instead of reencoding: the ones within
+    /// `--audio-copy-threshold` ("copy-if-efficient"), none ("always-encode"), or all the ones
+    /// the output can hold ("always-copy").
+    #[clap(long, value_enum, default_value_t)]
+    pub audio_policy: AudioPolicy,
+
+    /// What to do with lossless audio streams, like FLAC, TrueHD or DTS-HD MA: "keep" them as
+    /// they are, "encode" them like other audio, or encode them with a "warn"ing. They are encoded
+    /// regardless when audio filters are needed, like with `--speed`.
+    #[clap(long, value_enum, default_value_t)]
+    pub audio_lossless: LosslessAudioPolicy,
+
+    /// Normalize the audio loudness with ffmpeg's `loudnorm` filter. Each audio stream is measured
+    /// first, so the whole file is adjusted evenly instead of being compressed on the fly. This
+    /// requires reencoding the audio.
+    #[clap(long, conflicts_with_all = ["copy_streams", "copy_audio", "no_audio"])]
+    pub normalize_audio: bool,
+
+    /// Only keep audio streams in these languages, for example "eng,jpn". The languages are
+    /// matched against the streams' language tags, which are usually three letter codes. If no
+    /// audio stream matches, they are all kept. This has no effect with `--no-map-0`.
+    #[clap(long, value_delimiter = ',')]
+    pub audio_lang: Vec<String>,
+
+    /// Drop commentary audio streams, which are recognized by titles like "Commentary" or
+    /// "Director's commentary". This has no effect with `--no-map-0`.
+    #[clap(long)]
+    pub drop_commentary: bool,
+
+    /// Save the text subtitle streams as .srt files next to each output, named like
+    /// "<output name>.eng.srt". This is useful for formats like mp4 that can't hold every kind of
+    /// subtitle. Image based subtitles are not extracted.
+    #[clap(long)]
+    pub extract_subs: bool,
+
+    /// Add the subtitle files next to each video, like "movie.en.srt" or "movie.ass", to the
+    /// output as soft subtitles. The language and forced flag are taken from the file name. Only
+    /// mkv outputs get them, since mp4 can't hold these formats.
+    #[clap(long, conflicts_with_all = ["for_tv", "burn_sub", "speed", "no_map_0"])]
+    pub mux_external_subs: bool,
+
+    /// The directories next to a video to look for its subtitle files in, for `--for-tv` and
+    /// `--mux-external-subs`. The names are matched loosely, and a subdirectory named after the
+    /// video is searched as well.
+    #[clap(long, value_delimiter = ',', default_value = "Subs,Subtitles")]
+    pub sub_dirs: Vec<String>,
+
+    /// Add chapters to the output, either every this many minutes, or at the major scene changes
+    /// with "scene", for long recordings like lectures. These replace the chapters of the input.
+    /// Finding the scene changes takes an extra pass over the video.
+    #[clap(long, value_parser = AutoChapters::parse)]
+    pub auto_chapters: Option<AutoChapters>,
+
+    /// Don't copy the attachments, like the fonts used by ASS subtitles, to mkv outputs. Without
+    /// the fonts, styled subtitles may be shown in the wrong font.
+    #[clap(long)]
+    pub no_attachments: bool,
+
+    /// What to do with subtitles when the output is mp4 or mov, which can't hold SRT or ASS
+    /// subtitles as they are: "convert" text subtitles to mov_text and drop image based ones,
+    /// "drop" them all, or "burn" one into the video, chosen as with `--burn-sub`.
+    #[clap(long, value_enum, default_value_t)]
+    pub sub_handling: SubHandling,
+
+    /// Burn this subtitle stream into the video. It can be "lang=eng", "index=2" (the stream index
+    /// shown by ffprobe), "forced" for a stream flagged as forced, or a combination like
+    /// "lang=eng,forced". Otherwise full subtitles are preferred over forced ones. If this isn't
+    /// given, `--for-tv` burns a forced subtitle stream, or else the first one. Image based
+    /// subtitles can't be burned in.
+    #[clap(long, value_parser = SubtitleSelector::parse, conflicts_with = "copy_streams")]
+    pub burn_sub: Option<SubtitleSelector>,
+
+    /// Wait for other ffmpeg jobs to cease, so there are `--jobs` total ffmpeg
+    /// processes, not more. This allows a jiffy instance to wait for another,
+    /// without needing all its jobs to finish before starting. Instances with this option also
+    /// count each other's encodes between one ffmpeg process and the next.
+    #[clap(long, aliases = ["slow-start", "global"])]
+    pub slow_start: bool,
+
+    /// Share a budget of this many ffmpeg processes with other jiffy instances given the same
+    /// number. Unlike `--slow-start`, this doesn't count processes: each encode holds a lock file
+    /// in the temp directory while it runs, including its passes like `--autocrop`. Manual ffmpeg runs can join the budget with
+    /// `jiffy --global-jobs N slot -- ffmpeg ...`.
+    #[clap(long, conflicts_with = "slow_start")]
+    pub global_jobs: Option<NonZeroUsize>,
+
+    /// Before starting each encode, wait until the output's filesystem has at least this much
+    /// free space, like "10G". If there is no suffix, it's taken to mean megabytes.
+    #[clap(long, value_parser = parse_size)]
+    pub min_free_space: Option<u64>,
+
+    /// The encoding preset to use--by default this is fairly slow. By default, "5" for libaom,
+    /// "slow" for x265.
+    #[clap(
+        long,
+        hide_default_value = true,
+        default_value = "slow",
+        default_value_if("reference", "true", "veryfast"),
+        default_value_if("av1", "true", "5"),
+        default_value_if("eco", "true", "fast"),
+        default_value_if("for_tv", "true", "fast")
+    )]
+    pub preset: String,
+
+    /// Encode quietly and in the background, like on a living room computer: one job at a time
+    /// with `--slow-start` (or within `--global-jobs`, if it's given), a fast x265 preset, only a few encoder threads, and ffmpeg at the
+    /// lowest CPU and disk priority (with nice and ionice, where they're available). Options
+    /// given explicitly, like `--jobs` or `--preset`, still apply.
+    #[clap(long)]
+    pub eco: bool,
+
+    /// Tune the encoder for a kind of source, for example "grain" for grainy film or "animation".
+    /// x264 and x265 support grain, animation, fastdecode, zerolatency, psnr and ssim (and x264
+    /// also supports film and stillimage). libaom supports psnr and ssim.
+    #[clap(long, conflicts_with = "copy_streams")]
+    pub tune: Option<String>,
+
+    /// Use AV1 film grain synthesis at this level (1-50): the encoder removes the grain and
+    /// describes it instead, so it's regenerated on playback. This greatly improves compression of
+    /// noisy sources like film scans. Around 8 is a good start for light grain.
+    #[clap(long, requires = "av1", value_parser = clap::value_parser!(u8).range(1..=50))]
+    pub grain_synth: Option<u8>,
+
+    /// If a video is HDR, tone map it to SDR, so it doesn't look washed out on SDR displays. This
+    /// is useful with `--for-tv` and `--reference`. ffmpeg must be built with zimg (libzimg).
+    #[clap(long, conflicts_with = "copy_streams")]
+    pub tonemap_sdr: bool,
+
+    /// Decode the video in hardware, even when encoding in software. "auto" lets ffmpeg pick a
+    /// decoder. If an encode fails, like for a profile the hardware can't decode, the video is
+    /// encoded again with software decoding, which the report notes.
+    #[clap(long, value_enum, default_value_t, conflicts_with = "copy_streams")]
+    pub hwdecode: HwDecode,
+
+    /// The device to decode on with `--hwdecode`, like "/dev/dri/renderD128" for VAAPI or "0" for
+    /// the first NVIDIA GPU. By default, ffmpeg picks one.
+    #[clap(long, requires = "hwdecode")]
+    pub hwdecode_device: Option<String>,
+
+    /// Reduce the frame rate of videos above this many frames per second, like 60 fps screen
+    /// recordings to 30. Videos at or below it are left alone.
+    #[clap(long, conflicts_with = "copy_streams", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_fps: Option<u32>,
+
+    /// Detect black bars by sampling a few points in each video, and crop them off.
+    #[clap(long, conflicts_with = "copy_streams")]
+    pub autocrop: bool,
+
+    /// With `--grain-synth`, also lightly denoise the video before encoding.
+    #[clap(long, requires = "grain_synth")]
+    pub grain_prefilter: bool,
+
+    /// Denoise the video before scaling and encoding. This helps a lot with noisy sources like
+    /// old camcorder footage. "heavy" is much slower than the others.
+    #[clap(long, value_enum, conflicts_with_all = ["grain_prefilter", "copy_streams"])]
+    pub denoise: Option<Denoise>,
+
+    /// Overwrite existing output files
+    // TODO: integration test that --overwrite and --noop still does not overwrite files
+    #[clap(long)]
+    pub overwrite: bool,
+
+    /// Don't check if the audio streams are within acceptable limits--just reencode them (unless
+    /// `--copy-audio` was specified). This saves a little time in some circumstances.
+    #[clap(
+        long = "skip-bitrate-check",
+        default_value_if("copy_streams", "true", "true"),
+        default_value_if("no_audio", "true", "true")
+    )]
+    pub skip_audio_bitrate_check: bool,
+
+    /// Encode the videos in this directory. By default, encode in the current directory. Output
+    /// files are put in "video_root/encoded". If the given path ends in "encoded", the real video
+    /// root is taken to be the parent directory.
+    #[clap(default_value = ".", hide_default_value = true)]
+    pub video_root: PathBuf,
+
+    /// More directories to encode, like ones on other drives. Each one's outputs go in its own
+    /// "encoded" directory, unless `--output-dir` is given, and all the videos share the jobs.
+    /// Pruning and `--diff` only look at the first video root's output directory, but with
+    /// `--output-dir`, its outputs' sources are looked for in all the video roots.
+    pub more_video_roots: Vec<PathBuf>,
+
+    /// Paths (usually glob patterns) that can be excluded. They match from the video encode root.
+    /// For example, "*S01*/*E01*" might be used to skip the first episode of a TV show, and
+    /// "**/*E01*" would skip the first episode of each season. This argument must be given once
+    /// per exclude pattern.  See the `--include` option.
+    #[clap(long)]
+    pub exclude: Vec<String>,
+
+    /// Paths (usually glob patterns) to be included; all others are excluded. They match from the
+    /// video encode root. If `--include` and `--exclude` are both given, only those that are
+    /// matched by the include globs and not matched by the exclude globs will be encoded.  See the
+    /// `--exclude` option.
+    #[clap(long)]
+    pub include: Vec<String>,
+
+    /// Read `--exclude` patterns from this file, one per line. Blank lines and lines starting
+    /// with "#" are skipped. This can be given more than once, and together with `--exclude`.
+    #[clap(long)]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Read `--include` patterns from this file, one per line, like `--exclude-from`.
+    #[clap(long)]
+    pub include_from: Vec<PathBuf>,
+
+    /// Encode videos even if they have a skip marker. A video is never encoded while a file named
+    /// like it with ".jiffyskip" added, like "video.mkv.jiffyskip", is next to it. The marker can
+    /// say why, which is logged.
+    #[clap(long)]
+    pub ignore_skip_markers: bool,
+
+    /// Match the `--include` and `--exclude` globs without regard to case, so "*.mkv" also
+    /// matches "EPISODE.MKV".
+    #[clap(long)]
+    pub ignore_case: bool,
+
+    /// Regexes of videos to exclude, matched against the path from the video encode root, like
+    /// "E0[5-9]|E1[0-2]". A path is excluded if any regex matches part of it; use "^" and "$" to
+    /// match the whole path. This can be given more than once, and together with `--exclude`.
+    #[clap(long, value_parser = Regex::new)]
+    pub exclude_re: Vec<Regex>,
+
+    /// Regexes of videos to include, matched against the path from the video encode root; all
+    /// other videos are excluded. Directories are always searched. See `--exclude-re`.
+    #[clap(long, value_parser = Regex::new)]
+    pub include_re: Vec<Regex>,
+
+    /// Encode the videos listed in this file, one per line, instead of searching the video root,
+    /// like the output of `find`. Use "-" to read the list from stdin. The videos must be in the
+    /// video root, so their outputs go to the same places in the output directory.
+    #[clap(long, conflicts_with_all = ["include", "include_from", "include_re", "input_url"])]
+    pub files_from: Option<PathBuf>,
+
+    /// Print a report at the end of the run, with a table of the results and the space saved, as
+    /// Markdown ("md") or HTML.
+    #[clap(long, value_enum)]
+    pub report_format: Option<ReportFormat>,
+
+    /// Print the effective value of every option, and whether it came from a default, an
+    /// environment variable or a flag, then exit without encoding.
+    #[clap(long)]
+    pub print_config: bool,
+
+    /// Measure the CPU time and peak memory of each encode's ffmpeg, and add them to the
+    /// report, to compare what the presets and codecs cost.
+    #[clap(long)]
+    pub resource_stats: bool,
+
+    /// Skip the videos that jiffy encoded, which it marks with a "jiffy" metadata tag, so a
+    /// directory of both sources and outputs can be encoded again. This probes every video.
+    #[clap(long)]
+    pub skip_jiffy_outputs: bool,
+
+    /// Skip the videos whose video stream is already in one of these codecs, like "hevc,av1", since
+    /// encoding them again loses quality for little gain. This probes every video. The list is
+    /// given with "=", like `--skip-if-codec=hevc,av1`. Without a list, the codecs as efficient as
+    /// the one being encoded to are skipped: hevc and av1 for x265, and av1 for AV1.
+    #[clap(long, value_delimiter = ',', num_args = 0..=1, require_equals = true, default_missing_value = "auto")]
+    pub skip_if_codec: Vec<String>,
+
+    /// The extensions of the files to encode, like "mp4,mkv,ts,m2ts,mts". By default, the common
+    /// video extensions are used, not including transport streams.
+    #[clap(long, value_delimiter = ',')]
+    pub extensions: Vec<String>,
+
+    /// Only encode files modified after this time. It can be a date like 2024-03-01 or
+    /// "2024-03-01 18:30", or a duration before now like 7d, 12h, 30m or 2w.
+    #[clap(long, alias = "newer-than", value_parser = parse_since)]
+    pub since: Option<SystemTime>,
+
+    /// Only encode files modified before this time, in the same forms as `--since`. For example,
+    /// `--older-than 1h` leaves files that may still be being copied in.
+    #[clap(long, value_parser = parse_since)]
+    pub older_than: Option<SystemTime>,
+
+    /// Only look for videos this many directory levels below the video root. With 0, only the
+    /// videos directly in the video root are encoded.
+    #[clap(long, conflicts_with = "no_recurse")]
+    pub max_depth: Option<usize>,
+
+    /// Only encode the videos directly in the video root, not those in its subdirectories. This
+    /// is the same as `--max-depth 0`.
+    #[clap(long)]
+    pub no_recurse: bool,
+
+    /// Follow symlinks to videos and directories while looking for videos. This is the default.
+    /// A directory or video reached by more than one path is only used once.
+    #[clap(long, overrides_with = "no_follow_symlinks")]
+    pub follow_symlinks: bool,
+
+    /// Skip symlinks while looking for videos.
+    #[clap(long, overrides_with = "follow_symlinks")]
+    pub no_follow_symlinks: bool,
+
+    /// Encode a certain number of files, then stop.
+    #[clap(long)]
+    pub limit: Option<usize>,
+
+    /// What to do when several editions of a title (like "Director's Cut" and "Extended") are
+    /// found next to each other, by their file names: encode all of them, or only the newest or
+    /// smallest one.
+    #[clap(long, value_enum, default_value_t)]
+    pub editions: EditionPolicy,
+
+    /// The order to encode the videos in. "size-desc" saves the most space soonest, and "random"
+    /// mixes series together, repeatably with `--seed`. `--limit` applies after sorting.
+    #[clap(long, value_enum, default_value_t)]
+    pub order: QueueOrder,
+
+    /// Encode a random sample of the matched files, like "5%", to estimate the savings or check
+    /// the settings before encoding a whole library. The sample depends on `--seed`.
+    #[clap(long, value_parser = parse_fraction)]
+    pub sample_fraction: Option<f64>,
+
+    /// Seed the random choices jiffy makes, so a run can be repeated. Otherwise a new seed is
+    /// picked for each run and shown with `-v`.
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Make a high quality but inefficient file for low spec televisions. The output is intended
+    /// for watching, not for archival purposes. This is the only option that encodes with x264.
+    /// Subtitles are hard-coded if available. These files should be compatible with Chromecast
+    /// without the need for transcoding.
+    ///
+    // If you find this option is not compatible with your TV, please let me know what model and what encoding
+    // options do work.
+    #[clap(long = "for-tv", conflicts_with_all = ["av1", "x265", "reference", "anime", "anime_slow_well_lit", "anime_mixed_dark_battle"])]
+    pub for_tv: bool,
+
+    /// If a certain size reduction is expected, this option will warn about
+    /// videos that do not reach that target. For example, 75 if file size is
+    /// expected to be reduced by 25%. This option does not affect encoding. Without it, outputs
+    /// larger than their source are warned about.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..100))]
+    pub expected_size: Option<u8>,
+
+    /// If an output file is larger than expected (or larger than the original),
+    /// it will be deleted. This prevents accidentally re-encoding highly
+    /// compressed videos to lower compression, losing quality in the process. Encodes are
+    /// stopped early once the partial output is already too large.
+    #[clap(long, requires("expected_size"))]
+    pub delete_too_large: bool,
+
+    /// When `--delete-too-large` deletes an output, encode the file again with a higher CRF.
+    /// See `--crf-retry-step` and `--max-crf-retries`.
+    #[clap(long, requires("delete_too_large"))]
+    pub retry_too_large: bool,
+
+    /// What to do when an output is projected to be larger than `--expected-size` allows, judged
+    /// a few minutes into each encode: keep encoding ("ignore"), "skip" the file, or "retry" with a
+    /// higher CRF. See `--crf-retry-step` and `--max-crf-retries`.
+    #[clap(long, value_enum, default_value_t, requires = "expected_size")]
+    pub projected_size_policy: TooLargePolicy,
+
+    /// When retrying a file because its output is too large, raise the CRF by this much.
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=20))]
+    pub crf_retry_step: u8,
+
+    /// Retry a file at most this many times because its output is too large.
+    #[clap(long, default_value_t = 2)]
+    pub max_crf_retries: u8,
+
+    /// Outputs smaller than this are taken to be failed encodes, and deleted. If there is no
+    /// suffix, it's taken to mean megabytes.
+    #[clap(long, value_parser = parse_size, default_value = "300b")]
+    pub min_output_size: u64,
+
+    /// Warn about outputs that are smaller than `--expected-size` divided by this ratio, since the
+    /// encode may have gone wrong. Sources like screen recordings can legitimately shrink much
+    /// more than usual, so this can be raised, or set to 0 to disable the warning.
+    #[clap(long, default_value_t = 3.0)]
+    pub suspect_small_ratio: f64,
+
+    /// Skip files that another program has open for writing, such as a recording that isn't finished. This
+    /// is always done on Windows; on Linux, it searches the open files of all processes, so it must be enabled.
+    #[clap(long)]
+    pub skip_open_files: bool,
+
+    /// Files smaller than this size will be skipped. If there is no suffix,
+    /// it's taken to mean megabytes.
+    #[clap(long)]
+    pub minimum_size: Option<String>,
+
+    /// Skip videos shorter than this, like "30" or "1:30". This probes the length of every video.
+    #[clap(long, value_parser = parse_timestamp)]
+    pub min_duration: Option<f64>,
+
+    /// Skip videos longer than this, like "10:00". This probes the length of every video.
+    #[clap(long, value_parser = parse_timestamp)]
+    pub max_duration: Option<f64>,
+
+    /// Files with a lower overall bitrate than this, like "1500k", will be skipped, since they're
+    /// already compressed well. If there is no suffix, it's taken to mean bits per second.
+    #[clap(long, value_parser = parse_bitrate)]
+    pub minimum_bitrate: Option<u64>,
+
+    /// Files larger than this size will be skipped, for example "20G" to leave Blu-ray remuxes
+    /// for a separate run. If there is no suffix, it's taken to mean megabytes.
+    #[clap(long, alias = "max-size")]
+    pub maximum_size: Option<String>,
+
+    /// Output files will be written with this name. Fields that will be filled:
+    /// {preset}, {basename}, {crf}, {speed}, {dir} (the input's directory within the video root,
+    /// like "Show - Season 1"), {hash} (8 hex digits that are unique to the input's path),
+    /// {original_ext} (the input's extension, like "avi")
+    /// For example: --output-name "{basename}-crf{crf}"
+    #[clap(long, aliases = ["output-format", "name-format", "naming-format"])]
+    pub output_name: Option<String>,
+
+    /// What to do when videos would write the same output, like "show.mkv" and "show.avi": fail
+    /// before anything is encoded, or rename the outputs with the input's extension.
+    #[clap(long, value_enum, default_value_t)]
+    pub output_collision: OutputCollision,
+
+    /// Log files will be written with this name, within the output directory. Fields that will be
+    /// filled: {relpath} (the input path within the video root), {basename} (the same without the
+    /// extension), and the {date} and {time} the encode started. The default is "{relpath}.log",
+    /// so each encode of an input appends to the same log. For example:
+    /// --log-name "logs/{date}/{relpath}.{time}.log"
+    #[clap(long)]
+    pub log_name: Option<String>,
+
+    /// Draw a small text label on the video, so review copies can be told apart. The fields of
+    /// `--output-name` are filled, for example: --label-overlay "{basename} crf{crf}"
+    #[clap(long, conflicts_with = "copy_streams")]
+    pub label_overlay: Option<String>,
+
+    /// Set the title of the output. The fields of `--output-name` are filled, for example:
+    /// --set-title "{basename}"
+    #[clap(long)]
+    pub set_title: Option<String>,
+
+    /// Write a "<output>.sha256" file next to each output when it's finished, which can be
+    /// checked with `sha256sum --check`.
+    #[clap(long)]
+    pub emit_checksums: bool,
+
+    /// Move each source video into this directory once its output is finished and checked, in
+    /// the same layout as under the video root. The output is checked by its size and length.
+    #[clap(long, conflicts_with = "trash_source")]
+    pub move_source_to: Option<PathBuf>,
+
+    /// Move each source video to the trash once its output is finished and checked. This is only
+    /// supported on Linux and similar systems.
+    #[clap(long)]
+    pub trash_source: bool,
+
+    /// Copy each output to this target when it's finished, like "remote:videos" for rclone or
+    /// "host:/backup/videos" for rsync. The outputs keep their paths within the output directory.
+    /// The run waits for the copies to finish at the end.
+    #[clap(long)]
+    pub sync_to: Option<String>,
+
+    /// The program that copies outputs for `--sync-to`.
+    #[clap(long, value_enum, default_value_t, requires = "sync_to")]
+    pub sync_tool: SyncTool,
+
+    /// How many outputs to copy at once for `--sync-to`.
+    #[clap(long, default_value = "2", value_parser = clap::value_parser!(u32).range(1..), requires = "sync_to")]
+    pub sync_jobs: u32,
+
+    /// Upload each output to an S3-compatible bucket when it's finished, like
+    /// "s3://bucket/videos", and remove it from the output directory. The object keys follow the
+    /// paths and names in the output directory. Outputs that can't be uploaded are kept. The
+    /// credentials, region and endpoint are read from the AWS_* environment variables. Needs a
+    /// build with the "s3" feature.
+    #[clap(long, value_parser = OutputUrl::parse, conflicts_with = "sync_to")]
+    pub output_url: Option<OutputUrl>,
+
+    /// Fetch the videos from an S3-compatible bucket, like "s3://bucket/uploads", or a WebDAV
+    /// directory, like "https://example.com/dav/uploads", instead of finding them in the video
+    /// root. Each video is fetched into the scratch directory, keeping its path, just before it's
+    /// encoded, and deleted after. The outputs go in the video root's output directory, as usual.
+    /// Needs a build with the "s3" or "webdav" feature.
+    #[clap(long, value_parser = InputUrl::parse)]
+    pub input_url: Option<InputUrl>,
+
+    /// Where the videos from `--input-url` are fetched to before they're encoded. It should be
+    /// a directory that's only used by jiffy, since the fetched videos overwrite files there. By
+    /// default, a new directory in the system's temp directory, removed at the end of the run.
+    #[clap(long, requires = "input_url")]
+    pub scratch_dir: Option<PathBuf>,
+
+    /// Don't copy the metadata of the input, and clear the title and comment, for outputs that
+    /// will be shared. The stream tags, like languages, are dropped as well. The output is still
+    /// marked as jiffy's, for `--skip-jiffy-outputs`.
+    #[clap(long)]
+    pub strip_metadata: bool,
+
+    /// The font file used by `--label-overlay`. By default a common system font is used.
+    #[clap(long, requires = "label_overlay")]
+    pub label_font: Option<PathBuf>,
+
+    /// Output files will be saved in this directory. By default, it is
+    /// <VIDEO_ROOT>/encoded.
+    #[clap(long, short, aliases = ["output-directory", "output-dir", "output-path"])]
+    pub output_dir: Option<PathBuf>,
+
+    /// Write all the outputs directly in the output directory, instead of in the same layout as
+    /// the videos in the video root. {basename} is then just the file name, and the default
+    /// output name adds {hash} so videos with the same name don't collide.
+    #[clap(long)]
+    pub flatten: bool,
+
+    /// Don't encode. Instead compare the files that would be encoded with the output directory,
+    /// and list the outputs that would be added or updated, and the orphaned outputs whose
+    /// source videos no longer exist.
+    #[clap(long)]
+    pub diff: bool,
+
+    /// With `--diff`, delete the orphaned outputs and their logs.
+    #[clap(long, requires = "diff")]
+    pub prune: bool,
+
+    #[command(flatten)]
+    pub test_opts: TestOpts,
+
+    #[command(subcommand)]
+    pub command: Option<Subcommands>,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum Subcommands {
+    /// Remove outputs and logs whose source videos no longer exist. Sources are found from the
+    /// history of previous encodes, or else by matching the output name template.
+    Prune(PruneOpts),
+    /// Run a command, such as a manual ffmpeg, once one of the `--global-jobs` slots is free, and
+    /// hold the slot until it exits.
+    Slot(SlotOpts),
+    /// Print the videos that would be encoded, after all the filters, without encoding them.
+    List(ListOpts),
+    /// Analyze the audio of the videos that would be encoded, and print whether each audio
+    /// stream would be copied or reencoded with the current options. Nothing is encoded.
+    AudioReport(AudioReportOpts),
+}
+
+#[derive(Args, Clone, Default)]
+pub struct AudioReportOpts {
+    /// Don't measure the loudness of each video, which means decoding all of its audio.
+    #[clap(long)]
+    pub no_loudness: bool,
+}
+
+#[derive(Args, Clone, Default)]
+pub struct ListOpts {
+    /// Print just the paths, or the paths with the size, length, codec, dimensions and bitrate
+    /// of each video as JSON or CSV.
+    #[clap(long, value_enum, default_value_t)]
+    pub format: ListFormat,
+}
+
+#[derive(Args, Clone, Default)]
+pub struct SlotOpts {
+    /// The command and its arguments.
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Args, Clone, Default)]
+pub struct PruneOpts {
+    /// Move the files to the trash instead of deleting them.
+    #[clap(long)]
+    pub trash: bool,
+}
+
+#[derive(Args, Clone, Default)]
+#[group(required = false, multiple = true)]
+pub struct TestOpts {
+    /// Run through all logic except invoking ffmpeg.
+    #[clap(long)]
+    pub noop: bool,
+
+    /// Run ffmpeg without `-map 0`. This occasionally fixes an encoding error.
+    #[clap(long, default_value_if("for_tv", "true", "true"))]
+    pub no_map_0: bool,
+
+    /// Keep the audio stream unchanged. This is useful if audio bitrate can't be determined.
+    #[clap(long = "copy-audio", default_value_ifs = [("copy_streams", "true", "true"), ("lossless", "true", "true")])]
+    pub copy_audio: bool,
+
+    /// Copy audio and video streams (don't encode). Used for testing, for example passing
+    /// `--copy-streams --end 30` would copy a 30 second clip from each video. Implies
+    /// `--copy-audio`.
+    #[clap(long = "copy-streams", conflicts_with_all = ["av1", "x265", "reference", "for_tv", "height_720p",
+        "anime", "anime_mixed_dark_battle", "anime_slow_well_lit", "crf", "preset"])]
+    pub copy_streams: bool,
+
+    /// For testing and benchmarking.
+    #[clap(long = "no-audio", conflicts_with = "copy_audio")]
+    pub no_audio: bool,
+
+    /// Add additional ffmpeg flags, such as "-g 240" to set the keyframe interval. Each option
+    /// should be passed separately, for example: `jiffy --extra-flag='-g 240' --extra-flag='-bf 4'`.
+    /// To encode part of a video, use `--start` and `--end` instead.
+    #[clap(long, allow_hyphen_values(true))]
+    pub extra_flag: Vec<String>,
+
+    /// Don't write log files for each ffmpeg invocation. This avoids polluting your output
+    /// directory with a log file per input.
+    #[clap(long, short)]
+    pub no_log: bool,
+
+    /// Can specify -q -q (-qq) to make the program ever more quiet.
+    #[clap(long, short, action = ArgAction::Count)]
+    pub quiet: u8,
+
+    /// Increase the log verbosity.
+    #[clap(long, short, action = ArgAction::Count)]
+    pub verbose: u8,
+}
+
+impl Cli {
+    pub fn get_video_codec(&self) -> Codec {
+        if self.test_opts.copy_streams {
+            Codec::Copy
+        } else if self.ffv1 {
+            Codec::Ffv1
+        } else if let Some(mezzanine) = self.mezzanine {
+            match mezzanine {
+                Mezzanine::Prores => Codec::ProRes,
+                Mezzanine::Dnxhr => Codec::Dnxhr,
+            }
+        } else if self.reference || self.for_tv {
+            Codec::H264
+        } else if self.av1 {
+            Codec::Av1
+        } else {
+            Codec::H265
+        }
+    }
+
+    pub fn get_follow_symlinks(&self) -> bool {
+        !self.no_follow_symlinks
+    }
+
+    /// Whether to wait for other ffmpeg processes, with `--slow-start`. `--eco` implies it, unless
+    /// the jobs are limited by `--global-jobs` instead.
+    pub fn get_slow_start(&self) -> bool {
+        self.slow_start || (self.eco && self.global_jobs.is_none())
+    }
+
+    /// All the video roots: the first one, then any more that were given.
+    pub fn get_video_roots(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.video_root)
+            .chain(&self.more_video_roots)
+            .cloned()
+            .collect()
+    }
+
+    /// The options for the videos in another video root. Only the video root differs.
+    pub fn for_video_root(&self, video_root: &Path) -> Cli {
+        Cli {
+            video_root: video_root.to_owned(),
+            more_video_roots: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Add the patterns from the `--include-from` and `--exclude-from` files to `--include` and
+    /// `--exclude`. A pattern that isn't a valid glob, or a path that exists, is an error.
+    pub fn load_pattern_files(&mut self) -> Result<()> {
+        let video_root = self.video_root.clone();
+        for (files, patterns) in [
+            (&self.include_from, &mut self.include),
+            (&self.exclude_from, &mut self.exclude),
+        ] {
+            for file in files {
+                let text = std::fs::read_to_string(file)
+                    .with_context(|| format!("Could not read the pattern file {file:?}"))?;
+                for pattern in parse_pattern_file(&text) {
+                    let is_path =
+                        Path::new(&pattern).exists() || video_root.join(&pattern).exists();
+                    if let (false, Err(err)) = (is_path, globset::Glob::new(&pattern)) {
+                        let line_number = text
+                            .lines()
+                            .position(|line| line.strip_suffix('\r').unwrap_or(line) == pattern)
+                            .map_or(0, |i| i + 1);
+                        bail!("Invalid pattern on line {line_number} of {file:?}: {err}");
+                    }
+                    patterns.push(pattern);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the video keeps its resolution and pixel format, so nothing is lost but what the
+    /// encoder loses.
+    pub fn keeps_source_picture(&self) -> bool {
+        self.lossless || self.near_lossless
+    }
+
+    /// The regex that matches the extensions of the videos to encode.
+    pub fn get_video_extensions_regex(&self) -> Result<Regex> {
+        if self.extensions.is_empty() {
+            video_extensions_regex(DEFAULT_VIDEO_EXTENSIONS)
+        } else {
+            video_extensions_regex(&self.extensions)
+        }
+    }
+
+    /// How many directory levels below the video root to look for videos, if limited.
+    pub fn get_max_depth(&self) -> Option<usize> {
+        if self.no_recurse {
+            Some(0)
+        } else {
+            self.max_depth
+        }
+    }
+
+    /// The codecs of `--skip-if-codec`, with ffprobe's names.
+    pub fn get_skip_codecs(&self) -> Vec<String> {
+        let mut codecs = Vec::new();
+        for codec in &self.skip_if_codec {
+            match codec.trim().to_lowercase().as_str() {
+                "auto" => codecs.extend(match self.get_video_codec() {
+                    Codec::H265 => vec!["hevc".to_string(), "av1".to_string()],
+                    Codec::Av1 => vec!["av1".to_string()],
+                    Codec::ProRes => vec!["prores".to_string()],
+                    Codec::Dnxhr => vec!["dnxhd".to_string()],
+                    Codec::Ffv1 => vec!["ffv1".to_string()],
+                    Codec::H264 | Codec::Copy => vec![],
+                }),
+                "h265" | "x265" => codecs.push("hevc".to_string()),
+                "x264" | "avc" => codecs.push("h264".to_string()),
+                codec => codecs.push(codec.to_string()),
+            }
+        }
+        codecs
+    }
+
+    pub fn get_verbosity(&self) -> i8 {
+        self.test_opts.verbose as i8 - self.test_opts.quiet as i8
+    }
+
+    /// How many jobs should run in parallel?
+    pub(crate) fn get_jobs(&self) -> Result<usize> {
+        let jobs = match self.jobs {
+            Some(0) => {
+                bail!("Cannot run with 0 jobs.");
+            }
+            None if self.eco => 1,
+            None => {
+                if self.x265 {
+                    max(1, (num_cpus::get_physical() as f64 / 3f64).round() as usize)
+                } else {
+                    max(1, num_cpus::get_physical() / 2)
+                }
+            }
+            Some(n) => n,
+        };
+
+        Ok(jobs)
+    }
+
+    fn get_extra_flags(&self) -> Result<Vec<String>> {
+        let whitespace_re = Regex::new(r"\s+")?;
+        Ok(self
+            .test_opts
+            .extra_flag
+            .iter()
+            .flat_map(|extra_flag| {
+                // Split once on space, or leave as is if there's no space:
+                whitespace_re.splitn(extra_flag, 2).map(String::from)
+            })
+            .collect())
+    }
+
+    pub fn get_extra_normal_flags(&self) -> Result<Vec<String>> {
+        let raw = self.get_extra_flags()?;
+        // Combine these to detect -vf and the arg together:
+        // [-vf     hflip           ]
+        // [        -vf        hflip]
+        Ok(raw
+            .iter()
+            .chain(["".to_string()].iter())
+            .zip(["".to_string()].iter().chain(raw.iter()))
+            .filter_map(|(arg, prev_arg)| {
+                if arg != "" && arg != "-vf" && prev_arg != "-vf" {
+                    Some(arg.to_owned())
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub fn get_extra_vf_flags(&self) -> Result<Vec<String>> {
+        let raw = self.get_extra_flags()?;
+        // Combine these to detect -vf and the arg together:
+        // [-vf     hflip           ]
+        // [        -vf        hflip]
+        Ok(raw
+            .iter()
+            .chain(["".to_string()].iter())
+            .zip(["".to_string()].iter().chain(raw.iter()))
+            .filter_map(|(arg, prev_arg)| {
+                if prev_arg == "-vf" {
+                    Some(arg.to_owned())
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub(crate) fn get_height(&self) -> u32 {
+        if self.height_720p {
+            720
+        } else {
+            1080
+        }
+    }
+
+    /// Get the `--tune` value, if it is supported by the chosen codec.
+    pub fn get_tune(&self) -> Result<Option<&str>> {
+        let Some(tune) = self.tune.as_deref() else {
+            return Ok(None);
+        };
+        let supported: &[&str] = match self.get_video_codec() {
+            Codec::H264 => &[
+                "film",
+                "animation",
+                "grain",
+                "stillimage",
+                "fastdecode",
+                "zerolatency",
+                "psnr",
+                "ssim",
+            ],
+            Codec::H265 => &[
+                "grain",
+                "animation",
+                "fastdecode",
+                "zerolatency",
+                "psnr",
+                "ssim",
+            ],
+            Codec::Av1 => &["psnr", "ssim"],
+            Codec::ProRes | Codec::Dnxhr | Codec::Ffv1 | Codec::Copy => &[],
+        };
+        if !supported.contains(&tune) {
+            bail!(
+                "--tune {tune} is not supported by {:?}. Choose one of: {}",
+                self.get_video_codec(),
+                supported.join(", ")
+            );
+        }
+        Ok(Some(tune))
+    }
+
+    /// The part of each video to encode.
+    pub fn get_trim(&self) -> Result<Trim> {
+        Trim::new(self.start, self.end, self.duration)
+    }
+
+    /// The `--crf-offsets` table, or the default one.
+    pub fn get_crf_offsets(&self) -> CrfOffsets {
+        self.crf_offsets.clone().unwrap_or_else(|| {
+            CrfOffsets::parse(DEFAULT_CRF_OFFSETS).expect("The default CRF offsets are valid")
+        })
+    }
+
+    /// The output name template used if `--output-name` is not given.
+    pub fn get_default_naming_format(&self) -> String {
+        let codec = self.get_video_codec();
+        let mut naming_format = if codec == Codec::Av1 {
+            String::from("{basename}-{preset}-crf{crf}")
+        } else if self.lossless && codec == Codec::H265 {
+            String::from("{basename}-lossless")
+        } else if self.near_lossless {
+            String::from("{basename}-near-lossless-crf{crf}")
+        } else if !codec.uses_crf() && codec != Codec::Copy {
+            format!("{{basename}}-{}", format!("{codec:?}").to_lowercase())
+        } else {
+            String::from("{basename}-crf{crf}")
+        };
+        if self.speed.is_some() {
+            naming_format += "-{speed}";
+        }
+        if self.flatten {
+            // Videos in different directories may have the same name:
+            naming_format = naming_format.replacen("{basename}", "{basename}-{hash}", 1);
+        }
+        naming_format
+    }
+
+    /// The bitrate to reencode stereo audio at, in bits per second. Other channel counts are
+    /// scaled from this.
+    pub fn get_audio_bitrate(&self) -> u64 {
+        self.audio_bitrate
+            .unwrap_or(if self.for_tv { 192_000 } else { 128_000 })
+    }
+
+    /// Get a random number generator for this run. Generators made for the same seed give the
+    /// same numbers, so each use should make its own.
+    pub fn get_rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.seed.unwrap_or_else(rand::random))
+    }
+
+    /// The speed factor formatted for output names, like "2x" or "1.5x".
+    pub fn get_speed_str(&self) -> String {
+        format!("{}x", self.speed.unwrap_or(1.0))
+    }
+}
+
+pub fn get_output_dir(cli: &Cli) -> PathBuf {
+    cli.output_dir
+        .as_ref()
+        .map_or(cli.video_root.join(ENCODED), |path| path.to_owned())
+}
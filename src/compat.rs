@@ -0,0 +1,131 @@
+//! Which codecs each output container can hold, so a stream that can't be copied into the
+//! output is found before the encode, rather than when ffmpeg fails to write it at the end.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+#[allow(unused_imports)]
+use log::*;
+
+#[allow(unused_imports)]
+use crate::{_debug, _error, _info, _log, _trace, _warn};
+use crate::{Codec, Encoder, InputFile};
+
+/// The video codecs that mp4 can hold, with ffprobe's names.
+const MP4_VIDEO: &[&str] = &[
+    "h264",
+    "hevc",
+    "av1",
+    "vp9",
+    "mpeg4",
+    "mpeg2video",
+    "mpeg1video",
+    "mjpeg",
+    "png",
+];
+/// The audio codecs that mp4 can hold. DTS and TrueHD need ffmpeg's experimental support, and
+/// most players can't play them from mp4 anyway.
+const MP4_AUDIO: &[&str] = &["aac", "mp3", "mp2", "ac3", "eac3", "opus", "flac", "alac"];
+const MOV_VIDEO: &[&str] = &[
+    "h264",
+    "hevc",
+    "prores",
+    "dnxhd",
+    "mpeg4",
+    "mpeg2video",
+    "mjpeg",
+    "png",
+];
+const MOV_AUDIO: &[&str] = &["aac", "mp3", "ac3", "eac3", "alac"];
+
+/// A container that jiffy writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Container {
+    Mkv,
+    Mp4,
+    Mov,
+}
+
+impl Container {
+    /// The container of an output, from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_ascii_lowercase().to_str()? {
+            "mkv" => Some(Self::Mkv),
+            "mp4" => Some(Self::Mp4),
+            "mov" => Some(Self::Mov),
+            _ => None,
+        }
+    }
+
+    /// Whether a stream of this type ("video", "audio", ...) and codec can be copied into the
+    /// container as it is.
+    pub fn can_hold(&self, codec_type: &str, codec: &str) -> bool {
+        match (self, codec_type) {
+            (Self::Mkv, _) => true,
+            (Self::Mp4, "video") => MP4_VIDEO.contains(&codec),
+            (Self::Mp4, "audio") => MP4_AUDIO.contains(&codec),
+            (Self::Mov, "video") => MOV_VIDEO.contains(&codec),
+            (Self::Mov, "audio") => MOV_AUDIO.contains(&codec) || codec.starts_with("pcm_"),
+            (Self::Mp4 | Self::Mov, "subtitle") => codec == "mov_text",
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Container {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_lowercase())
+    }
+}
+
+impl Encoder {
+    /// Check that the streams that will be copied as they are, because of `--copy-streams` or
+    /// `--copy-audio`, fit in the output container. Audio that's only copied to save time is
+    /// reencoded instead when it doesn't fit; see `get_audio_args`. Subtitles are never copied
+    /// into mp4 or mov: they're converted to mov_text or dropped, as `--sub-handling` says.
+    pub(crate) async fn check_container_compatibility(
+        &self,
+        input: &InputFile,
+        output_path: &Path,
+    ) -> Result<()> {
+        let Some(container) = Container::from_path(output_path) else {
+            return Ok(());
+        };
+        let copy_video = self.cli.get_video_codec() == Codec::Copy;
+        let copy_audio = self.cli.test_opts.copy_audio && !self.cli.test_opts.no_audio;
+        if container == Container::Mkv || (!copy_video && !copy_audio) {
+            return Ok(());
+        }
+        let streams = match input.get_streams().await {
+            Ok(streams) => streams,
+            Err(err) => {
+                _warn!(input, "Could not get the streams, so not checking that they fit in {container}: {err}");
+                return Ok(());
+            }
+        };
+        for stream in &streams {
+            let Some(codec) = stream.codec.as_deref() else {
+                continue;
+            };
+            let copied = match stream.codec_type.as_str() {
+                "video" => copy_video,
+                "audio" => copy_audio,
+                _ => false,
+            };
+            if !copied {
+                continue;
+            }
+            if !container.can_hold(&stream.codec_type, codec) {
+                bail!(
+                    "The {codec} {} stream {} can't be copied into {container}. Encode it, or make an mkv output",
+                    stream.codec_type,
+                    stream.index
+                );
+            }
+            if container == Container::Mp4 && stream.codec_type == "video" && codec == "vp9" {
+                _warn!(input, "VP9 video in mp4 doesn't play on many older devices");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! `--print-config`: the effective value of every option, and where each one came from.
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches};
+
+use crate::{Cli, DEFAULT_VIDEO_EXTENSIONS};
+
+/// The environment variables that change how videos are encoded, besides the options. The
+/// per-video `VF_*` and `FFMPEG_FLAGS_*` variables aren't listed, since they depend on the video.
+const CONFIG_ENV_VARS: &[&str] = &["FFMPEG_FLAGS"];
+
+/// Where the value of a setting came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigSource {
+    /// The built-in default, or the option was not given.
+    Default,
+    Env,
+    Flag,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Env => write!(f, "env"),
+            Self::Flag => write!(f, "flag"),
+        }
+    }
+}
+
+/// The effective value of one setting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigEntry {
+    /// The option, like "--crf", or the name of a positional argument or environment variable.
+    pub name: String,
+    /// The value, or None if it's unset. Several values are joined with commas.
+    pub value: Option<String>,
+    pub source: ConfigSource,
+}
+
+/// Get the effective value of every option in the parsed command line, including the
+/// subcommand's, and of the environment variables that affect encoding. Options set by other
+/// options, like `--lossless` by `--ffv1`, count as defaults. Options whose default is worked
+/// out when encoding, like `--jobs`, show the value that would be used.
+pub fn get_effective_config(matches: &ArgMatches) -> Vec<ConfigEntry> {
+    let command = Cli::command();
+    let mut entries = Vec::new();
+    add_arg_entries(&command, matches, "", &mut entries);
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        if let Some(subcommand) = command.find_subcommand(name) {
+            add_arg_entries(subcommand, sub_matches, &format!("{name} "), &mut entries);
+        }
+    }
+    if let Ok(cli) = Cli::from_arg_matches(matches) {
+        resolve_defaults(&cli, &mut entries);
+    }
+    for var in CONFIG_ENV_VARS {
+        let value = std::env::var_os(var).map(|value| value.to_string_lossy().into_owned());
+        let source = if value.is_some() {
+            ConfigSource::Env
+        } else {
+            ConfigSource::Default
+        };
+        entries.push(ConfigEntry {
+            name: var.to_string(),
+            value,
+            source,
+        });
+    }
+    entries
+}
+
+/// Add the values of a command's options, with their names after `prefix`.
+fn add_arg_entries(
+    command: &Command,
+    matches: &ArgMatches,
+    prefix: &str,
+    entries: &mut Vec<ConfigEntry>,
+) {
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(arg.get_action(), ArgAction::Help | ArgAction::Version) || id == "print_config"
+        {
+            continue;
+        }
+        let name = arg
+            .get_long()
+            .map_or_else(|| id.to_string(), |long| format!("--{long}"));
+        let value = if matches!(arg.get_action(), ArgAction::Count) {
+            Some(matches.get_count(id).to_string())
+        } else {
+            matches.try_get_raw(id).ok().flatten().map(|values| {
+                values
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => ConfigSource::Flag,
+            Some(ValueSource::EnvVariable) => ConfigSource::Env,
+            _ => ConfigSource::Default,
+        };
+        entries.push(ConfigEntry {
+            name: format!("{prefix}{name}"),
+            value,
+            source,
+        });
+    }
+}
+
+/// Fill in the options that are unset on the command line, but get a value when encoding.
+fn resolve_defaults(cli: &Cli, entries: &mut [ConfigEntry]) {
+    let mut resolve = |name: &str, value: Option<String>| {
+        if let Some(entry) = entries
+            .iter_mut()
+            .find(|entry| entry.name == name && entry.value.is_none())
+        {
+            entry.value = value;
+        }
+    };
+    resolve("--jobs", cli.get_jobs().ok().map(|jobs| jobs.to_string()));
+    resolve("--audio-bitrate", Some(cli.get_audio_bitrate().to_string()));
+    // Without --crf, it depends on each video's size, and on --crf-map if it's given:
+    if cli.crf_map.is_none() && cli.get_video_codec().uses_crf() {
+        resolve("--crf", Some("(chosen for each video)".to_string()));
+    }
+    if cli.extensions.is_empty() {
+        resolve("--extensions", Some(DEFAULT_VIDEO_EXTENSIONS.join(",")));
+    }
+}
+
+/// Print the settings one per line, with the values and sources lined up.
+pub fn render_config(entries: &[ConfigEntry]) -> String {
+    let name_width = entries
+        .iter()
+        .map(|entry| entry.name.len())
+        .max()
+        .unwrap_or_default();
+    let values: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.value.as_deref().unwrap_or("(unset)"))
+        .collect();
+    let value_width = values
+        .iter()
+        .map(|value| value.len())
+        .max()
+        .unwrap_or_default();
+    entries
+        .iter()
+        .zip(values)
+        .map(|(entry, value)| {
+            format!(
+                "{:name_width$}  {value:value_width$}  ({})\n",
+                entry.name, entry.source
+            )
+        })
+        .collect()
+}
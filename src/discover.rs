@@ -0,0 +1,1294 @@
+//! Finding the videos to encode, and matching outputs back to their sources.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fs::remove_file,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+#[allow(unused_imports)]
+use log::*;
+use regex::Regex;
+
+use crate::{
+    find_output_collisions, find_tool, get_collision_suffixes, get_file_key, get_file_size,
+    get_mtime, get_output_dir, get_template_hash, is_same_file, move_to_trash, normalize_path,
+    order_paths, CaseSensitivity, Cli, EditionPolicy, Encoder, Executable, InputFile, JiffyError,
+    JiffyResult, OutputCollision, QueueOrder, SampleInfo, DEFAULT_LOG_NAME, JIFFY_SIGNATURE_PREFIX,
+    JIFFY_SIGNATURE_TAG,
+};
+
+/// Get the paths from a newline-separated list of files, like the output of `find`. Blank lines
+/// and repeated paths are skipped.
+pub fn parse_file_list(text: &str) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    text.lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .filter(|line| !line.trim().is_empty())
+        .filter(|line| seen.insert(*line))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Get the patterns from an `--include-from` or `--exclude-from` file, one per line. Blank lines
+/// and lines starting with "#" are skipped. Spaces are kept, since file names can have them.
+pub fn parse_pattern_file(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The extension of the files that mark a video to never encode, like "video.mkv.jiffyskip".
+pub const SKIP_MARKER_EXTENSION: &str = "jiffyskip";
+
+/// The skip marker file for a video.
+pub fn get_skip_marker_path(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".");
+    marker.push(SKIP_MARKER_EXTENSION);
+    PathBuf::from(marker)
+}
+
+/// If the video has a skip marker, get the reason written in it, which may be empty.
+pub fn read_skip_marker(path: &Path) -> Option<String> {
+    let marker = get_skip_marker_path(path);
+    if !marker.is_file() {
+        return None;
+    }
+    let reason = std::fs::read_to_string(&marker).unwrap_or_else(|err| {
+        log::warn!("Could not read the skip marker {marker:?}: {err}");
+        String::new()
+    });
+    Some(reason.trim().lines().collect::<Vec<_>>().join(" "))
+}
+
+/// The file in the output directory that records which source each output was made from.
+pub const HISTORY_FILE: &str = ".jiffy-history";
+
+/// Markers of an edition of a title in a file name, including Plex's "{edition-...}".
+const EDITION_RE: &str = r"(?i)\{edition-[^}]*\}|\b(?:director'?s[ ._-]cut|(?:extended|unrated|theatrical|special|ultimate|collector'?s)[ ._-](?:edition|cut|version)|extended|unrated|theatrical|final[ ._-]cut|uncut|remastered)\b";
+
+/// The extensions of the videos to encode, unless `--extensions` is given.
+pub const DEFAULT_VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "mkv", "m4v", "vob", "ogg", "ogv", "wmv", "yuv", "y4v", "mpg", "mpeg", "3gp", "3g2",
+    "f4v", "f4p", "avi", "webm", "flv",
+];
+
+/// Make the regex that matches these extensions, in lower case, without the dot.
+pub fn video_extensions_regex<S: AsRef<str>>(extensions: &[S]) -> Result<Regex> {
+    let alternatives = extensions
+        .iter()
+        .map(|extension| {
+            regex::escape(
+                &extension
+                    .as_ref()
+                    .trim()
+                    .trim_start_matches('.')
+                    .to_ascii_lowercase(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("|");
+    Ok(Regex::new(&format!("^(?:{alternatives})$"))?)
+}
+
+/// Whether any of the regexes matches a path, relative to the video root. The path is matched
+/// with "/" separators on every platform.
+pub fn regex_matches_path(regexes: &[Regex], relative_path: &Path) -> bool {
+    let path = relative_path
+        .to_string_lossy()
+        .replace(std::path::MAIN_SEPARATOR, "/");
+    regexes.iter().any(|re| re.is_match(&path))
+}
+
+impl Encoder {
+    /// The options for each video root, starting with the first one.
+    pub(crate) fn root_clis(&self) -> impl Iterator<Item = &Arc<Cli>> {
+        std::iter::once(&self.cli).chain(&self.more_root_clis)
+    }
+
+    /// The options for a video, from the video root it's in. If the roots are nested, the
+    /// deepest one that holds the video is used.
+    pub(crate) fn cli_for_path(&self, path: &Path) -> Arc<Cli> {
+        self.root_clis()
+            .filter(|cli| path.starts_with(&cli.video_root))
+            .max_by_key(|cli| cli.video_root.components().count())
+            .unwrap_or(&self.cli)
+            .clone()
+    }
+
+    pub fn is_match<P>(&self, (globset, paths): &(GlobSet, Vec<PathBuf>), path: P) -> bool
+    where
+        P: AsRef<Path>,
+        PathBuf: From<P>,
+    {
+        let path = PathBuf::from(path);
+        globset.is_match(&path)
+            || paths.iter().any(|p| {
+                is_same_file(p, &path) || is_same_file(p, &Path::join(&self.video_root, &path))
+            })
+    }
+
+    /// Make a matcher from the `--include` or `--exclude` arguments. With `case_insensitive`,
+    /// "*.mkv" also matches "EPISODE.MKV".
+    pub fn get_matcher_from_globs<P>(
+        video_root: P,
+        inputs: &Vec<String>,
+        allow_empty: bool,
+        case_insensitive: bool,
+    ) -> Result<Option<(GlobSet, Vec<PathBuf>)>>
+    where
+        P: AsRef<Path> + AsRef<OsStr>,
+    {
+        if inputs.len() == 0 && !allow_empty {
+            return Ok(None);
+        }
+
+        let mut paths = vec![];
+        let mut globset = GlobSetBuilder::new();
+        let video_root = PathBuf::from(&video_root);
+
+        for input in inputs {
+            // Interpret "video_root/x.mp4" or "x.mp4" as a glob only if video_root/x.mp4 does not exist.
+            // This loose interpretation is more convenient than being strict about the path only being
+            // relative to the video root.
+            let as_path = PathBuf::from(input);
+            if as_path.exists() {
+                paths.push(as_path);
+            } else {
+                let as_path = Path::join(&video_root, input);
+                if as_path.exists() {
+                    paths.push(as_path);
+                } else {
+                    globset.add(
+                        GlobBuilder::new(input)
+                            .case_insensitive(case_insensitive)
+                            .build()
+                            .with_context(|| format!("Could not build glob pattern {input:?}"))?,
+                    );
+                }
+            }
+        }
+        Ok(Some((
+            globset.build().context("Could not build glob set.")?,
+            paths,
+        )))
+    }
+
+    /// Get the paths of all videos in the parent directory, excluding those in this directory.
+    /// (This directory is considered the encode directory.)
+    pub(crate) async fn get_video_paths(&self) -> Result<Vec<InputFile>> {
+        let exclude = Self::get_matcher_from_globs(
+            &self.video_root,
+            &self.cli.exclude,
+            true,
+            self.cli.ignore_case,
+        )?;
+        let include = Self::get_matcher_from_globs(
+            &self.video_root,
+            &self.cli.include,
+            false,
+            self.cli.ignore_case,
+        )?;
+
+        let video_re = self.cli.get_video_extensions_regex()?;
+        let skip_codecs = self.cli.get_skip_codecs();
+        let has_duration_filter =
+            self.cli.min_duration.is_some() || self.cli.max_duration.is_some();
+        // Each video root has its own output directory, unless --output-dir is given:
+        let encode_dirs: Vec<_> = self.root_clis().map(|cli| get_output_dir(cli)).collect();
+        // With --files-from, the listed files are used instead of the ones in the video roots:
+        let (mut paths, mut dirs) = match &self.cli.files_from {
+            Some(list) => (
+                self.read_files_from(list, &video_re, &encode_dirs, &skip_codecs)
+                    .await?,
+                VecDeque::new(),
+            ),
+            None => (
+                Vec::new(),
+                self.cli
+                    .get_video_roots()
+                    .into_iter()
+                    .map(|root| (root, 0))
+                    .collect(),
+            ),
+        };
+        let max_depth = self.cli.get_max_depth();
+        let follow_symlinks = self.cli.get_follow_symlinks();
+        // The directories found so far, by their real paths, so a symlink loop isn't followed
+        // forever, and the files by their keys, so a video reached through a symlink, a hard link
+        // or overlapping video roots isn't encoded twice:
+        let mut visited_dirs: HashSet<PathBuf> = dirs
+            .iter()
+            .filter_map(|(dir, _)| dir.canonicalize().ok())
+            .collect();
+        let mut found_files = HashSet::new();
+        let real_encode_dirs: HashSet<_> = encode_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .collect();
+        while let Some((dir, depth)) = dirs.pop_front() {
+            let mut entries = Vec::new();
+            for entry in dir.read_dir()? {
+                entries.push(entry?);
+            }
+            entries.sort_by(|s1, s2| {
+                lexical_sort::natural_lexical_cmp(
+                    &s1.path().to_string_lossy(),
+                    &s2.path().to_string_lossy(),
+                )
+            });
+            for entry in entries {
+                // With a sample, the limit applies to the sample, not to the files it's chosen from.
+                // With a duration filter, it applies to the files that pass the filter:
+                // With another --order, the limit applies after sorting:
+                if let (Some(limit), None, false, QueueOrder::Natural) = (
+                    self.cli.limit,
+                    self.cli.sample_fraction,
+                    has_duration_filter,
+                    self.cli.order,
+                ) {
+                    if paths.len() == limit {
+                        log::debug!("Reached video limit={limit}, won't encode any more");
+                        break;
+                    }
+                }
+                let fname = entry.path();
+                let relative_path =
+                    pathdiff::diff_paths(fname.clone(), &self.cli_for_path(&fname).video_root);
+                let matchable_path = relative_path.unwrap_or(fname.clone());
+
+                if let Some(include) = &include {
+                    if !self.is_match(&include, &matchable_path) {
+                        log::debug!("Skipping path because it's not an included path: {fname:?}");
+                        continue;
+                    }
+                }
+
+                if encode_dirs
+                    .iter()
+                    .any(|encode_dir| is_same_file(&fname, encode_dir))
+                {
+                    continue;
+                } else if exclude
+                    .as_ref()
+                    .map_or(false, |x| self.is_match(&x, &matchable_path))
+                {
+                    log::debug!("Skipping path because of exclude: {fname:?}");
+                    continue;
+                } else if regex_matches_path(&self.cli.exclude_re, &matchable_path) {
+                    log::debug!("Skipping path because of --exclude-re: {fname:?}");
+                    continue;
+                }
+
+                if entry.file_type()?.is_symlink() && !follow_symlinks {
+                    log::debug!("Skipping path because it's a symlink: {fname:?}");
+                    continue;
+                }
+                // This follows symlinks:
+                let md = match fname.metadata() {
+                    Ok(md) => md,
+                    Err(err) => {
+                        log::warn!("Skipping path that can't be read, like a broken symlink: {fname:?}: {err}");
+                        continue;
+                    }
+                };
+                let real_path = match fname.canonicalize() {
+                    Ok(real_path) => real_path,
+                    Err(err) => {
+                        log::warn!("Skipping path that can't be resolved, like a broken symlink: {fname:?}: {err}");
+                        continue;
+                    }
+                };
+                if md.is_dir() {
+                    if max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                        log::debug!(
+                            "Skipping directory because it's deeper than --max-depth: {fname:?}"
+                        );
+                        continue;
+                    }
+                    if real_encode_dirs.contains(&real_path) {
+                        continue;
+                    }
+                    if !visited_dirs.insert(real_path) {
+                        log::debug!("Skipping directory because it was already searched through another path: {fname:?}");
+                        continue;
+                    }
+                    dirs.push_back((fname, depth + 1));
+                } else if extension_matches(&fname, &video_re)? {
+                    if !self.cli.include_re.is_empty()
+                        && !regex_matches_path(&self.cli.include_re, &matchable_path)
+                    {
+                        log::debug!(
+                            "Skipping path because it's not matched by --include-re: {fname:?}"
+                        );
+                    } else if !found_files.insert(get_file_key(&fname)?) {
+                        log::debug!("Skipping path because it was already found through another path: {fname:?}");
+                    } else if self.passes_filters(&fname, &md, &skip_codecs).await {
+                        paths.push(fname);
+                    }
+                }
+            }
+        }
+
+        let mut paths = self.filter_editions(paths);
+        order_paths(&mut paths, self.cli.order, &mut self.cli.get_rng());
+        // The sample is chosen from the videos that pass the duration filter, which needs them to
+        // be probed first:
+        let sample_after_probe = has_duration_filter && self.cli.sample_fraction.is_some();
+        if !sample_after_probe {
+            paths = self.take_sample(paths, PathBuf::as_path);
+        }
+
+        let mut videos = Vec::new();
+        let mut probe_failures = Vec::new();
+        for path in paths {
+            if !sample_after_probe && self.cli.limit.is_some_and(|limit| videos.len() == limit) {
+                log::debug!(
+                    "Reached video limit={}, won't encode any more",
+                    videos.len()
+                );
+                break;
+            }
+            let video = match InputFile::new(&path, self.cli_for_path(&path)).await {
+                Ok(video) => video,
+                Err(JiffyError::ProbeFailed(err)) if self.cli.strict_probe => {
+                    log::warn!("Skipping {path:?}, since it could not be probed: {err}");
+                    probe_failures.push((path, err));
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if has_duration_filter && !self.duration_matches(&video).await {
+                continue;
+            }
+            videos.push(video);
+        }
+        *self.probe_failures.lock().unwrap() = probe_failures;
+        if sample_after_probe {
+            videos = self.take_sample(videos, |video| video.path.as_path());
+        }
+        if self.cli.output_collision == OutputCollision::Rename {
+            self.rename_colliding_outputs(&mut videos);
+        }
+        Ok(videos)
+    }
+
+    /// Choose a random sample of the videos for `--sample-fraction`, and keep what was chosen
+    /// for the report. `--limit` applies to the sample. Without `--sample-fraction`, all the
+    /// videos are kept.
+    fn take_sample<T>(&self, videos: Vec<T>, get_path: impl Fn(&T) -> &Path) -> Vec<T> {
+        let Some(fraction) = self.cli.sample_fraction else {
+            return videos;
+        };
+        let matched = videos.len();
+        let matched_size = videos
+            .iter()
+            .filter_map(|video| get_file_size(get_path(video)).ok())
+            .sum();
+        let mut sample = choose_sample(videos, fraction, &mut self.cli.get_rng());
+        log::info!(
+            "Encoding a random sample of {} of the {matched} matched videos",
+            sample.len()
+        );
+        *self.sample.lock().unwrap() = Some(SampleInfo {
+            fraction,
+            sampled: sample.len(),
+            matched,
+            matched_size,
+        });
+        if let Some(limit) = self.cli.limit {
+            sample.truncate(limit);
+        }
+        sample
+    }
+
+    /// Add a suffix to the outputs of videos that would write the same output, for
+    /// `--output-collision rename`.
+    fn rename_colliding_outputs(&self, videos: &mut [InputFile]) {
+        // Each video root may have its own output directory, and only outputs in the same one
+        // can collide:
+        let mut by_output_dir: HashMap<PathBuf, Vec<(usize, PathBuf)>> = HashMap::new();
+        for (i, video) in videos.iter().enumerate() {
+            let cli = video.get_cli();
+            if let Ok(output_path) = video.get_output_path(cli.output_name.clone()) {
+                by_output_dir
+                    .entry(get_output_dir(cli))
+                    .or_default()
+                    .push((i, output_path));
+            }
+        }
+        for (output_dir, known_outputs) in by_output_dir {
+            let known_paths: Vec<_> = known_outputs
+                .iter()
+                .map(|(_, output_path)| output_path.clone())
+                .collect();
+            let case = CaseSensitivity::detect(&output_dir);
+            for group in find_output_collisions(&known_paths, case) {
+                let indexes: Vec<_> = group.iter().map(|&i| known_outputs[i].0).collect();
+                let inputs: Vec<_> = indexes.iter().map(|&i| videos[i].path.clone()).collect();
+                let input_refs: Vec<_> = inputs.iter().map(PathBuf::as_path).collect();
+                for (&i, suffix) in indexes.iter().zip(get_collision_suffixes(&input_refs)) {
+                    videos[i].set_output_suffix(suffix);
+                    log::info!(
+                        "Renaming the output of {:?} to {:?}, since other videos would write {:?}",
+                        videos[i].path,
+                        videos[i]
+                            .get_output_path(videos[i].get_cli().output_name.clone())
+                            .unwrap_or_default(),
+                        known_paths[group[0]]
+                    );
+                }
+            }
+        }
+    }
+
+    /// Check a video against the filters that only need the file: skip markers, `--since`,
+    /// `--older-than`, `--skip-jiffy-outputs` and `--skip-if-codec`.
+    pub(crate) async fn passes_filters(
+        &self,
+        fname: &Path,
+        md: &std::fs::Metadata,
+        skip_codecs: &[String],
+    ) -> bool {
+        if !self.cli.ignore_skip_markers {
+            if let Some(reason) = read_skip_marker(fname) {
+                let reason = if reason.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({reason})")
+                };
+                log::info!(
+                    "Skipping path because of its {SKIP_MARKER_EXTENSION} file{reason}: {fname:?}"
+                );
+                return false;
+            }
+        }
+        if self
+            .cli
+            .since
+            .is_some_and(|since| md.modified().is_ok_and(|mtime| mtime <= since))
+        {
+            log::debug!("Skipping path because it was not modified since --since: {fname:?}");
+            return false;
+        }
+        if self
+            .cli
+            .older_than
+            .is_some_and(|before| md.modified().is_ok_and(|mtime| mtime >= before))
+        {
+            log::debug!("Skipping path because it was modified after --older-than: {fname:?}");
+            return false;
+        }
+        if self.cli.skip_jiffy_outputs {
+            match read_jiffy_signature(fname, &self.cli).await {
+                Ok(Some(signature)) => {
+                    log::debug!("Skipping path because jiffy encoded it ({signature}): {fname:?}");
+                    return false;
+                }
+                Ok(None) => {}
+                Err(err) => log::warn!("Could not check whether jiffy encoded {fname:?}: {err}"),
+            }
+        }
+        if !skip_codecs.is_empty() {
+            match read_video_codec(fname, &self.cli).await {
+                Ok(Some(codec)) if skip_codecs.contains(&codec) => {
+                    log::info!("Skipping path because its video is already {codec}: {fname:?}");
+                    return false;
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("Could not get the video codec of {fname:?}: {err}"),
+            }
+        }
+        true
+    }
+
+    /// Read the videos listed for `--files-from`, from a file or from stdin for "-". The files
+    /// must be in a video root, so their outputs can be placed in the output directory.
+    /// Files that aren't videos, or are in an output directory, are skipped.
+    async fn read_files_from(
+        &self,
+        list: &Path,
+        video_re: &Regex,
+        encode_dirs: &[PathBuf],
+        skip_codecs: &[String],
+    ) -> Result<Vec<PathBuf>> {
+        let text = if list == Path::new("-") {
+            std::io::read_to_string(std::io::stdin())
+                .context("Could not read the list of files from stdin")?
+        } else {
+            std::fs::read_to_string(list)
+                .with_context(|| format!("Could not read the list of files {list:?}"))?
+        };
+        let mut video_roots = Vec::new();
+        for video_root in self.cli.get_video_roots() {
+            let real_root = video_root
+                .canonicalize()
+                .with_context(|| format!("Could not find the video root {video_root:?}"))?;
+            video_roots.push((video_root, real_root));
+        }
+        let encode_dirs: Vec<_> = encode_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .collect();
+        let mut paths = Vec::new();
+        let mut found_files = HashSet::new();
+        for listed in parse_file_list(&text) {
+            let path = listed
+                .canonicalize()
+                .with_context(|| format!("Could not find the listed file {listed:?}"))?;
+            // The deepest root that holds the file, in case one root is inside another:
+            let Some((video_root, relative_path)) = video_roots
+                .iter()
+                .filter_map(|(video_root, real_root)| {
+                    Some((video_root, path.strip_prefix(real_root).ok()?))
+                })
+                .min_by_key(|(_, relative_path)| relative_path.components().count())
+            else {
+                bail!(
+                    "The listed file {listed:?} is not in the video root {:?}",
+                    self.video_root
+                );
+            };
+            if encode_dirs
+                .iter()
+                .any(|encode_dir| path.starts_with(encode_dir))
+            {
+                log::debug!(
+                    "Skipping listed path because it's in the output directory: {listed:?}"
+                );
+                continue;
+            }
+            let md = path.metadata()?;
+            if !md.is_file() || !extension_matches(&path, video_re)? {
+                log::debug!("Skipping listed path because it's not a video: {listed:?}");
+                continue;
+            }
+            if regex_matches_path(&self.cli.exclude_re, relative_path) {
+                log::debug!("Skipping listed path because of --exclude-re: {listed:?}");
+                continue;
+            }
+            if !found_files.insert(get_file_key(&path)?) {
+                log::debug!("Skipping listed path because it was already listed as another path: {listed:?}");
+                continue;
+            }
+            // Keep the paths in the same form as the ones found by walking the video root:
+            let fname = video_root.join(relative_path);
+            if self.passes_filters(&fname, &md, skip_codecs).await {
+                paths.push(fname);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Check the video's length against `--min-duration` and `--max-duration`. Videos whose
+    /// length can't be found are kept.
+    pub(crate) async fn duration_matches(&self, video: &InputFile) -> bool {
+        let duration = match video.get_duration().await {
+            Ok(duration) => duration as f64,
+            Err(err) => {
+                log::warn!(
+                    "Could not get the length of {:?}, so not filtering it by length: {err}",
+                    video.path
+                );
+                return true;
+            }
+        };
+        if self
+            .cli
+            .min_duration
+            .is_some_and(|min_duration| duration < min_duration)
+        {
+            log::debug!(
+                "Skipping path because it's shorter than --min-duration: {:?}",
+                video.path
+            );
+            false
+        } else if self
+            .cli
+            .max_duration
+            .is_some_and(|max_duration| duration > max_duration)
+        {
+            log::debug!(
+                "Skipping path because it's longer than --max-duration: {:?}",
+                video.path
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Report the editions of the same title among the paths, and drop the ones `--editions`
+    /// doesn't want.
+    fn filter_editions(&self, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut dropped = HashSet::new();
+        for group in find_editions(&paths) {
+            let names = group
+                .iter()
+                .map(|&i| paths[i].file_name().unwrap_or_default().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let keep = match self.cli.editions {
+                EditionPolicy::All => {
+                    log::info!("Found editions of the same title, encoding all of them (see --editions): {names}");
+                    continue;
+                }
+                EditionPolicy::Newest => group.iter().max_by_key(|&&i| get_mtime(&paths[i]).ok()),
+                EditionPolicy::Smallest => group
+                    .iter()
+                    .min_by_key(|&&i| get_file_size(&paths[i]).unwrap_or(u64::MAX)),
+            };
+            let keep = *keep.expect("Edition groups are not empty");
+            log::info!(
+                "Found editions of the same title: {names}. Only encoding {:?}",
+                paths[keep]
+            );
+            dropped.extend(group.into_iter().filter(|&i| i != keep));
+        }
+        paths
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !dropped.contains(i))
+            .map(|(_, path)| path)
+            .collect()
+    }
+
+    /// Compare the planned outputs with the output directory, and print what would be added,
+    /// updated, or is orphaned. With `--prune`, delete the orphans.
+    pub async fn diff_outputs(&self) -> JiffyResult<()> {
+        let input_files = self.get_video_paths().await?;
+        let mut planned_outputs = HashSet::new();
+        let (mut added, mut updated, mut unchanged) = (0, 0, 0);
+        for input in &input_files {
+            let output_path = input.get_output_path(input.get_cli().output_name.clone())?;
+            if !output_path.exists() {
+                println!("add:      {:?} -> {:?}", input.path, output_path);
+                added += 1;
+            } else if get_mtime(&input.path)? > get_mtime(&output_path)? {
+                println!("update:   {:?} -> {:?}", input.path, output_path);
+                updated += 1;
+            } else {
+                unchanged += 1;
+            }
+            planned_outputs.insert(normalize_path(&output_path));
+        }
+
+        let orphans = self.find_orphans(&planned_outputs)?;
+        let prune = self.cli.prune && !self.cli.test_opts.noop;
+        for orphan in &orphans {
+            println!("orphan:   {orphan:?}");
+            if prune {
+                remove_file(orphan).context(format!("Could not delete orphan: {orphan:?}"))?;
+            }
+        }
+
+        log::info!(
+            "{added} to add, {updated} to update, {unchanged} unchanged, {} orphaned{}",
+            orphans.len(),
+            if prune {
+                " (deleted)"
+            } else if self.cli.prune {
+                " (not deleted with --noop)"
+            } else {
+                ""
+            }
+        );
+        Ok(())
+    }
+
+    /// Delete (or move to the trash) the outputs and logs whose source videos no longer exist.
+    pub async fn prune_outputs(&self, trash: bool) -> JiffyResult<()> {
+        let orphans = self.find_orphans(&HashSet::new())?;
+        for orphan in &orphans {
+            if self.cli.test_opts.noop {
+                log::info!("Would remove orphan (--noop): {orphan:?}");
+            } else if trash {
+                log::info!("Moving orphan to the trash: {orphan:?}");
+                move_to_trash(orphan)?;
+            } else {
+                log::info!("Deleting orphan: {orphan:?}");
+                remove_file(orphan).context(format!("Could not delete orphan: {orphan:?}"))?;
+            }
+        }
+        log::info!("{} orphaned outputs and logs found", orphans.len());
+        Ok(())
+    }
+
+    /// Remember which source an output was made from, so pruning doesn't have to guess from the
+    /// output name.
+    pub(crate) fn record_history(&self, input: &InputFile, output_path: &Path) -> Result<()> {
+        let source = pathdiff::diff_paths(&input.path, &input.get_cli().video_root)
+            .context("Could not get the input path within the video root")?;
+        self.record_history_source(input, output_path, &source)
+    }
+
+    /// Add a line to the history file. The source is relative to the video root, or absolute
+    /// once it was moved out of it. A later line for the same output replaces an earlier one.
+    pub(crate) fn record_history_source(
+        &self,
+        input: &InputFile,
+        output_path: &Path,
+        source: &Path,
+    ) -> Result<()> {
+        let output_dir = get_output_dir(input.get_cli());
+        let output = pathdiff::diff_paths(output_path, &output_dir)
+            .context("Could not get the output path within the output directory")?;
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(output_dir.join(HISTORY_FILE))?;
+        // One write per line, so concurrent jobs don't interleave their lines:
+        file.write_all(
+            format!(
+                "{}\t{}\n",
+                output.to_string_lossy(),
+                source.to_string_lossy()
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Read the history file: a map of output paths to source paths, relative to the output
+    /// directory and video root.
+    pub(crate) fn read_history(&self) -> Result<HashMap<PathBuf, PathBuf>> {
+        let history_path = get_output_dir(&self.cli).join(HISTORY_FILE);
+        if !history_path.exists() {
+            return Ok(HashMap::new());
+        }
+        Ok(std::fs::read_to_string(history_path)?
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(output, source)| (normalize_path(Path::new(output)), PathBuf::from(source)))
+            .collect())
+    }
+
+    /// Find outputs and logs in the output directory whose source videos no longer exist.
+    /// Outputs that are part of the plan are never orphans. With `--output-dir`, the sources
+    /// may be in any of the video roots.
+    pub(crate) fn find_orphans(&self, planned_outputs: &HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+        let output_dir = get_output_dir(&self.cli);
+        let video_re = self.cli.get_video_extensions_regex()?;
+        let video_roots: Vec<_> = self
+            .root_clis()
+            .filter(|cli| get_output_dir(cli) == output_dir)
+            .map(|cli| cli.video_root.clone())
+            .collect();
+        let source_exists =
+            |source: &Path| video_roots.iter().any(|root| root.join(source).exists());
+        let find_source = |basename: &str| -> Result<bool> {
+            for video_root in &video_roots {
+                if find_source_video(video_root, basename, &video_re)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        let history = self.read_history()?;
+        let name_re = naming_format_to_regex(
+            &self
+                .cli
+                .output_name
+                .clone()
+                .unwrap_or_else(|| self.cli.get_default_naming_format()),
+        )?;
+        let log_re = log_name_to_regex(self.cli.log_name.as_deref().unwrap_or(DEFAULT_LOG_NAME))?;
+        // The {hash} of each source, found when an output name first needs one:
+        let mut source_hashes = None;
+
+        let mut orphans = Vec::new();
+        let mut dirs = VecDeque::from([output_dir.clone()]);
+        while let Some(dir) = dirs.pop_front() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in dir.read_dir()? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push_back(path);
+                    continue;
+                }
+                let relative_path =
+                    pathdiff::diff_paths(&path, &output_dir).unwrap_or(path.clone());
+                let relative_str = relative_path.to_string_lossy();
+
+                let log_name = relative_str.replace('\\', "/");
+                let log_captures = log_name
+                    .ends_with(".log")
+                    .then(|| log_re.captures(&log_name))
+                    .flatten();
+                if let Some(captures) = log_captures {
+                    let has_source = if let Some(source) = captures.name("relpath") {
+                        source_exists(Path::new(source.as_str()))
+                    } else if let Some(basename) = captures.name("basename") {
+                        find_source(basename.as_str())?
+                    } else {
+                        // A log not named after its source can't be matched to it:
+                        true
+                    };
+                    if !has_source {
+                        orphans.push(path);
+                    }
+                } else if extension_matches(&path, &video_re)?
+                    && !planned_outputs.contains(&normalize_path(&path))
+                    // Partial files may belong to an encode that's running:
+                    && !relative_str.contains(".part.")
+                {
+                    let stem = relative_path.with_extension("");
+                    let stem = stem.to_string_lossy().replace('\\', "/");
+                    let captures = name_re.captures(&stem);
+                    let has_source =
+                        if let Some(source) = history.get(&normalize_path(&relative_path)) {
+                            source_exists(source)
+                        } else if let Some(hash) =
+                            captures.as_ref().and_then(|captures| captures.name("hash"))
+                        {
+                            if source_hashes.is_none() {
+                                let mut hashes = HashSet::new();
+                                for video_root in &video_roots {
+                                    hashes.extend(get_source_hashes(
+                                        video_root,
+                                        &output_dir,
+                                        &video_re,
+                                    )?);
+                                }
+                                source_hashes = Some(hashes);
+                            }
+                            source_hashes
+                                .as_ref()
+                                .is_some_and(|hashes| hashes.contains(hash.as_str()))
+                        } else if self.cli.flatten {
+                            // A flattened output's name doesn't say which directory its source is in:
+                            true
+                        } else if let Some(basename) = captures
+                            .as_ref()
+                            .and_then(|captures| captures.name("basename"))
+                        {
+                            find_source(basename.as_str())?
+                        } else {
+                            // An output not named by the template can't be matched to a source:
+                            true
+                        };
+                    if !has_source {
+                        orphans.push(path);
+                    }
+                }
+            }
+        }
+        orphans.sort();
+        Ok(orphans)
+    }
+}
+
+/// Split a file name (without the extension) into a key for its title, and its edition marker,
+/// if it has one. Editions of the same title have the same key.
+pub fn split_edition(stem: &str) -> (String, Option<String>) {
+    let re = Regex::new(EDITION_RE).unwrap();
+    let edition = re.find(stem).map(|found| found.as_str().to_string());
+    (simplify_name(&re.replace_all(stem, "")), edition)
+}
+
+/// Find the groups of paths that are editions of the same title: in the same directory, with the
+/// same name except for edition markers, and at least one of them has a marker. The groups hold
+/// indexes into `paths`.
+pub fn find_editions(paths: &[PathBuf]) -> Vec<Vec<usize>> {
+    let mut groups = Vec::<((Option<&Path>, String), Vec<usize>, bool)>::new();
+    for (i, path) in paths.iter().enumerate() {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let (title, edition) = split_edition(&stem);
+        let key = (path.parent(), title);
+        match groups
+            .iter_mut()
+            .find(|(group_key, _, _)| *group_key == key)
+        {
+            Some((_, members, has_edition)) => {
+                members.push(i);
+                *has_edition |= edition.is_some();
+            }
+            None => groups.push((key, vec![i], edition.is_some())),
+        }
+    }
+    groups
+        .into_iter()
+        .filter(|(_, members, has_edition)| members.len() > 1 && *has_edition)
+        .map(|(_, members, _)| members)
+        .collect()
+}
+
+/// Choose a random sample of about this fraction of the items (at least one, if there are any),
+/// keeping them in their original order.
+pub fn choose_sample<T>(items: Vec<T>, fraction: f64, rng: &mut impl rand::Rng) -> Vec<T> {
+    if items.is_empty() {
+        return items;
+    }
+    let count = ((items.len() as f64 * fraction).round() as usize).clamp(1, items.len());
+    let mut chosen = rand::seq::index::sample(rng, items.len(), count).into_vec();
+    chosen.sort_unstable();
+    let mut chosen = chosen.into_iter().peekable();
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| chosen.next_if_eq(i).is_some())
+        .map(|(_, item)| item)
+        .collect()
+}
+
+/// Turn an output naming template into a regex that matches output names (without the directory
+/// or extension). The basename is captured as "basename", and the hash as "hash".
+pub fn naming_format_to_regex(naming_format: &str) -> Result<Regex> {
+    let pattern = regex::escape(naming_format)
+        .replacen(r"\{basename\}", "(?P<basename>.+)", 1)
+        .replace(r"\{basename\}", ".+")
+        .replacen(r"\{hash\}", "(?P<hash>[0-9a-f]{8})", 1)
+        .replace(r"\{crf\}", r"\d+")
+        .replace(r"\{preset\}", r"[^/]+")
+        .replace(r"\{speed\}", r"[0-9.]+x")
+        .replace(r"\{dir\}", r"[^/]*")
+        .replace(r"\{hash\}", r"[0-9a-f]{8}")
+        .replace(r"\{original_ext\}", r"[^/]*");
+    Ok(Regex::new(&format!("^{pattern}$"))?)
+}
+
+/// Turn a `--log-name` template into a regex that matches log paths within the output directory.
+/// The source path is captured as "relpath" or "basename", depending on the field in the template.
+pub fn log_name_to_regex(log_name: &str) -> Result<Regex> {
+    let pattern = regex::escape(&log_name.replace('\\', "/"))
+        .replacen(r"\{relpath\}", "(?P<relpath>.+)", 1)
+        .replacen(r"\{basename\}", "(?P<basename>.+)", 1)
+        .replace(r"\{relpath\}", ".+")
+        .replace(r"\{basename\}", ".+")
+        .replace(r"\{date\}", r"\d{4}-\d{2}-\d{2}")
+        .replace(r"\{time\}", r"\d{6}");
+    Ok(Regex::new(&format!("^{pattern}$"))?)
+}
+
+/// Get the {hash} of every video in the video root, which identifies it by its path there. The
+/// output directory is left out.
+fn get_source_hashes(
+    video_root: &Path,
+    output_dir: &Path,
+    video_re: &Regex,
+) -> Result<HashSet<String>> {
+    let mut hashes = HashSet::new();
+    let mut dirs = VecDeque::from([video_root.to_owned()]);
+    while let Some(dir) = dirs.pop_front() {
+        for entry in dir.read_dir()? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if !is_same_file(path.as_path(), output_dir) {
+                    dirs.push_back(path);
+                }
+            } else if extension_matches(&path, video_re)? {
+                let relative_path = pathdiff::diff_paths(&path, video_root).unwrap_or(path);
+                hashes.insert(get_template_hash(&relative_path));
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Whether a source video with this basename (its path within the video root, without the
+/// extension) exists.
+pub(crate) fn find_source_video(
+    video_root: &Path,
+    basename: &str,
+    video_re: &Regex,
+) -> Result<bool> {
+    let candidate = video_root.join(basename);
+    let Some(parent) = candidate.parent().filter(|parent| parent.is_dir()) else {
+        return Ok(false);
+    };
+    for sibling in parent.read_dir()? {
+        let sibling = sibling?.path();
+        if sibling.with_extension("") == candidate && extension_matches(&sibling, video_re)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub(crate) fn extension_matches(fname: &Path, video_re: &Regex) -> Result<bool> {
+    if let Some(extension) = fname.extension() {
+        let extension = extension
+            .to_ascii_lowercase()
+            .to_str()
+            .map(|s| s.to_string())
+            .ok_or(anyhow!("Path can't be represented as utf-8: {:?}", &fname))?;
+        return Ok(video_re.is_match(&extension));
+    }
+    return Ok(false);
+}
+
+/// Find jiffy's signature among the format tags printed by ffprobe, which are lines like
+/// "TAG:jiffy=jiffy:0.1.0:h265:crf22". Containers differ in the case of the tag name. Earlier
+/// versions wrote the signature to the comment tag, so it's found there too.
+pub fn parse_jiffy_signature(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (key, value) = line.trim().split_once('=')?;
+        let is_signature = key.strip_prefix("TAG:").is_some_and(|key| {
+            key.eq_ignore_ascii_case(JIFFY_SIGNATURE_TAG) || key.eq_ignore_ascii_case("comment")
+        });
+        (is_signature && value.starts_with(JIFFY_SIGNATURE_PREFIX)).then(|| value.to_string())
+    })
+}
+
+/// Read jiffy's signature from a video, if jiffy encoded it.
+async fn read_jiffy_signature(path: &Path, cli: &Cli) -> Result<Option<String>> {
+    let output = find_tool(Executable::FFPROBE, cli)?
+        .command()
+        .args(
+            "-v error -show_entries format_tags -of default=noprint_wrappers=1".split_whitespace(),
+        )
+        .arg(path)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!("ffprobe could not read the tags"));
+    }
+    Ok(parse_jiffy_signature(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Get the codec of a video's first video stream, with ffprobe's name for it.
+async fn read_video_codec(path: &Path, cli: &Cli) -> Result<Option<String>> {
+    let output = find_tool(Executable::FFPROBE, cli)?
+        .command()
+        .args(
+            "-v error -select_streams V:0 -show_entries stream=codec_name -of default=noprint_wrappers=1:nokey=1"
+                .split_whitespace(),
+        )
+        .arg(path)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!("ffprobe could not read the streams"));
+    }
+    let codec = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((!codec.is_empty()).then_some(codec))
+}
+
+/// Find the subtitle file to burn in for `--for-tv`: one next to the video with the same name, or
+/// else one in the `--sub-dirs`.
+pub(crate) fn find_subtitle_file(
+    input: &InputFile,
+    sub_dirs: &[String],
+) -> Result<Option<PathBuf>> {
+    Ok(find_external_subtitles(input, sub_dirs)?
+        .into_iter()
+        .next()
+        .map(|subtitle| subtitle.path))
+}
+
+/// A subtitle file for a video, like "movie.en.srt" or "movie.eng.forced.ass" for "movie.mkv", or
+/// "Subs/movie/2_English.srt".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExternalSubtitle {
+    pub path: PathBuf,
+    /// The ISO 639-2 language code from the file name, if there is one.
+    pub language: Option<String>,
+    /// The disposition flags from the file name, like "forced" or "hearing_impaired".
+    pub dispositions: Vec<String>,
+}
+
+impl ExternalSubtitle {
+    /// Check whether `sub_path` is a subtitle file for the video, and get the language and flags
+    /// from its name. The names are compared case insensitively.
+    pub fn from_name(video_path: &Path, sub_path: &Path) -> Option<Self> {
+        let video_stem = video_path.file_stem()?.to_str()?.to_lowercase();
+        let sub_name = sub_path.file_name()?.to_str()?.to_lowercase();
+        let rest = sub_name.strip_prefix(&video_stem)?;
+        let tags = rest
+            .strip_suffix(".srt")
+            .or_else(|| rest.strip_suffix(".ass"))?;
+        let mut subtitle = Self {
+            path: sub_path.to_owned(),
+            ..Default::default()
+        };
+        if tags.is_empty() {
+            return Some(subtitle);
+        }
+        // Another video can have a name that starts with this one's, like "movie 2.mkv":
+        let tags = tags.strip_prefix('.')?;
+        for tag in tags.split('.') {
+            subtitle.add_tag(tag, true);
+        }
+        Some(subtitle)
+    }
+
+    /// Check whether `sub_path`, in one of the `--sub-dirs`, is a subtitle file for the video. The
+    /// names are matched loosely, word by word, ignoring case and punctuation, so
+    /// "Show.S01E02.en.srt" matches "Show S01E02.mkv". If the file is in a directory just for this video, any name matches.
+    pub fn from_sub_dir(video_path: &Path, sub_path: &Path, only_video: bool) -> Option<Self> {
+        if let Some(subtitle) = Self::from_name(video_path, sub_path) {
+            return Some(subtitle);
+        }
+        let extension = sub_path.extension()?.to_str()?.to_lowercase();
+        if extension != "srt" && extension != "ass" {
+            return None;
+        }
+        let video_stem = video_path.file_stem()?.to_str()?.to_lowercase();
+        let sub_stem = sub_path.file_stem()?.to_str()?.to_lowercase();
+        // The subtitle's name must start with the video's whole name, so "Show E1" doesn't match
+        // "Show E10.srt":
+        let video_word_count = name_words(&video_stem).count();
+        if !only_video
+            && !name_words(&sub_stem)
+                .take(video_word_count)
+                .eq(name_words(&video_stem))
+        {
+            return None;
+        }
+        let video_words: HashSet<_> = name_words(&video_stem).collect();
+        let mut subtitle = Self {
+            path: sub_path.to_owned(),
+            ..Default::default()
+        };
+        for word in name_words(&sub_stem).filter(|word| !video_words.contains(word)) {
+            subtitle.add_tag(word, false);
+        }
+        Some(subtitle)
+    }
+
+    /// Set the language or a flag from a part of the file name. Unless `loose`, only known
+    /// languages are recognized, since a loosely matched name has other words in it.
+    fn add_tag(&mut self, tag: &str, loose: bool) {
+        match tag {
+            "forced" => self.dispositions.push("forced".to_string()),
+            "sdh" | "cc" | "hi" => self.dispositions.push("hearing_impaired".to_string()),
+            _ if self.language.is_none() => self.language = parse_language(tag, loose),
+            _ => {}
+        }
+    }
+}
+
+/// Some languages' two letter codes, the three letter codes that Matroska uses, and their names.
+const LANGUAGES: [(&str, &str, &str); 23] = [
+    ("ar", "ara", "arabic"),
+    ("cs", "cze", "czech"),
+    ("da", "dan", "danish"),
+    ("de", "ger", "german"),
+    ("el", "gre", "greek"),
+    ("en", "eng", "english"),
+    ("es", "spa", "spanish"),
+    ("fi", "fin", "finnish"),
+    ("fr", "fre", "french"),
+    ("he", "heb", "hebrew"),
+    ("hi", "hin", "hindi"),
+    ("hu", "hun", "hungarian"),
+    ("it", "ita", "italian"),
+    ("ja", "jpn", "japanese"),
+    ("ko", "kor", "korean"),
+    ("nl", "dut", "dutch"),
+    ("no", "nor", "norwegian"),
+    ("pl", "pol", "polish"),
+    ("pt", "por", "portuguese"),
+    ("ru", "rus", "russian"),
+    ("sv", "swe", "swedish"),
+    ("tr", "tur", "turkish"),
+    ("zh", "chi", "chinese"),
+];
+
+/// Get the three letter code for a lowercase language code or English language name. If `loose`,
+/// any two or three letter word is taken to be a language code, and kept if it isn't known.
+fn parse_language(word: &str, loose: bool) -> Option<String> {
+    let known = LANGUAGES
+        .iter()
+        .find(|(two, three, name)| word == *two || word == *three || word == *name)
+        .map(|(_, three, _)| three.to_string());
+    let is_code = (2..=3).contains(&word.len()) && word.chars().all(|c| c.is_ascii_lowercase());
+    known.or_else(|| (loose && is_code).then(|| word.to_string()))
+}
+
+/// The lowercase words of a file name, split at punctuation and spaces.
+fn name_words(name: &str) -> impl Iterator<Item = &str> {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+/// Simplify a file name for loose matching, keeping only the letters and digits.
+fn simplify_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Find the subtitle files for a video: the ones next to it, in name order, and then the ones in
+/// the `sub_dirs` next to it. A subdirectory there named after the video is searched too.
+pub(crate) fn find_external_subtitles(
+    input: &InputFile,
+    sub_dirs: &[String],
+) -> Result<Vec<ExternalSubtitle>> {
+    let video_dir = input
+        .path
+        .parent()
+        .context("Could not get directory of video file")?;
+    let mut siblings = Vec::new();
+    for sibling in video_dir.read_dir()? {
+        siblings.push(sibling?.path());
+    }
+    siblings.sort();
+
+    let mut subtitles: Vec<_> = siblings
+        .iter()
+        .filter_map(|sibling| ExternalSubtitle::from_name(&input.path, sibling))
+        .collect();
+
+    // Subtitles in a plain sub directory can belong to any video next to it, unless it's the only one:
+    let video_re = input.get_cli().get_video_extensions_regex()?;
+    let mut video_count = 0;
+    for sibling in siblings.iter().filter(|sibling| sibling.is_file()) {
+        if extension_matches(sibling, &video_re)? {
+            video_count += 1;
+        }
+    }
+    let video_key = simplify_name(&input.path.file_stem().unwrap_or_default().to_string_lossy());
+    let is_sub_dir = |path: &Path| {
+        path.is_dir()
+            && path.file_name().is_some_and(|name| {
+                sub_dirs
+                    .iter()
+                    .any(|dir| name.eq_ignore_ascii_case(dir.as_str()))
+            })
+    };
+    for sub_dir in siblings.iter().filter(|sibling| is_sub_dir(sibling)) {
+        let mut entries = Vec::new();
+        for entry in sub_dir.read_dir()? {
+            entries.push(entry?.path());
+        }
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() {
+                if simplify_name(&entry.file_name().unwrap_or_default().to_string_lossy())
+                    != video_key
+                {
+                    continue;
+                }
+                let mut files = Vec::new();
+                for file in entry.read_dir()? {
+                    files.push(file?.path());
+                }
+                files.sort();
+                subtitles.extend(
+                    files
+                        .iter()
+                        .filter_map(|file| ExternalSubtitle::from_sub_dir(&input.path, file, true)),
+                );
+            } else if let Some(subtitle) =
+                ExternalSubtitle::from_sub_dir(&input.path, &entry, video_count == 1)
+            {
+                subtitles.push(subtitle);
+            }
+        }
+    }
+    Ok(subtitles)
+}
@@ -0,0 +1,125 @@
+use std::{fmt, io, path::PathBuf};
+
+/// The kinds of errors the library's API returns, so callers can act on them. Internally, errors
+/// are `anyhow::Error`s, which are `Other` unless they wrap one of the other kinds.
+#[derive(Debug)]
+pub enum JiffyError {
+    /// ffprobe or ffmpeg could not get information about a file, or about ffmpeg itself.
+    ProbeFailed(String),
+    /// ffmpeg exited with an error while encoding. The stderr tail is the end of ffmpeg's log,
+    /// if it was logged to a file.
+    EncodeFailed {
+        path: PathBuf,
+        exit_code: Option<i32>,
+        stderr_tail: String,
+    },
+    /// The output file already exists, and `--overwrite` was not given.
+    OutputExists(PathBuf),
+    /// The output was already uploaded to `--output-url` by an earlier run.
+    OutputUploaded(PathBuf),
+    /// The input file is smaller than `--minimum-size`.
+    TooSmall {
+        path: PathBuf,
+        size: u64,
+    },
+    /// The input file is larger than `--maximum-size`.
+    TooLarge {
+        path: PathBuf,
+        size: u64,
+    },
+    /// The input's bitrate, in bits per second, is lower than `--minimum-bitrate`.
+    LowBitrate {
+        path: PathBuf,
+        bitrate: u64,
+    },
+    /// Another program has the input file open for writing, so it may not be complete yet.
+    InUse(PathBuf),
+    /// The run was cancelled before the work was done.
+    Cancelled,
+    /// ffmpeg was stopped by a signal, like Ctrl-C, rather than failing.
+    Interrupted(PathBuf),
+    Io(io::Error),
+    Other(anyhow::Error),
+}
+
+pub type JiffyResult<T> = Result<T, JiffyError>;
+
+impl fmt::Display for JiffyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JiffyError::ProbeFailed(msg) => write!(f, "{msg}"),
+            JiffyError::EncodeFailed {
+                path,
+                exit_code,
+                stderr_tail,
+            } => {
+                write!(f, "ffmpeg failed to encode {path:?}")?;
+                if let Some(exit_code) = exit_code {
+                    write!(f, " (exit code {exit_code})")?;
+                }
+                if !stderr_tail.is_empty() {
+                    write!(f, ":\n{stderr_tail}")?;
+                }
+                Ok(())
+            }
+            JiffyError::OutputExists(path) => write!(f, "The output file already exists: {path:?}"),
+            JiffyError::OutputUploaded(path) => {
+                write!(f, "The output was already uploaded: {path:?}")
+            }
+            JiffyError::TooSmall { path, size } => {
+                write!(f, "Skipping {path:?} as too small to encode ({size} bytes)")
+            }
+            JiffyError::TooLarge { path, size } => {
+                write!(
+                    f,
+                    "Skipping {path:?} as larger than --maximum-size ({})",
+                    crate::format_size(*size)
+                )
+            }
+            JiffyError::LowBitrate { path, bitrate } => {
+                write!(
+                    f,
+                    "Skipping {path:?} since its bitrate is below --minimum-bitrate ({} kb/s)",
+                    bitrate / 1000
+                )
+            }
+            JiffyError::InUse(path) => {
+                write!(
+                    f,
+                    "Skipping {path:?} since another program is writing to it"
+                )
+            }
+            JiffyError::Cancelled => write!(f, "Cancelled"),
+            JiffyError::Interrupted(path) => write!(f, "Encoding {path:?} was interrupted"),
+            JiffyError::Io(err) => write!(f, "{err}"),
+            // Include the causes, since the error won't be printed with anyhow's formatting:
+            JiffyError::Other(err) => write!(f, "{err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for JiffyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JiffyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for JiffyError {
+    fn from(err: io::Error) -> Self {
+        JiffyError::Io(err)
+    }
+}
+
+impl From<anyhow::Error> for JiffyError {
+    fn from(err: anyhow::Error) -> Self {
+        // Errors of a known kind may have been passed through code that uses anyhow:
+        // Other errors are kept whole, since their context says what failed:
+        match err.downcast::<JiffyError>() {
+            Ok(err) => err,
+            Err(err) => JiffyError::Other(err),
+        }
+    }
+}
@@ -0,0 +1,951 @@
+//! Building the ffmpeg arguments for audio, filters and encoder parameters.
+
+use std::{cmp::min, collections::BTreeMap, ffi::OsString, path::PathBuf, sync::mpsc::Sender};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use regex::Regex;
+
+#[allow(unused_imports)]
+use crate::{_debug, _error, _info, _log, _trace, _warn};
+use crate::{
+    dump_stream, has_wanted_language, interval_chapters, is_commentary, scene_chapters,
+    to_ffmetadata, AudioAction, AudioCopyPolicy, AudioPolicy, AutoChapters, Codec, Container,
+    Encoder, ExternalSubtitle, FilterChain, FilterStage, InputFile, LosslessAudioPolicy, Loudness,
+    StreamInfo, StreamMap,
+};
+
+/// The audio args of an encode, and what they do with each output audio stream.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioArgs {
+    pub args: Vec<OsString>,
+    pub actions: AudioActions,
+    /// The loudness of each audio stream that was measured for `--normalize-audio`, in order.
+    pub loudness: Vec<Loudness>,
+}
+
+impl AudioArgs {
+    /// Args that do the same to every audio stream.
+    pub fn for_all(args: Vec<OsString>, action: AudioAction) -> Self {
+        Self {
+            args,
+            actions: AudioActions::All(action),
+            loudness: Vec::new(),
+        }
+    }
+
+    /// Add the args for the next output audio stream.
+    fn add_stream(&mut self, args: Vec<OsString>, action: AudioAction) {
+        self.args.extend(args);
+        match &mut self.actions {
+            AudioActions::PerStream(actions) => actions.push(action),
+            actions => *actions = AudioActions::PerStream(vec![action]),
+        }
+    }
+}
+
+/// What an encode does with the output audio streams: the same to all of them, like when they
+/// aren't known, or something for each one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioActions {
+    All(AudioAction),
+    PerStream(Vec<AudioAction>),
+}
+
+impl Default for AudioActions {
+    fn default() -> Self {
+        Self::PerStream(Vec::new())
+    }
+}
+
+impl AudioActions {
+    /// Get the action for each of `count` output audio streams. Streams without an action of
+    /// their own are reencoded by ffmpeg.
+    pub fn for_streams(&self, count: usize) -> Vec<AudioAction> {
+        match self {
+            Self::All(action) => vec![action.clone(); count],
+            Self::PerStream(actions) => {
+                let mut actions = actions.clone();
+                actions.resize(count, AudioAction::Reencode(None));
+                actions
+            }
+        }
+    }
+}
+
+impl Encoder {
+    /// Remove disposition flags that this version of ffmpeg would reject.
+    pub(crate) fn remove_unsupported_dispositions(&self, stream_map: &mut StreamMap) {
+        // The flags, and the version that added them:
+        let newer_dispositions = [
+            ("still_image", 5, 0),
+            ("non_diegetic", 6, 0),
+            ("multilayer", 7, 1),
+        ];
+        for (flag, major, minor) in newer_dispositions {
+            if !self.ffmpeg_at_least(major, minor) {
+                for stream in stream_map.streams.iter_mut() {
+                    stream.dispositions.remove(flag);
+                }
+            }
+        }
+    }
+
+    /// Whether this version of ffmpeg has the encoder option `arg`, added in `major.minor`. If
+    /// not, the `flag` that asked for it is ignored for this input, with a warning.
+    pub(crate) fn ffmpeg_has_option(
+        &self,
+        input: &InputFile,
+        flag: &str,
+        arg: &str,
+        (major, minor): (u32, u32),
+        warning_tx: &Sender<(PathBuf, String)>,
+    ) -> Result<bool> {
+        let Some(version) = self
+            .ffmpeg_version
+            .filter(|_| !self.ffmpeg_at_least(major, minor))
+        else {
+            return Ok(true);
+        };
+        let msg = format!(
+            "Ignoring {flag}: ffmpeg {version} has no {arg} option (it needs ffmpeg {major}.{minor})"
+        );
+        _warn!(input, "{}", msg);
+        warning_tx.send((input.path.to_owned(), msg))?;
+        Ok(false)
+    }
+
+    /// Get the audio args of an encode, and what they do with each output audio stream.
+    pub(crate) async fn get_audio_args(&self, input: &InputFile) -> AudioArgs {
+        let bitrate = self.cli.get_audio_bitrate();
+        let copy_policy = AudioCopyPolicy::new(&self.cli);
+        if self.cli.test_opts.no_audio {
+            _debug!(input, "Removing audio entirely, due to argument");
+            return AudioArgs::for_all(os_args!["-an"], AudioAction::Drop);
+        } else if self.cli.test_opts.copy_audio {
+            _debug!(
+                input,
+                "Skipping audio bitrate check and not encoding, due to argument"
+            );
+            return AudioArgs::for_all(os_args!["-c:a", "copy"], AudioAction::Copy);
+        }
+
+        let mut audio_filters = Vec::new();
+        if let Some(speed) = self.cli.speed {
+            audio_filters.extend(get_atempo_filters(speed));
+        }
+
+        if self.cli.mezzanine.is_some() {
+            // Editors work with uncompressed audio, and it's small next to the video:
+            let mut audio_args = AudioArgs::for_all(
+                os_args!["-c:a", MEZZANINE_AUDIO_CODEC],
+                AudioAction::Reencode(Some(MEZZANINE_AUDIO_CODEC.to_string())),
+            );
+            self.add_audio_filter_args(input, &audio_filters, &mut audio_args)
+                .await;
+            return audio_args;
+        } else if !audio_filters.is_empty() || self.cli.normalize_audio {
+            _debug!(
+                input,
+                "Skipping audio bitrate check: audio must be reencoded to apply filters"
+            );
+            // Filtered streams can't be copied:
+            let mut audio_args = self.get_audio_encode_args(input, bitrate, false).await;
+            self.add_audio_filter_args(input, &audio_filters, &mut audio_args)
+                .await;
+            return audio_args;
+        } else if self.cli.skip_audio_bitrate_check {
+            _debug!(input, "Skipping audio bitrate check due to option chosen.");
+            return self.get_audio_encode_args(input, bitrate, true).await;
+        } else if self.cli.for_tv {
+            _debug!(
+                input,
+                "Skipping audio bitrate check: always encode for TV playback"
+            );
+            return self.get_audio_encode_args(input, bitrate, true).await;
+        }
+        // Measuring the bitrates reads the whole file, so only do it if they're needed:
+        let bitrates = if copy_policy.policy != AudioPolicy::CopyIfEfficient {
+            BTreeMap::new()
+        } else {
+            match input.get_audio_bitrates().await {
+                Ok(bitrates) => bitrates,
+                Err(err) => {
+                    _warn!(input, "Could not get audio bitrate: {err}");
+                    return self.get_audio_encode_args(input, bitrate, true).await;
+                }
+            }
+        };
+
+        let Some(streams) = self.get_output_audio_streams(input).await else {
+            // The output streams are unknown, so either copy or reencode them all:
+            if copy_policy.policy == AudioPolicy::AlwaysCopy
+                || (!bitrates.is_empty()
+                    && bitrates
+                        .values()
+                        .all(|&stream_bitrate| copy_policy.should_copy(Some(stream_bitrate), None)))
+            {
+                _debug!(
+                    input,
+                    "Audio bitrates are {bitrates:?} kb/s. Will not reencode"
+                );
+                return AudioArgs::for_all(os_args!["-c:a", "copy"], AudioAction::Copy);
+            }
+            _trace!(input, "Audio bitrates are {bitrates:?} kb/s. Will reencode");
+            return self.get_audio_encode_args(input, bitrate, true).await;
+        };
+
+        let mut audio_args = AudioArgs::default();
+        for (i, stream) in streams.iter().enumerate() {
+            let can_copy = self.output_can_hold(input, stream);
+            if let Some(copy_args) = self.get_lossless_copy_args(input, i, stream, can_copy) {
+                audio_args.add_stream(copy_args, AudioAction::Copy);
+                continue;
+            }
+            let stream_bitrate = bitrates.get(&stream.index).copied();
+            if can_copy && copy_policy.should_copy(stream_bitrate, stream.channels) {
+                _debug!(
+                    input,
+                    "Audio stream {} will not be reencoded. {copy_policy}",
+                    stream.index
+                );
+                audio_args.add_stream(os_args![format!("-c:a:{i}"), "copy"], AudioAction::Copy);
+                continue;
+            }
+            match stream_bitrate {
+                Some(stream_bitrate) => {
+                    _trace!(
+                        input,
+                        "Audio stream {} bitrate is {stream_bitrate} kb/s. Will reencode",
+                        stream.index
+                    )
+                }
+                None if can_copy && copy_policy.policy == AudioPolicy::CopyIfEfficient => {
+                    _warn!(
+                        input,
+                        "Could not get the bitrate of audio stream {}. Will reencode",
+                        stream.index
+                    )
+                }
+                None => {}
+            }
+            audio_args.add_stream(
+                self.get_stream_encode_args(i, stream, bitrate),
+                AudioAction::Reencode(Some("aac".to_string())),
+            );
+        }
+        audio_args
+    }
+
+    /// Add the args to filter the audio with `audio_filters`. With `--normalize-audio`, each
+    /// output audio stream is measured, and gets its own `loudnorm` filter after them.
+    async fn add_audio_filter_args(
+        &self,
+        input: &InputFile,
+        audio_filters: &[String],
+        audio_args: &mut AudioArgs,
+    ) {
+        if !self.cli.normalize_audio {
+            if !audio_filters.is_empty() {
+                audio_args
+                    .args
+                    .extend(os_args!["-af", audio_filters.join(",")]);
+            }
+            return;
+        }
+        let Some(streams) = self.get_output_audio_streams(input).await else {
+            // Without `-map 0`, ffmpeg picks a single audio stream, which is usually the first:
+            let first_stream = input
+                .get_audio_streams()
+                .await
+                .ok()
+                .and_then(|streams| streams.first().cloned());
+            let mut filters = audio_filters.to_vec();
+            filters.extend(
+                self.get_loudness_filters(input, first_stream.as_ref(), audio_args)
+                    .await,
+            );
+            if !filters.is_empty() {
+                audio_args.args.extend(os_args!["-af", filters.join(",")]);
+            }
+            return;
+        };
+        for (i, stream) in streams.iter().enumerate() {
+            let mut filters = audio_filters.to_vec();
+            filters.extend(
+                self.get_loudness_filters(input, Some(stream), audio_args)
+                    .await,
+            );
+            if !filters.is_empty() {
+                audio_args
+                    .args
+                    .extend(os_args![format!("-filter:a:{i}"), filters.join(",")]);
+            }
+        }
+    }
+
+    /// Measure the loudness of an input audio stream, and get the filters that normalize it. The
+    /// measurement is kept in `audio_args`. If it can't be measured, it's warned about and left as
+    /// it is.
+    async fn get_loudness_filters(
+        &self,
+        input: &InputFile,
+        stream: Option<&StreamInfo>,
+        audio_args: &mut AudioArgs,
+    ) -> Vec<String> {
+        let Some(stream) = stream else {
+            _warn!(input, "The video has no audio to normalize");
+            return Vec::new();
+        };
+        match input.measure_loudness(stream.index).await {
+            Ok(loudness) => {
+                _debug!(
+                    input,
+                    "Measured the loudness of audio stream {}: {loudness:?}",
+                    stream.index
+                );
+                let filter = loudness.to_filter();
+                audio_args.loudness.push(loudness);
+                // loudnorm upsamples to 192 kHz, which is more than AAC supports:
+                vec![filter, "aresample=48000".to_string()]
+            }
+            Err(err) => {
+                _warn!(input, "Could not measure the loudness of audio stream {}, so it will not be normalized: {err}", stream.index);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Get the audio streams that will be in the output, in order. This is None if it can't be
+    /// known, like with `--no-map-0`.
+    pub(crate) async fn get_output_audio_streams(
+        &self,
+        input: &InputFile,
+    ) -> Option<Vec<StreamInfo>> {
+        if self.cli.test_opts.no_map_0 {
+            // Without `-map 0`, the output audio streams don't line up with the input streams.
+            return None;
+        }
+        match input.get_audio_streams().await {
+            // Only the kept streams are in the output:
+            Ok(streams) => Some(
+                streams
+                    .iter()
+                    .filter(|stream| self.keep_stream(stream, &streams))
+                    .cloned()
+                    .collect(),
+            ),
+            Err(err) => {
+                _warn!(
+                    input,
+                    "Could not get the audio streams' channels and languages: {err}"
+                );
+                None
+            }
+        }
+    }
+
+    /// Get the args to reencode audio as AAC. The bitrate is given for stereo, and is scaled for
+    /// each stream's channel count. Surround streams are mixed down to stereo unless
+    /// `--keep-surround` was given. The reencoded streams are tagged with a title and their
+    /// original language, so players don't show them as "Unknown".
+    /// Lossless streams may be copied instead, if `can_copy` is set; see `--audio-lossless`.
+    pub(crate) async fn get_audio_encode_args(
+        &self,
+        input: &InputFile,
+        stereo_bitrate: u64,
+        can_copy: bool,
+    ) -> AudioArgs {
+        let reencode = AudioAction::Reencode(Some("aac".to_string()));
+        let Some(streams) = self.get_output_audio_streams(input).await else {
+            let args = os_args![
+                "-c:a",
+                "aac",
+                "-b:a",
+                stereo_bitrate.to_string(),
+                "-ac",
+                "2",
+                "-metadata:s:a",
+                format!("title=AAC {}", get_channel_layout_name(2))
+            ];
+            return AudioArgs::for_all(args, reencode);
+        };
+
+        let mut audio_args = AudioArgs::default();
+        for (i, stream) in streams.iter().enumerate() {
+            match self.get_lossless_copy_args(
+                input,
+                i,
+                stream,
+                can_copy && self.output_can_hold(input, stream),
+            ) {
+                Some(copy_args) => audio_args.add_stream(copy_args, AudioAction::Copy),
+                None => audio_args.add_stream(
+                    self.get_stream_encode_args(i, stream, stereo_bitrate),
+                    reencode.clone(),
+                ),
+            }
+        }
+        audio_args
+    }
+
+    /// Whether the stream can be copied into the output container as it is. If not, it's logged,
+    /// since the stream is reencoded instead.
+    fn output_can_hold(&self, input: &InputFile, stream: &StreamInfo) -> bool {
+        let Ok(output_path) = input.get_output_path(input.get_cli().output_name.clone()) else {
+            return true;
+        };
+        let codec = stream.codec.as_deref().unwrap_or_default();
+        if Container::from_path(&output_path)
+            .is_none_or(|container| container.can_hold(&stream.codec_type, codec))
+        {
+            return true;
+        }
+        _info!(
+            input,
+            "Reencoding {} stream {}, since {codec} can't be copied into the output",
+            stream.codec_type,
+            stream.index
+        );
+        false
+    }
+
+    /// Apply `--audio-lossless` to the `i`th output audio stream. If it's lossless and should be
+    /// kept, get the args to copy it.
+    pub(crate) fn get_lossless_copy_args(
+        &self,
+        input: &InputFile,
+        i: usize,
+        stream: &StreamInfo,
+        can_copy: bool,
+    ) -> Option<Vec<OsString>> {
+        if !stream.is_lossless_audio() {
+            return None;
+        }
+        let codec = stream
+            .profile
+            .as_ref()
+            .or(stream.codec.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        match self.cli.audio_lossless {
+            LosslessAudioPolicy::Keep if can_copy => {
+                _debug!(
+                    input,
+                    "Audio stream {} is lossless ({codec}). Will not reencode",
+                    stream.index
+                );
+                return Some(os_args![format!("-c:a:{i}"), "copy"]);
+            }
+            LosslessAudioPolicy::Encode => {}
+            LosslessAudioPolicy::Keep | LosslessAudioPolicy::Warn => {
+                _warn!(
+                    input,
+                    "{:?}: Reencoding lossless audio stream {} ({codec}) as AAC",
+                    input.path,
+                    stream.index
+                );
+            }
+        }
+        None
+    }
+
+    /// Get the args to reencode the `i`th output audio stream as AAC.
+    pub(crate) fn get_stream_encode_args(
+        &self,
+        i: usize,
+        stream: &StreamInfo,
+        stereo_bitrate: u64,
+    ) -> Vec<OsString> {
+        let channels = stream.channels.unwrap_or(2);
+        let channels = if self.cli.keep_surround {
+            channels
+        } else {
+            min(channels, 2)
+        };
+        let mut args = os_args![
+            format!("-c:a:{i}"),
+            "aac",
+            format!("-ac:a:{i}"),
+            channels.to_string(),
+            format!("-b:a:{i}"),
+            get_bitrate_for_channels(stereo_bitrate, channels).to_string()
+        ];
+        // A real title from the source, like "Commentary", is kept, unless the source's metadata
+        // is dropped:
+        let has_title = stream
+            .title
+            .as_deref()
+            .is_some_and(|title| !title.is_empty());
+        if !has_title || self.cli.strip_metadata {
+            args.extend(os_args![
+                format!("-metadata:s:a:{i}"),
+                format!("title=AAC {}", get_channel_layout_name(channels))
+            ]);
+        }
+        if let Some(language) = &stream.language {
+            args.extend(os_args![
+                format!("-metadata:s:a:{i}"),
+                format!("language={language}")
+            ]);
+        }
+        args
+    }
+
+    /// Check whether a stream should be mapped into the output. The input's other audio streams
+    /// are needed, since a stream may be kept only because no better one exists.
+    pub(crate) fn keep_stream(&self, stream: &StreamInfo, audio_streams: &[StreamInfo]) -> bool {
+        if stream.codec_type != "audio" {
+            return true;
+        }
+        if self.cli.drop_commentary {
+            let main_streams: Vec<_> = audio_streams
+                .iter()
+                .filter(|stream| !is_commentary(stream))
+                .cloned()
+                .collect();
+            // If every stream looks like commentary, the titles must be misleading:
+            if !main_streams.is_empty() {
+                return !is_commentary(stream)
+                    && has_wanted_language(stream, &main_streams, &self.cli.audio_lang);
+            }
+        }
+        has_wanted_language(stream, audio_streams, &self.cli.audio_lang)
+    }
+
+    pub(crate) fn get_label_filter(
+        &self,
+        input: &InputFile,
+        label_template: &str,
+    ) -> Result<String> {
+        let text = escape_vf_path(&input.fill_text_template(label_template)?)?;
+        let mut filter = String::from("drawtext=");
+        match self.cli.label_font.clone().or_else(find_label_font) {
+            Some(font) => {
+                let font = font
+                    .to_str()
+                    .context("Could not convert font path to utf-8.")?
+                    .to_owned();
+                filter += &format!("fontfile={}:", escape_vf_path(&font)?);
+            }
+            None => {
+                _debug!(
+                    input,
+                    "No font file found for the label. Relying on ffmpeg's fontconfig"
+                );
+            }
+        }
+        filter += &format!(
+            "text={text}:expansion=none:fontcolor=white:fontsize=h/30:box=1:boxcolor=black@0.5:boxborderw=4:x=10:y=10"
+        );
+        Ok(filter)
+    }
+
+    /// Write the chapters for `--auto-chapters` to an ffmetadata file, to be given to ffmpeg as an
+    /// input. The chapter times are adjusted for the trim and `--speed`. ffmpeg moves chapters
+    /// back by the output's `-ss` itself, so without `input_seek` they're written that much later.
+    pub(crate) async fn write_auto_chapters(
+        &self,
+        input: &InputFile,
+        auto_chapters: AutoChapters,
+        input_seek: bool,
+    ) -> Result<tempfile::NamedTempFile> {
+        let trim = self.cli.get_trim()?;
+        let duration = trim.trimmed_duration(input.get_duration().await? as f64);
+        let starts = match auto_chapters {
+            AutoChapters::Interval(minutes) => interval_chapters(duration, minutes),
+            AutoChapters::Scene => {
+                let trim_start = trim.start.unwrap_or(0.0);
+                let scene_times: Vec<_> = input
+                    .detect_scenes()
+                    .await?
+                    .into_iter()
+                    .map(|time| time - trim_start)
+                    .filter(|&time| time > 0.0)
+                    .collect();
+                scene_chapters(&scene_times, duration)
+            }
+        };
+        let speed = self.cli.speed.unwrap_or(1.0);
+        let offset = trim.output_start(input_seek, speed);
+        let starts: Vec<_> = starts
+            .into_iter()
+            .map(|start| offset + start / speed)
+            .collect();
+        _debug!(input, "Adding {} chapters", starts.len());
+
+        let chapters_file = tempfile::Builder::new().suffix(".ffmetadata").tempfile()?;
+        std::fs::write(
+            chapters_file.path(),
+            to_ffmetadata(&starts, offset + duration / speed),
+        )?;
+        Ok(chapters_file)
+    }
+
+    pub(crate) fn get_x265_params(&self, crf: u8) -> Option<Vec<&str>> {
+        if self.cli.av1 || !self.cli.anime {
+            None
+        } else {
+            assert!(self.cli.anime);
+
+            // These encoding tips are from: https://kokomins.wordpress.com/2019/10/10/anime-encoding-guide-for-x265-and-why-to-never-use-flac/
+            let x265_params = if self.cli.anime_slow_well_lit {
+                vec![
+                    "bframes=8",
+                    "psy-rd=1",
+                    "aq-mode=3",
+                    "aq-strength=0.8",
+                    "deblock=1,1",
+                ]
+            } else if self.cli.anime_mixed_dark_battle {
+                if crf >= 19 {
+                    // Note: recommended if: non-complex, motion only alternative
+                    vec![
+                        "bframes=8",
+                        "psy-rd=1",
+                        "psy-rdoq=1",
+                        "aq-mode=3",
+                        "qcomp=0.8",
+                    ]
+                } else {
+                    // Note: recommended if: motion + fancy & detailed FX
+                    vec![
+                        "limit-sao",
+                        "bframes=8",
+                        "psy-rd=1.5",
+                        "psy-rdoq=2",
+                        "aq-mode=3",
+                    ]
+                }
+            } else if crf > 19 {
+                vec!["bframes=8", "psy-rd=1", "aq-mode=3"]
+            } else {
+                vec!["limit-sao", "bframes=8", "psy-rd=1", "aq-mode=3"]
+            };
+            Some(x265_params)
+        }
+    }
+}
+
+/// The -vf filters for `--tonemap-sdr`: convert HDR to linear light, tone map it with the Hable
+/// curve, and convert it to BT.709 so it looks right on SDR displays.
+pub const TONEMAP_FILTER: &str =
+    "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv";
+
+/// The audio codec of `--mezzanine` outputs: 24-bit PCM.
+pub const MEZZANINE_AUDIO_CODEC: &str = "pcm_s24le";
+
+/// Get the output flags that tag the video with the same color properties as the source, so
+/// players don't guess wrong, such as showing BT.601 video as BT.709.
+pub fn get_color_args(stream: &StreamInfo) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for (flag, value) in [
+        ("-color_primaries", &stream.color_primaries),
+        ("-color_trc", &stream.color_transfer),
+        ("-colorspace", &stream.color_space),
+        ("-color_range", &stream.color_range),
+    ] {
+        if let Some(value) = value {
+            args.extend(os_args![flag, value]);
+        }
+    }
+    args
+}
+
+/// How strongly to denoise the video with `--denoise`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Denoise {
+    Light,
+    Medium,
+    Heavy,
+}
+
+impl Denoise {
+    /// The -vf filter for this strength. Heavy uses nlmeans, which keeps edges much better than
+    /// hqdn3d at high strength, but is many times slower.
+    pub fn filter(&self) -> &'static str {
+        match self {
+            Denoise::Light => "hqdn3d=1.5:1.5:3:3",
+            Denoise::Medium => "hqdn3d=4:3:6:4.5",
+            Denoise::Heavy => "nlmeans=s=3:p=7:r=9",
+        }
+    }
+}
+
+/// Where the video is decoded, for `--hwdecode`. Decoding in hardware leaves more of the CPU for
+/// a software encoder, which helps most with heavy sources like 4K HEVC or AV1.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum HwDecode {
+    #[default]
+    None,
+    /// Let ffmpeg pick a hardware decoder, and decode in software if none can decode the video.
+    Auto,
+    Vaapi,
+    Nvdec,
+}
+
+impl HwDecode {
+    /// The input args that turn on hardware decoding, with the device to use if one is given,
+    /// like "/dev/dri/renderD128" for VAAPI or "0" for the first NVIDIA GPU.
+    pub fn input_args(&self, device: Option<&str>) -> Vec<OsString> {
+        let mut args = match self {
+            HwDecode::None => return Vec::new(),
+            HwDecode::Auto => os_args!(str: "-hwaccel auto"),
+            HwDecode::Vaapi => os_args!(str: "-hwaccel vaapi -hwaccel_output_format vaapi"),
+            HwDecode::Nvdec => os_args!(str: "-hwaccel cuda -hwaccel_output_format cuda"),
+        };
+        if let Some(device) = device {
+            args.extend(os_args!["-hwaccel_device", device]);
+        }
+        args
+    }
+
+    /// The filter that copies the decoded frames from the GPU, so the software filters and
+    /// encoder can use them. With `auto`, ffmpeg copies them itself, since the frames may not be
+    /// on the GPU at all. The frames are copied in the layout the decoder gives for the video's
+    /// pixel format, which is only known for 8 and 10-bit 4:2:0 video; see `can_decode`.
+    pub fn download_filter(&self, pix_fmt: Option<&str>) -> Option<&'static str> {
+        match self {
+            HwDecode::None | HwDecode::Auto => None,
+            HwDecode::Vaapi | HwDecode::Nvdec => match pix_fmt? {
+                "yuv420p" | "yuvj420p" | "nv12" => Some("hwdownload, format=nv12"),
+                "yuv420p10le" | "yuv420p10be" | "p010le" => Some("hwdownload, format=p010le"),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether this can decode a video with the pixel format, like "yuv422p10le". Decoding to
+    /// the GPU is only useful if the frames can be copied back from it.
+    pub fn can_decode(&self, pix_fmt: Option<&str>) -> bool {
+        matches!(self, HwDecode::None | HwDecode::Auto) || self.download_filter(pix_fmt).is_some()
+    }
+}
+
+/// The start of the signature that jiffy writes into its outputs.
+pub const JIFFY_SIGNATURE_PREFIX: &str = "jiffy:";
+
+/// The metadata tag that holds the signature. It has its own tag, so it's kept with
+/// `--strip-metadata`, and the source's comment isn't replaced.
+pub const JIFFY_SIGNATURE_TAG: &str = "jiffy";
+
+/// The signature written into each output, like "jiffy:0.1.0:h265:crf22", so
+/// `--skip-jiffy-outputs` can recognize jiffy's own outputs.
+pub fn get_jiffy_signature(codec: &Codec, crf: u8) -> String {
+    let codec_name = format!("{codec:?}").to_lowercase();
+    let mut signature = format!(
+        "{JIFFY_SIGNATURE_PREFIX}{}:{codec_name}",
+        env!("CARGO_PKG_VERSION")
+    );
+    if codec.uses_crf() {
+        signature += &format!(":crf{crf}");
+    }
+    signature
+}
+
+/// The part of each video to encode, from `--start`, `--end` and `--duration`, in seconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Trim {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+impl Trim {
+    /// Check that the trim isn't empty. `duration` is the alternative to `end`.
+    pub fn new(start: Option<f64>, end: Option<f64>, duration: Option<f64>) -> Result<Self> {
+        let end = end.or_else(|| duration.map(|duration| start.unwrap_or(0.0) + duration));
+        if let Some(end) = end {
+            if end <= start.unwrap_or(0.0) {
+                bail!("The end of the trim must be after the start");
+            }
+        }
+        Ok(Self { start, end })
+    }
+
+    pub fn is_trimmed(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+
+    /// The arguments for before `-i`. Seeking there is fast, but the timestamps start over from
+    /// zero, so it's only done when nothing else depends on the input's timestamps.
+    pub fn get_input_args(&self, input_seek: bool) -> Vec<OsString> {
+        match self.start {
+            Some(start) if input_seek => os_args!["-ss", start.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The arguments for the output. Without `input_seek`, ffmpeg decodes up to the start and
+    /// throws it away. The output's times are after `--speed`, so they're divided by the speed.
+    pub fn get_output_args(&self, input_seek: bool, speed: f64) -> Vec<OsString> {
+        let mut args = Vec::new();
+        if self.start.is_some() && !input_seek {
+            args.extend(os_args![
+                "-ss",
+                self.output_start(input_seek, speed).to_string()
+            ]);
+        }
+        if let Some(end) = self.end {
+            args.extend(os_args![
+                "-t",
+                ((end - self.start.unwrap_or(0.0)) / speed).to_string()
+            ]);
+        }
+        args
+    }
+
+    /// Where the output's `-ss` cuts it, on the output's timeline. It's zero when the input is
+    /// seeked instead.
+    pub fn output_start(&self, input_seek: bool, speed: f64) -> f64 {
+        match self.start {
+            Some(start) if !input_seek => start / speed,
+            _ => 0.0,
+        }
+    }
+
+    /// The length of the trimmed part of a video this long.
+    pub fn trimmed_duration(&self, duration: f64) -> f64 {
+        let end = self.end.map_or(duration, |end| end.min(duration));
+        (end - self.start.unwrap_or(0.0)).max(0.0)
+    }
+}
+
+/// Scale a stereo audio bitrate for a different number of channels, for example 128k stereo
+/// becomes 256k for 5.1.
+pub fn get_bitrate_for_channels(stereo_bitrate: u64, channels: u32) -> u64 {
+    match channels {
+        0 | 1 => stereo_bitrate / 2,
+        2 => stereo_bitrate,
+        3..=6 => stereo_bitrate * 2,
+        _ => stereo_bitrate * 5 / 2,
+    }
+}
+
+/// Get the common name of an audio channel layout, like "2.0" for stereo or "5.1" for surround.
+pub fn get_channel_layout_name(channels: u32) -> String {
+    match channels {
+        1 => "1.0".to_string(),
+        2 => "2.0".to_string(),
+        3 => "2.1".to_string(),
+        6 => "5.1".to_string(),
+        8 => "7.1".to_string(),
+        n => format!("{n}ch"),
+    }
+}
+
+/// Get the atempo filters that change the audio speed by this factor. Each atempo filter is
+/// limited to a factor between 0.5 and 2.0 on older ffmpeg versions, so larger changes are made
+/// by chaining several of them.
+pub fn get_atempo_filters(speed: f64) -> Vec<String> {
+    let mut filters = Vec::new();
+    let mut remaining = speed;
+    while remaining > 2.0 {
+        filters.push("atempo=2".to_string());
+        remaining /= 2.0;
+    }
+    while remaining < 0.5 {
+        filters.push("atempo=0.5".to_string());
+        remaining /= 0.5;
+    }
+    if (remaining - 1.0).abs() > f64::EPSILON || filters.is_empty() {
+        filters.push(format!("atempo={remaining}"));
+    }
+    filters
+}
+
+/// Extract a text subtitle stream and add a filter to burn it into the video. The returned file
+/// must be kept until ffmpeg is done.
+pub(crate) async fn add_subtitles(
+    input: &InputFile,
+    stream_index: u32,
+    vf: &mut FilterChain,
+) -> Result<tempfile::NamedTempFile> {
+    let sub_file = tempfile::Builder::new().suffix(".ass").tempfile()?;
+    let sub_path = sub_file.path();
+    let escaped_sub_path = escape_vf_path(
+        sub_path
+            .to_str()
+            .context("Could not convert temp path to utf-8. Needed for subtitles.")?,
+    )?;
+    dump_stream(
+        &input.path,
+        sub_path,
+        false,
+        Some(stream_index),
+        input.get_cli(),
+    )
+    .await?;
+    vf.add(
+        FilterStage::Subtitles,
+        format!("subtitles={escaped_sub_path}"),
+    );
+    Ok(sub_file)
+}
+
+/// Escape a path for use with the ffmpeg -vf argument. The escaping rules are hard to discover except
+/// by testing.
+pub(crate) fn escape_vf_path(sub_path: &str) -> Result<String> {
+    let sub_path = sub_path.replace(r"\", r"\\\\");
+    let sub_path = sub_path.replace(r"'", r"\\\'");
+    let sub_path = sub_path.replace(r":", r"\\:");
+    let sub_path = sub_path.replace(r"=", r"\\=");
+    let sub_path = Regex::new(r"([;,\[\] =])")?.replace_all(&sub_path, "\\$1");
+    Ok(sub_path.to_string())
+}
+
+/// Find a font for drawing text on videos, from the usual locations for each platform.
+pub(crate) fn find_label_font() -> Option<PathBuf> {
+    let candidates: &[&str] = if cfg!(windows) {
+        &[
+            r"C:\Windows\Fonts\arial.ttf",
+            r"C:\Windows\Fonts\segoeui.ttf",
+        ]
+    } else if cfg!(target_os = "macos") {
+        &[
+            "/System/Library/Fonts/Supplemental/Arial.ttf",
+            "/Library/Fonts/Arial.ttf",
+            "/System/Library/Fonts/Helvetica.ttc",
+        ]
+    } else {
+        &[
+            "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+            "/usr/share/fonts/TTF/DejaVuSans.ttf",
+            "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+            "/usr/share/fonts/truetype/liberation/LiberationSans-Regular.ttf",
+            "/usr/share/fonts/liberation/LiberationSans-Regular.ttf",
+        ]
+    };
+    candidates
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+}
+
+/// Get the args that map the external subtitle inputs after the video's own output streams, and
+/// set their language and flags. The subtitles must be given as inputs 1, 2, and so on.
+pub fn get_external_subtitle_args(
+    subtitles: &[ExternalSubtitle],
+    mapped_count: usize,
+) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for (i, subtitle) in subtitles.iter().enumerate() {
+        let output_index = mapped_count + i;
+        args.extend(os_args!["-map", format!("{}:0", i + 1)]);
+        if let Some(language) = &subtitle.language {
+            args.extend(os_args![
+                format!("-metadata:s:{output_index}"),
+                format!("language={language}")
+            ]);
+        }
+        let dispositions = if subtitle.dispositions.is_empty() {
+            "0".to_string()
+        } else {
+            subtitle.dispositions.join("+")
+        };
+        args.extend(os_args![
+            format!("-disposition:{output_index}"),
+            dispositions
+        ]);
+    }
+    args
+}
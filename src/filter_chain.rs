@@ -0,0 +1,69 @@
+//! The -vf filter chain, built from filters that each belong to a stage, so the order of the
+//! filters doesn't depend on the order the options are handled in.
+
+use std::ffi::OsString;
+
+/// The stages of the -vf filter chain, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilterStage {
+    /// Frames decoded in hardware are copied from the GPU before any other filter sees them.
+    Download,
+    /// Stabilization must see the same frames the camera motion was detected on.
+    Stabilize,
+    Deinterlace,
+    /// Cropping comes before anything that works on the whole frame, so the black bars aren't
+    /// denoised or scaled, and burned in subtitles aren't placed in the bars and cut off.
+    Crop,
+    /// Denoising comes before scaling, while the grain is still sharp.
+    Denoise,
+    Scale,
+    /// Tone mapping comes after scaling, since it's slow, and before burning in subtitles, so
+    /// they aren't dimmed.
+    Tonemap,
+    Format,
+    /// The filters from `--extra-flag` and the per-video environment variables.
+    UserExtras,
+    /// Subtitles are burned in before the speed changes, since their times match the input's.
+    Subtitles,
+    /// Changing the speed and capping the frame rate. The frame rate is capped after the speed
+    /// is changed, since that changes it too.
+    Timing,
+    /// Text drawn over the finished picture, like `--label-overlay`.
+    Overlay,
+}
+
+/// The filters for -vf. Filters are run in the order of their stages, and filters in the same
+/// stage run in the order they were added.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterChain {
+    filters: Vec<(FilterStage, OsString)>,
+}
+
+impl FilterChain {
+    pub fn add(&mut self, stage: FilterStage, filter: impl Into<OsString>) {
+        self.filters.push((stage, filter.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// The filters in the order they run.
+    pub fn filters(&self) -> Vec<&OsString> {
+        let mut filters: Vec<_> = self.filters.iter().collect();
+        // The sort is stable, so filters in the same stage keep their order:
+        filters.sort_by_key(|(stage, _)| *stage);
+        filters.into_iter().map(|(_, filter)| filter).collect()
+    }
+
+    /// Join the filters into the argument for -vf, or None if there are none.
+    pub fn to_arg(&self) -> Option<OsString> {
+        let mut filters = self.filters().into_iter();
+        let mut arg = filters.next()?.to_owned();
+        for filter in filters {
+            arg.push(", ");
+            arg.push(filter);
+        }
+        Some(arg)
+    }
+}
@@ -0,0 +1,379 @@
+//! Where the videos to encode come from. With `--input-url`, each video in a bucket or WebDAV
+//! share is fetched into a scratch directory just before it's encoded, and deleted after.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::{mpsc::Sender, Arc},
+};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::*;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    discover::extension_matches, format_size, get_output_dir, regex_matches_path, Cli, Encoder,
+    EncodingDone, EncodingErr, InputFile, ENCODED,
+};
+
+/// A remote location of videos, parsed from a URL like "s3://bucket/uploads" or
+/// "https://example.com/dav/uploads".
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputUrl {
+    S3 {
+        bucket: String,
+        /// The prefix of the object keys, without slashes at the ends. It may be empty.
+        prefix: String,
+    },
+    /// The URL of a WebDAV directory.
+    WebDav(String),
+}
+
+impl InputUrl {
+    pub fn parse(input: &str) -> Result<Self> {
+        if let Some(rest) = input.strip_prefix("s3://") {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                bail!("The input URL has no bucket name: {input}");
+            }
+            return Ok(Self::S3 {
+                bucket: bucket.to_owned(),
+                prefix: prefix.trim_matches('/').to_owned(),
+            });
+        }
+        if input.starts_with("http://") || input.starts_with("https://") {
+            return Ok(Self::WebDav(input.trim_end_matches('/').to_owned()));
+        }
+        if input.starts_with("sftp://") {
+            bail!("SFTP isn't supported by --input-url. Mount the directory with sshfs and encode it directly instead");
+        }
+        bail!("The input URL must start with \"s3://\", \"http://\" or \"https://\", like \"s3://bucket/uploads\"")
+    }
+
+    /// The prefix of the keys to list, within the store.
+    pub fn prefix(&self) -> &str {
+        match self {
+            Self::S3 { prefix, .. } => prefix,
+            // The store is rooted at the URL:
+            Self::WebDav(_) => "",
+        }
+    }
+
+    /// Where an object is fetched to, relative to the video root: its key without the prefix.
+    /// Returns None for objects that shouldn't be fetched, like the outputs of an earlier run.
+    pub fn local_path(&self, key: &str) -> Option<PathBuf> {
+        let relative_key = key.strip_prefix(self.prefix())?.trim_start_matches('/');
+        let path = PathBuf::from(relative_key);
+        // Keys are untrusted, so they must not escape the video root:
+        if !path
+            .components()
+            .all(|part| matches!(part, Component::Normal(_)))
+        {
+            return None;
+        }
+        if path
+            .components()
+            .next()
+            .is_none_or(|part| part.as_os_str() == ENCODED)
+        {
+            return None;
+        }
+        Some(path)
+    }
+}
+
+impl std::fmt::Display for InputUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::S3 { bucket, prefix } => write!(f, "s3://{bucket}/{prefix}"),
+            Self::WebDav(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// Where the videos from `--input-url` are fetched to. It's like a video root for them, but
+/// their outputs go in the output directory of the real video root.
+pub(crate) struct Scratch {
+    /// The options for the fetched videos, with the scratch directory as their video root.
+    cli: Arc<Cli>,
+    /// The directory made for this run when `--scratch-dir` isn't given, which is removed with
+    /// everything in it when the run ends.
+    _temp_dir: Option<tempfile::TempDir>,
+}
+
+impl Scratch {
+    /// Make the scratch directory for `--input-url`, or None without it.
+    pub(crate) fn new(cli: &Cli) -> Result<Option<Self>> {
+        if cli.input_url.is_none() {
+            return Ok(None);
+        }
+        let (dir, temp_dir) = match &cli.scratch_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Could not create the scratch directory {dir:?}"))?;
+                (dir.clone(), None)
+            }
+            None => {
+                let temp_dir = tempfile::Builder::new().prefix("jiffy-fetch").tempdir()?;
+                (temp_dir.path().to_owned(), Some(temp_dir))
+            }
+        };
+        let mut scratch_cli = cli.for_video_root(&dir);
+        scratch_cli.output_dir = Some(get_output_dir(cli));
+        Ok(Some(Self {
+            cli: Arc::new(scratch_cli),
+            _temp_dir: temp_dir,
+        }))
+    }
+}
+
+/// An object to fetch, and where it goes within the scratch directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteInput {
+    pub key: String,
+    pub local_path: PathBuf,
+    pub size: u64,
+}
+
+/// The store the videos are fetched from.
+pub struct InputBackend {
+    #[cfg(feature = "cloud")]
+    store: Box<dyn object_store::ObjectStore>,
+    url: InputUrl,
+}
+
+impl InputBackend {
+    /// Set up the store. For S3, the credentials, region and endpoint are read from the usual
+    /// AWS_* environment variables. Failed requests are retried.
+    pub fn new(url: &InputUrl) -> Result<Self> {
+        #[cfg(feature = "cloud")]
+        {
+            let store: Box<dyn object_store::ObjectStore> = match url {
+                #[cfg(feature = "s3")]
+                InputUrl::S3 { bucket, .. } => Box::new(
+                    object_store::aws::AmazonS3Builder::from_env()
+                        .with_bucket_name(bucket)
+                        .with_retry(object_store::RetryConfig::default())
+                        .build()?,
+                ),
+                #[cfg(feature = "webdav")]
+                InputUrl::WebDav(dav_url) => Box::new(
+                    object_store::http::HttpBuilder::new()
+                        .with_url(dav_url)
+                        .with_retry(object_store::RetryConfig::default())
+                        .build()?,
+                ),
+                #[allow(unreachable_patterns)]
+                _ => bail!("Can't fetch from {url}: jiffy was built without the feature for this kind of URL"),
+            };
+            Ok(Self {
+                store,
+                url: url.clone(),
+            })
+        }
+        #[cfg(not(feature = "cloud"))]
+        bail!("Can't fetch from {url}: jiffy was built without the \"s3\" or \"webdav\" feature")
+    }
+
+    pub fn url(&self) -> &InputUrl {
+        &self.url
+    }
+
+    /// List the objects under the URL, in key order.
+    pub async fn list(&self) -> Result<Vec<RemoteInput>> {
+        #[cfg(feature = "cloud")]
+        {
+            use futures::TryStreamExt;
+            let prefix = object_store::path::Path::from(self.url.prefix());
+            let mut objects: Vec<_> = self
+                .store
+                .list(Some(&prefix))
+                .try_filter_map(|meta| async move {
+                    let key = meta.location.to_string();
+                    Ok(self.url.local_path(&key).map(|local_path| RemoteInput {
+                        key,
+                        local_path,
+                        size: meta.size,
+                    }))
+                })
+                .try_collect()
+                .await?;
+            objects.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(objects)
+        }
+        #[cfg(not(feature = "cloud"))]
+        bail!(
+            "Can't list {}: jiffy was built without cloud storage support",
+            self.url
+        )
+    }
+
+    /// Download an object to `destination`. It's written to a ".part" file first, so an
+    /// interrupted download is never mistaken for a finished one.
+    #[cfg_attr(not(feature = "cloud"), allow(unused_variables))]
+    pub async fn fetch(&self, key: &str, destination: &Path) -> Result<()> {
+        #[cfg(feature = "cloud")]
+        {
+            use futures::StreamExt;
+            use tokio::io::AsyncWriteExt;
+
+            if let Some(parent) = destination.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut partial_path = destination.as_os_str().to_owned();
+            partial_path.push(".part");
+            let partial_path = PathBuf::from(partial_path);
+            let result: Result<()> = async {
+                let mut file = tokio::fs::File::create(&partial_path).await?;
+                let mut stream = self
+                    .store
+                    .get(&object_store::path::Path::from(key))
+                    .await?
+                    .into_stream();
+                while let Some(chunk) = stream.next().await {
+                    file.write_all(&chunk?).await?;
+                }
+                file.flush().await?;
+                Ok(())
+            }
+            .await;
+            if result.is_err() {
+                let _ = tokio::fs::remove_file(&partial_path).await;
+            }
+            result?;
+            tokio::fs::rename(&partial_path, destination).await?;
+            Ok(())
+        }
+        #[cfg(not(feature = "cloud"))]
+        bail!("Can't fetch {key}: jiffy was built without cloud storage support")
+    }
+}
+
+impl Encoder {
+    /// List the videos to fetch from `--input-url`, or None without it. `--limit` counts the
+    /// listed videos.
+    pub(crate) async fn list_inputs(&self) -> Result<Option<Vec<RemoteInput>>> {
+        let Some(backend) = &self.input_backend else {
+            return Ok(None);
+        };
+        let video_re = self.cli.get_video_extensions_regex()?;
+        let exclude = Self::get_matcher_from_globs(
+            &self.video_root,
+            &self.cli.exclude,
+            true,
+            self.cli.ignore_case,
+        )?;
+        std::fs::create_dir_all(&self.video_root)?;
+        let mut inputs = Vec::new();
+        for input in backend
+            .list()
+            .await
+            .with_context(|| format!("Could not list {}", backend.url()))?
+        {
+            if !extension_matches(&input.local_path, &video_re)? {
+                continue;
+            }
+            if exclude
+                .as_ref()
+                .is_some_and(|exclude| self.is_match(exclude, &input.local_path))
+            {
+                log::debug!("Not fetching {} because of exclude", input.key);
+                continue;
+            }
+            if regex_matches_path(&self.cli.exclude_re, &input.local_path)
+                || (!self.cli.include_re.is_empty()
+                    && !regex_matches_path(&self.cli.include_re, &input.local_path))
+            {
+                log::debug!(
+                    "Not fetching {} because of --exclude-re or --include-re",
+                    input.key
+                );
+                continue;
+            }
+            inputs.push(input);
+        }
+        log::info!("Found {} videos in {}", inputs.len(), backend.url());
+        if let Some(limit) = self.cli.limit {
+            inputs.truncate(limit);
+        }
+        Ok(Some(inputs))
+    }
+
+    /// Fetch a video from `--input-url` into the scratch directory, encode it, and delete the
+    /// fetched copy, so only the videos being encoded take up space.
+    pub(crate) async fn encode_remote_video(
+        &self,
+        remote: &RemoteInput,
+        warning_tx: Sender<(PathBuf, String)>,
+        i: usize,
+        total: usize,
+        cancel: &CancellationToken,
+    ) -> Result<EncodingDone, EncodingErr> {
+        if cancel.is_cancelled() {
+            return Ok(EncodingDone::EncodingDone);
+        }
+        let scratch = self
+            .scratch
+            .as_ref()
+            .expect("Only --input-url has remote inputs");
+        let path = scratch.cli.video_root.join(&remote.local_path);
+        let result = self
+            .fetch_and_encode(remote, &scratch.cli, warning_tx, i, total, cancel)
+            .await;
+        // The source may already be gone, moved away by --move-source, or never fetched:
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Could not delete the fetched copy {path:?}: {err}");
+            }
+            _ => {}
+        }
+        result
+    }
+
+    async fn fetch_and_encode(
+        &self,
+        remote: &RemoteInput,
+        scratch_cli: &Arc<Cli>,
+        warning_tx: Sender<(PathBuf, String)>,
+        i: usize,
+        total: usize,
+        cancel: &CancellationToken,
+    ) -> Result<EncodingDone, EncodingErr> {
+        let backend = self
+            .input_backend
+            .as_ref()
+            .expect("Only --input-url has remote inputs");
+        let path = &scratch_cli.video_root.join(&remote.local_path);
+        let failed = |err: anyhow::Error| {
+            EncodingErr(
+                path.to_owned(),
+                format!("Could not fetch {}: {err:#}", remote.key),
+            )
+        };
+        log::info!(
+            "Fetching {}/{}: {} ({})",
+            i + 1,
+            total,
+            remote.key,
+            format_size(remote.size)
+        );
+        backend.fetch(&remote.key, path).await.map_err(failed)?;
+        let md = path.metadata().map_err(|err| failed(err.into()))?;
+        if !self
+            .passes_filters(path, &md, &self.cli.get_skip_codecs())
+            .await
+        {
+            return Ok(EncodingDone::EncodingDone);
+        }
+        let input = InputFile::new(path, scratch_cli.clone())
+            .await
+            .map_err(|err| EncodingErr(path.to_owned(), format!("{err:?}")))?;
+        let has_duration_filter =
+            self.cli.min_duration.is_some() || self.cli.max_duration.is_some();
+        if has_duration_filter && !self.duration_matches(&input).await {
+            return Ok(EncodingDone::EncodingDone);
+        }
+        self.encode_video(&input, warning_tx, i, total, cancel)
+            .await
+    }
+}
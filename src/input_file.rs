@@ -1,38 +1,375 @@
 use std::{
     cmp::{max, min},
+    collections::{BTreeMap, BTreeSet, HashMap},
     env,
     ffi::OsString,
+    fs::OpenOptions,
+    io::Write,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use anyhow::{bail, Context, Result};
 use regex::Regex;
-use tokio::process::Command;
 
 #[allow(unused_imports)]
 use crate::{_debug, _error, _info, _log, _trace, _warn};
-use crate::{find_executable, get_output_dir, normalize_path, Cli, Codec, Executable};
+use crate::{
+    find_tool, get_file_size, get_original_ext, get_output_dir, normalize_path, parse_scene_times,
+    Cli, Codec, Executable, JiffyError, JiffyResult, SubtitleSelector, NEAR_LOSSLESS_CRF,
+    SCENE_THRESHOLD,
+};
+
+/// A stream's properties, as reported by ffprobe.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamInfo {
+    /// The index of the stream among all streams in the file.
+    pub index: u32,
+    /// "video", "audio", "subtitle", "attachment" or "data".
+    pub codec_type: String,
+    pub codec: Option<String>,
+    /// The codec profile, like "DTS-HD MA" or "High".
+    pub profile: Option<String>,
+    pub channels: Option<u32>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    /// The color properties of a video stream, with ffprobe's names like "bt709". The transfer
+    /// is like "bt709" or "smpte2084", and the range is "tv" or "pc".
+    pub color_primaries: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_space: Option<String>,
+    pub color_range: Option<String>,
+    /// The average frame rate of a video stream.
+    pub frame_rate: Option<f64>,
+    /// The pixel format of a video stream, like "yuv420p10le".
+    pub pix_fmt: Option<String>,
+    /// The disposition flags that are set, like "default" or "forced".
+    pub dispositions: BTreeSet<String>,
+}
+
+impl StreamInfo {
+    pub fn from_section(section: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            index: section
+                .get("index")
+                .context("ffprobe did not report the stream index")?
+                .parse()?,
+            codec_type: section.get("codec_type").cloned().unwrap_or_default(),
+            codec: section.get("codec_name").cloned(),
+            profile: section
+                .get("profile")
+                .cloned()
+                .filter(|profile| profile != "unknown"),
+            channels: section.get("channels").and_then(|n| n.parse().ok()),
+            language: section.get("TAG:language").cloned(),
+            title: section.get("TAG:title").cloned(),
+            color_primaries: Self::get_color_property(section, "color_primaries"),
+            color_transfer: Self::get_color_property(section, "color_transfer"),
+            color_space: Self::get_color_property(section, "color_space"),
+            color_range: Self::get_color_property(section, "color_range"),
+            pix_fmt: section
+                .get("pix_fmt")
+                .cloned()
+                .filter(|pix_fmt| pix_fmt != "unknown"),
+            frame_rate: section
+                .get("avg_frame_rate")
+                .and_then(|rate| parse_frame_rate(rate))
+                .or_else(|| {
+                    section
+                        .get("r_frame_rate")
+                        .and_then(|rate| parse_frame_rate(rate))
+                }),
+            dispositions: section
+                .iter()
+                .filter_map(|(key, value)| {
+                    let flag = key.strip_prefix("DISPOSITION:")?;
+                    (value == "1").then(|| flag.to_owned())
+                })
+                .collect(),
+        })
+    }
+
+    fn get_color_property(section: &HashMap<String, String>, key: &str) -> Option<String> {
+        section
+            .get(key)
+            .filter(|value| !matches!(value.as_str(), "unknown" | "unspecified" | "reserved" | ""))
+            .cloned()
+    }
+
+    pub fn has_disposition(&self, flag: &str) -> bool {
+        self.dispositions.contains(flag)
+    }
+
+    /// Check whether this is a subtitle stream made of text, rather than images.
+    pub fn is_text_subtitle(&self) -> bool {
+        self.codec_type == "subtitle"
+            && matches!(
+                self.codec.as_deref(),
+                Some(
+                    "subrip" | "srt" | "ass" | "ssa" | "mov_text" | "webvtt" | "text" | "microdvd"
+                )
+            )
+    }
+
+    /// The bits per color component of a video stream, from its pixel format. Formats without a
+    /// depth in their name, like "yuv420p", are 8-bit.
+    pub fn bit_depth(&self) -> Option<u32> {
+        let pix_fmt = self.pix_fmt.as_deref()?;
+        let captures = Regex::new(r"(\d+)(?:le|be)$").unwrap().captures(pix_fmt);
+        Some(
+            captures
+                .and_then(|captures| captures[1].parse().ok())
+                .unwrap_or(8),
+        )
+    }
+
+    /// Check whether this is an HDR video stream: one with the PQ (HDR10, Dolby Vision) or HLG
+    /// transfer characteristics.
+    pub fn is_hdr(&self) -> bool {
+        self.codec_type == "video"
+            && matches!(
+                self.color_transfer.as_deref(),
+                Some("smpte2084" | "arib-std-b67")
+            )
+    }
+
+    /// Check whether this is an audio stream with lossless compression, like FLAC or TrueHD.
+    pub fn is_lossless_audio(&self) -> bool {
+        let codec = self.codec.as_deref().unwrap_or_default();
+        self.codec_type == "audio"
+            && (matches!(
+                codec,
+                "flac" | "truehd" | "mlp" | "alac" | "wavpack" | "ape" | "tta"
+            ) || codec.starts_with("pcm_")
+                || (codec == "dts"
+                    && self
+                        .profile
+                        .as_deref()
+                        .is_some_and(|profile| profile.starts_with("DTS-HD MA"))))
+    }
+}
+
+/// Parse a frame rate as ffprobe shows it, like "30000/1001" or "25/1". Unknown rates are "0/0".
+pub fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (numerator, denominator) = rate.trim().split_once('/').unwrap_or((rate.trim(), "1"));
+    let rate = numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?;
+    (rate.is_finite() && rate > 0.0).then_some(rate)
+}
 
+/// Parse ffprobe output in the default format, which has sections like:
+///
+/// [STREAM]
+/// index=1
+/// TAG:language=eng
+/// [/STREAM]
+pub fn parse_ffprobe_sections(output: &str) -> Vec<HashMap<String, String>> {
+    let mut sections = Vec::new();
+    let mut section = None;
+    for line in output.lines().map(str::trim) {
+        if line.starts_with("[/") {
+            sections.extend(section.take());
+        } else if line.starts_with('[') {
+            section = Some(HashMap::new());
+        } else if let (Some(section), Some((key, value))) = (section.as_mut(), line.split_once('='))
+        {
+            section.insert(key.to_owned(), value.to_owned());
+        }
+    }
+    sections
+}
+
+/// The loudness target for `--normalize-audio`: integrated loudness, true peak and loudness range.
+pub const LOUDNORM_TARGET: &str = "I=-16:TP=-1.5:LRA=11";
+
+/// How many points in the video `--autocrop` samples, and how many seconds it analyzes at each.
+const CROP_SAMPLES: usize = 5;
+const CROP_SAMPLE_SECONDS: u32 = 2;
+
+/// A crop rectangle, as printed by ffmpeg's `cropdetect` filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CropRect {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl CropRect {
+    /// Find the crop that `cropdetect` suggested most often in its output, if any.
+    pub fn parse(output: &str) -> Option<Self> {
+        let re = Regex::new(r"crop=(\d+):(\d+):(\d+):(\d+)").unwrap();
+        let mut counts = HashMap::<CropRect, usize>::new();
+        for cap in re.captures_iter(output) {
+            let rect = CropRect {
+                width: cap[1].parse().ok()?,
+                height: cap[2].parse().ok()?,
+                x: cap[3].parse().ok()?,
+                y: cap[4].parse().ok()?,
+            };
+            *counts.entry(rect).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|(rect, count)| (*count, rect.width * rect.height))
+            .map(|(rect, _)| rect)
+    }
+
+    /// Combine the crops detected at different points of the video into the smallest rectangle containing all of
+    /// them, so a dark scene can't cause picture to be cut off. The size is rounded down to be even.
+    pub fn union(rects: &[CropRect]) -> Option<Self> {
+        let left = rects.iter().map(|r| r.x).min()?;
+        let top = rects.iter().map(|r| r.y).min()?;
+        let right = rects.iter().map(|r| r.x + r.width).max()?;
+        let bottom = rects.iter().map(|r| r.y + r.height).max()?;
+        Some(CropRect {
+            width: (right - left) & !1,
+            height: (bottom - top) & !1,
+            x: left,
+            y: top,
+        })
+    }
+
+    /// Whether this crop would remove anything from a video of the given size.
+    pub fn crops(&self, width: u32, height: u32) -> bool {
+        self.width < width || self.height < height
+    }
+
+    pub fn to_filter(&self) -> String {
+        format!("crop={}:{}:{}:{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+/// Loudness measurements from the first pass of ffmpeg's `loudnorm` filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loudness {
+    pub input_i: f64,
+    pub input_tp: f64,
+    pub input_lra: f64,
+    pub input_thresh: f64,
+    pub target_offset: f64,
+}
+
+impl Loudness {
+    /// Parse the JSON that `loudnorm` prints at the end of its output with `print_format=json`.
+    pub fn parse(output: &str) -> Result<Self> {
+        let get = |key: &str| -> Result<f64> {
+            let re = Regex::new(&format!(r#""{key}"\s*:\s*"([^"]*)""#)).unwrap();
+            let value = re
+                .captures_iter(output)
+                .last()
+                .with_context(|| format!("loudnorm did not report {key}"))?[1]
+                .parse::<f64>()?;
+            // Silent audio is measured as -inf:
+            if !value.is_finite() {
+                bail!("loudnorm measured {key} as {value}");
+            }
+            Ok(value)
+        };
+        Ok(Self {
+            input_i: get("input_i")?,
+            input_tp: get("input_tp")?,
+            input_lra: get("input_lra")?,
+            input_thresh: get("input_thresh")?,
+            target_offset: get("target_offset")?,
+        })
+    }
+
+    /// Get the filter for the second pass, which applies these measurements.
+    pub fn to_filter(&self) -> String {
+        format!(
+            "loudnorm={LOUDNORM_TARGET}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+            self.input_i, self.input_tp, self.input_lra, self.input_thresh, self.target_offset
+        )
+    }
+}
+
+/// Total the packet sizes from ffprobe's "stream_index,size" csv output, by stream index.
+pub fn parse_packet_sizes(output: &str) -> BTreeMap<u32, u64> {
+    let mut sizes = BTreeMap::new();
+    for line in output.lines() {
+        let packet = line.split_once(',').and_then(|(index, size)| {
+            // Some packets have trailing fields, like side data:
+            let size = size.split(',').next()?;
+            Some((
+                index.trim().parse::<u32>().ok()?,
+                size.trim().parse::<u64>().ok()?,
+            ))
+        });
+        match packet {
+            Some((index, size)) => *sizes.entry(index).or_default() += size,
+            None if line.trim().is_empty() => {}
+            None => log::warn!("Ignoring unexpected line when getting the audio size: {line}"),
+        }
+    }
+    sizes
+}
+
+/// The {dir} template field: the directory of a video within the video root, with the levels
+/// joined by " - ", like "Show - Season 1". It's empty for videos directly in the video root.
+pub fn get_template_dir(relative_path: &Path) -> String {
+    let parent = relative_path.parent().unwrap_or(Path::new(""));
+    let parts: Vec<_> = parent
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    parts.join(" - ")
+}
+
+/// The {hash} template field: 8 hex digits that identify a video by its path within the video
+/// root, so flattened outputs of videos with the same name don't collide.
+pub fn get_template_hash(relative_path: &Path) -> String {
+    use sha2::{Digest, Sha256};
+
+    let path = relative_path.to_string_lossy().replace('\\', "/");
+    let digest = Sha256::digest(path.as_bytes());
+    digest[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The default `--log-name`: the input's path within the video root, with ".log" added.
+pub const DEFAULT_LOG_NAME: &str = "{relpath}.log";
+
+#[derive(Clone)]
 pub struct InputFile {
     pub path: PathBuf,
     pub log_path: Option<PathBuf>,
     pub crf: u8,
+    /// Added to the output name, so it doesn't collide with another video's output.
+    output_suffix: Option<String>,
+    /// Why ffprobe couldn't tell what the CRF depends on, if it couldn't. The CRF was chosen
+    /// without it.
+    probe_errors: Vec<String>,
     cli: Arc<Cli>,
 }
 
 impl InputFile {
-    pub async fn new(path: &Path, cli: Arc<Cli>) -> Result<Self> {
+    pub(crate) fn get_cli(&self) -> &Cli {
+        &self.cli
+    }
+
+    pub async fn new(path: &Path, cli: Arc<Cli>) -> JiffyResult<Self> {
         let mut ret = Self {
             path: path.to_owned(),
             log_path: Self::get_log_path(path, &cli)?,
             crf: u8::MAX, // placeholder
+            output_suffix: None,
+            probe_errors: Vec::new(),
             cli,
         };
         ret.init().await?;
+        if let (true, Some(err)) = (ret.cli.strict_probe, ret.probe_errors.first()) {
+            return Err(JiffyError::ProbeFailed(err.clone()));
+        }
         Ok(ret)
     }
 
+    /// Why ffprobe couldn't tell what the CRF depends on, like the video's dimensions.
+    pub fn probe_errors(&self) -> &[String] {
+        &self.probe_errors
+    }
+
     /// Trim off the front part of an input path, so the video root
     /// directory is not included.
     ///
@@ -60,6 +397,12 @@ impl InputFile {
             .collect::<PathBuf>())
     }
 
+    pub fn fill_template(naming_format: &str, basename: &str, preset: &str, crf: &str) -> String {
+        let name = naming_format.replace("{basename}", basename);
+        let name = name.replace("{preset}", preset);
+        name.replace("{crf}", crf)
+    }
+
     pub fn fill_output_template(
         naming_format: &str,
         directory: PathBuf,
@@ -68,15 +411,13 @@ impl InputFile {
         crf: &str,
         extension: &str,
     ) -> PathBuf {
-        let name = naming_format.replace("{basename}", basename);
-        let name = name.replace("{preset}", preset);
-        let name = name.replace("{crf}", crf);
+        let name = Self::fill_template(naming_format, basename, preset, crf);
         // Don't use with_extension() since we must add it, not change it:
         let name = format!("{name}.{extension}");
         directory.join(PathBuf::from(name))
     }
 
-    pub fn get_output_path(&self, naming_format: Option<String>) -> Result<PathBuf> {
+    pub fn get_output_path(&self, naming_format: Option<String>) -> JiffyResult<PathBuf> {
         let output_dir = get_output_dir(&self.cli);
 
         let extension = self
@@ -85,23 +426,22 @@ impl InputFile {
             .map(|extension| extension.to_ascii_lowercase().to_string_lossy().to_string());
         // Let mp4 keep its extension, but change others to mkv:
         let extension = match extension.as_deref() {
+            // Editors expect ProRes and DNxHR in QuickTime files:
+            _ if self.cli.mezzanine.is_some() => "mov",
+            // FFV1 can only be stored in mkv:
+            _ if self.cli.ffv1 => "mkv",
             Some("mp4") => "mp4",
             _ => "mkv",
         };
 
-        let basename = Self::trim_input_path(&self.path, &self.cli.video_root)?
-            .with_extension("")
-            .to_string_lossy()
-            .to_string();
-
-        let naming_format = naming_format.unwrap_or({
-            if self.cli.get_video_codec() == Codec::Av1 {
-                "{basename}-{preset}-crf{crf}"
-            } else {
-                "{basename}-crf{crf}"
-            }
-            .into()
-        });
+        let basename = self.get_template_basename()?;
+
+        let naming_format = naming_format.unwrap_or_else(|| self.cli.get_default_naming_format());
+        let naming_format = match &self.output_suffix {
+            Some(suffix) => format!("{naming_format}-{suffix}"),
+            None => naming_format,
+        };
+        let naming_format = self.fill_path_fields(&naming_format)?;
 
         Ok(Self::fill_output_template(
             &naming_format,
@@ -113,87 +453,224 @@ impl InputFile {
         ))
     }
 
-    /// Get the log path for this input file. Also create the directory for the log
-    /// file, since logging starts before encoding, so the directory may not exist
-    /// if we delay.
+    /// Fill a template like the output name template, but without adding a directory or extension.
+    /// This is used for text that is drawn on the video.
+    pub fn fill_text_template(&self, template: &str) -> Result<String> {
+        let template = self.fill_path_fields(template)?;
+        Ok(Self::fill_template(
+            &template,
+            &self.get_template_basename()?,
+            &self.cli.preset,
+            &self.crf.to_string(),
+        ))
+    }
+
+    /// The {basename} template field: the path within the video root, without the extension.
+    /// With `--flatten`, it's just the file name without the extension.
+    fn get_template_basename(&self) -> Result<String> {
+        let relative_path = Self::trim_input_path(&self.path, &self.cli.video_root)?;
+        let basename = if self.cli.flatten {
+            PathBuf::from(relative_path.file_stem().unwrap_or_default())
+        } else {
+            relative_path.with_extension("")
+        };
+        Ok(basename.to_string_lossy().to_string())
+    }
+
+    /// Add a suffix to the output name, like "-avi" in "show-crf22-avi.mkv".
+    pub(crate) fn set_output_suffix(&mut self, suffix: String) {
+        self.output_suffix = Some(suffix);
+    }
+
+    /// Fill the template fields that depend on the input path and options, other than the ones
+    /// `fill_template` fills: {speed}, {original_ext}, {dir} and {hash}.
+    fn fill_path_fields(&self, template: &str) -> Result<String> {
+        let template = template.replace("{speed}", &self.cli.get_speed_str());
+        let template = template.replace("{original_ext}", &get_original_ext(&self.path));
+        if !template.contains("{dir}") && !template.contains("{hash}") {
+            return Ok(template);
+        }
+        let relative_path = Self::trim_input_path(&self.path, &self.cli.video_root)?;
+        let template = template.replace("{dir}", &get_template_dir(&relative_path));
+        Ok(template.replace("{hash}", &get_template_hash(&relative_path)))
+    }
+
+    /// Fill a `--log-name` template. The fields are {relpath} (the path within the video root),
+    /// {basename} (the same without the extension), and {date} and {time} of the encode.
+    pub fn fill_log_template(
+        template: &str,
+        relpath: &Path,
+        now: &chrono::DateTime<chrono::Local>,
+    ) -> PathBuf {
+        let name = template.replace("{relpath}", &relpath.to_string_lossy());
+        let name = name.replace("{basename}", &relpath.with_extension("").to_string_lossy());
+        let name = name.replace("{date}", &now.format("%Y-%m-%d").to_string());
+        PathBuf::from(name.replace("{time}", &now.format("%H%M%S").to_string()))
+    }
+
+    /// Get the log path for this input file, in the output directory. The directory for it is
+    /// created when the first line is logged.
     fn get_log_path(input_path: &Path, cli: &Cli) -> Result<Option<PathBuf>> {
         if cli.test_opts.no_log {
             Ok(None)
         } else {
-            let output_dir = get_output_dir(cli);
-            let mut output = output_dir
-                .join(Self::trim_input_path(&input_path, &cli.video_root)?)
-                .into_os_string();
-            output.push(".log");
+            let relpath = Self::trim_input_path(input_path, &cli.video_root)?;
+            let template = cli.log_name.as_deref().unwrap_or(DEFAULT_LOG_NAME);
+            let log_name = Self::fill_log_template(template, &relpath, &chrono::Local::now());
+            Ok(Some(get_output_dir(cli).join(log_name)))
+        }
+    }
 
-            Ok(Some(output.into()))
+    /// Name the log by the time the encode starts, for the {date} and {time} fields of
+    /// `--log-name`. The lines logged since the input was found are moved to it.
+    pub fn start_log(&mut self) -> Result<()> {
+        let log_path = Self::get_log_path(&self.path, &self.cli)?;
+        if let (Some(old_path), Some(new_path)) = (&self.log_path, &log_path) {
+            if old_path != new_path && old_path.exists() {
+                let earlier_lines = std::fs::read(old_path)?;
+                if let Some(parent) = new_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(new_path)?
+                    .write_all(&earlier_lines)?;
+                std::fs::remove_file(old_path)?;
+            }
         }
+        self.log_path = log_path;
+        Ok(())
     }
 
     async fn init(&mut self) -> Result<()> {
-        let codec = self.cli.get_video_codec();
+        let mut probe_errors = Vec::new();
         self.crf = if let Some(crf) = self.cli.crf {
             crf
-        } else {
-            let mut crf = match codec {
-                Codec::Av1 => 24,
-                Codec::H265 => 22,
-                Codec::H264 if self.cli.for_tv => 17,
-                Codec::H264 => 8, // if not for TV, this old codec is most useful for making a reference clip
-                Codec::Copy => 0,
-            };
-            if self.cli.anime {
-                crf += 3;
+        } else if let Some(crf_map) = &self.cli.crf_map {
+            match self.get_video_dimensions().await {
+                Ok((w, h)) => {
+                    let crf = crf_map.get_crf(w, h);
+                    _debug!(
+                        &*self,
+                        "Using CRF {crf} from --crf-map for the {w}x{h} video"
+                    );
+                    crf
+                }
+                Err(err) => {
+                    log::warn!("Could not get video dimensions for --crf-map, so the CRF is inferred: {err}");
+                    probe_errors.push(format!(
+                        "Could not get the video dimensions for --crf-map: {err}"
+                    ));
+                    self.infer_crf(&mut probe_errors).await
+                }
             }
+        } else {
+            self.infer_crf(&mut probe_errors).await
+        };
+        self.probe_errors = probe_errors;
 
-            match self.get_video_dimensions().await {
-                Ok((w, h)) if codec != Codec::Copy => {
-                    let max_dimension = max(w, h);
-                    if max_dimension < 1920 {
-                        // Smaller videos need better CRF, so subtract some points--
-                        // But 1080p needs no delta, but let's give 720p or lower delta=4.
-                        let shrinkage = 1920 - max_dimension;
-                        let delta = min(
-                            4,
-                            (4f32 * shrinkage as f32 / (1920 - 1080) as f32).round() as u8,
-                        );
-                        if delta > 0 {
+        Ok(())
+    }
+
+    /// Choose a CRF for the codec, adjusted for animation and for small videos. Probes that fail
+    /// are added to `probe_errors`.
+    async fn infer_crf(&self, probe_errors: &mut Vec<String>) -> u8 {
+        let codec = self.cli.get_video_codec();
+        let mut crf = match codec {
+            // x265 ignores the CRF in lossless mode:
+            _ if self.cli.lossless => 0,
+            _ if self.cli.near_lossless => NEAR_LOSSLESS_CRF,
+            Codec::Av1 => 24,
+            Codec::H265 => 22,
+            Codec::H264 if self.cli.for_tv => 17,
+            Codec::H264 => 8, // if not for TV, this old codec is most useful for making a reference clip
+            Codec::ProRes | Codec::Dnxhr | Codec::Ffv1 | Codec::Copy => 0,
+        };
+        if self.cli.anime && codec.uses_crf() && !self.cli.lossless && !self.cli.near_lossless {
+            crf += 3;
+        }
+
+        if codec.uses_crf() && !self.cli.near_lossless {
+            match self.get_video_stream().await {
+                Ok(Some(stream)) => {
+                    if let (Some(source_codec), Some(bits)) =
+                        (stream.codec.as_deref(), stream.bit_depth())
+                    {
+                        let offset = self.cli.get_crf_offsets().get_offset(source_codec, bits);
+                        if offset != 0 {
+                            let adjusted =
+                                (crf as i16 + offset as i16).clamp(0, codec.max_crf() as i16) as u8;
                             _info!(
-                                &*self,
-                                "Changing inferred CRF from {} to {} because input is small",
-                                crf,
-                                crf - delta
+                                self,
+                                "Changing inferred CRF from {crf} to {adjusted} for the {bits}-bit {source_codec} source"
                             );
-                            crf -= delta;
+                            crf = adjusted;
                         }
                     }
                 }
+                Ok(None) => {}
                 Err(err) => {
-                    log::warn!(
-                        "Error running ffprobe, could not get video dimensions: {}",
-                        err
-                    );
+                    log::warn!("Could not probe the video stream to adjust the CRF: {err}");
+                    probe_errors.push(format!("Could not probe the video stream: {err}"));
                 }
-                _ => {}
             }
+        }
 
-            crf
-        };
+        match self.get_video_dimensions().await {
+            Ok((w, h)) if codec.uses_crf() && !self.cli.near_lossless => {
+                let max_dimension = max(w, h);
+                if max_dimension < 1920 {
+                    // Smaller videos need better CRF, so subtract some points--
+                    // But 1080p needs no delta, but let's give 720p or lower delta=4.
+                    let shrinkage = 1920 - max_dimension;
+                    let delta = min(
+                        4,
+                        (4f32 * shrinkage as f32 / (1920 - 1080) as f32).round() as u8,
+                    );
+                    if delta > 0 {
+                        _info!(
+                            self,
+                            "Changing inferred CRF from {} to {} because input is small",
+                            crf,
+                            crf - delta
+                        );
+                        crf -= delta;
+                    }
+                }
+            }
+            Err(err) => {
+                log::warn!(
+                    "Error running ffprobe, could not get video dimensions: {}",
+                    err
+                );
+                probe_errors.push(format!("Could not get the video dimensions: {err}"));
+            }
+            _ => {}
+        }
 
-        Ok(())
+        crf
     }
 
-    /// Returns bitrate in kb/second, for example 128 or 256.
-    pub(crate) async fn get_audio_bitrate(&self) -> Result<f32> {
-        let seconds = self.get_audio_seconds().await?;
-        Ok(self.get_audio_size_kb().await? / seconds * 8f32)
+    /// Get the bitrate of each audio stream in kb/s, for example 128 or 256, by the index of the
+    /// stream.
+    pub(crate) async fn get_audio_bitrates(&self) -> Result<BTreeMap<u32, f32>> {
+        let seconds = self.get_duration().await?;
+        Ok(self
+            .get_audio_sizes_kb()
+            .await?
+            .into_iter()
+            .map(|(index, size)| (index, size / seconds * 8f32))
+            .collect())
     }
 
-    async fn get_audio_seconds(&self) -> Result<f32> {
-        let ffprobe = find_executable(Executable::FFPROBE)?;
+    /// Get the length of the video in seconds.
+    pub(crate) async fn get_duration(&self) -> Result<f32> {
+        let ffprobe = find_tool(Executable::FFPROBE, &self.cli)?;
 
         _trace!(self, "Trying to get the length from the container");
-        let output = Command::new(&ffprobe)
+        let output = ffprobe
+            .command()
             .args(
                 "-v error -show_entries format=duration -of default=noprint_wrappers=1:nokey=1"
                     .split_whitespace(),
@@ -209,7 +686,8 @@ impl InputFile {
         }
 
         _trace!(self, "Trying to get the length from the video stream");
-        let output = Command::new(&ffprobe)
+        let output = ffprobe
+            .command()
             .args(
                 "-v error -select_streams v:0 -show_entries stream=duration -of default=noprint_wrappers=1:nokey=1"
                     .split_whitespace(),
@@ -230,8 +708,9 @@ impl InputFile {
         //
         // NOTE: the simpler commands that do this don't work on all files.
         // See: https://trac.ffmpeg.org/wiki/FFprobeTips
-        let ffmpeg = find_executable(Executable::FFMPEG)?;
-        let output = Command::new(&ffmpeg)
+        let ffmpeg = find_tool(Executable::FFMPEG, &self.cli)?;
+        let output = ffmpeg
+            .command()
             .arg("-i")
             .arg(&self.path)
             .args("-vn -f null -".split_whitespace())
@@ -260,49 +739,213 @@ impl InputFile {
         Ok(seconds)
     }
 
-    /// Get the audio stream's size in kilobytes
-    async fn get_audio_size_kb(&self) -> Result<f32> {
+    /// Get the bitrate of the whole file in bits per second, from the container if it says, or
+    /// else from the size and length.
+    pub(crate) async fn get_overall_bitrate(&self) -> Result<u64> {
+        let ffprobe = find_tool(Executable::FFPROBE, &self.cli)?;
+        let output = ffprobe
+            .command()
+            .args(
+                "-v error -show_entries format=bit_rate -of default=noprint_wrappers=1:nokey=1"
+                    .split_whitespace(),
+            )
+            .arg(&self.path)
+            .output()
+            .await?
+            .stdout;
+        if let Ok(bitrate) = String::from_utf8_lossy(&output).trim().parse::<u64>() {
+            return Ok(bitrate);
+        }
+        _trace!(self, "The container has no bitrate, so calculating it");
+        let duration = self.get_duration().await?;
+        if duration <= 0.0 {
+            bail!("The video has no length");
+        }
+        Ok((get_file_size(&self.path)? as f64 * 8.0 / duration as f64) as u64)
+    }
+
+    /// Get the size of each audio stream in kilobytes, by the index of the stream.
+    async fn get_audio_sizes_kb(&self) -> Result<BTreeMap<u32, f32>> {
         _trace!(self, "Calculating audio size");
-        let ffprobe = find_executable(Executable::FFPROBE)?;
-        let output = Command::new(ffprobe)
-            .args("-v error -select_streams a -show_entries packet=size -of default=nokey=1:noprint_wrappers=1".split_whitespace())
+        let ffprobe = find_tool(Executable::FFPROBE, &self.cli)?;
+        let output = ffprobe
+            .command()
+            .args(
+                "-v error -select_streams a -show_entries packet=stream_index,size -of csv=p=0"
+                    .split_whitespace(),
+            )
             .arg(&self.path)
             .output()
             .await?
             .stdout;
         let output = String::from_utf8_lossy(&output);
-        let mut sum = 0f32;
-        for line in output.lines() {
-            if let Ok(bytes) = line.parse::<f32>() {
-                sum += bytes;
-            } else {
-                _warn!(
-                    self,
-                    "Ignoring non-numeric line when getting the audio size: {}",
-                    line,
-                );
+        let sizes = parse_packet_sizes(&output)
+            .into_iter()
+            .map(|(index, bytes)| (index, bytes as f32 / 1024f32))
+            .collect();
+        _trace!(self, "Audio sizes: {:?}", sizes);
+        Ok(sizes)
+    }
+
+    /// Get the properties of each stream, in order.
+    pub async fn get_streams(&self) -> JiffyResult<Vec<StreamInfo>> {
+        let ffprobe = find_tool(Executable::FFPROBE, &self.cli)?;
+        let output = ffprobe
+            .command()
+            .args(
+                "-v error -show_entries stream=index,codec_type,codec_name,profile,channels,color_primaries,color_transfer,color_space,color_range,avg_frame_rate,r_frame_rate,pix_fmt:stream_tags=language,title:stream_disposition -of default"
+                    .split_whitespace(),
+            )
+            .arg(&self.path)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(JiffyError::ProbeFailed(format!(
+                "ffprobe could not read the streams of {:?}",
+                self.path
+            )));
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        Ok(parse_ffprobe_sections(&output)
+            .iter()
+            .map(StreamInfo::from_section)
+            .collect::<Result<_>>()?)
+    }
+
+    /// Get the properties of the main video stream, which is not a cover picture.
+    pub async fn get_video_stream(&self) -> JiffyResult<Option<StreamInfo>> {
+        Ok(self
+            .get_streams()
+            .await?
+            .into_iter()
+            .find(|stream| stream.codec_type == "video" && !stream.has_disposition("attached_pic")))
+    }
+
+    /// Get the properties of each audio stream, in order.
+    pub async fn get_audio_streams(&self) -> JiffyResult<Vec<StreamInfo>> {
+        Ok(self
+            .get_streams()
+            .await?
+            .into_iter()
+            .filter(|stream| stream.codec_type == "audio")
+            .collect())
+    }
+
+    /// Measure the loudness of an audio stream, given by its index among all the streams, for the
+    /// first pass of `--normalize-audio`. This decodes all the stream, so it takes a while.
+    pub async fn measure_loudness(&self, stream_index: u32) -> JiffyResult<Loudness> {
+        let ffmpeg = find_tool(Executable::FFMPEG, &self.cli)?;
+        _debug!(
+            self,
+            "Measuring the loudness of stream {stream_index} of {:?}",
+            self.path
+        );
+        let output = ffmpeg
+            .command()
+            .args("-hide_banner -nostats -i".split_whitespace())
+            .arg(&self.path)
+            .args([
+                "-map",
+                &format!("0:{stream_index}"),
+                "-af",
+                &format!("loudnorm={LOUDNORM_TARGET}:print_format=json"),
+            ])
+            .args("-f null -".split_whitespace())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(JiffyError::ProbeFailed(
+                "ffmpeg failed to measure the loudness. Does the video have audio?".to_string(),
+            ));
+        }
+        Ok(Loudness::parse(&String::from_utf8_lossy(&output.stderr))?)
+    }
+
+    /// Run `cropdetect` at several points in the video for `--autocrop`, and find a crop that removes the black
+    /// bars from all of them. Returns None if there are no bars to remove.
+    pub async fn detect_crop(&self) -> JiffyResult<Option<CropRect>> {
+        let ffmpeg = find_tool(Executable::FFMPEG, &self.cli)?;
+        let duration = self.get_duration().await?;
+        let mut rects = Vec::new();
+        for i in 0..CROP_SAMPLES {
+            // Skip the very start and end, which are often black or have credits:
+            let timestamp = duration * (i as f32 + 0.5) / CROP_SAMPLES as f32;
+            _trace!(self, "Detecting the crop at {timestamp:.1}s");
+            let output = ffmpeg
+                .command()
+                .args([
+                    "-hide_banner",
+                    "-nostats",
+                    "-ss",
+                    &timestamp.to_string(),
+                    "-i",
+                ])
+                .arg(&self.path)
+                .args([
+                    "-t",
+                    &CROP_SAMPLE_SECONDS.to_string(),
+                    "-map",
+                    "0:v:0",
+                    "-vf",
+                    "cropdetect=limit=24:round=2",
+                ])
+                .args("-f null -".split_whitespace())
+                .output()
+                .await?;
+            if !output.status.success() {
+                return Err(JiffyError::ProbeFailed(format!(
+                    "ffmpeg failed to detect the crop at {timestamp:.1}s"
+                )));
             }
+            rects.extend(CropRect::parse(&String::from_utf8_lossy(&output.stderr)));
         }
-        sum /= 1024f32;
-        _trace!(self, "Audio size: {}", sum);
-        Ok(sum)
+        let Some(rect) = CropRect::union(&rects) else {
+            return Err(JiffyError::ProbeFailed(
+                "cropdetect did not report a crop".to_string(),
+            ));
+        };
+        let (width, height) = self.get_video_dimensions().await?;
+        Ok(rect.crops(width, height).then_some(rect))
     }
 
-    pub async fn contains_subtitle(&self) -> Result<bool> {
-        let ffmpeg = find_executable(Executable::FFMPEG)?;
-        let status = Command::new(ffmpeg)
-            .arg("-i")
+    /// Find the times of the major scene changes, for `--auto-chapters scene`. This decodes the
+    /// whole video, though at a small size.
+    pub async fn detect_scenes(&self) -> JiffyResult<Vec<f64>> {
+        let ffmpeg = find_tool(Executable::FFMPEG, &self.cli)?;
+        _debug!(self, "Detecting the scene changes of {:?}", self.path);
+        let output = ffmpeg
+            .command()
+            .args("-hide_banner -nostats -i".split_whitespace())
             .arg(&self.path)
-            .args("-c copy -map 0:s:0 -frames:s 1 -f null - -v 0 -hide_banner".split_whitespace())
-            .status()
+            .args([
+                "-map",
+                "0:v:0",
+                "-vf",
+                &format!("scale=320:-2, select='gt(scene,{SCENE_THRESHOLD})', showinfo"),
+            ])
+            .args("-f null -".split_whitespace())
+            .output()
             .await?;
-        // The command returns true only if a subtitle is in the video
-        Ok(status.success())
+        if !output.status.success() {
+            return Err(JiffyError::ProbeFailed(
+                "ffmpeg failed to detect the scene changes".to_string(),
+            ));
+        }
+        Ok(parse_scene_times(&String::from_utf8_lossy(&output.stderr)))
+    }
+
+    /// Find the subtitle stream to burn in, if any stream matches the selector.
+    pub async fn find_subtitle_stream(
+        &self,
+        selector: &SubtitleSelector,
+    ) -> JiffyResult<Option<StreamInfo>> {
+        Ok(selector.select(&self.get_streams().await?).cloned())
     }
 
-    async fn get_video_dimensions(&self) -> Result<(u32, u32)> {
-        let ffprobe_path = find_executable(Executable::FFPROBE)?;
-        let output = Command::new(&ffprobe_path)
+    pub(crate) async fn get_video_dimensions(&self) -> Result<(u32, u32)> {
+        let ffprobe_path = find_tool(Executable::FFPROBE, &self.cli)?;
+        let output = ffprobe_path
+            .command()
             .args(
                 "-v error -select_streams v:0 -show_entries stream=width,height -of csv=s=x:p=0"
                     .split_whitespace(),
@@ -1,343 +1,132 @@
 use std::{
-    cmp::max, collections::{HashSet, VecDeque}, env, ffi::{OsStr, OsString}, fs::remove_file, future::Future, io::Write, path::{Path, PathBuf}, pin::Pin, sync::{
+    collections::{HashMap, HashSet}, env, ffi::{OsStr, OsString}, fs::remove_file, future::Future, io::Write, path::{Path, PathBuf}, pin::Pin, sync::{
         mpsc::{channel, Sender},
         Arc,
+        Mutex,
         RwLock,
-    }, time::Duration
+    }, time::{Duration, Instant}
 };
 
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{ArgAction, Args, Parser};
 use futures::stream::{FuturesUnordered, StreamExt};
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use lexical_sort;
 #[allow(unused_imports)]
 use log::*;
 use regex::Regex;
 
+// Easily package different kinds of args as OsString
+macro_rules! os_args {
+    (str: $str:expr) => {
+        $str.split_whitespace().map(|x| OsString::from(x)).collect::<Vec<_>>()
+    };
+    ($($x : expr),* $(,)?) => {
+        vec![$(OsString::from($x)), *]
+    };
+}
+
+pub mod error;
+pub use error::*;
 pub mod input_file;
 pub use input_file::*;
 pub mod logger;
 #[allow(unused_imports)]
 pub use logger::*;
+pub mod stream_map;
+pub use stream_map::*;
+pub mod output_validation;
+pub use output_validation::*;
+pub mod progress;
+pub use progress::*;
+pub mod trash;
+pub use trash::*;
+pub mod cli;
+pub use cli::*;
+pub mod discover;
+pub use discover::*;
+pub mod ffmpeg_args;
+pub use ffmpeg_args::*;
+pub mod policy;
+pub use policy::*;
+pub mod schedule;
+pub use schedule::*;
+pub mod util;
+pub use util::*;
+pub mod report;
+pub use report::*;
+pub mod filter_chain;
+pub use filter_chain::*;
+pub mod chapters;
+pub use chapters::*;
+pub mod sync;
+pub use sync::*;
+pub mod output_backend;
+pub use output_backend::*;
+pub mod input_backend;
+pub use input_backend::*;
+pub mod usage;
+pub use usage::*;
+pub mod run;
+pub use run::*;
+pub mod compat;
+pub use compat::*;
+pub mod list;
+pub use list::*;
+pub mod relocate;
+pub use relocate::*;
+pub mod audio_report;
+pub use audio_report::*;
+pub mod config;
+pub use config::*;
 use tokio::{io::AsyncReadExt, process::Command, select, time::sleep};
-
-pub const ENCODED: &str = "encoded";
-
-#[derive(PartialEq, std::fmt::Debug)]
-pub enum Codec {
-    Av1,
-    H265,
-    H264,
-    Copy,
-}
-
-// TODO: the encode dir is unnecessary if both --include and -o are specified
-#[derive(Parser, Default)]
-pub struct Cli {
-    /// Set the quality level (for either encoded). The default is 24 for AV1 and 22 for H265, but
-    /// if unspecified, a better CRF may be used for small videos, or a lower quality CRF may be
-    /// used for animation.
-    #[clap(long)]
-    pub crf: Option<u8>,
-
-    /// Use x265 instead of aom-av1. This is the default.
-    #[clap(long, alias = "h265", conflicts_with_all = ["av1", "reference"])]
-    pub x265: bool,
-
-    /// Use x264 to make a high quality (high disk space) fast encode.
-    #[clap(long, conflicts_with_all = ["av1", "x265"])]
-    pub reference: bool,
-
-    /// Use libaom-av1 for encoding.
-    #[clap(long = "av1", aliases = ["aom", "libaom", "aom-av1"])]
-    pub av1: bool,
-
-    /// Use settings that work well for anime or animation.
-    #[clap(long = "animation", alias = "anime",
-        default_value_ifs = [
-            ("anime_slow_well_lit", "true", "true"),
-            ("anime_mixed_dark_battle", "true", "true"),
-        ],
-    )]
-    pub anime: bool,
-
-    /// Use this setting for slow well lit anime, like slice of life:
-    #[clap(long, conflicts_with_all = ["av1", "anime_mixed_dark_battle", "reference"])]
-    pub anime_slow_well_lit: bool,
-
-    /// Use this setting for anime with some dark scenes, some battle scenes (shonen, historical, etc.)
-    #[clap(long, conflicts_with_all = ["av1", "anime_slow_well_lit", "reference"])]
-    pub anime_mixed_dark_battle: bool,
-
-    /// Encode this many videos in parallel. The default varies per encoder.
-    #[clap(long, short, alias = "max-jobs")]
-    pub jobs: Option<usize>,
-
-    /// Encode as 720p. Otherwise the video will be 1080p. The source size is taken into
-    /// consideration; in no case is a video scaled up.
-    #[clap(long = "720p")]
-    pub height_720p: bool,
-
-    /// Encode as 8-bit.  Otherwise the video will be 10-bit, except if creating
-    /// a file as reference or for TV. However, this depends on the compilation
-    /// options of the encoder.
-    #[clap(
-        long = "8-bit",
-        alias = "8bit",
-        default_value_if("for_tv", "true", "true"),
-        default_value_if("reference", "true", "true")
-    )]
-    pub eight_bit: bool,
-
-    /// Wait for other ffmpeg jobs to cease, so there are `--jobs` total ffmpeg
-    /// processes, not more. This allows a jiffy instance to wait for another,
-    /// without needing all its jobs to finish before starting.
-    #[clap(long, aliases = ["slow-start", "global"])]
-    pub slow_start: bool,
-
-    /// The encoding preset to use--by default this is fairly slow. By default, "5" for libaom,
-    /// "slow" for x265.
-    #[clap(
-        long,
-        hide_default_value = true,
-        default_value = "slow",
-        default_value_if("reference", "true", "veryfast"),
-        default_value_if("av1", "true", "5"),
-        default_value_if("for_tv", "true", "fast")
-    )]
-    pub preset: String,
-
-    /// Overwrite existing output files
-    // TODO: integration test that --overwrite and --noop still does not overwrite files
-    #[clap(long)]
-    pub overwrite: bool,
-
-    /// Don't check if the audio streams are within acceptable limits--just reencode them (unless
-    /// `--copy-audio` was specified). This saves a little time in some circumstances.
-    #[clap(
-        long = "skip-bitrate-check",
-        default_value_if("copy_streams", "true", "true"),
-        default_value_if("no_audio", "true", "true")
-    )]
-    pub skip_audio_bitrate_check: bool,
-
-    /// Encode the videos in this directory. By default, encode in the current directory. Output
-    /// files are put in "video_root/encoded". If the given path ends in "encoded", the real video
-    /// root is taken to be the parent directory.
-    #[clap(default_value = ".", hide_default_value = true)]
-    pub video_root: PathBuf,
-
-    /// Paths (usually glob patterns) that can be excluded. They match from the video encode root.
-    /// For example, "*S01*/*E01*" might be used to skip the first episode of a TV show, and
-    /// "**/*E01*" would skip the first episode of each season. This argument must be given once
-    /// per exclude pattern.  See the `--include` option.
-    #[clap(long)]
-    pub exclude: Vec<String>,
-
-    /// Paths (usually glob patterns) to be included; all others are excluded. They match from the
-    /// video encode root. If `--include` and `--exclude` are both given, only those that are
-    /// matched by the include globs and not matched by the exclude globs will be encoded.  See the
-    /// `--exclude` option.
-    #[clap(long)]
-    pub include: Vec<String>,
-
-    /// Encode a certain number of files, then stop.
-    #[clap(long)]
-    pub limit: Option<usize>,
-
-    /// Make a high quality but inefficient file for low spec televisions. The output is intended
-    /// for watching, not for archival purposes. This is the only option that encodes with x264.
-    /// Subtitles are hard-coded if available. These files should be compatible with Chromecast
-    /// without the need for transcoding.
-    ///
-    // If you find this option is not compatible with your TV, please let me know what model and what encoding
-    // options do work.
-    #[clap(long = "for-tv", conflicts_with_all = ["av1", "x265", "reference", "anime", "anime_slow_well_lit", "anime_mixed_dark_battle"])]
-    pub for_tv: bool,
-
-    /// If a certain size reduction is expected, this option will warn about
-    /// videos that do not reach that target. For example, 75 if file size is
-    /// expected to be reduced by 25%. This option does not affect encoding.
-    #[clap(long, value_parser = clap::value_parser!(u8).range(1..100))]
-    pub expected_size: Option<u8>,
-
-    /// If an output file is larger than expected (or larger than the original),
-    /// it will be deleted. This prevents accidentally re-encoding highly
-    /// compressed videos to lower compression, losing quality in the process.
-    #[clap(long, requires("expected_size"))]
-    pub delete_too_large: bool,
-
-    /// Files smaller than this size will be skipped. If there is no suffix,
-    /// it's taken to mean megabytes.
-    #[clap(long)]
-    pub minimum_size: Option<String>,
-
-    /// Output files will be written with this name. Fields that will be filled:
-    /// {preset}, {basename}, {crf}
-    /// For example: --output-name "{basename}-crf{crf}"
-    #[clap(long, aliases = ["output-format", "name-format", "naming-format"])]
-    pub output_name: Option<String>,
-
-    /// Output files will be saved in this directory. By default, it is
-    /// <VIDEO_ROOT>/encoded.
-    #[clap(long, short, aliases = ["output-directory", "output-dir", "output-path"])]
-    pub output_dir: Option<PathBuf>,
-
-    #[command(flatten)]
-    pub test_opts: TestOpts,
+use tokio_util::sync::CancellationToken;
+
+/// How often to check the partial output while encoding.
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// The encoder threads each encode uses with `--eco`.
+pub const ECO_THREADS: usize = 2;
+/// The x265 thread settings for `--eco`, since libx265 doesn't follow -threads.
+const ECO_X265_PARAMS: [&str; 2] = ["pools=2", "frame-threads=1"];
+
+/// An ffmpeg release version, like 6.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FfmpegVersion {
+    pub major: u32,
+    pub minor: u32,
 }
 
-#[derive(Args, Default)]
-#[group(required = false, multiple = true)]
-pub struct TestOpts {
-    /// Run through all logic except invoking ffmpeg.
-    #[clap(long)]
-    pub noop: bool,
-
-    /// Run ffmpeg without `-map 0`. This occasionally fixes an encoding error.
-    #[clap(long, default_value_if("for_tv", "true", "true"))]
-    pub no_map_0: bool,
-
-    /// Keep the audio stream unchanged. This is useful if audio bitrate can't be determined.
-    #[clap(long = "copy-audio", default_value_if("copy_streams", "true", "true"))]
-    pub copy_audio: bool,
-
-    /// Copy audio and video streams (don't encode). Used for testing, for example passing
-    /// `--copy-streams --extra-flag='-to 30'` would copy a 30 second from each video. Implies
-    /// `--copy-audio`.
-    #[clap(long = "copy-streams", conflicts_with_all = ["av1", "x265", "reference", "for_tv", "height_720p",
-        "anime", "anime_mixed_dark_battle", "anime_slow_well_lit", "crf", "preset"])]
-    pub copy_streams: bool,
-
-    /// For testing and benchmarking.
-    #[clap(long = "no-audio", conflicts_with = "copy_audio")]
-    pub no_audio: bool,
-
-    /// Add additional ffmpeg flags, such as "-to 5:00" to quickly test the first few minutes of a
-    /// file.  Each option should be passed separately, for example:
-    /// `jiffy --extra-flag='-ss 30' --extra-flag='-t 5:00'`
-    #[clap(long, allow_hyphen_values(true))]
-    pub extra_flag: Vec<String>,
-
-    /// Don't write log files for each ffmpeg invocation. This avoids polluting your output
-    /// directory with a log file per input.
-    #[clap(long, short)]
-    pub no_log: bool,
-
-    /// Can specify -q -q (-qq) to make the program ever more quiet.
-    #[clap(long, short, action = ArgAction::Count)]
-    pub quiet: u8,
-
-    /// Increase the log verbosity.
-    #[clap(long, short, action = ArgAction::Count)]
-    pub verbose: u8,
-}
-
-impl Cli {
-    pub fn get_video_codec(&self) -> Codec {
-        if self.test_opts.copy_streams {
-            Codec::Copy
-        } else if self.reference || self.for_tv {
-            Codec::H264
-        } else if self.av1 {
-            Codec::Av1
-        } else {
-            Codec::H265
-        }
-    }
-
-    pub fn get_verbosity(&self) -> i8 {
-        self.test_opts.verbose as i8 - self.test_opts.quiet as i8
-    }
-
-    /// How many jobs should run in parallel?
-    fn get_jobs(&self) -> Result<usize> {
-        let jobs = match self.jobs {
-            Some(0) => {
-                bail!("Cannot run with 0 jobs.");
-            }
-            None => {
-                if self.x265 {
-                    max(1, (num_cpus::get_physical() as f64 / 3f64).round() as usize)
-                } else {
-                    max(1, num_cpus::get_physical() / 2)
-                }
-            }
-            Some(n) => n,
-        };
-
-        Ok(jobs)
-    }
-
-    fn get_extra_flags(&self) -> Result<Vec<String>> {
-        let whitespace_re = Regex::new(r"\s+")?;
-        Ok(self
-            .test_opts
-            .extra_flag
-            .iter()
-            .flat_map(|extra_flag| {
-                // Split once on space, or leave as is if there's no space:
-                whitespace_re.splitn(extra_flag, 2).map(String::from)
-            })
-            .collect())
-    }
-
-    pub fn get_extra_normal_flags(&self) -> Result<Vec<String>> {
-        let raw = self.get_extra_flags()?;
-        // Combine these to detect -vf and the arg together:
-        // [-vf     hflip           ]
-        // [        -vf        hflip]
-        Ok(raw
-            .iter()
-            .chain(["".to_string()].iter())
-            .zip(["".to_string()].iter().chain(raw.iter()))
-            .filter_map(|(arg, prev_arg)| {
-                if arg != "" && arg != "-vf" && prev_arg != "-vf" {
-                    Some(arg.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect())
-    }
-
-    pub fn get_extra_vf_flags(&self) -> Result<Vec<String>> {
-        let raw = self.get_extra_flags()?;
-        // Combine these to detect -vf and the arg together:
-        // [-vf     hflip           ]
-        // [        -vf        hflip]
-        Ok(raw
-            .iter()
-            .chain(["".to_string()].iter())
-            .zip(["".to_string()].iter().chain(raw.iter()))
-            .filter_map(|(arg, prev_arg)| {
-                if prev_arg == "-vf" {
-                    Some(arg.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect())
+impl FfmpegVersion {
+    /// Parse the output of `ffmpeg -version`, which starts like "ffmpeg version 6.1.1-3ubuntu5".
+    /// Development builds (like "ffmpeg version N-112233-gabcdef") have no release version.
+    pub fn parse(output: &str) -> Option<Self> {
+        let captures = Regex::new(r"ffmpeg version n?(\d+)\.(\d+)")
+            .unwrap()
+            .captures(output)?;
+        Some(Self {
+            major: captures.get(1)?.as_str().parse().ok()?,
+            minor: captures.get(2)?.as_str().parse().ok()?,
+        })
     }
+}
 
-    pub(crate) fn get_height(&self) -> u32 {
-        if self.height_720p {
-            720
-        } else {
-            1080
-        }
+impl std::fmt::Display for FfmpegVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
     }
 }
 
-// Easily package different kinds of args as OsString
-macro_rules! os_args {
-    (str: $str:expr) => {
-        $str.split_whitespace().map(|x| OsString::from(x)).collect::<Vec<_>>()
-    };
-    ($($x : expr),* $(,)?) => {
-        vec![$(OsString::from($x)), *]
+fn get_ffmpeg_version(ffmpeg: &ExternalCommand) -> Option<FfmpegVersion> {
+    let output = match ffmpeg.std_command().arg("-version").output() {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("Could not run ffmpeg to get its version: {err}");
+            return None;
+        }
     };
+    let version = FfmpegVersion::parse(&String::from_utf8_lossy(&output.stdout));
+    match version {
+        Some(version) => debug!("ffmpeg version: {version}"),
+        None => debug!("ffmpeg version is unknown. Assuming it's recent"),
+    }
+    version
 }
 
 enum Executable {
@@ -345,68 +134,281 @@ enum Executable {
     FFPROBE,
 }
 
-enum EncodingDone {
-    EncodingDone,
-    WaitTaskDone,
-}
-
-struct EncodingErr(PathBuf, String);
-
 #[derive(Default)]
 pub struct Encoder {
     cli: Arc<Cli>,
-    ffmpeg_path: OsString,
+    ffmpeg: ExternalCommand,
+    /// None if the version could not be detected, or for development builds.
+    ffmpeg_version: Option<FfmpegVersion>,
     video_root: PathBuf,
-    finished: RwLock<bool>,
+    /// The options for the videos in each of the other video roots.
+    more_root_clis: Vec<Arc<Cli>>,
+    /// What the queue is doing, for the tasks waiting in `--slow-start`.
+    queue_state: QueueWatch,
+    /// Set if the encoder can't make 10-bit video, so 8-bit is used even without `--8-bit`.
+    eight_bit_fallback: RwLock<bool>,
+    /// The result of each file, for the report.
+    results: Mutex<Vec<FileResult>>,
+    /// Set if `--sample-fraction` chose the files to encode.
+    sample: Mutex<Option<SampleInfo>>,
+    /// Limits the copies to the `--sync-to` target running at once. None without `--sync-to`.
+    sync_slots: Option<Arc<tokio::sync::Semaphore>>,
+    /// The running copies to the `--sync-to` target.
+    syncs: Mutex<Vec<tokio::task::JoinHandle<Vec<SyncResult>>>>,
+    /// Where the finished outputs are kept, from `--output-url`.
+    output_backend: OutputBackend,
+    /// Where the videos are fetched from, with `--input-url`.
+    input_backend: Option<InputBackend>,
+    /// Where the videos from `--input-url` are fetched to.
+    scratch: Option<Scratch>,
+    /// What each input's encodes used so far, for `--resource-stats`.
+    usage: Mutex<HashMap<PathBuf, ResourceUsage>>,
+    /// The inputs whose encode failed with `--hwdecode`, which are encoded again with the video
+    /// decoded in software.
+    software_decode: Mutex<HashSet<PathBuf>>,
+    /// The videos skipped with `--strict-probe` because they couldn't be probed, and why.
+    probe_failures: Mutex<Vec<(PathBuf, String)>>,
 }
 
 impl Encoder {
-    pub fn new(cli: Cli) -> Result<Encoder> {
+    pub fn new(mut cli: Cli) -> JiffyResult<Encoder> {
+        let ffmpeg = find_tool(Executable::FFMPEG, &cli)?;
+        // Validate the options that depend on each other:
+        cli.get_tune()?;
+        cli.get_trim()?;
+        cli.load_pattern_files()?;
+        // Pick the seed once, so the whole run can be repeated with it:
+        let seed = *cli.seed.get_or_insert_with(rand::random);
+        debug!("Random seed: {seed} (use --seed {seed} to repeat this run)");
+        let sync_slots = Self::new_sync_slots(&cli);
+        let output_backend = OutputBackend::new(cli.output_url.as_ref())?;
+        let input_backend = cli.input_url.as_ref().map(InputBackend::new).transpose()?;
+        let scratch = Scratch::new(&cli)?;
+        let more_root_clis = cli
+            .more_video_roots
+            .iter()
+            .map(|root| Arc::new(cli.for_video_root(root)))
+            .collect();
         return Ok(Encoder {
             video_root: cli.video_root.clone(),
+            more_root_clis,
             cli: Arc::new(cli),
-            ffmpeg_path: find_executable(Executable::FFMPEG)?,
-            finished: Default::default(),
+            ffmpeg_version: get_ffmpeg_version(&ffmpeg),
+            ffmpeg,
+            queue_state: Default::default(),
+            eight_bit_fallback: Default::default(),
+            results: Default::default(),
+            sample: Default::default(),
+            sync_slots,
+            syncs: Default::default(),
+            output_backend,
+            input_backend,
+            scratch,
+            usage: Default::default(),
+            probe_failures: Default::default(),
+            software_decode: Default::default(),
         });
     }
 
-    pub async fn encode_videos(&self) -> Result<()> {
+    /// Whether ffmpeg is at least this version. If the version is unknown, it's assumed to be new.
+    fn ffmpeg_at_least(&self, major: u32, minor: u32) -> bool {
+        self.ffmpeg_version
+            .is_none_or(|version| version >= FfmpegVersion { major, minor })
+    }
+
+    /// Encode all the videos, and print the report if `--report-format` is given. When `cancel`
+    /// is cancelled, no more encodes are started, and the running ones are stopped and their
+    /// partial outputs deleted.
+    pub async fn encode_videos(&self, cancel: CancellationToken) -> JiffyResult<()> {
+        let report = self.run_encodes(cancel).await?;
+        if let Some(format) = self.cli.report_format {
+            print!("{}", report.render(format));
+        }
+        if report.cancelled {
+            return Err(JiffyError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Encode all the videos, like `encode_videos`, and return what happened instead of
+    /// printing it. A cancelled run still returns the report of what was done.
+    pub async fn run_encodes(&self, cancel: CancellationToken) -> JiffyResult<RunReport> {
+        if let Err(err) = self.check_capabilities().await {
+            if !self.cli.test_opts.noop {
+                return Err(err);
+            }
+            log::warn!("{err:?}");
+        }
+
         let (warning_tx, failures) = channel();
-        let input_files = self.get_video_paths().await?;
-        let task_count = input_files.len();
+        // With --input-url, the videos are fetched by their own jobs instead of found on disk:
+        let remote_inputs = self.list_inputs().await?;
+        let input_files = match remote_inputs {
+            Some(_) => Vec::new(),
+            None => self.get_video_paths().await?,
+        };
+        let remote_inputs = remote_inputs.unwrap_or_default();
+        let probe_skipped = self.report_probe_failures(&input_files, &warning_tx);
+        let probe_fallbacks = input_files
+            .iter()
+            .filter(|input| !input.probe_errors().is_empty())
+            .count();
+        let task_count = input_files.len() + remote_inputs.len();
+        let total_input_size = input_files
+            .iter()
+            .filter_map(|input| get_file_size(&input.path).ok())
+            .sum::<u64>()
+            + remote_inputs.iter().map(|remote| remote.size).sum::<u64>();
+        if let Some(warning) = lossless_size_warning(&self.cli, total_input_size) {
+            log::warn!("{warning}");
+        }
         let mut finished_encode_count = 0;
-        let mut tasks_not_started = input_files
+        let mut tasks_not_started = JobQueue::default();
+        // Encodes that write the same output run one after the other, so they don't both write
+        // the partial output file:
+        let mut output_tasks = HashMap::new();
+        let output_paths: Vec<_> = input_files
             .iter()
-            .enumerate()
-            .map(|(i, input_file)| {
-                let job = self.encode_video(input_file, warning_tx.clone(), i, task_count);
-                Box::pin(job) as Pin<Box<dyn Future<Output = _>>>
+            .map(|input_file| {
+                input_file
+                    .get_output_path(input_file.get_cli().output_name.clone())
+                    .ok()
             })
-            .collect::<VecDeque<_>>();
+            .collect();
+        let known_outputs: Vec<_> = output_paths
+            .iter()
+            .enumerate()
+            .filter_map(|(i, output_path)| Some((i, output_path.clone()?)))
+            .collect();
+        let known_paths: Vec<_> = known_outputs
+            .iter()
+            .map(|(_, output_path)| output_path.clone())
+            .collect();
+        // Videos that write the same output would overwrite each other's, so nothing is encoded
+        // until they're told apart. With --output-collision rename, they already were:
+        let output_collisions = find_output_collisions(&known_paths, CaseSensitivity::Sensitive);
+        if !output_collisions.is_empty() {
+            let collisions: Vec<_> = output_collisions
+                .iter()
+                .map(|group| {
+                    let inputs: Vec<_> = group
+                        .iter()
+                        .map(|&i| format!("{:?}", input_files[known_outputs[i].0].path))
+                        .collect();
+                    format!("{:?} from {}", known_paths[group[0]], inputs.join(", "))
+                })
+                .collect();
+            return Err(anyhow!(
+                "Some videos would write the same output, so nothing was encoded. Use --output-collision rename, or --output-name with {{original_ext}}:\n{}",
+                collisions.join("\n")
+            )
+            .into());
+        }
+        // On a case-insensitive filesystem, outputs whose names differ only in case would
+        // overwrite each other, so the later input isn't encoded. Each video root may have its
+        // own output directory, on its own filesystem:
+        let mut by_output_dir: Vec<(PathBuf, Vec<usize>)> = Vec::new();
+        for (k, (i, _)) in known_outputs.iter().enumerate() {
+            let output_dir = get_output_dir(input_files[*i].get_cli());
+            match by_output_dir.iter_mut().find(|(dir, _)| *dir == output_dir) {
+                Some((_, indexes)) => indexes.push(k),
+                None => by_output_dir.push((output_dir, vec![k])),
+            }
+        }
+        let mut colliding = HashSet::new();
+        for (output_dir, indexes) in by_output_dir {
+            let case = CaseSensitivity::detect(&output_dir);
+            let paths: Vec<_> = indexes.iter().map(|&k| known_paths[k].clone()).collect();
+            for (later, first) in find_case_collisions(&paths, case) {
+                let ((later, later_output), (first, first_output)) = (
+                    &known_outputs[indexes[later]],
+                    &known_outputs[indexes[first]],
+                );
+                colliding.insert(*later);
+                let msg = format!(
+                    "Not encoding, since the output {later_output:?} would overwrite the output {first_output:?} of {:?} on this case-insensitive filesystem. Rename one of them, or use --output-name",
+                    input_files[*first].path
+                );
+                warning_tx
+                    .send((input_files[*later].path.clone(), msg))
+                    .unwrap();
+            }
+        }
+        for (i, input_file) in input_files.iter().enumerate() {
+            if colliding.contains(&i) {
+                continue;
+            }
+            let after = output_paths[i]
+                .clone()
+                .and_then(|output_path| output_tasks.insert(output_path, i))
+                .into_iter()
+                .collect();
+            let job = self.encode_video(input_file, warning_tx.clone(), i, task_count, &cancel);
+            let task = Box::pin(async move { (i, job.await) }) as Pin<Box<dyn Future<Output = _>>>;
+            tasks_not_started.push_back(i, task, after);
+        }
+        for (i, remote) in remote_inputs.iter().enumerate() {
+            let i = input_files.len() + i;
+            let job = self.encode_remote_video(remote, warning_tx.clone(), i, task_count, &cancel);
+            let task = Box::pin(async move { (i, job.await) }) as Pin<Box<dyn Future<Output = _>>>;
+            tasks_not_started.push_back(i, task, Vec::new());
+        }
 
         // Start with JOBS tasks waiting for existing ffmpeg processes, unless
         // we aren't waiting. They don't all need to wait; it depends on the
         // number of ffmpeg processes compared to the number of jobs.
-        let wait_count = if self.cli.slow_start { self.cli.get_jobs()? } else { 0 };
+        let wait_count = if self.cli.get_slow_start() {
+            self.cli.get_jobs()?
+        } else {
+            0
+        };
         for job_id in 0..wait_count {
-            tasks_not_started.push_front(Box::pin(self.wait_for_ffmpeg(job_id)));
+            let id = task_count + job_id;
+            let wait = self.wait_for_ffmpeg(job_id, &cancel);
+            tasks_not_started.push_front(id, Box::pin(async move { (id, wait.await) }));
         }
 
-        let mut tasks_started = FuturesUnordered::new();
-        for _ in 0..self.cli.get_jobs().expect("Jobs should be set already") {
-            if let Some(task) = tasks_not_started.pop_front() {
-                log::trace!("Pushing a task into the job list (not started)");
-                tasks_started.push(task);
+        // Other --slow-start instances count this run's encodes from this file:
+        let slow_start_counter = self
+            .cli
+            .get_slow_start()
+            .then(|| SlowStartCounter::new(&slow_start_dir()))
+            .transpose()?;
+        let mut running_encodes = 0;
+        let publish_state = |running_encodes: usize, waiting: usize| {
+            self.queue_state.publish(QueueState {
+                running: running_encodes,
+                waiting,
+            });
+            if let Some(counter) = &slow_start_counter {
+                if let Err(err) = counter.publish(running_encodes) {
+                    log::debug!("Could not share the number of running encodes: {err}");
+                }
             }
+        };
+
+        let jobs = self.cli.get_jobs().expect("Jobs should be set already");
+        let mut tasks_started = FuturesUnordered::new();
+        while tasks_started.len() < jobs {
+            let Some((id, task)) = tasks_not_started.pop_ready() else {
+                break;
+            };
+            log::trace!("Pushing a task into the job list (not started)");
+            running_encodes += usize::from(id < task_count);
+            tasks_started.push(task);
         }
+        // The waiting tasks see this before they first run:
+        publish_state(running_encodes, tasks_not_started.len());
 
         log::trace!("Will start jobs (concurrently)");
-        while let Some(finished_task) = tasks_started.next().await {
+        while let Some((finished_id, finished_task)) = tasks_started.next().await {
             log::trace!("Popped a finished a task into the job list (not started)");
+            tasks_not_started.finish(finished_id);
+            running_encodes -= usize::from(finished_id < task_count);
             match finished_task {
                 Err(EncodingErr(path, msg)) => {
                     finished_encode_count += 1;
-                    warning_tx.send((path, msg))?;
+                    warning_tx.send((path, msg)).unwrap();
                 }
                 Ok(EncodingDone::EncodingDone) => {
                     finished_encode_count += 1;
@@ -416,42 +418,297 @@ impl Encoder {
 
             if finished_encode_count == task_count {
                 log::debug!("All encode tasks are complete");
-                let finished_writer = self.finished.write();
-                *finished_writer.expect("Could not get writer to mark tasks finished") = true;
             }
 
-            if let Some(next_task) = tasks_not_started.pop_front() {
+            if cancel.is_cancelled() {
+                log::trace!("Not starting more jobs, since the run was cancelled");
+                publish_state(running_encodes, 0);
+                continue;
+            }
+            // A finished task may let more than one waiting task start:
+            while tasks_started.len() < jobs {
+                let Some((id, next_task)) = tasks_not_started.pop_ready() else {
+                    break;
+                };
                 log::trace!("Pushing another job to be run concurrently");
+                running_encodes += usize::from(id < task_count);
                 tasks_started.push(next_task);
-            } else {
+            }
+            if tasks_not_started.is_empty() {
                 log::trace!("There are no more jobs to be started");
             }
+            publish_state(running_encodes, tasks_not_started.len());
         }
         log::trace!("Done with concurrent jobs");
+        let syncs = self.finish_syncs(cancel.is_cancelled()).await;
+        for sync in &syncs {
+            if let Some(error) = &sync.error {
+                warning_tx
+                    .send((
+                        sync.file.clone(),
+                        format!("Could not sync the output: {error}"),
+                    ))
+                    .unwrap();
+            }
+        }
+
+        let mut files = std::mem::take(&mut *self.results.lock().unwrap());
+        files.sort_by(|a, b| a.input.cmp(&b.input));
+        let report = RunReport {
+            files,
+            warnings: WarningSummary::new(failures.try_iter()),
+            sample: self.sample.lock().unwrap().clone(),
+            syncs,
+            cancelled: cancel.is_cancelled(),
+            probe_skipped,
+            probe_fallbacks,
+        };
+        report.warnings.log(self.cli.report_format.is_some());
+        if let Some(line) = report.sample_line() {
+            log::info!("{line}");
+        }
+        if let Some(line) = report.probe_line() {
+            log::warn!("{line}");
+        }
+        Ok(report)
+    }
+
+    /// Make sure ffmpeg has the encoders needed for the chosen options, so we fail once up front
+    /// rather than once per file.
+    pub async fn check_capabilities(&self) -> JiffyResult<()> {
+        let output = self
+            .ffmpeg
+            .command()
+            .args(["-hide_banner", "-encoders"])
+            .output()
+            .await
+            .context(format!("Could not run ffmpeg ({})", self.ffmpeg))?;
+        if !output.status.success() {
+            return Err(JiffyError::ProbeFailed(format!(
+                "Could not list the ffmpeg encoders ({} -encoders failed)",
+                self.ffmpeg
+            )));
+        }
+        let encoders = parse_encoders(&String::from_utf8_lossy(&output.stdout));
 
-        let failures: Vec<_> = failures.try_iter().collect();
-        if failures.len() > 0 {
-            log::warn!("Failure and warning summary:");
-            for (path, msg) in failures {
-                log::warn!("{}: {}", path.to_string_lossy(), msg);
+        let mut needed = Vec::new();
+        needed.extend(self.cli.get_video_codec().encoder_name());
+        if !self.cli.test_opts.no_audio && !self.cli.test_opts.copy_audio {
+            needed.push("aac");
+        }
+        for encoder in needed {
+            if !encoders.contains(encoder) {
+                return Err(anyhow!("This ffmpeg does not support the {encoder} encoder. Please install an ffmpeg built with it, or choose a different codec.").into());
             }
         }
 
+        if let (false, Some(encoder)) = (
+            self.cli.eight_bit,
+            self.cli.get_video_codec().encoder_name(),
+        ) {
+            let output = self
+                .ffmpeg
+                .command()
+                .args(["-hide_banner", "-h", &format!("encoder={encoder}")])
+                .output()
+                .await
+                .context(format!("Could not run ffmpeg ({})", self.ffmpeg))?;
+            let pixel_formats = parse_pixel_formats(&String::from_utf8_lossy(&output.stdout));
+            // If the formats couldn't be listed, let ffmpeg report any problem:
+            if !pixel_formats.is_empty()
+                && !pixel_formats.iter().any(|format| format.contains("p10"))
+            {
+                if self.cli.strict_capabilities {
+                    return Err(anyhow!("The {encoder} encoder does not support 10-bit video. Use --8-bit, or install an ffmpeg built with 10-bit support.").into());
+                }
+                log::warn!("The {encoder} encoder does not support 10-bit video, so encoding as 8-bit. Pass --strict-capabilities to fail instead.");
+                *self.eight_bit_fallback.write().unwrap() = true;
+            }
+        }
         Ok(())
     }
 
+    /// How to decode this input's video: as `--hwdecode` says, unless that already failed for it.
+    fn get_hwdecode(&self, input: &InputFile) -> HwDecode {
+        if self.software_decode.lock().unwrap().contains(&input.path) {
+            HwDecode::None
+        } else {
+            self.cli.hwdecode
+        }
+    }
+
+    /// Whether to encode as 8-bit, either by request or because the encoder can't do 10-bit.
+    fn is_eight_bit(&self) -> bool {
+        self.cli.eight_bit || *self.eight_bit_fallback.read().unwrap()
+    }
+
     async fn encode_video(
         &self,
         input: &InputFile,
         warning_tx: Sender<(PathBuf, String)>,
         i: usize,
         total: usize,
+        cancel: &CancellationToken,
     ) -> Result<EncodingDone, EncodingErr> {
-        let input_path = input.path.clone();
-        if let Err(err) = self.encode_video_inner(input, warning_tx, i, total).await {
-            return Err(EncodingErr(input_path, format!("{err:?}")));
+        let mut input = input.clone();
+        if let Err(err) = input.start_log() {
+            _warn!(&input, "Could not start the log of {:?}: {err}", input.path);
+        }
+        let mut retries = 0;
+        loop {
+            let verdict = match self
+                .encode_video_inner(&input, warning_tx.clone(), i, total, cancel)
+                .await
+            {
+                Ok(verdict) => verdict,
+                Err(err) => {
+                    let msg = format!("{err:?}");
+                    if let Some(JiffyError::Cancelled | JiffyError::Interrupted(_)) =
+                        err.downcast_ref::<JiffyError>()
+                    {
+                        // The run is being stopped, which isn't worth a warning for every file:
+                        self.record_result(&input, FileOutcome::Skipped(err.to_string()));
+                        return Ok(EncodingDone::EncodingDone);
+                    }
+                    if let Some(JiffyError::EncodeFailed { .. }) = err.downcast_ref::<JiffyError>()
+                    {
+                        if self.get_hwdecode(&input) != HwDecode::None {
+                            // Hardware decoders fail on unsupported profiles and session limits:
+                            let msg = "Encoding failed with hardware decoding. Retrying with software decoding";
+                            _warn!(&input, "{:?}: {}", input.path, msg);
+                            warning_tx
+                                .send((input.path.to_owned(), msg.to_string()))
+                                .unwrap();
+                            self.software_decode
+                                .lock()
+                                .unwrap()
+                                .insert(input.path.clone());
+                            continue;
+                        }
+                    }
+                    let outcome = match err.downcast_ref::<JiffyError>() {
+                        Some(
+                            JiffyError::OutputExists(_)
+                            | JiffyError::OutputUploaded(_)
+                            | JiffyError::TooSmall { .. }
+                            | JiffyError::TooLarge { .. }
+                            | JiffyError::LowBitrate { .. }
+                            | JiffyError::InUse(_),
+                        ) => FileOutcome::Skipped(err.to_string()),
+                        _ => FileOutcome::Failed(msg.clone()),
+                    };
+                    self.record_result(&input, outcome);
+                    return Err(EncodingErr(input.path.clone(), msg));
+                }
+            };
+            // The output (if any) was already deleted if the verdict called for it.
+            let msg = match verdict {
+                OutputVerdict::Keep => {
+                    // The source is only moved away once its size is in the report, and before
+                    // the output is uploaded, so the output can still be checked. Only a clean
+                    // output is trusted enough to move its source away:
+                    self.record_result(&input, FileOutcome::Encoded);
+                    self.relocate_source(&input, &warning_tx).await;
+                    self.upload_outputs(&input, &warning_tx).await;
+                    return Ok(EncodingDone::EncodingDone);
+                }
+                OutputVerdict::Warn(msg) => {
+                    self.record_result(&input, FileOutcome::Encoded);
+                    self.upload_outputs(&input, &warning_tx).await;
+                    warning_tx.send((input.path.to_owned(), msg)).unwrap();
+                    return Ok(EncodingDone::EncodingDone);
+                }
+                OutputVerdict::Delete(msg) => msg,
+                OutputVerdict::Retry(msg) => {
+                    let next_crf = input.crf.saturating_add(self.cli.crf_retry_step);
+                    if retries < self.cli.max_crf_retries
+                        && next_crf <= self.cli.get_video_codec().max_crf()
+                    {
+                        retries += 1;
+                        let msg = format!("{msg}. Retrying with CRF {next_crf}");
+                        _warn!(&input, "{:?}: {}", input.path, msg);
+                        warning_tx.send((input.path.to_owned(), msg)).unwrap();
+                        input.crf = next_crf;
+                        continue;
+                    }
+                    msg
+                }
+            };
+            _warn!(&input, "{:?}: {}", input.path, msg);
+            self.record_result(&input, FileOutcome::Deleted(msg.clone()));
+            warning_tx.send((input.path.to_owned(), msg)).unwrap();
+            return Ok(EncodingDone::EncodingDone);
+        }
+    }
+
+    /// Warn about the videos that couldn't be probed, and record the ones `--strict-probe`
+    /// skipped as failed. Returns how many were skipped.
+    fn report_probe_failures(
+        &self,
+        input_files: &[InputFile],
+        warning_tx: &Sender<(PathBuf, String)>,
+    ) -> usize {
+        for input in input_files {
+            if let Some(err) = input.probe_errors().first() {
+                let msg =
+                    format!("Could not probe the video, so its CRF was chosen without it:\n{err}");
+                warning_tx.send((input.path.clone(), msg)).unwrap();
+            }
+        }
+        let failures = std::mem::take(&mut *self.probe_failures.lock().unwrap());
+        for (path, err) in &failures {
+            let msg = format!("Could not probe the video, so it was skipped:\n{err}");
+            warning_tx.send((path.clone(), msg.clone())).unwrap();
+            let video_root = &self.cli_for_path(path).video_root;
+            self.results.lock().unwrap().push(FileResult {
+                input: pathdiff::diff_paths(path, video_root).unwrap_or_else(|| path.clone()),
+                input_size: get_file_size(path).unwrap_or_default(),
+                output_size: None,
+                crf: 0,
+                outcome: FileOutcome::Failed(msg),
+                usage: None,
+                software_fallback: false,
+            });
+        }
+        failures.len()
+    }
+
+    /// Remember how a file turned out, for the report.
+    fn record_result(&self, input: &InputFile, outcome: FileOutcome) {
+        let output_size = input
+            .get_output_path(self.cli.output_name.clone())
+            .ok()
+            .filter(|_| outcome == FileOutcome::Encoded)
+            .and_then(|output_path| get_file_size(&output_path).ok());
+        let outcome = match outcome {
+            // Without an output, nothing was really encoded:
+            FileOutcome::Encoded if output_size.is_none() => {
+                FileOutcome::Skipped("No output was written".to_string())
+            }
+            outcome => outcome,
+        };
+        self.results.lock().unwrap().push(FileResult {
+            input: pathdiff::diff_paths(&input.path, &input.get_cli().video_root)
+                .unwrap_or_else(|| input.path.clone()),
+            input_size: get_file_size(&input.path).unwrap_or_default(),
+            output_size,
+            crf: input.crf,
+            outcome,
+            usage: self.usage.lock().unwrap().remove(&input.path),
+            software_fallback: self.software_decode.lock().unwrap().contains(&input.path),
+        });
+    }
+
+    /// Add what an encode's ffmpeg used to the input's total, for the report.
+    fn record_usage(&self, input: &InputFile, sampler: Option<&UsageSampler>) {
+        if let Some(sampler) = sampler {
+            self.usage
+                .lock()
+                .unwrap()
+                .entry(input.path.clone())
+                .or_default()
+                .add(sampler.usage());
         }
-        Ok(EncodingDone::EncodingDone)
     }
 
     /// Multiple failure messages may be sent along the tx.
@@ -461,8 +718,48 @@ impl Encoder {
         warning_tx: Sender<(PathBuf, String)>,
         i: usize,
         total: usize,
-    ) -> Result<()> {
-        let output_path = input.get_output_path(self.cli.output_name.clone())?;
+        cancel: &CancellationToken,
+    ) -> Result<OutputVerdict> {
+        if cancel.is_cancelled() {
+            return Err(JiffyError::Cancelled.into());
+        }
+        let input_size = get_file_size(&input.path).context("Could not get the input file size")?;
+        if input_too_large(input_size, &self.cli.maximum_size)? {
+            return Err(JiffyError::TooLarge {
+                path: input.path.clone(),
+                size: input_size,
+            }
+            .into());
+        }
+        // The size is checked before the passes that decode the video, like --autocrop:
+        if input_too_small(input_size, &self.cli.minimum_size)? {
+            return Err(JiffyError::TooSmall {
+                path: input.path.clone(),
+                size: input_size,
+            }
+            .into());
+        }
+        if let Some(minimum_bitrate) = self.cli.minimum_bitrate {
+            match input.get_overall_bitrate().await {
+                Ok(bitrate) if bitrate < minimum_bitrate => {
+                    return Err(JiffyError::LowBitrate {
+                        path: input.path.clone(),
+                        bitrate,
+                    }
+                    .into());
+                }
+                Ok(_) => {}
+                Err(err) => _warn!(
+                    input,
+                    "Could not get the bitrate, so encoding it anyway: {err}"
+                ),
+            }
+        }
+        // Windows share locks make this a cheap check, but on Linux every process's open files are searched:
+        if (cfg!(windows) || self.cli.skip_open_files) && is_open_for_writing(&input.path)? {
+            return Err(JiffyError::InUse(input.path.clone()).into());
+        }
+        let output_path = input.get_output_path(input.get_cli().output_name.clone())?;
         let parent = output_path
             .parent()
             .expect("Generated path must have a parent directory");
@@ -473,11 +770,121 @@ impl Encoder {
             // No need for a mutex, this is thread-safe:
             std::fs::create_dir_all(parent)?;
         }
+        self.check_container_compatibility(input, &output_path)
+            .await?;
+
+        // mp4 and mov only hold mov_text subtitles:
+        let mov_text_output = matches!(
+            Container::from_path(&output_path),
+            Some(Container::Mp4 | Container::Mov)
+        );
+        let mkv_output = output_path
+            .extension()
+            .is_some_and(|extension| extension == "mkv");
+        // Only mkv can hold attachments, and the fonts aren't needed once subtitles are burned in for TV:
+        let keep_attachments = mkv_output && !self.cli.no_attachments && !self.cli.for_tv;
+        let external_subs = if !self.cli.mux_external_subs {
+            Vec::new()
+        } else if mkv_output {
+            find_external_subtitles(input, &self.cli.sub_dirs)?
+        } else {
+            _debug!(
+                input,
+                "Not adding external subtitles, since the output is not mkv"
+            );
+            Vec::new()
+        };
+
+        let codec = self.cli.get_video_codec();
+        let burn_sub = match &self.cli.burn_sub {
+            Some(selector) => Some(selector.clone()),
+            // Subtitles can't be burned into a copied video stream:
+            None if self.cli.for_tv
+                || (mov_text_output
+                    && self.cli.sub_handling == SubHandling::Burn
+                    && codec != Codec::Copy) =>
+            {
+                Some(SubtitleSelector::automatic())
+            }
+            None => None,
+        };
+        let trim = self.cli.get_trim()?;
+        // Seeking the input resets its timestamps, which burned in and external subtitles and
+        // stabilization depend on. A copied video stream could only be cut at a keyframe.
+        let input_seek = burn_sub.is_none()
+            && external_subs.is_empty()
+            && !self.cli.stabilize
+            && codec != Codec::Copy;
+
+        let pix_fmt = match self.get_hwdecode(input) {
+            HwDecode::None => None,
+            _ => input
+                .get_video_stream()
+                .await
+                .ok()
+                .flatten()
+                .and_then(|stream| stream.pix_fmt),
+        };
+        if !self.get_hwdecode(input).can_decode(pix_fmt.as_deref()) {
+            let pix_fmt = pix_fmt.as_deref().unwrap_or("an unknown pixel format");
+            _info!(input, "Decoding the video in software, since its frames can't be copied from the GPU in {pix_fmt}");
+            self.software_decode
+                .lock()
+                .unwrap()
+                .insert(input.path.clone());
+        }
+        let hwdecode = self.get_hwdecode(input);
+
+        // The passes that decode the video before the encode, like --autocrop, count against
+        // --global-jobs too, so the slot is taken before them. Wait before taking it, so a full
+        // disk doesn't hold up other instances:
+        let _slot = if self.cli.test_opts.noop {
+            None
+        } else {
+            self.wait_for_free_space(input, &output_path, &warning_tx, cancel)
+                .await?;
+            match self.cli.global_jobs {
+                Some(slots) => {
+                    Some(GlobalSlot::acquire(&global_slot_dir(), slots.get(), cancel).await?)
+                }
+                None => None,
+            }
+        };
 
         // Normal args for ffmpeg:
-        let mut child_args = os_args!["-i", &input.path, "-hide_banner"];
+        let mut child_args = trim.get_input_args(input_seek);
+        // A copied video stream isn't decoded at all:
+        if codec != Codec::Copy {
+            child_args.extend(hwdecode.input_args(self.cli.hwdecode_device.as_deref()));
+        }
+        child_args.extend(os_args!["-i", &input.path]);
+        // The subtitle inputs must come before any output options:
+        for subtitle in &external_subs {
+            child_args.extend(os_args!["-i", &subtitle.path]);
+        }
+        // The chapters must live until ffmpeg is done with them:
+        let chapters_file = match self.cli.auto_chapters {
+            Some(auto_chapters) => match self
+                .write_auto_chapters(input, auto_chapters, input_seek)
+                .await
+            {
+                Ok(chapters_file) => Some(chapters_file),
+                Err(err) => {
+                    warning_tx.send((
+                        input.path.to_owned(),
+                        format!("Could not add chapters: {err}"),
+                    ))?;
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(chapters_file) = &chapters_file {
+            child_args.extend(os_args!["-f", "ffmetadata", "-i", chapters_file.path()]);
+        }
+        child_args.push("-hide_banner".into());
         // Options for -vf:
-        let mut vf = Vec::<OsString>::new();
+        let mut vf = FilterChain::default();
 
         match self.cli.get_verbosity() {
             ..-2 => {
@@ -499,51 +906,204 @@ impl Encoder {
             1.. => {}
         }
 
-        child_args.extend(os_args!(
-            str: "-nostdin -map_metadata 0 -movflags +faststart -movflags +use_metadata_tags -strict experimental"));
-        let codec = self.cli.get_video_codec();
-        if codec != Codec::Copy {
+        child_args.extend(os_args!(str: "-nostdin -movflags +faststart -movflags +use_metadata_tags -strict experimental"));
+        if self.cli.strip_metadata {
+            child_args
+                .extend(os_args!(str: "-map_metadata -1 -metadata title= -metadata comment="));
+        } else {
+            child_args.extend(os_args!(str: "-map_metadata 0"));
+        }
+        child_args.extend(os_args![
+            "-metadata",
+            format!(
+                "{JIFFY_SIGNATURE_TAG}={}",
+                get_jiffy_signature(&codec, input.crf)
+            )
+        ]);
+        if let Some(title_template) = &self.cli.set_title {
+            child_args.extend(os_args![
+                "-metadata",
+                format!("title={}", input.fill_text_template(title_template)?)
+            ]);
+        }
+        child_args.extend(trim.get_output_args(input_seek, self.cli.speed.unwrap_or(1.0)));
+        if codec.uses_crf() && !self.cli.lossless {
             child_args.extend(os_args!["-crf", input.crf.to_string()]);
         }
 
+        let burn_stream = match &burn_sub {
+            Some(selector) => input.find_subtitle_stream(selector).await?,
+            None => None,
+        };
+        // The soft subtitles are dropped with -sn: when subtitles are burned in, when the mp4 or
+        // mov output can't hold them, and when they'd be out of sync with a sped up video. They
+        // aren't mapped either, so the flags of the other streams stay on the right streams:
+        let drops_subtitles = burn_stream.is_some()
+            || (mov_text_output && self.cli.sub_handling != SubHandling::Convert)
+            || self.cli.mezzanine.is_some()
+            || self.cli.speed.is_some();
+
+        // The number of output streams, if known, so more can be added after them:
+        let mut mapped_count = None;
         if !self.cli.test_opts.no_map_0 {
-            child_args.extend(os_args!(str: "-map 0"));
+            match input.get_streams().await {
+                Ok(streams) if !streams.is_empty() => {
+                    let audio_streams: Vec<_> = streams
+                        .iter()
+                        .filter(|stream| stream.codec_type == "audio")
+                        .cloned()
+                        .collect();
+                    if !self.cli.audio_lang.is_empty()
+                        && !audio_streams.is_empty()
+                        && !audio_streams
+                            .iter()
+                            .any(|stream| is_language(stream, &self.cli.audio_lang))
+                    {
+                        _warn!(
+                            input,
+                            "No audio streams are in the languages {:?}, so keeping them all",
+                            self.cli.audio_lang
+                        );
+                    }
+                    let mut stream_map = StreamMap::plan(&streams, |stream| {
+                        self.keep_stream(stream, &audio_streams)
+                            && (!mov_text_output || self.cli.sub_handling.keeps_in_mp4(stream))
+                            && (keep_attachments || stream.codec_type != "attachment")
+                            && !(drops_subtitles && stream.codec_type == "subtitle")
+                    });
+                    if mov_text_output && self.cli.sub_handling != SubHandling::Burn {
+                        let dropped: Vec<_> = streams
+                            .iter()
+                            .filter(|stream| !self.cli.sub_handling.keeps_in_mp4(stream))
+                            .map(|stream| stream.index.to_string())
+                            .collect();
+                        if !dropped.is_empty() {
+                            _warn!(input, "Dropping subtitle streams {}, since the mp4 output can't hold them", dropped.join(", "));
+                        }
+                    }
+                    self.remove_unsupported_dispositions(&mut stream_map);
+                    child_args.extend(stream_map.to_args());
+                    mapped_count = Some(stream_map.streams.len());
+                }
+                Ok(_) => {
+                    _warn!(
+                        input,
+                        "No streams were found by ffprobe, so mapping them all"
+                    );
+                    child_args.extend(os_args!(str: "-map 0"));
+                    if !keep_attachments {
+                        child_args.extend(os_args!(str: "-map -0:t"));
+                    }
+                }
+                Err(err) => {
+                    _warn!(
+                        input,
+                        "Could not get the streams, so mapping them all: {err}"
+                    );
+                    child_args.extend(os_args!(str: "-map 0"));
+                    if !keep_attachments {
+                        child_args.extend(os_args!(str: "-map -0:t"));
+                    }
+                }
+            }
+        } else if keep_attachments {
+            // ffmpeg never chooses attachments by itself, and mapping any stream stops it from
+            // choosing the others, so choose the first of each like it would:
+            child_args.extend(os_args!(str: "-map 0:V:0? -map 0:a:0? -map 0:s:0? -map 0:t?"));
+        }
+        if chapters_file.is_some() {
+            // The chapters input comes after the video and the subtitle inputs:
+            child_args.extend(os_args![
+                "-map_chapters",
+                (external_subs.len() + 1).to_string()
+            ]);
+        } else if mkv_output {
+            child_args.extend(os_args!(str: "-map_chapters 0"));
         }
 
-        if self.cli.for_tv {
-            if input.contains_subtitle().await? {
-                if let Err(err) = add_subtitles(input, &mut vf).await {
-                    warning_tx.send((
+        if !external_subs.is_empty() {
+            match mapped_count {
+                Some(mapped_count) => child_args.extend(get_external_subtitle_args(&external_subs, mapped_count)),
+                None => warning_tx.send((
+                    input.path.to_owned(),
+                    "Could not add the external subtitles, since the streams of the video are unknown".to_string(),
+                ))?,
+            }
+        }
+
+        // The extracted subtitles must live until ffmpeg is done with them:
+        let mut _sub_file = None;
+        if let Some(stream) = burn_stream {
+            if stream.is_text_subtitle() {
+                match add_subtitles(input, stream.index, &mut vf).await {
+                    Ok(sub_file) => _sub_file = Some(sub_file),
+                    Err(err) => warning_tx.send((
                         input.path.to_owned(),
                         format!("Error adding subtitles: {err:?}"),
-                    ))?;
+                    ))?,
                 }
-
-                // And don't include the existing soft subs:
-                child_args.push("-sn".into());
-            } else if let Some(sub_path) = find_subtitle_file(input)? {
-                let sub_path = sub_path
-                    .to_str()
-                    .context("Could not convert subtitle name to utf-8.")?
-                    .to_owned();
-                let sub_path = escape_vf_path(&sub_path);
-                let mut subs_option = OsString::from("subtitles=");
-                subs_option.push(sub_path?);
-                vf.push(subs_option);
             } else {
-                child_args.extend(os_args!(str: "-c copy"));
+                warning_tx.send((
+                    input.path.to_owned(),
+                    format!(
+                        "Subtitle stream {} is image based, so it can't be burned in",
+                        stream.index
+                    ),
+                ))?;
             }
+        } else if let Some(sub_path) = self
+            .cli
+            .for_tv
+            .then(|| find_subtitle_file(input, &self.cli.sub_dirs))
+            .transpose()?
+            .flatten()
+        {
+            let sub_path = sub_path
+                .to_str()
+                .context("Could not convert subtitle name to utf-8.")?
+                .to_owned();
+            let sub_path = escape_vf_path(&sub_path);
+            vf.add(FilterStage::Subtitles, format!("subtitles={}", sub_path?));
         } else {
+            if self.cli.burn_sub.is_some() {
+                warning_tx.send((
+                    input.path.to_owned(),
+                    "No subtitle stream matches --burn-sub".to_string(),
+                ))?;
+            }
             child_args.extend(os_args!(str: "-c copy"));
         }
 
-        if let Some(audio_args) = self.get_audio_args(input).await {
-            child_args.extend(audio_args);
+        if mov_text_output && self.cli.sub_handling == SubHandling::Convert && !drops_subtitles {
+            child_args.extend(os_args!["-c:s", "mov_text"]);
+        }
+        // This also covers `--no-map-0` and "-map 0", where the subtitles aren't mapped one by
+        // one. Editors don't read the subtitles in a mezzanine mov file:
+        if drops_subtitles {
+            child_args.push("-sn".into());
         }
+        // ffmpeg has no encoder for attachments, so they must be copied even when "-c copy"
+        // isn't given, like when subtitles are burned in:
+        if keep_attachments {
+            child_args.extend(os_args!(str: "-c:t copy"));
+        }
+
+        child_args.extend(self.get_audio_args(input).await.args);
 
         let mut x265_params = self.get_x265_params(input.crf);
+        if self.cli.lossless && codec == Codec::H265 {
+            x265_params.get_or_insert_with(Vec::new).push("lossless=1");
+        }
+        if self.cli.eco && codec == Codec::H265 {
+            // libx265 doesn't follow -threads, so its thread pool is limited directly:
+            x265_params
+                .get_or_insert_with(Vec::new)
+                .extend(ECO_X265_PARAMS);
+        } else if self.cli.eco && codec != Codec::Copy {
+            child_args.extend(os_args!["-threads", ECO_THREADS.to_string()]);
+        }
         if let Some(x265_params) = x265_params.as_mut() {
-            let x265_params = x265_params.join(", ");
+            let x265_params = x265_params.join(":");
             child_args.extend(os_args!["-x265-params", &x265_params]);
         }
 
@@ -559,23 +1119,31 @@ impl Encoder {
 
         if self.cli.overwrite {
             child_args.extend(os_args!["-y"]);
-        } else if output_path.exists() || partial_output_path.exists() {
-            if output_path.exists() {
-                warning_tx.send((
-                    output_path.to_owned(),
-                    format!("Output file already exists: {output_path:?}"),
-                ))?;
-            }
+        } else if output_path.exists() {
+            return Err(JiffyError::OutputExists(output_path).into());
+        } else if partial_output_path.exists() {
             // This may indicate an encode process is already running for that file:
-            if partial_output_path.exists() {
-                warning_tx.send((
-                    partial_output_path.to_owned(),
-                    format!("Partial output file already exists: {partial_output_path:?}"),
-                ))?;
-            }
-            return Ok(());
+            return Err(JiffyError::OutputExists(partial_output_path).into());
+        } else if self.output_uploaded(input, &output_path).await? {
+            return Err(JiffyError::OutputUploaded(output_path).into());
         }
 
+        // The camera motion is detected in a separate pass and saved in this file, next to the
+        // part file. It's removed when the encode ends, however it ends:
+        let stabilize_file = self
+            .cli
+            .stabilize
+            .then(|| RemoveOnDrop(partial_output_path.with_extension("trf")));
+        let stabilize_path = stabilize_file
+            .as_ref()
+            .map(|file| {
+                file.0
+                    .to_str()
+                    .context("Could not convert the motion file path to utf-8. Needed for stabilization.")
+                    .and_then(escape_vf_path)
+            })
+            .transpose()?;
+
         // Add the codec-specific flags:
         if codec == Codec::Copy {
             child_args.extend(os_args!(str: "-c:v copy"));
@@ -585,40 +1153,132 @@ impl Encoder {
                 Codec::H265 => os_args!(str: "-c:v libx265 -preset"),
                 // NOTE: not tested. Let me know if these parameters don't work well with Chromecast,
                 // or some other TV-related use-case.
-                Codec::H264 if self.cli.for_tv =>
-                    os_args!(str: "-c:v libx264 -maxrate 10M -bufsize 16M -profile:v high -level 4.1 -preset"),
                 Codec::H264 => os_args!(str: "-c:v libx264 -profile:v high -level 4.1 -preset"),
+                Codec::ProRes => os_args!(str: "-c:v prores_ks -profile:v 3 -vendor apl0"),
+                Codec::Dnxhr => os_args!(str: "-c:v dnxhd -profile:v dnxhr_hqx"),
+                // Every frame is a keyframe, with checksums so damage can be found and contained:
+                Codec::Ffv1 => os_args!(str: "-c:v ffv1 -level 3 -g 1 -slices 16 -slicecrc 1"),
                 _ => bail!("Codec not handled: {codec:?}"),
             });
-            child_args.push(OsString::from(&self.cli.preset));
+            if codec.uses_crf() {
+                child_args.push(OsString::from(&self.cli.preset));
+            }
+
+            // libaom's options are newer than those of the x26x encoders:
+            if let Some(tune) = self.cli.get_tune()? {
+                if codec != Codec::Av1
+                    || self.ffmpeg_has_option(input, "--tune", "-tune", (4, 3), &warning_tx)?
+                {
+                    child_args.extend(os_args!["-tune", tune]);
+                }
+            }
+
+            if let Some(level) = self.cli.grain_synth {
+                let arg = "-denoise-noise-level";
+                if self.ffmpeg_has_option(input, "--grain-synth", arg, (4, 2), &warning_tx)? {
+                    child_args.extend(os_args![arg, level.to_string()]);
+                }
+            }
+
+            if let Some(maxrate) = self.cli.maxrate {
+                let bufsize = self.cli.bufsize.unwrap_or(maxrate * 2);
+                child_args.extend(os_args![
+                    "-maxrate",
+                    maxrate.to_string(),
+                    "-bufsize",
+                    bufsize.to_string()
+                ]);
+            }
 
             let max_height = self.cli.get_height();
-            // This -vf argument string was pretty thoroughly tested: it makes the shorter dimension equivalent to
-            // the desired height (or width for portrait mode), without changing the aspect ratio, and without upscaling.
-            // Using -2 instead of -1 ensures that the scaled dimension will be a factor of 2. Some filters need that.
-            let vf_height = format!("scale=if(gte(iw\\,ih)\\,-2\\,min({max_height}\\,iw)):if(gte(iw\\,ih)\\,min({max_height}\\,ih)\\,-2)").into();
-            let vf_pix_fmt: OsString = if self.cli.eight_bit {
-                "format=yuv420p".into()
-            } else {
-                "format=yuv420p10le".into()
-            };
-            vf.extend([vf_height, vf_pix_fmt]);
-            vf.extend(self.cli.get_extra_vf_flags()?.iter().map(|s| s.into()));
-
-            // Transform list into string:
-            let vf = {
-                match &mut *vf {
-                    [head, tail @ ..] => {
-                        let builder = head;
-                        for option in tail {
-                            builder.push(", ");
-                            builder.push(option);
-                        }
-                        builder
+            if !self.cli.keeps_source_picture() {
+                // This -vf argument string was pretty thoroughly tested: it makes the shorter dimension equivalent to
+                // the desired height (or width for portrait mode), without changing the aspect ratio, and without upscaling.
+                // Using -2 instead of -1 ensures that the scaled dimension will be a factor of 2. Some filters need that.
+                vf.add(
+                    FilterStage::Scale,
+                    format!("scale=if(gte(iw\\,ih)\\,-2\\,min({max_height}\\,iw)):if(gte(iw\\,ih)\\,min({max_height}\\,ih)\\,-2)"),
+                );
+                vf.add(
+                    FilterStage::Format,
+                    match codec {
+                        // Both mezzanine profiles are 10-bit 4:2:2:
+                        Codec::ProRes | Codec::Dnxhr => "format=yuv422p10le",
+                        _ if self.is_eight_bit() => "format=yuv420p",
+                        _ => "format=yuv420p10le",
+                    },
+                );
+            }
+            let video_stream = input.get_video_stream().await.unwrap_or_else(|err| {
+                _warn!(input, "Could not probe the video stream, so its colors and frame rate are unknown: {err}");
+                None
+            });
+            if let Some(download_filter) = hwdecode.download_filter(pix_fmt.as_deref()) {
+                vf.add(FilterStage::Download, download_filter);
+            }
+            if self.cli.tonemap_sdr && video_stream.as_ref().is_some_and(StreamInfo::is_hdr) {
+                _info!(input, "Tone mapping the HDR video to SDR");
+                vf.add(FilterStage::Tonemap, TONEMAP_FILTER);
+                child_args.extend(os_args!(str: "-color_primaries bt709 -color_trc bt709 -colorspace bt709 -color_range tv"));
+            } else if let Some(video_stream) = &video_stream {
+                if self.cli.tonemap_sdr {
+                    _debug!(input, "Not tone mapping, since the video is not HDR");
+                }
+                child_args.extend(get_color_args(video_stream));
+            }
+            if self.cli.autocrop {
+                match input.detect_crop().await {
+                    Ok(Some(rect)) => {
+                        _info!(input, "Cropping to {}x{}", rect.width, rect.height);
+                        vf.add(FilterStage::Crop, rect.to_filter());
                     }
-                    _ => bail!("vf cannot be empty"),
+                    Ok(None) => _debug!(input, "No black bars were detected"),
+                    Err(err) => _warn!(
+                        input,
+                        "Could not detect the crop, so the video will not be cropped: {err}"
+                    ),
                 }
-            };
+            }
+            if let Some(stabilize_path) = &stabilize_path {
+                vf.add(
+                    FilterStage::Stabilize,
+                    format!("vidstabtransform=input={stabilize_path}, unsharp=5:5:0.8:3:3:0.4"),
+                );
+            }
+            if let Some(denoise) = self.cli.denoise {
+                vf.add(FilterStage::Denoise, denoise.filter());
+            } else if self.cli.grain_prefilter {
+                vf.add(FilterStage::Denoise, Denoise::Light.filter());
+            }
+            if let Some(speed) = self.cli.speed {
+                vf.add(FilterStage::Timing, format!("setpts=PTS/{speed}"));
+            }
+            if let Some(max_fps) = self.cli.max_fps {
+                let frame_rate = video_stream.as_ref().and_then(|stream| stream.frame_rate);
+                match frame_rate.map(|frame_rate| frame_rate * self.cli.speed.unwrap_or(1.0)) {
+                    Some(frame_rate) if frame_rate > max_fps as f64 + 0.01 => {
+                        _info!(
+                            input,
+                            "Reducing the frame rate from {frame_rate:.2} to {max_fps} fps"
+                        );
+                        vf.add(FilterStage::Timing, format!("fps={max_fps}"));
+                    }
+                    Some(_) => {}
+                    None => _warn!(
+                        input,
+                        "Could not get the frame rate, so it may be more than {max_fps} fps"
+                    ),
+                }
+            }
+            if let Some(label_template) = &self.cli.label_overlay {
+                vf.add(
+                    FilterStage::Overlay,
+                    self.get_label_filter(input, label_template)?,
+                );
+            }
+            for extra in self.cli.get_extra_vf_flags()? {
+                vf.add(FilterStage::UserExtras, extra);
+            }
 
             // Add extra -vf arguments if they are set for this video:
             // foo.mp4 can have vf args set as VF_foo_mp4 or VF_foo
@@ -627,10 +1287,12 @@ impl Encoder {
                     input,
                     "Adding extra -vf arguments because environment variable was set"
                 );
-                vf.push(", ");
-                vf.push(env_vf_args);
+                vf.add(FilterStage::UserExtras, env_vf_args);
+            }
+            // A lossless encode of the untouched picture may need no filters at all:
+            if let Some(vf) = vf.to_arg() {
+                child_args.extend(os_args!["-vf", &vf]);
             }
-            child_args.extend(os_args!["-vf", &vf]);
         }
 
         // Add other args specific to this filename
@@ -652,13 +1314,42 @@ impl Encoder {
             }
         }
 
+        // The duration is needed to project the output size from the progress, and to know how
+        // much of the input a trimmed output holds:
+        let output_policy = OutputPolicy::new(&self.cli);
+        let input_duration = if !output_policy.wants_projection() && !trim.is_trimmed() {
+            None
+        } else {
+            match input.get_duration().await {
+                Ok(duration) => Some(duration as f64),
+                Err(err) => {
+                    _warn!(input, "Could not get the duration, so the output size can't be checked well: {err}");
+                    None
+                }
+            }
+        };
+        // The length of the output, which is shorter if it's trimmed or sped up:
+        let duration = input_duration
+            .filter(|_| output_policy.wants_projection())
+            .map(|duration| trim.trimmed_duration(duration) / self.cli.speed.unwrap_or(1.0));
+        if duration.is_some() {
+            child_args.extend(os_args!(str: "-progress pipe:1"));
+        }
+
         child_args.extend(os_args![&partial_output_path]);
 
         _info!(input, "");
-        _info!(input, "Executing: {:?} {:?}\n(file {}/{})", &self.ffmpeg_path, child_args, i + 1, total);
+        _info!(
+            input,
+            "Executing: {} {:?}\n(file {}/{})",
+            &self.ffmpeg,
+            child_args,
+            i + 1,
+            total
+        );
         _info!(input, "");
 
-        let mut program = Command::new(&self.ffmpeg_path);
+        let mut program = self.ffmpeg.command();
         let mut command = program.args(child_args);
         if let Some(ref log_path) = input.log_path {
             input.create_log_directory()?;
@@ -676,15 +1367,24 @@ impl Encoder {
             }
             command = command.env("FFREPORT", ffreport);
         }
-        let orig_size = get_file_size(&input.path).context(format!(
-            "Could not get original file disk space before encoding"
-        ))?;
-        if input_too_small(orig_size, &self.cli.minimum_size)? {
-            bail!("Skipping file as too small to encode");
-        }
+        // A trimmed output is compared to the share of the input it holds:
+        let orig_size = match input_duration {
+            Some(input_duration) if trim.is_trimmed() && input_duration > 0.0 => {
+                (input_size as f64 * trim.trimmed_duration(input_duration) / input_duration) as u64
+            }
+            _ => input_size,
+        };
         if self.cli.test_opts.noop {
             _info!(input, "Not running ffmpeg because of --noop");
-            return Ok(());
+            return Ok(OutputVerdict::Keep);
+        }
+        if self.cli.extract_subs {
+            self.extract_subtitles(input, &output_path).await;
+        }
+
+        if let Some(stabilize_path) = &stabilize_path {
+            _info!(input, "Detecting camera motion for stabilization");
+            detect_camera_motion(&input.path, stabilize_path, &self.cli).await?;
         }
 
         let mut child = command
@@ -697,491 +1397,363 @@ impl Encoder {
         let mut child_stdout = Pin::new(&mut child_stdout);
         // let mut stderr = Box::new(child.stderr.take().unwrap()) as Box<dyn Read>;
 
+        let mut usage_sampler = child
+            .id()
+            .filter(|_| self.cli.resource_stats)
+            .map(UsageSampler::new);
+        let mut progress = Progress::default();
+        let mut progress_text = String::new();
+        let mut next_size_check = Instant::now() + SIZE_CHECK_INTERVAL;
         let mut buf = vec![0; 1024];
         loop {
             let exit_status = child.try_wait()?;
             let read_fut = child_stdout.read(&mut buf);
             select! {
                 bytes_read = read_fut => {
-                    std::io::stdout().lock().write_all(&buf[..bytes_read?])?;
+                    let bytes_read = bytes_read?;
+                    if duration.is_some() {
+                        progress_text.push_str(&String::from_utf8_lossy(&buf[..bytes_read]));
+                        while let Some(newline) = progress_text.find('\n') {
+                            let line: String = progress_text.drain(..=newline).collect();
+                            progress.update(&line);
+                        }
+                    } else {
+                        std::io::stdout().lock().write_all(&buf[..bytes_read])?;
+                    }
+                    if bytes_read == 0 {
+                        // The output is closed, so don't take up CPU time polling again right away
+                        sleep(Duration::from_millis(50)).await;
+                    }
                 }
-                else => {
-                    // Nothing ready for read, so don't take up CPU time polling again right away
-                    sleep(Duration::from_millis(50)).await;
+                _ = sleep(SIZE_CHECK_INTERVAL) => {}
+                _ = cancel.cancelled() => {
+                    child.kill().await?;
+                    if partial_output_path.exists() {
+                        remove_file(&partial_output_path)?;
+                    }
+                    return Err(JiffyError::Cancelled.into());
                 }
             };
+            if let Some(sampler) = &mut usage_sampler {
+                sampler.sample();
+            }
+
+            if exit_status.is_none() {
+                let mut verdict = OutputVerdict::Keep;
+                if output_policy.delete_too_large && Instant::now() >= next_size_check {
+                    next_size_check = Instant::now() + SIZE_CHECK_INTERVAL;
+                    let size = get_file_size(&partial_output_path).unwrap_or(0);
+                    verdict = output_policy.judge_partial(orig_size, size);
+                }
+                if verdict == OutputVerdict::Keep && !progress.finished {
+                    if let Some(projected_size) =
+                        duration.and_then(|duration| progress.projected_size(duration))
+                    {
+                        verdict = output_policy.judge_projection(orig_size, projected_size);
+                    }
+                }
+                if verdict != OutputVerdict::Keep {
+                    child.kill().await?;
+                    self.record_usage(input, usage_sampler.as_ref());
+                    remove_file(&partial_output_path)?;
+                    return Ok(verdict);
+                }
+            }
 
             if let Some(exit_status) = exit_status {
+                self.record_usage(input, usage_sampler.as_ref());
                 if exit_status.success() {
                     if !self.cli.overwrite && output_path.exists() {
-                        bail!(
-                            "Finished writing part file without --overwrite, but now the full output path exists: {:?}",
-                            &output_path
-                        )
+                        _warn!(input, "Finished writing part file without --overwrite, but now the full output path exists");
+                        return Err(JiffyError::OutputExists(output_path).into());
                     }
                     tokio::fs::rename(&partial_output_path, &output_path).await?;
-                } else {
-                    let mut msg = String::from("Encoding error. Check ffmpeg args");
-                    if !self.cli.test_opts.no_map_0 {
-                        msg += ", or try again without `-map 0`";
+                    if let Err(err) = self.record_history(input, &output_path) {
+                        _warn!(
+                            input,
+                            "Could not record the output in the history file: {err}"
+                        );
                     }
-                    // This error is significant enough to show right away, not just at the end:
-                    _warn!(input, "{:?}: {}", input.path, msg);
-                    warning_tx.send((input.path.to_owned(), msg)).unwrap();
-                }
-
-                self.check_encoded_size(orig_size, input.path.clone(), output_path, warning_tx)?;
-                break;
-            }
-        }
-
-        Ok(())
-    }
-
-    async fn get_audio_args(&self, input: &InputFile) -> Option<Vec<OsString>> {
-        let default = Some(os_args!["-c:a", "aac", "-b:a", "128k", "-ac", "2"]);
-        let audio_copy_arg = Some(os_args!["-c:a", "copy"]);
-        if self.cli.test_opts.no_audio {
-            _debug!(input, "Removing audio entirely, due to argument");
-            return Some(os_args!["-an"]);
-        } else if self.cli.test_opts.copy_audio {
-            _debug!(
-                input,
-                "Skipping audio bitrate check and not encoding, due to argument"
-            );
-            return audio_copy_arg;
-        } else if self.cli.skip_audio_bitrate_check {
-            _debug!(input, "Skipping audio bitrate check due to option chosen.");
-            return default;
-        } else if self.cli.for_tv {
-            _debug!(
-                input,
-                "Skipping audio bitrate check: always encode for TV playback"
-            );
-            return Some(os_args!["-c:a", "aac", "-b:a", "192k", "-ac", "2"]);
-        }
-        match input.get_audio_bitrate().await {
-            Ok(bitrate) if bitrate <= 200f32 => {
-                _debug!(input, "Audio bitrate is {bitrate} kb/s. Will not reencode");
-                return audio_copy_arg;
-            }
-            Ok(bitrate) => {
-                _trace!(input, "Audio bitrate is {bitrate} kb/s. Will reencode");
-            }
-            Err(err) => _warn!(input, "Could not get audio bitrate: {err}"),
-        }
-        return default;
-    }
-
-    fn get_x265_params(&self, crf: u8) -> Option<Vec<&str>> {
-        if self.cli.av1 || !self.cli.anime {
-            None
-        } else {
-            assert!(self.cli.anime);
-
-            // These encoding tips are from: https://kokomins.wordpress.com/2019/10/10/anime-encoding-guide-for-x265-and-why-to-never-use-flac/
-            let x265_params = if self.cli.anime_slow_well_lit {
-                vec![
-                    "bframes=8",
-                    "psy-rd=1",
-                    "aq-mode=3",
-                    "aq-strength=0.8",
-                    "deblock=1,1",
-                ]
-            } else if self.cli.anime_mixed_dark_battle {
-                if crf >= 19 {
-                    // Note: recommended if: non-complex, motion only alternative
-                    vec![
-                        "bframes=8",
-                        "psy-rd=1",
-                        "psy-rdoq=1",
-                        "aq-mode=3",
-                        "qcomp=0.8",
-                    ]
                 } else {
-                    // Note: recommended if: motion + fancy & detailed FX
-                    vec![
-                        "limit-sao",
-                        "bframes=8",
-                        "psy-rd=1.5",
-                        "psy-rdoq=2",
-                        "aq-mode=3",
-                    ]
-                }
-            } else if crf > 19 {
-                vec!["bframes=8", "psy-rd=1", "aq-mode=3"]
-            } else {
-                vec!["limit-sao", "bframes=8", "psy-rd=1", "aq-mode=3"]
-            };
-            Some(x265_params)
-        }
-    }
-
-    pub fn is_match<P>(&self, (globset, paths): &(GlobSet, Vec<PathBuf>), path: P) -> bool
-    where
-        P: AsRef<Path>,
-        PathBuf: From<P>,
-    {
-        let path = PathBuf::from(path);
-        globset.is_match(&path) || paths.iter().any(|p|
-            is_same_file(p, &path)
-            || is_same_file(p, &Path::join(&self.video_root, &path))
-        )
-    }
-
-    pub fn get_matcher_from_globs<P>(
-        video_root: P,
-        inputs: &Vec<String>,
-        allow_empty: bool,
-    ) -> Option<(GlobSet, Vec<PathBuf>)>
-    where
-        P: AsRef<Path> + AsRef<OsStr>,
-    {
-        if inputs.len() == 0 && !allow_empty {
-            return None;
-        }
-
-        let mut paths = vec![];
-        let mut globset = GlobSetBuilder::new();
-        let video_root = PathBuf::from(&video_root);
-
-        for input in inputs {
-            // Interpret "video_root/x.mp4" or "x.mp4" as a glob only if video_root/x.mp4 does not exist.
-            // This loose interpretation is more convenient than being strict about the path only being
-            // relative to the video root.
-            let as_path = PathBuf::from(input);
-            if as_path.exists() {
-                paths.push(as_path);
-            } else {
-                let as_path = Path::join(&video_root, input);
-                if as_path.exists() {
-                    paths.push(as_path);
-                } else {
-                    globset.add(Glob::new(&input).expect("Could not build glob pattern"));
-                }
-            }
-        }
-        Some((globset.build().expect("Could not build glob set."), paths))
-    }
-
-    /// Get the paths of all videos in the parent directory, excluding those in this directory.
-    /// (This directory is considered the encode directory.)
-    async fn get_video_paths(&self) -> Result<Vec<InputFile>> {
-        let exclude = Self::get_matcher_from_globs(&self.video_root, &self.cli.exclude, true);
-        let include = Self::get_matcher_from_globs(&self.video_root, &self.cli.include, false);
-
-        let video_re = Regex::new(
-            r"^mp4|mkv|m4v|vob|ogg|ogv|wmv|yuv|y4v|mpg|mpeg|3gp|3g2|f4v|f4p|avi|webm|flv$",
-        )?;
-        let mut videos = Vec::new();
-        let mut dirs = VecDeque::from([self.video_root.to_owned()]);
-        let encode_dir = get_output_dir(&self.cli);
-        while let Some(dir) = dirs.pop_front() {
-            let mut entries = Vec::new();
-            for entry in dir.read_dir()? {
-                entries.push(entry?);
-            }
-            entries.sort_by(|s1, s2| {
-                lexical_sort::natural_lexical_cmp(
-                    &s1.path().to_string_lossy(),
-                    &s2.path().to_string_lossy(),
-                )
-            });
-            for entry in entries {
-                if let Some(limit) = self.cli.limit {
-                    if videos.len() == limit {
-                        log::debug!("Reached video limit={limit}, won't encode any more");
-                        return Ok(videos);
+                    let stderr_tail = input
+                        .log_path
+                        .as_deref()
+                        .map(read_log_tail)
+                        .unwrap_or_default();
+                    if cancel.is_cancelled()
+                        || is_interrupted_exit(
+                            exit_status.code(),
+                            exit_signal(&exit_status),
+                            &stderr_tail,
+                        )
+                    {
+                        // This isn't an encoding error, so don't send a warning about it:
+                        _warn!(input, "ffmpeg was interrupted");
+                        if partial_output_path.exists() {
+                            remove_file(&partial_output_path)?;
+                        }
+                        return Err(JiffyError::Interrupted(input.path.clone()).into());
                     }
-                }
-                let fname = entry.path();
-                let relative_path = pathdiff::diff_paths(fname.clone(), self.video_root.clone());
-                let matchable_path = relative_path.unwrap_or(fname.clone());
-
-                if let Some(include) = &include {
-                    if !self.is_match(&include, &matchable_path) {
-                        log::debug!("Skipping path because it's not an included path: {fname:?}");
-                        continue;
+                    if hwdecode != HwDecode::None {
+                        // It will be tried again with software decoding, which is warned about then:
+                        if partial_output_path.exists() {
+                            remove_file(&partial_output_path)?;
+                        }
+                    } else {
+                        let mut msg = String::from("Encoding error. Check ffmpeg args");
+                        if !self.cli.test_opts.no_map_0 {
+                            msg += ", or try again without `-map 0`";
+                        }
+                        if !self.ffmpeg_at_least(4, 4) {
+                            if let Some(version) = self.ffmpeg_version {
+                                msg += &format!(
+                                    ". ffmpeg {version} is old, and may not support these args"
+                                );
+                            }
+                        }
+                        // This error is significant enough to show right away, not just at the end:
+                        _warn!(input, "{:?}: {}", input.path, msg);
+                        warning_tx.send((input.path.to_owned(), msg)).unwrap();
+                    }
+                    return Err(JiffyError::EncodeFailed {
+                        path: input.path.clone(),
+                        exit_code: exit_status.code(),
+                        stderr_tail,
                     }
+                    .into());
                 }
 
-                if is_same_file(&fname, &encode_dir) {
-                    continue;
-                } else if exclude
-                    .as_ref()
-                    .map_or(false, |x| self.is_match(&x, &matchable_path))
-                {
-                    log::debug!("Skipping path because of exclude: {fname:?}");
-                    continue;
+                let verdict = self.check_encoded_size(orig_size, output_path.clone())?;
+                if !matches!(verdict, OutputVerdict::Keep | OutputVerdict::Warn(_)) {
+                    return Ok(verdict);
                 }
-
-                let md = entry.metadata()?;
-                if md.is_dir() {
-                    dirs.push_back(fname);
-                } else if extension_matches(&fname, &video_re)? {
-                    videos.push(InputFile::new(&fname, self.cli.clone()).await?);
+                let mut finished_files = vec![output_path.clone()];
+                if self.cli.emit_checksums {
+                    let output_path = output_path.clone();
+                    // Hashing a large output takes a while, so keep it off the async threads:
+                    match tokio::task::spawn_blocking(move || write_checksum_file(&output_path))
+                        .await?
+                    {
+                        Ok(checksum_path) => finished_files.push(checksum_path),
+                        Err(err) => warning_tx.send((
+                            input.path.to_owned(),
+                            format!("Could not write the checksum file: {err}"),
+                        ))?,
+                    }
                 }
+                self.start_sync(input, finished_files);
+                return Ok(verdict);
             }
         }
-
-        Ok(videos)
     }
 
-    fn check_encoded_size(
-        &self,
-        orig_size: u64,
-        input_path: PathBuf,
-        output_path: PathBuf,
-        warning_tx: Sender<(PathBuf, String)>,
-    ) -> Result<()> {
-        let size = get_file_size(&output_path).context("Could not get file size after encoding")?;
-        if size < 300 {
-            warning_tx
-                .send((
-                    input_path,
-                    format!("Deleting {size} byte output file: {output_path:?}"),
-                ))
-                .unwrap();
-            remove_file(output_path)?;
-            return Ok(());
-        }
-
-        let percent = size * 100 / orig_size;
-        if let Some(expected_size) = self.cli.expected_size {
-            if percent > expected_size.into() {
-                if self.cli.delete_too_large {
-                    warning_tx.send((input_path, format!("Deleting too large output file (too large at {percent}%): {output_path:?}"))).unwrap();
-                    remove_file(output_path)?;
-                } else {
-                    warning_tx.send((input_path, format!("Output file was larger than expected at {percent}%: {output_path:?}"))).unwrap();
-                }
-            } else if percent < (expected_size / 3).into() {
-                warning_tx.send((input_path, format!("Output file was much smaller than expected at {percent}%: {output_path:?}"))).unwrap();
-            } else if percent > 100 && self.cli.delete_too_large {
-                warning_tx.send((input_path, format!("Deleting output file larger than the original ({percent}%): {output_path:?}"))).unwrap();
-                remove_file(output_path)?;
+    /// Save each text subtitle stream next to the output, as "<output name>.<language>.srt", so
+    /// players pick them up. Failures are only warnings, since the video can still be encoded.
+    async fn extract_subtitles(&self, input: &InputFile, output_path: &Path) {
+        let streams = match input.get_streams().await {
+            Ok(streams) => streams,
+            Err(err) => {
+                _warn!(
+                    input,
+                    "Could not get the subtitle streams to extract them: {err}"
+                );
+                return;
             }
-        }
-
-        return Ok(());
-    }
-
-    async fn wait_for_ffmpeg(&self, job_id: usize) -> Result<EncodingDone, EncodingErr> {
-        fn get_running_ffmpeg() -> HashSet<sysinfo::Pid> {
-            let this_process = std::process::id();
-
-            let sysinfo = sysinfo::System::new_all();
-            sysinfo
-                .processes_by_exact_name("ffmpeg".as_ref())
-                .filter(|process| {
-                    let mut process = *process;
-                    loop {
-                        let parent_pid = process.parent();
-                        if let Some(parent_pid) = parent_pid {
-                            if parent_pid.as_u32() == this_process {
-                                // This is not a ffmpeg we would wait for
-                                return false;
-                            }
-
-                            if let Some(parent) = sysinfo.process(parent_pid) {
-                                // Continue checking
-                                process = parent;
-                                continue;
-                            }
-                        }
-                        // There are no more parents
-                        return true;
-                    }
-                })
-                .map(|process| process.pid())
-                .collect()
-        }
-
-        let mut running_ffmpegs = get_running_ffmpeg();
-        loop {
-            if *self.finished.read().expect("Could not get reader to check if encoding is finished") {
-                log::trace!("Encoding is finished, so we won't wait for ffmpeg anymore");
-                break;
+        };
+        let mut used_names = HashSet::new();
+        for stream in streams
+            .iter()
+            .filter(|stream| stream.codec_type == "subtitle")
+        {
+            if !stream.is_text_subtitle() {
+                _debug!(
+                    input,
+                    "Not extracting subtitle stream {}, which is not text",
+                    stream.index
+                );
+                continue;
             }
-
-            // Note: job 0 waits for JOBS-1 external ffmpegs to be running so the global total will be right,
-            // job 1 waits for JOBS-2 processes to be running...
-            let jobs = self.cli.get_jobs().expect("Jobs should be set by now");
-            let allowed = jobs - 1 - job_id;
-            let too_many = running_ffmpegs.len() as i32 - allowed as i32;
-            debug!("{} ffmpeg processes too many to start job {}", too_many, job_id);
-            if too_many <= 0 {
-                trace!("Not too many ffmpeg processes. Will wait and see if that's a final count. (job {job_id})");
-                // not too many, but check again to be sure new processes aren't
-                // still being spawned:
-                sleep(Duration::from_millis(10_000)).await;
-                let new_ffmpegs = get_running_ffmpeg();
-                if new_ffmpegs.is_subset(&running_ffmpegs) {
-                    trace!("The process count is stable. (job {job_id})");
-                    break; // no new ffmpeg process started
-                }
-                trace!("The process count is still changing. (job {job_id})");
-
-                running_ffmpegs = new_ffmpegs;
+            let language = stream.language.as_deref().unwrap_or("und");
+            let mut suffix = language.to_string();
+            // Keep the forced flag in the name, where players (and `--mux-external-subs`) find it:
+            if stream.has_disposition("forced") {
+                suffix += ".forced";
+            }
+            if !used_names.insert(suffix.clone()) {
+                // Another stream has the same language:
+                suffix = format!("{suffix}.{}", stream.index);
+            }
+            let sub_path = output_path.with_extension(format!("{suffix}.srt"));
+            if sub_path.exists() && !self.cli.overwrite {
+                _debug!(
+                    input,
+                    "Not extracting subtitles to {sub_path:?}, which already exists"
+                );
+                continue;
+            }
+            _info!(
+                input,
+                "Extracting subtitle stream {} to {sub_path:?}",
+                stream.index
+            );
+            if let Err(err) =
+                dump_stream(&input.path, &sub_path, false, Some(stream.index), &self.cli).await
+            {
+                _warn!(
+                    input,
+                    "Could not extract subtitle stream {}: {err}",
+                    stream.index
+                );
             }
-            sleep(Duration::from_millis(10_000)).await;
         }
-        Ok(EncodingDone::WaitTaskDone)
     }
 }
 
-pub fn parse_size(input: &str) -> Result<u64> {
-    let msg = "Size string must be a number with optional K, M, G suffix";
-    let input = input.to_lowercase();
-    let captures = Regex::new(r"^(\.\d+|\d+(?:\.\d*)?)([bkmgt])?$")?
-        .captures(&input)
-        .context(msg)?;
-    let n = captures
-        .get(1)
-        .context(msg)?
-        .as_str()
-        .parse::<f64>()
-        .context(msg)?;
-    let suffix = captures.get(2).map_or("m", |m| m.as_str());
-    let factor = match suffix {
-        "b" => 1,
-        "k" => 1 << 10,
-        "m" => 1 << 20,
-        "g" => 1 << 30,
-        "t" => 1u64 << 40,
-        _ => bail!(msg),
-    };
-    Ok((factor as f64 * n) as u64)
+/// Parse the output of `ffmpeg -encoders` into the set of encoder names, like "libx265".
+pub fn parse_encoders(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        // The encoder list comes after the legend, which ends with a line of dashes:
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect()
+}
+
+/// Parse the pixel formats from the output of `ffmpeg -h encoder=<name>`.
+pub fn parse_pixel_formats(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Supported pixel formats:"))
+        .map(|formats| formats.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
 }
 
-pub fn input_too_small(size: u64, input_str: &Option<String>) -> Result<bool> {
-    if let Some(input_str) = input_str {
-        let input = parse_size(input_str)?;
-        return Ok(size < input);
+/// A file that's removed when this is dropped, if it exists.
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
     }
-    Ok(false)
 }
 
-async fn add_subtitles(input: &InputFile, vf_opts: &mut Vec<OsString>) -> Result<()> {
-    let sub_file = tempfile::Builder::new().suffix(".ass").tempfile()?;
-    let sub_path = sub_file.path();
-    let escaped_sub_path = escape_vf_path(
-        sub_path
-            .to_str()
-            .context("Could not convert temp path to utf-8. Needed for subtitles.")?,
-    )?;
-    dump_stream(&input.path, sub_path, false).await?;
-    vf_opts.push(OsString::from(format!("subtitles={escaped_sub_path}")));
+/// Run the first stabilization pass, which saves the camera motion to a file for the
+/// vidstabtransform filter. The path must already be escaped for use in -vf.
+async fn detect_camera_motion(input_path: &Path, escaped_trf_path: &str, cli: &Cli) -> Result<()> {
+    let status = find_tool(Executable::FFMPEG, cli)?
+        .command()
+        // Other ffmpeg runs share the terminal, so this one neither reads it nor prints progress:
+        .args(["-nostdin", "-nostats", "-i"])
+        .arg(input_path)
+        .args(["-hide_banner", "-loglevel", "error", "-vf"])
+        .arg(format!(
+            "vidstabdetect=shakiness=5:result={escaped_trf_path}"
+        ))
+        .args(["-f", "null", "-"])
+        .status()
+        .await?;
+    if !status.success() {
+        bail!("Could not detect camera motion for stabilization. Is ffmpeg built with vid.stab?");
+    }
     Ok(())
 }
 
-fn get_file_size(output_fname: &Path) -> Result<u64> {
-    let md = output_fname.metadata()?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::MetadataExt;
-        return Ok(md.size());
-    }
-    #[cfg(windows)]
-    {
-        use std::os::windows::fs::MetadataExt;
-        return Ok(md.file_size());
-    }
+/// A program to run, with the arguments that always come first. This lets ffmpeg be run through
+/// a wrapper, like "nice -n 19 ffmpeg".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExternalCommand {
+    pub program: OsString,
+    pub args: Vec<OsString>,
 }
 
-// From Cargo: https://github.com/rust-lang/cargo/blob/7b7af3077bff8d60b7f124189bc9de227d3063a9/crates/cargo-util/src/paths.rs#L84
-/// Normalize a path, removing things like `.` and `..`.
-///
-/// CAUTION: This does not resolve symlinks
-pub fn normalize_path(path: &Path) -> PathBuf {
-    use std::path::Component;
-
-    let mut components = path.components().peekable();
-    let mut ret = if let Some(c @ Component::Prefix(..)) = components.peek().cloned() {
-        components.next();
-        PathBuf::from(c.as_os_str())
-    } else {
-        PathBuf::new()
-    };
+impl ExternalCommand {
+    /// Parse a command, which is either the path to a program, or a program and arguments with
+    /// shell-like quoting.
+    pub fn parse(command: &OsStr) -> Result<Self> {
+        // A path to an existing program is used as is, even if it has spaces:
+        if Path::new(command).exists() {
+            return Ok(Self {
+                program: command.to_owned(),
+                args: Vec::new(),
+            });
+        }
+        let Some(command_str) = command.to_str() else {
+            return Ok(Self {
+                program: command.to_owned(),
+                args: Vec::new(),
+            });
+        };
+        let mut words = shell_words::split(command_str)
+            .with_context(|| format!("Could not parse the command {command_str:?}"))?
+            .into_iter()
+            .map(OsString::from);
+        let program = words
+            .next()
+            .with_context(|| format!("The command {command_str:?} is empty"))?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
 
-    for component in components {
-        match component {
-            Component::Prefix(..) => unreachable!(),
-            Component::RootDir => {
-                ret.push(component.as_os_str());
-            }
-            Component::CurDir => {}
-            Component::ParentDir => {
-                ret.pop();
-            }
-            Component::Normal(c) => {
-                ret.push(c);
+    /// Run the program at the lowest CPU priority with nice, and the lowest disk priority with
+    /// ionice, if they're installed. Otherwise it's run as usual.
+    pub fn with_low_priority(self) -> Self {
+        let mut wrapped = self;
+        for (wrapper, wrapper_args) in [("ionice", ["-c", "3"]), ("nice", ["-n", "19"])] {
+            if !is_in_path(wrapper) {
+                debug!(
+                    "{wrapper} wasn't found, so the priority of {:?} isn't lowered with it",
+                    wrapped.program
+                );
+                continue;
             }
+            let mut args: Vec<OsString> = wrapper_args.iter().map(OsString::from).collect();
+            args.push(wrapped.program);
+            args.extend(wrapped.args);
+            wrapped = Self {
+                program: wrapper.into(),
+                args,
+            };
         }
+        wrapped
     }
-    ret
-}
 
-fn extension_matches(fname: &Path, video_re: &Regex) -> Result<bool> {
-    if let Some(extension) = fname.extension() {
-        let extension = extension
-            .to_ascii_lowercase()
-            .to_str()
-            .map(|s| s.to_string())
-            .ok_or(anyhow!("Path can't be represented as utf-8: {:?}", &fname))?;
-        return Ok(video_re.is_match(&extension));
+    /// Get a command that will run the program, ready for more arguments.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command
     }
-    return Ok(false);
-}
-
-fn is_same_file<P>(pattern: P, matchable_path: P) -> bool
-where
-    P: AsRef<Path> + std::fmt::Debug,
-{
-    let pattern: &Path = pattern.as_ref();
-    let matchable_path: &Path = matchable_path.as_ref();
 
-    debug!("Comparing paths: {:?} and {:?}", pattern, matchable_path);
-
-    if let Ok(canonical_pattern) = std::fs::canonicalize(pattern) {
-        if let Ok(canonical_path) = std::fs::canonicalize(matchable_path) {
-            debug!(
-                "Paths' canonical forms are equal: {}",
-                canonical_path == canonical_pattern
-            );
-            if canonical_path == canonical_pattern {
-                return true;
-            }
-        } else {
-            trace!("Could not canonicalize: {:?}", matchable_path);
-        }
-    } else {
-        trace!("Could not canonicalize: {:?}", pattern);
+    /// Like `command`, but for running synchronously.
+    pub fn std_command(&self) -> std::process::Command {
+        let mut command = std::process::Command::new(&self.program);
+        command.args(&self.args);
+        command
     }
+}
 
-    let pattern_comps = pattern.components();
-    let path_comps = matchable_path.components();
-    if pattern_comps.clone().count() > path_comps.clone().count() {
-        debug!(
-            "Not equal because component counts differ: {:?} and {:?}",
-            pattern, matchable_path
-        );
-        return false;
+impl std::fmt::Display for ExternalCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program.to_string_lossy())?;
+        for arg in &self.args {
+            write!(f, " {}", arg.to_string_lossy())?;
+        }
+        Ok(())
     }
+}
 
-    let are_match = pattern_comps
-        .rev()
-        .zip(path_comps.rev())
-        .all(|(a, b)| a == b);
-    debug!(
-        "Files are a match?: {} ({:?} and {:?})",
-        are_match, pattern, matchable_path
-    );
-    return are_match;
+/// Whether a program with this name is in one of the PATH directories.
+fn is_in_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
 }
 
-fn find_executable(executable: Executable) -> Result<OsString> {
-    // let the user override the path to ffmpeg
+fn find_executable(executable: Executable) -> Result<ExternalCommand> {
+    // let the user override the path to ffmpeg, or run it through a wrapper
     let (executable_name, environment_var) = match executable {
         Executable::FFMPEG => ("ffmpeg", "FFMPEG"),
         Executable::FFPROBE => ("ffprobe", "FFPROBE"),
@@ -1189,60 +1761,47 @@ fn find_executable(executable: Executable) -> Result<OsString> {
 
     if let Some(variable_value) = env::var_os(environment_var) {
         if !variable_value.is_empty() {
-            return Ok(variable_value.into());
+            return ExternalCommand::parse(&variable_value)
+                .with_context(|| format!("Invalid {environment_var} environment variable"));
         }
     }
-    Ok(executable_name.into())
+    Ok(ExternalCommand {
+        program: executable_name.into(),
+        args: Vec::new(),
+    })
 }
 
-/// Use ffmpeg to convert one path to another path, optionally with the `-c copy` option.
-async fn dump_stream(input_path: &Path, output_path: &Path, copy: bool) -> Result<()> {
-    let mut cmd = Command::new(find_executable(Executable::FFMPEG)?);
-    let cmd = cmd.arg("-i").arg(input_path);
+/// Find ffmpeg or ffprobe, to run at a low priority with `--eco`.
+fn find_tool(executable: Executable, cli: &Cli) -> Result<ExternalCommand> {
+    let tool = find_executable(executable)?;
+    Ok(if cli.eco {
+        tool.with_low_priority()
+    } else {
+        tool
+    })
+}
+
+/// Use ffmpeg to convert one path to another path, optionally with the `-c copy` option. If a
+/// stream index is given, only that stream is converted. The output is overwritten if it exists.
+async fn dump_stream(
+    input_path: &Path,
+    output_path: &Path,
+    copy: bool,
+    stream_index: Option<u32>,
+    cli: &Cli,
+) -> Result<()> {
+    let mut cmd = find_tool(Executable::FFMPEG, cli)?.command();
+    let cmd = cmd
+        .args(["-nostdin", "-y", "-loglevel", "error", "-i"])
+        .arg(input_path);
+    let cmd = match stream_index {
+        Some(index) => cmd.args(["-map", &format!("0:{index}")]),
+        None => cmd,
+    };
     let cmd = if copy { cmd.args(["-c", "copy"]) } else { cmd };
     let status = cmd.arg(output_path).status().await?;
     if !status.success() {
-        warn!("Could not convert path {input_path:?} to {output_path:?}");
+        bail!("Could not convert path {input_path:?} to {output_path:?}");
     }
     Ok(())
 }
-
-/// Escape a path for use with the ffmpeg -vf argument. The escaping rules are hard to discover except
-/// by testing.
-fn escape_vf_path(sub_path: &str) -> Result<String> {
-    let sub_path = sub_path.replace(r"\", r"\\\\");
-    let sub_path = sub_path.replace(r"'", r"\\\'");
-    let sub_path = sub_path.replace(r":", r"\\:");
-    let sub_path = sub_path.replace(r"=", r"\\=");
-    let sub_path = Regex::new(r"([;,\[\] =])")?.replace_all(&sub_path, "\\$1");
-    Ok(sub_path.to_string())
-}
-
-fn find_subtitle_file(input: &InputFile) -> Result<Option<PathBuf>> {
-    let srt_name = input
-        .path
-        .with_extension("srt")
-        .file_name()
-        .context("Could not get filename of video file")?
-        .to_ascii_lowercase();
-    for sibling in input
-        .path
-        .parent()
-        .context("Could not get directory of video file")?
-        .read_dir()?
-    {
-        if let Ok(sibling) = sibling {
-            if sibling.file_name().to_ascii_lowercase() == srt_name {
-                return Ok(Some(sibling.path()));
-            }
-        }
-    }
-
-    Ok(None)
-}
-
-pub fn get_output_dir(cli: &Cli) -> PathBuf {
-    cli.output_dir
-        .as_ref()
-        .map_or(cli.video_root.join(ENCODED), |path| path.to_owned())
-}
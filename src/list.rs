@@ -0,0 +1,157 @@
+//! The `list` subcommand: print the videos that would be encoded, for use in shell pipelines.
+
+use clap::ValueEnum;
+#[allow(unused_imports)]
+use log::*;
+
+use crate::{get_file_size, input_too_large, input_too_small, Encoder, JiffyResult};
+
+/// The formats `jiffy list` can print the videos in.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ListFormat {
+    /// One path per line.
+    #[default]
+    Paths,
+    /// A JSON array, with the probed properties of each video.
+    Json,
+    /// CSV with a header row, with the probed properties of each video.
+    Csv,
+}
+
+/// A video found for `jiffy list`. The probed properties are None if ffprobe couldn't get them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListedVideo {
+    pub path: String,
+    pub size: u64,
+    /// The length in seconds.
+    pub duration: Option<f64>,
+    pub codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The bitrate of the whole file, in bits per second.
+    pub bitrate: Option<u64>,
+}
+
+const CSV_HEADER: &str = "path,size,duration,codec,width,height,bitrate";
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped + "\""
+}
+
+fn json_value<T: ToString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map_or("null".to_string(), ToString::to_string)
+}
+
+/// Quote a CSV field if it needs it, doubling any quotes.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn csv_value<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map_or(String::new(), ToString::to_string)
+}
+
+/// Print the videos in the chosen format.
+pub fn render_list(videos: &[ListedVideo], format: ListFormat) -> String {
+    match format {
+        ListFormat::Paths => videos
+            .iter()
+            .map(|video| format!("{}\n", video.path))
+            .collect(),
+        ListFormat::Json => {
+            let entries: Vec<_> = videos
+                .iter()
+                .map(|video| {
+                    format!(
+                        "  {{\"path\": {}, \"size\": {}, \"duration\": {}, \"codec\": {}, \"width\": {}, \"height\": {}, \"bitrate\": {}}}",
+                        json_string(&video.path),
+                        video.size,
+                        json_value(&video.duration),
+                        video.codec.as_deref().map_or("null".to_string(), json_string),
+                        json_value(&video.width),
+                        json_value(&video.height),
+                        json_value(&video.bitrate),
+                    )
+                })
+                .collect();
+            if entries.is_empty() {
+                "[]\n".to_string()
+            } else {
+                format!("[\n{}\n]\n", entries.join(",\n"))
+            }
+        }
+        ListFormat::Csv => {
+            let mut csv = format!("{CSV_HEADER}\n");
+            for video in videos {
+                csv += &format!(
+                    "{},{},{},{},{},{},{}\n",
+                    csv_field(&video.path),
+                    video.size,
+                    csv_value(&video.duration),
+                    csv_field(video.codec.as_deref().unwrap_or_default()),
+                    csv_value(&video.width),
+                    csv_value(&video.height),
+                    csv_value(&video.bitrate),
+                );
+            }
+            csv
+        }
+    }
+}
+
+impl Encoder {
+    /// Find the videos with the same options and filters as an encode would, and print them
+    /// to stdout. Nothing is encoded.
+    pub async fn list_videos(&self, format: ListFormat) -> JiffyResult<()> {
+        let mut videos = Vec::new();
+        for input in self.get_video_paths().await? {
+            let size = get_file_size(&input.path).unwrap_or_default();
+            // An encode checks the size limits when it starts, so they're checked here too:
+            if input_too_small(size, &self.cli.minimum_size)?
+                || input_too_large(size, &self.cli.maximum_size)?
+            {
+                continue;
+            }
+            let mut video = ListedVideo {
+                path: input.path.to_string_lossy().into_owned(),
+                size,
+                ..Default::default()
+            };
+            // Only the paths are printed by default, so don't wait for ffprobe:
+            if format != ListFormat::Paths {
+                video.duration = input.get_duration().await.ok().map(f64::from);
+                video.codec = input
+                    .get_video_stream()
+                    .await
+                    .ok()
+                    .flatten()
+                    .and_then(|stream| stream.codec);
+                if let Ok((width, height)) = input.get_video_dimensions().await {
+                    (video.width, video.height) = (Some(width), Some(height));
+                }
+                video.bitrate = input.get_overall_bitrate().await.ok();
+            }
+            videos.push(video);
+        }
+        print!("{}", render_list(&videos, format));
+        Ok(())
+    }
+}
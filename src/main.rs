@@ -1,15 +1,18 @@
-use std::{env, thread, time::Duration};
+use std::{env, time::Duration};
 
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::Result;
+use clap::{CommandFactory, FromArgMatches};
 #[allow(unused_imports)]
 use log::*;
+use tokio_util::sync::CancellationToken;
 
-use jiffy::{get_output_dir, Cli, Encoder};
+use jiffy::{get_effective_config, render_config, Cli, Encoder, JiffyError, Subcommands};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    // The matches are kept to tell which options were given, for --print-config:
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
     match cli.get_verbosity() {
         2.. => {
@@ -39,24 +42,47 @@ async fn main() -> Result<()> {
         }
     }
 
-    if !cli.video_root.exists() {
-        bail!("Video root does not exist: {:?}", cli.video_root);
+    if cli.print_config {
+        print!("{}", render_config(&get_effective_config(&matches)));
+        return Ok(());
     }
 
-    if let Ok(video_root) = cli.video_root.canonicalize() {
-        if video_root
-            .components()
-            .last()
-            .expect("Cannot get components of encode path")
-            .as_os_str()
-            == get_output_dir(&cli)
-        {
-            warn!(
-                "The video directory is named {:?}. Did you mean to encode the parent directory?",
-                cli.output_dir
-            );
-            thread::sleep(Duration::from_millis(2000));
-        }
+    // This runs another command, so it has no report:
+    if let Some(Subcommands::Slot(opts)) = cli.command.clone() {
+        let encoder = Encoder::new(cli)?;
+        std::process::exit(encoder.run_in_global_slot(&opts.command).await?);
+    }
+
+    let cancel = CancellationToken::new();
+    // On Ctrl-C, stop the running encodes and don't start more, so the summary is still
+    // printed. A second Ctrl-C quits right away, in case stopping takes too long. Pruning,
+    // listing and --diff are just stopped:
+    if cli.command.is_none() && !cli.diff {
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_err() {
+                    return;
+                }
+                warn!("Stopping the encodes. Press Ctrl-C again to quit now, leaving partial outputs behind");
+                cancel.cancel();
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    // The usual exit status for a process stopped by SIGINT:
+                    std::process::exit(130);
+                }
+            }
+        });
+    }
+    if jiffy::warn_if_output_dir(&cli) {
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+    }
+    let report_format = cli.report_format;
+    let report = jiffy::run_until_cancelled(cli, cancel).await?;
+    if let Some(format) = report_format {
+        print!("{}", report.render(format));
+    }
+    if report.cancelled {
+        return Err(JiffyError::Cancelled.into());
     }
-    Encoder::new(cli)?.encode_videos().await
+    Ok(())
 }
@@ -0,0 +1,227 @@
+//! Where finished outputs are kept. Outputs are always written to the output directory first;
+//! with `--output-url` they're then uploaded to an S3-compatible bucket and removed locally.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::*;
+
+#[allow(unused_imports)]
+use crate::{_debug, _error, _info, _log, _trace, _warn};
+use crate::{get_checksum_path, get_output_dir, Encoder, InputFile};
+
+/// The size of each part of a multipart upload. S3 needs parts of at least 5 MiB.
+pub const UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// How many parts of one file are uploaded at once.
+pub const UPLOAD_CONCURRENCY: usize = 4;
+
+/// A bucket and key prefix, parsed from a URL like "s3://bucket/videos".
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputUrl {
+    pub bucket: String,
+    /// The prefix of the object keys, without slashes at the ends. It may be empty.
+    pub prefix: String,
+}
+
+impl OutputUrl {
+    pub fn parse(input: &str) -> Result<Self> {
+        let Some(rest) = input.strip_prefix("s3://") else {
+            bail!("The output URL must start with \"s3://\", like \"s3://bucket/videos\"");
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            bail!("The output URL has no bucket name: {input}");
+        }
+        Ok(Self {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+        })
+    }
+
+    /// The key for a file at this path within the output directory. The keys follow the same
+    /// layout and naming as the files in the output directory.
+    pub fn object_key(&self, relative_path: &Path) -> String {
+        let mut parts: Vec<_> = (!self.prefix.is_empty())
+            .then(|| self.prefix.clone())
+            .into_iter()
+            .collect();
+        parts.extend(
+            relative_path
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy().into_owned()),
+        );
+        parts.join("/")
+    }
+}
+
+impl std::fmt::Display for OutputUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+/// Where the finished outputs are kept.
+#[derive(Default)]
+pub enum OutputBackend {
+    /// The outputs stay in the output directory.
+    #[default]
+    Local,
+    /// The outputs are uploaded to a bucket. The credentials, region and endpoint are read from
+    /// the usual AWS_* environment variables, and failed requests are retried.
+    #[cfg(feature = "s3")]
+    S3 {
+        store: object_store::aws::AmazonS3,
+        url: OutputUrl,
+    },
+}
+
+impl OutputBackend {
+    pub fn new(output_url: Option<&OutputUrl>) -> Result<Self> {
+        let Some(url) = output_url else {
+            return Ok(Self::Local);
+        };
+        #[cfg(feature = "s3")]
+        {
+            let store = object_store::aws::AmazonS3Builder::from_env()
+                .with_bucket_name(&url.bucket)
+                .with_retry(object_store::RetryConfig::default())
+                .build()
+                .with_context(|| format!("Could not set up the upload to {url}"))?;
+            Ok(Self::S3 {
+                store,
+                url: url.clone(),
+            })
+        }
+        #[cfg(not(feature = "s3"))]
+        bail!("Can't upload to {url}: jiffy was built without the \"s3\" feature")
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self, Self::Local)
+    }
+
+    /// Whether an output at this path within the output directory was already uploaded.
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    pub async fn exists(&self, relative_path: &Path) -> Result<bool> {
+        match self {
+            Self::Local => Ok(false),
+            #[cfg(feature = "s3")]
+            Self::S3 { store, url } => {
+                use object_store::ObjectStore;
+                let key = object_store::path::Path::from(url.object_key(relative_path));
+                match store.head(&key).await {
+                    Ok(_) => Ok(true),
+                    Err(object_store::Error::NotFound { .. }) => Ok(false),
+                    Err(err) => {
+                        Err(err).with_context(|| format!("Could not check for {key} in {url}"))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Upload a file at this path within the output directory, in parts. Returns where it was
+    /// uploaded to.
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    pub async fn upload(&self, file: &Path, relative_path: &Path) -> Result<String> {
+        match self {
+            Self::Local => Ok(file.to_string_lossy().into_owned()),
+            #[cfg(feature = "s3")]
+            Self::S3 { store, url } => {
+                use object_store::{ObjectStore, WriteMultipart};
+                use tokio::io::AsyncReadExt;
+
+                let key = object_store::path::Path::from(url.object_key(relative_path));
+                let upload = store.put_multipart(&key).await?;
+                let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_CHUNK_SIZE);
+                let mut source = tokio::fs::File::open(file).await?;
+                let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+                let result: Result<()> = async {
+                    loop {
+                        writer.wait_for_capacity(UPLOAD_CONCURRENCY).await?;
+                        let len = source.read(&mut buf).await?;
+                        if len == 0 {
+                            return Ok(());
+                        }
+                        writer.write(&buf[..len]);
+                    }
+                }
+                .await;
+                if let Err(err) = result {
+                    // Don't leave the uploaded parts behind, since they're billed until removed:
+                    let _ = writer.abort().await;
+                    return Err(err);
+                }
+                writer.finish().await?;
+                Ok(format!("s3://{}/{key}", url.bucket))
+            }
+        }
+    }
+}
+
+impl Encoder {
+    /// Whether the output was already uploaded to the `--output-url` bucket by an earlier run.
+    pub(crate) async fn output_uploaded(
+        &self,
+        input: &InputFile,
+        output_path: &Path,
+    ) -> Result<bool> {
+        if self.output_backend.is_local() {
+            return Ok(false);
+        }
+        self.output_backend
+            .exists(&path_in_output_dir(input, output_path)?)
+            .await
+    }
+
+    /// Upload the finished output, and its checksum file, to the `--output-url` bucket and
+    /// remove them from the output directory. This also uploads outputs that an earlier run
+    /// finished but couldn't upload. A file that can't be uploaded is kept, with a warning.
+    pub(crate) async fn upload_outputs(
+        &self,
+        input: &InputFile,
+        warning_tx: &Sender<(PathBuf, String)>,
+    ) {
+        if self.output_backend.is_local() {
+            return;
+        }
+        let Ok(output_path) = input.get_output_path(input.get_cli().output_name.clone()) else {
+            return;
+        };
+        let checksum_path = get_checksum_path(&output_path);
+        for file in [output_path, checksum_path]
+            .iter()
+            .filter(|file| file.exists())
+        {
+            let uploaded = match path_in_output_dir(input, file) {
+                Ok(relative_path) => self.output_backend.upload(file, &relative_path).await,
+                Err(err) => Err(err),
+            };
+            match uploaded {
+                Ok(location) => {
+                    _info!(input, "Uploaded {file:?} to {location}");
+                    if let Err(err) = std::fs::remove_file(file) {
+                        _warn!(input, "Could not remove the uploaded file {file:?}: {err}");
+                    }
+                }
+                Err(err) => warning_tx
+                    .send((
+                        input.path.to_owned(),
+                        format!("Could not upload {file:?}, so it was kept: {err:#}"),
+                    ))
+                    .unwrap(),
+            }
+        }
+    }
+}
+
+/// Get the path of an output within its input's output directory, which is where it's kept in
+/// the bucket.
+fn path_in_output_dir(input: &InputFile, path: &Path) -> Result<PathBuf> {
+    pathdiff::diff_paths(path, get_output_dir(input.get_cli()))
+        .context("Could not get the output path within the output directory")
+}
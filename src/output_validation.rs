@@ -0,0 +1,166 @@
+use crate::{Cli, TooLargePolicy};
+
+/// A judgement of a finished output's size, compared to its input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeVerdict {
+    Ok,
+    /// The output is too small to hold any video, so the encode must have failed.
+    Broken,
+    /// The output is larger than `--expected-size` allows. This and the other percentages are
+    /// the output size as a percent of the input size.
+    LargerThanExpected(u64),
+    /// The output is so much smaller than expected that something may have gone wrong.
+    SuspiciouslySmall(u64),
+}
+
+/// What to do with an output (or a partial output), and why.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputVerdict {
+    Keep,
+    /// Keep the output, but warn about it.
+    Warn(String),
+    /// Delete the output, or stop encoding it.
+    Delete(String),
+    /// Delete the output, or stop encoding it, and encode the input again with a higher CRF.
+    Retry(String),
+}
+
+/// The limits that outputs are checked against, and what to do about outputs outside them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputPolicy {
+    /// Outputs smaller than this many bytes are broken.
+    pub min_output_size: u64,
+    /// The expected output size, as a percent of the input size.
+    pub expected_size: Option<u8>,
+    /// Outputs smaller than the expected size divided by this ratio are suspicious. Zero disables
+    /// the check.
+    pub suspect_small_ratio: f64,
+    pub delete_too_large: bool,
+    pub retry_too_large: bool,
+    /// What to do when an output is projected to be too large, part way through the encode.
+    pub projected_size_policy: TooLargePolicy,
+    /// Set for lossless and mezzanine outputs, which are expected to be larger than their
+    /// inputs, so their size isn't compared to the input's.
+    pub expects_growth: bool,
+}
+
+impl OutputPolicy {
+    pub fn new(cli: &Cli) -> Self {
+        Self {
+            min_output_size: cli.min_output_size,
+            expected_size: cli.expected_size,
+            suspect_small_ratio: cli.suspect_small_ratio,
+            delete_too_large: cli.delete_too_large,
+            retry_too_large: cli.retry_too_large,
+            projected_size_policy: cli.projected_size_policy,
+            expects_growth: cli.keeps_source_picture() || cli.mezzanine.is_some(),
+        }
+    }
+
+    pub fn check_size(&self, orig_size: u64, size: u64) -> SizeVerdict {
+        if size < self.min_output_size {
+            return SizeVerdict::Broken;
+        }
+        if self.expects_growth {
+            return SizeVerdict::Ok;
+        }
+        // Without --expected-size, an output is still expected to be no larger than its source:
+        let percent = size * 100 / orig_size.max(1);
+        if percent > self.expected_size.map_or(100, u64::from) {
+            SizeVerdict::LargerThanExpected(percent)
+        } else if self.expected_size.is_some_and(|expected_size| {
+            self.suspect_small_ratio > 0.0
+                && (percent as f64) < f64::from(expected_size) / self.suspect_small_ratio
+        }) {
+            SizeVerdict::SuspiciouslySmall(percent)
+        } else {
+            SizeVerdict::Ok
+        }
+    }
+
+    /// Decide what to do with a finished output.
+    pub fn judge_output(&self, orig_size: u64, size: u64) -> OutputVerdict {
+        match self.check_size(orig_size, size) {
+            SizeVerdict::Ok => OutputVerdict::Keep,
+            SizeVerdict::Broken => {
+                OutputVerdict::Delete(format!("Deleting {size} byte output file"))
+            }
+            SizeVerdict::LargerThanExpected(percent) => {
+                if !self.delete_too_large {
+                    OutputVerdict::Warn(format!(
+                        "Output file was larger than expected at {percent}%"
+                    ))
+                } else {
+                    self.too_large(format!(
+                        "Deleted too large output file (too large at {percent}%)"
+                    ))
+                }
+            }
+            SizeVerdict::SuspiciouslySmall(percent) => OutputVerdict::Warn(format!(
+                "Output file was much smaller than expected at {percent}%"
+            )),
+        }
+    }
+
+    /// Decide whether to keep encoding, given the size of the partial output so far. With
+    /// `--delete-too-large`, there's no point finishing an output that is sure to be deleted.
+    pub fn judge_partial(&self, orig_size: u64, size: u64) -> OutputVerdict {
+        if !self.delete_too_large || self.expects_growth {
+            return OutputVerdict::Keep;
+        }
+        let percent = self.expected_size.map_or(100, u64::from);
+        // Allow a small margin, since the container may shrink a little when finalized:
+        if size <= orig_size * (percent + 2) / 100 {
+            return OutputVerdict::Keep;
+        }
+        self.too_large(format!(
+            "Stopped encoding because the output grew too large ({}% of the original)",
+            size * 100 / orig_size.max(1)
+        ))
+    }
+
+    /// Check whether `judge_projection` will ever stop an encode, which is worth knowing since
+    /// tracking the progress costs a probe of the input's duration.
+    pub fn wants_projection(&self) -> bool {
+        self.projected_size_policy != TooLargePolicy::Ignore && self.expected_size.is_some()
+    }
+
+    /// Decide whether to keep encoding, given the projected size of the finished output.
+    pub fn judge_projection(&self, orig_size: u64, projected_size: u64) -> OutputVerdict {
+        let Some(expected_size) = self.expected_size else {
+            return OutputVerdict::Keep;
+        };
+        // Only stop early if the output will clearly be too large, since the projection is rough:
+        if projected_size <= orig_size * (u64::from(expected_size) + 10) / 100 {
+            return OutputVerdict::Keep;
+        }
+        let msg = format!(
+            "Stopped encoding because the output is projected to be {}% of the original",
+            projected_size * 100 / orig_size.max(1)
+        );
+        match self.projected_size_policy {
+            TooLargePolicy::Ignore => OutputVerdict::Keep,
+            TooLargePolicy::Skip => OutputVerdict::Delete(msg),
+            TooLargePolicy::Retry => OutputVerdict::Retry(msg),
+        }
+    }
+
+    fn too_large(&self, msg: String) -> OutputVerdict {
+        if self.retry_too_large {
+            OutputVerdict::Retry(msg)
+        } else {
+            OutputVerdict::Delete(msg)
+        }
+    }
+}
+
+/// How far a finished output's length may be from the expected length, in seconds. Long videos
+/// are allowed a fraction of their length instead, if that's more.
+pub const LENGTH_TOLERANCE_SECONDS: f64 = 2.0;
+pub const LENGTH_TOLERANCE_RATIO: f64 = 0.01;
+
+/// Check whether a finished output is about as long as expected. The container's length is
+/// rounded differently by different muxers, so this isn't exact.
+pub fn length_matches(expected: f64, actual: f64) -> bool {
+    (expected - actual).abs() <= LENGTH_TOLERANCE_SECONDS.max(expected * LENGTH_TOLERANCE_RATIO)
+}
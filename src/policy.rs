@@ -0,0 +1,272 @@
+//! What to do about inputs and outputs that are too small or too large.
+
+use std::{fs::remove_file, path::PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    format_size, get_file_size, get_mtime, parse_bitrate, parse_size, Cli, Encoder, OutputPolicy,
+    OutputVerdict, StreamInfo,
+};
+
+/// What to do about an output that will be too large.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum TooLargePolicy {
+    #[default]
+    Ignore,
+    Skip,
+    Retry,
+}
+
+/// What to do with lossless audio streams.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LosslessAudioPolicy {
+    Keep,
+    Encode,
+    #[default]
+    Warn,
+}
+
+/// Which audio streams to copy rather than reencode, for `--audio-policy`. Lossless streams
+/// follow `--audio-lossless` instead.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum AudioPolicy {
+    /// Copy streams whose bitrate is within `--audio-copy-threshold`, since reencoding them
+    /// would save little.
+    #[default]
+    CopyIfEfficient,
+    AlwaysEncode,
+    /// Copy every stream the output container can hold, unless audio filters are needed.
+    AlwaysCopy,
+}
+
+/// The highest bitrate of an audio stream that's copied, in bits per second: either for the
+/// whole stream, or for each of its channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioCopyThreshold {
+    Total(u64),
+    PerChannel(u64),
+}
+
+impl AudioCopyThreshold {
+    /// Parse a threshold like "200k", or "96k/ch" for a threshold per channel.
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        match input
+            .strip_suffix("/ch")
+            .or_else(|| input.strip_suffix("/channel"))
+        {
+            Some(per_channel) => Ok(Self::PerChannel(parse_bitrate(per_channel.trim())?)),
+            None => Ok(Self::Total(parse_bitrate(input)?)),
+        }
+    }
+
+    /// The threshold for a stream with this many channels, in kb/s. Streams with an unknown
+    /// channel count are taken to be stereo.
+    pub fn for_channels(&self, channels: Option<u32>) -> f32 {
+        let bitrate = match *self {
+            Self::Total(bitrate) => bitrate,
+            Self::PerChannel(bitrate) => bitrate * u64::from(channels.unwrap_or(2).max(1)),
+        };
+        bitrate as f32 / 1000f32
+    }
+}
+
+impl std::fmt::Display for AudioCopyThreshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Total(bitrate) => write!(f, "{} kb/s", bitrate / 1000),
+            Self::PerChannel(bitrate) => write!(f, "{} kb/s per channel", bitrate / 1000),
+        }
+    }
+}
+
+/// Decides whether each audio stream is copied or reencoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioCopyPolicy {
+    pub policy: AudioPolicy,
+    pub threshold: AudioCopyThreshold,
+}
+
+impl AudioCopyPolicy {
+    pub fn new(cli: &Cli) -> Self {
+        Self {
+            policy: cli.audio_policy,
+            // Audio that's already close to the target bitrate isn't worth reencoding:
+            threshold: cli
+                .audio_copy_threshold
+                .unwrap_or(AudioCopyThreshold::Total(
+                    cli.get_audio_bitrate() * 200 / 128,
+                )),
+        }
+    }
+
+    /// Whether a stream with this bitrate in kb/s, and this many channels, should be copied.
+    /// Streams whose bitrate couldn't be measured are only copied with `always-copy`.
+    pub fn should_copy(&self, bitrate: Option<f32>, channels: Option<u32>) -> bool {
+        match self.policy {
+            AudioPolicy::AlwaysCopy => true,
+            AudioPolicy::AlwaysEncode => false,
+            AudioPolicy::CopyIfEfficient => {
+                bitrate.is_some_and(|bitrate| bitrate <= self.threshold.for_channels(channels))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for AudioCopyPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.policy {
+            AudioPolicy::AlwaysCopy => {
+                write!(f, "Streams are copied when the output can hold them")
+            }
+            AudioPolicy::AlwaysEncode => write!(f, "Streams are always reencoded"),
+            AudioPolicy::CopyIfEfficient => {
+                write!(f, "Streams of up to {} are copied", self.threshold)
+            }
+        }
+    }
+}
+
+/// What to do with subtitle streams when the output is mp4 or mov, which can't hold SRT or ASS
+/// subtitles as they are.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SubHandling {
+    #[default]
+    Convert,
+    Drop,
+    Burn,
+}
+
+impl SubHandling {
+    /// Check whether a stream can be kept in an mp4 or mov output. Only text subtitles can be, by
+    /// converting them to mov_text.
+    pub fn keeps_in_mp4(&self, stream: &StreamInfo) -> bool {
+        stream.codec_type != "subtitle"
+            || (*self == SubHandling::Convert && stream.is_text_subtitle())
+    }
+}
+
+/// Which files to encode when there are several editions of a title, like "Director's Cut" and
+/// "Theatrical".
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum EditionPolicy {
+    #[default]
+    All,
+    /// The most recently modified edition.
+    Newest,
+    Smallest,
+}
+
+/// The order the videos are encoded in, for `--order`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum QueueOrder {
+    /// The order they're found in, with numbers in names sorted by value, or the order of
+    /// `--files-from`.
+    #[default]
+    Natural,
+    /// The smallest first.
+    SizeAsc,
+    /// The largest first, to save the most space soonest.
+    SizeDesc,
+    /// The least recently modified first.
+    Mtime,
+    /// A random order, which is repeatable with `--seed`.
+    Random,
+}
+
+/// Sort the videos into the order they'll be encoded in. Files whose size or time can't be read
+/// go last.
+pub fn order_paths(paths: &mut [PathBuf], order: QueueOrder, rng: &mut impl rand::Rng) {
+    match order {
+        QueueOrder::Natural => {}
+        QueueOrder::SizeAsc => {
+            paths.sort_by_cached_key(|path| get_file_size(path).unwrap_or(u64::MAX))
+        }
+        QueueOrder::SizeDesc => paths
+            .sort_by_cached_key(|path| std::cmp::Reverse(get_file_size(path).unwrap_or_default())),
+        QueueOrder::Mtime => paths.sort_by_cached_key(|path| {
+            let mtime = get_mtime(path).ok();
+            (mtime.is_none(), mtime)
+        }),
+        QueueOrder::Random => paths.shuffle(rng),
+    }
+}
+
+/// What to do when videos in the queue would write the same output, like "show.mkv" and
+/// "show.avi", for `--output-collision`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputCollision {
+    /// Don't encode anything, and list the videos that collide.
+    #[default]
+    Fail,
+    /// Add the input's extension to each colliding output name, like "show-crf22-avi.mkv", and a
+    /// number if that's not enough.
+    Rename,
+}
+
+pub fn input_too_small(size: u64, input_str: &Option<String>) -> Result<bool> {
+    if let Some(input_str) = input_str {
+        let input = parse_size(input_str)?;
+        return Ok(size < input);
+    }
+    Ok(false)
+}
+
+/// How many times the size of a lossy input a `--lossless` output may be. It varies a lot with
+/// the source: noisy analog captures compress worst.
+pub const LOSSLESS_SIZE_FACTOR: u64 = 5;
+
+/// A warning about the space that `--lossless` or `--near-lossless` outputs need, for inputs of
+/// this total size.
+pub fn lossless_size_warning(cli: &Cli, total_input_size: u64) -> Option<String> {
+    if cli.lossless {
+        Some(format!(
+            "Lossless outputs are usually much larger than lossy inputs, up to {LOSSLESS_SIZE_FACTOR} times. The inputs are {}, so the outputs may need {}",
+            format_size(total_input_size),
+            format_size(total_input_size * LOSSLESS_SIZE_FACTOR)
+        ))
+    } else if cli.near_lossless {
+        Some(format!(
+            "Near-lossless outputs may be larger than their inputs, which are {} in total",
+            format_size(total_input_size)
+        ))
+    } else {
+        None
+    }
+}
+
+pub fn input_too_large(size: u64, input_str: &Option<String>) -> Result<bool> {
+    if let Some(input_str) = input_str {
+        let input = parse_size(input_str)?;
+        return Ok(size > input);
+    }
+    Ok(false)
+}
+
+impl Encoder {
+    pub(crate) fn check_encoded_size(
+        &self,
+        orig_size: u64,
+        output_path: PathBuf,
+    ) -> Result<OutputVerdict> {
+        let size = get_file_size(&output_path).context("Could not get file size after encoding")?;
+        Ok(
+            match OutputPolicy::new(&self.cli).judge_output(orig_size, size) {
+                OutputVerdict::Keep => OutputVerdict::Keep,
+                OutputVerdict::Warn(msg) => OutputVerdict::Warn(format!("{msg}: {output_path:?}")),
+                OutputVerdict::Delete(msg) => {
+                    remove_file(&output_path)?;
+                    OutputVerdict::Delete(format!("{msg}: {output_path:?}"))
+                }
+                OutputVerdict::Retry(msg) => {
+                    remove_file(&output_path)?;
+                    OutputVerdict::Retry(format!("{msg}: {output_path:?}"))
+                }
+            },
+        )
+    }
+}
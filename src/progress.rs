@@ -0,0 +1,44 @@
+/// The progress of an encode, parsed from ffmpeg's `-progress` output.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Progress {
+    /// How much of the video has been encoded, in seconds.
+    pub out_time: f64,
+    /// The size of the output so far, in bytes.
+    pub total_size: u64,
+    pub finished: bool,
+}
+
+impl Progress {
+    /// Update the progress from one line of `-progress` output, like "out_time_us=1000000".
+    pub fn update(&mut self, line: &str) {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            return;
+        };
+        // Values can be "N/A" at the start of an encode:
+        match key {
+            "out_time_us" => {
+                if let Ok(us) = value.parse::<i64>() {
+                    self.out_time = us.max(0) as f64 / 1_000_000.0;
+                }
+            }
+            "total_size" => {
+                if let Ok(size) = value.parse() {
+                    self.total_size = size;
+                }
+            }
+            "progress" => self.finished = value == "end",
+            _ => {}
+        }
+    }
+
+    /// Project the size of the finished output from the size so far. This is None until enough
+    /// of the video has been encoded for the projection to be meaningful: a quarter of the
+    /// video, or three minutes of it if that comes first.
+    pub fn projected_size(&self, duration: f64) -> Option<u64> {
+        let needed = (duration / 4.0).min(180.0);
+        if duration <= 0.0 || self.out_time <= 0.0 || self.out_time < needed {
+            return None;
+        }
+        Some((self.total_size as f64 * duration / self.out_time) as u64)
+    }
+}
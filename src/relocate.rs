@@ -0,0 +1,126 @@
+//! Clearing away the source of a finished encode, for `--move-source-to` and `--trash-source`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::*;
+
+#[allow(unused_imports)]
+use crate::{_debug, _error, _info, _log, _trace, _warn};
+use crate::{length_matches, move_to_trash, Encoder, InputFile};
+
+/// Where `--move-source-to` moves a source video: the same path within `move_to` as it has
+/// within the video root.
+pub fn get_moved_source_path(move_to: &Path, video_root: &Path, source: &Path) -> Result<PathBuf> {
+    let relative_path = source
+        .strip_prefix(video_root)
+        .with_context(|| format!("{source:?} is not in the video root {video_root:?}"))?;
+    Ok(move_to.join(relative_path))
+}
+
+/// Move a file, without replacing one that's already there. A file on another filesystem is
+/// copied and then removed.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        bail!("{to:?} already exists");
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    if let Err(err) = std::fs::copy(from, to) {
+        let _ = std::fs::remove_file(to);
+        return Err(err).with_context(|| format!("Could not copy {from:?} to {to:?}"));
+    }
+    std::fs::remove_file(from)
+        .with_context(|| format!("Copied {from:?} to {to:?}, but could not remove it"))
+}
+
+impl Encoder {
+    /// Check that a finished output can be read and is as long as the encode should have made
+    /// it, before its source is moved away.
+    async fn verify_output(&self, input: &InputFile, output_path: &Path) -> Result<()> {
+        let trim = self.cli.get_trim()?;
+        let expected = trim.trimmed_duration(input.get_duration().await? as f64)
+            / self.cli.speed.unwrap_or(1.0);
+        let mut output = input.clone();
+        output.path = output_path.to_owned();
+        let actual = output
+            .get_duration()
+            .await
+            .context("Could not get the length of the output")? as f64;
+        if !length_matches(expected, actual) {
+            bail!("The output is {actual:.1}s long, but should be {expected:.1}s long");
+        }
+        Ok(())
+    }
+
+    /// With `--move-source-to` or `--trash-source`, move the source of a freshly finished output
+    /// out of the video root. The history file is pointed at the new location, so pruning
+    /// doesn't take the output for an orphan. Sources that can't be moved are kept, with a
+    /// warning.
+    pub(crate) async fn relocate_source(
+        &self,
+        input: &InputFile,
+        warning_tx: &Sender<(PathBuf, String)>,
+    ) {
+        if (self.cli.move_source_to.is_none() && !self.cli.trash_source) || self.cli.test_opts.noop
+        {
+            return;
+        }
+        let output_path = match input.get_output_path(input.get_cli().output_name.clone()) {
+            Ok(output_path) => output_path,
+            Err(err) => {
+                warning_tx
+                    .send((
+                        input.path.to_owned(),
+                        format!("Kept the source, since the output is unknown: {err}"),
+                    ))
+                    .unwrap();
+                return;
+            }
+        };
+        if let Err(err) = self.verify_output(input, &output_path).await {
+            let msg = format!("Kept the source, since the output could not be checked: {err:#}");
+            _warn!(input, "{:?}: {}", input.path, msg);
+            warning_tx.send((input.path.to_owned(), msg)).unwrap();
+            return;
+        }
+        let moved = match &self.cli.move_source_to {
+            Some(move_to) => {
+                get_moved_source_path(move_to, &input.get_cli().video_root, &input.path).and_then(
+                    |destination| move_file(&input.path, &destination).map(|_| destination),
+                )
+            }
+            None => move_to_trash(&input.path),
+        };
+        match moved {
+            Ok(destination) => {
+                _info!(
+                    input,
+                    "Moved the source {:?} to {destination:?}",
+                    input.path
+                );
+                let destination = std::path::absolute(&destination).unwrap_or(destination);
+                if let Err(err) = self.record_history_source(input, &output_path, &destination) {
+                    _warn!(
+                        input,
+                        "Could not record the moved source in the history file: {err}"
+                    );
+                }
+            }
+            Err(err) => warning_tx
+                .send((
+                    input.path.to_owned(),
+                    format!("Could not move the source away, so it was kept: {err:#}"),
+                ))
+                .unwrap(),
+        }
+    }
+}
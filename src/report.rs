@@ -0,0 +1,668 @@
+//! The report of a run: the result of each file, and the summary of warnings and failures.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+#[allow(unused_imports)]
+use log::*;
+use regex::Regex;
+
+use crate::{format_cpu_time, format_size, ResourceUsage, SyncResult};
+
+/// How many directory levels the report rolls results up by: a show, then a season.
+const ROLLUP_DEPTH: usize = 2;
+
+/// Kinds with more warnings than this are summarized as one line with a count.
+const COALESCE_OVER: usize = 3;
+
+/// A common reason for a failure, which the summary can suggest a fix for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureCause {
+    /// A stream could not be mapped into the output, or the container can't hold it.
+    Mapping,
+    /// ffmpeg lacks an encoder or filter that the options need.
+    MissingEncoder,
+    DiskFull,
+    PermissionDenied,
+}
+
+impl FailureCause {
+    /// Detect the cause from an error message, which includes the end of ffmpeg's log for
+    /// failed encodes.
+    pub fn detect(msg: &str) -> Option<Self> {
+        let patterns: [(Self, &[&str]); 4] = [
+            (Self::DiskFull, &["No space left on device", "os error 28", "Disk quota exceeded", "os error 112"]),
+            (Self::PermissionDenied, &["Permission denied", "os error 13", "Access is denied", "os error 5)"]),
+            (
+                Self::MissingEncoder,
+                &["Unknown encoder", "Encoder not found", "No such filter", "Unrecognized option", "Filter not found"],
+            ),
+            (
+                Self::Mapping,
+                &[
+                    "matches no streams",
+                    "Invalid stream specifier",
+                    "Could not find tag for codec",
+                    "not currently supported in container",
+                    "Subtitle encoding currently only possible from text to text or bitmap to bitmap",
+                ],
+            ),
+        ];
+        patterns
+            .iter()
+            .find(|(_, needles)| needles.iter().any(|needle| msg.contains(needle)))
+            .map(|(cause, _)| *cause)
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::Mapping => "Streams could not be mapped into the output",
+            Self::MissingEncoder => "ffmpeg is missing an encoder or filter",
+            Self::DiskFull => "The disk is full",
+            Self::PermissionDenied => "Permission was denied",
+        }
+    }
+
+    /// A one line suggestion for fixing the problem.
+    pub fn suggestion(&self) -> &'static str {
+        match self {
+            Self::Mapping => "Try --no-map-0, or --sub-handling drop for mp4 outputs",
+            Self::MissingEncoder => {
+                "Use an ffmpeg build with the needed encoders, for example by setting FFMPEG"
+            }
+            Self::DiskFull => "Free some space, or use --output-dir to write to another disk",
+            Self::PermissionDenied => {
+                "Make sure the videos are readable and the output directory is writable"
+            }
+        }
+    }
+}
+
+/// Warnings of the same kind, like "The output file already exists", from different files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarningGroup {
+    pub kind: String,
+    /// The cause, if the warnings are failures with a known cause. They are grouped by it.
+    pub cause: Option<FailureCause>,
+    pub warnings: Vec<(PathBuf, String)>,
+}
+
+/// The warnings and failures of a run, grouped by kind in the order they first happened.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WarningSummary {
+    pub groups: Vec<WarningGroup>,
+}
+
+impl WarningSummary {
+    pub fn new<I>(warnings: I) -> Self
+    where
+        I: IntoIterator<Item = (PathBuf, String)>,
+    {
+        let mut groups: Vec<WarningGroup> = Vec::new();
+        for (path, msg) in warnings {
+            let cause = FailureCause::detect(&msg);
+            let kind = match cause {
+                Some(cause) => cause.title().to_string(),
+                None => warning_kind(&msg),
+            };
+            match groups.iter_mut().find(|group| group.kind == kind) {
+                Some(group) => group.warnings.push((path, msg)),
+                None => groups.push(WarningGroup {
+                    kind,
+                    cause,
+                    warnings: vec![(path, msg)],
+                }),
+            }
+        }
+        Self { groups }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// The lines to show at the end of the run. A kind with many warnings gets one line, like
+    /// "The output file already exists (237 files, list with -v)". Failures with a known cause
+    /// get a line with the suggested fix.
+    pub fn summary_lines(&self) -> Vec<String> {
+        self.summary_items(Some("list with -v"))
+            .into_iter()
+            .map(|(line, _)| line)
+            .collect()
+    }
+
+    /// The summary lines for the report. A kind with many warnings gets one line, like "The output
+    /// file already exists (237 files)", with its files to list under it.
+    pub fn report_items(&self) -> Vec<(String, Vec<String>)> {
+        self.summary_items(None)
+    }
+
+    /// The summary lines, each with the files of a coalesced kind. `list_hint` says where else
+    /// those files are listed.
+    fn summary_items(&self, list_hint: Option<&str>) -> Vec<(String, Vec<String>)> {
+        let file_line =
+            |(path, msg): &(PathBuf, String)| format!("{}: {}", path.to_string_lossy(), msg);
+        let mut items = Vec::new();
+        for group in &self.groups {
+            if let Some(cause) = group.cause {
+                items.push((
+                    format!("{}. {}.", cause.title(), cause.suggestion()),
+                    Vec::new(),
+                ));
+            }
+            if group.warnings.len() > COALESCE_OVER {
+                let count = match list_hint {
+                    Some(hint) => format!("{} files, {hint}", group.warnings.len()),
+                    None => format!("{} files", group.warnings.len()),
+                };
+                let files = group.warnings.iter().map(file_line).collect();
+                items.push((format!("{} ({count})", group.kind), files));
+            } else {
+                items.extend(
+                    group
+                        .warnings
+                        .iter()
+                        .map(|warning| (file_line(warning), Vec::new())),
+                );
+            }
+        }
+        items
+    }
+
+    /// Log the summary as warnings, and the files of each coalesced kind at debug level. With
+    /// `in_report`, the summary says the files are listed in the report instead.
+    pub fn log(&self, in_report: bool) {
+        if self.is_empty() {
+            return;
+        }
+        warn!("Failure and warning summary:");
+        let list_hint = if in_report {
+            "list in report"
+        } else {
+            "list with -v"
+        };
+        for (line, _) in self.summary_items(Some(list_hint)) {
+            warn!("{line}");
+        }
+        for group in self
+            .groups
+            .iter()
+            .filter(|group| group.warnings.len() > COALESCE_OVER)
+        {
+            debug!("{}:", group.kind);
+            for (path, msg) in &group.warnings {
+                debug!("    {}: {}", path.to_string_lossy(), msg);
+            }
+        }
+    }
+}
+
+/// Get the kind of a warning, so similar ones can be counted together: its first line, without
+/// the quoted paths, and with "…" in place of numbers.
+pub fn warning_kind(msg: &str) -> String {
+    let quoted_re = Regex::new(r#":?\s*"(?:[^"\\]|\\.)*""#).unwrap();
+    let number_re = Regex::new(r"\d+(?:\.\d+)?").unwrap();
+    let first_line = msg.lines().next().unwrap_or_default();
+    let kind = quoted_re.replace_all(first_line, "");
+    let kind = number_re.replace_all(&kind, "…");
+    kind.trim_end_matches([':', ' ']).to_string()
+}
+
+/// The formats the report can be printed in.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Md,
+    Html,
+}
+
+/// How an input file turned out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileOutcome {
+    Encoded,
+    /// The file wasn't encoded, for example because the output exists.
+    Skipped(String),
+    /// The output was deleted after encoding, for example because it was too large.
+    Deleted(String),
+    Failed(String),
+}
+
+impl FileOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileOutcome::Encoded => "Encoded",
+            FileOutcome::Skipped(_) => "Skipped",
+            FileOutcome::Deleted(_) => "Deleted",
+            FileOutcome::Failed(_) => "Failed",
+        }
+    }
+}
+
+/// The result of one input file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileResult {
+    /// The input path, relative to the video root if possible.
+    pub input: PathBuf,
+    pub input_size: u64,
+    /// The size of the output, if it was kept.
+    pub output_size: Option<u64>,
+    pub crf: u8,
+    pub outcome: FileOutcome,
+    /// What ffmpeg used to encode the file, with `--resource-stats`.
+    pub usage: Option<ResourceUsage>,
+    /// Set if the encode failed with `--hwdecode`, so the file was tried again with the video
+    /// decoded in software.
+    pub software_fallback: bool,
+}
+
+impl FileResult {
+    /// The percentage of the input size saved by encoding, which is negative if the output grew.
+    pub fn saved_percent(&self) -> Option<f64> {
+        let output_size = self.output_size?;
+        (self.input_size > 0).then(|| 100.0 - output_size as f64 * 100.0 / self.input_size as f64)
+    }
+}
+
+/// How `--sample-fraction` chose the files of a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleInfo {
+    pub fraction: f64,
+    /// How many files were chosen.
+    pub sampled: usize,
+    /// How many files matched, and their total size.
+    pub matched: usize,
+    pub matched_size: u64,
+}
+
+/// Everything that happened in a run, which can be printed as Markdown or HTML.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunReport {
+    pub files: Vec<FileResult>,
+    pub warnings: WarningSummary,
+    /// Set if only a sample of the matched files was encoded.
+    pub sample: Option<SampleInfo>,
+    /// The copies made for `--sync-to`.
+    pub syncs: Vec<SyncResult>,
+    /// Set if the run was cancelled before all the files were done.
+    pub cancelled: bool,
+    /// How many files `--strict-probe` skipped because ffprobe couldn't read them.
+    pub probe_skipped: usize,
+    /// How many files ffprobe couldn't read, so their CRF was chosen without knowing their
+    /// dimensions or video stream.
+    pub probe_fallbacks: usize,
+}
+
+impl RunReport {
+    /// Count the files with each outcome, in the order: encoded, skipped, deleted, failed.
+    pub fn counts(&self) -> [usize; 4] {
+        let mut counts = [0; 4];
+        for file in &self.files {
+            let i = match file.outcome {
+                FileOutcome::Encoded => 0,
+                FileOutcome::Skipped(_) => 1,
+                FileOutcome::Deleted(_) => 2,
+                FileOutcome::Failed(_) => 3,
+            };
+            counts[i] += 1;
+        }
+        counts
+    }
+
+    /// The total size of the inputs and outputs of the encoded files.
+    pub fn encoded_sizes(&self) -> (u64, u64) {
+        self.files
+            .iter()
+            .filter_map(|file| Some((file.input_size, file.output_size?)))
+            .fold((0, 0), |(inputs, outputs), (input, output)| {
+                (inputs + input, outputs + output)
+            })
+    }
+
+    /// The percentage of the input size saved over all the encoded files.
+    pub fn saved_percent(&self) -> Option<f64> {
+        let (input_size, output_size) = self.encoded_sizes();
+        (input_size > 0).then(|| 100.0 - output_size as f64 * 100.0 / input_size as f64)
+    }
+
+    /// Group the results by top-level directory (such as a show) and by second-level directory
+    /// (such as a season). The groups are sorted by path, so each show comes before its seasons.
+    /// Files directly in the video root aren't in any group.
+    pub fn directory_rollup(&self) -> Vec<(PathBuf, RunReport)> {
+        let mut groups = BTreeMap::<PathBuf, RunReport>::new();
+        for file in &self.files {
+            let dirs = file
+                .input
+                .parent()
+                .map(|dir| dir.components().collect::<Vec<_>>())
+                .unwrap_or_default();
+            for depth in 1..=dirs.len().min(ROLLUP_DEPTH) {
+                groups
+                    .entry(dirs[..depth].iter().collect())
+                    .or_default()
+                    .files
+                    .push(file.clone());
+            }
+        }
+        groups.into_iter().collect()
+    }
+
+    fn totals_line(&self) -> String {
+        let [encoded, skipped, deleted, failed] = self.counts();
+        let (input_size, output_size) = self.encoded_sizes();
+        let mut line =
+            format!("{encoded} encoded, {skipped} skipped, {deleted} deleted, {failed} failed.");
+        if let Some(saved) = self.saved_percent() {
+            line += &format!(
+                " {} to {} ({saved:.0}% saved).",
+                format_size(input_size),
+                format_size(output_size)
+            );
+        }
+        line
+    }
+
+    /// A note that the run was only a sample, with the savings projected onto all the matched files.
+    pub fn sample_line(&self) -> Option<String> {
+        let sample = self.sample.as_ref()?;
+        let mut line = format!(
+            "This was a random sample of {} of the {} matched files ({:.0}%).",
+            sample.sampled,
+            sample.matched,
+            sample.fraction * 100.0
+        );
+        if let Some(saved) = self.saved_percent() {
+            let projected = (sample.matched_size as f64 * (100.0 - saved) / 100.0) as u64;
+            line += &format!(
+                " At the same rate, all of them ({}) would be encoded to about {}.",
+                format_size(sample.matched_size),
+                format_size(projected)
+            );
+        }
+        Some(line)
+    }
+
+    /// How many of the files were copied for `--sync-to`, if any were tried.
+    pub fn sync_line(&self) -> Option<String> {
+        if self.syncs.is_empty() {
+            return None;
+        }
+        let synced = self
+            .syncs
+            .iter()
+            .filter(|sync| sync.error.is_none())
+            .count();
+        Some(format!(
+            "{synced} of {} files were synced.",
+            self.syncs.len()
+        ))
+    }
+
+    /// How many files were tried again with software decoding, if any were.
+    pub fn fallback_line(&self) -> Option<String> {
+        let fallbacks = self
+            .files
+            .iter()
+            .filter(|file| file.software_fallback)
+            .count();
+        (fallbacks > 0).then(|| {
+            format!("{fallbacks} files failed with hardware decoding, and were tried again with software decoding.")
+        })
+    }
+
+    /// How many files couldn't be probed, if any couldn't.
+    pub fn probe_line(&self) -> Option<String> {
+        match (self.probe_skipped, self.probe_fallbacks) {
+            (0, 0) => None,
+            (skipped, 0) => Some(format!("{skipped} files could not be probed, and were skipped.")),
+            (0, fallbacks) => Some(format!(
+                "{fallbacks} files could not be probed, so their CRF was chosen without knowing their dimensions."
+            )),
+            (skipped, fallbacks) => Some(format!(
+                "{} files could not be probed: {skipped} were skipped, and the CRF of {fallbacks} was chosen without knowing their dimensions.",
+                skipped + fallbacks
+            )),
+        }
+    }
+
+    /// The cells of the rows in the resource usage table: each file with `--resource-stats`,
+    /// its CPU time, and its peak memory.
+    fn usage_rows(&self) -> Vec<[String; 3]> {
+        self.files
+            .iter()
+            .filter_map(|file| {
+                let usage = file.usage?;
+                Some([
+                    file.input.to_string_lossy().into_owned(),
+                    format_cpu_time(usage.cpu_seconds),
+                    format_size(usage.peak_memory),
+                ])
+            })
+            .collect()
+    }
+
+    /// The total CPU time of the files with `--resource-stats`, if any.
+    pub fn usage_line(&self) -> Option<String> {
+        let usages = self
+            .files
+            .iter()
+            .filter_map(|file| file.usage)
+            .collect::<Vec<_>>();
+        if usages.is_empty() {
+            return None;
+        }
+        let cpu_seconds = usages.iter().map(|usage| usage.cpu_seconds).sum::<f64>();
+        Some(format!(
+            "ffmpeg used {} of CPU time for {} files.",
+            format_cpu_time(cpu_seconds),
+            usages.len()
+        ))
+    }
+
+    /// The cells of a row in the by-directory table. Only the outcomes that happened are counted.
+    fn group_cells(dir: &Path, group: &RunReport) -> [String; 5] {
+        let counts = group
+            .counts()
+            .into_iter()
+            .zip(["encoded", "skipped", "deleted", "failed"])
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{count} {name}"))
+            .collect::<Vec<_>>();
+        let (input_size, output_size) = group.encoded_sizes();
+        let saved = group.saved_percent();
+        [
+            dir.to_string_lossy().into_owned(),
+            counts.join(", "),
+            format_size(input_size),
+            saved.map(|_| format_size(output_size)).unwrap_or_default(),
+            saved
+                .map(|saved| format!("{saved:.0}%"))
+                .unwrap_or_default(),
+        ]
+    }
+
+    fn cells(file: &FileResult) -> [String; 5] {
+        [
+            file.input.to_string_lossy().into_owned(),
+            match &file.outcome {
+                FileOutcome::Encoded if file.software_fallback => {
+                    format!("Encoded at CRF {}, decoded in software", file.crf)
+                }
+                FileOutcome::Encoded => format!("Encoded at CRF {}", file.crf),
+                outcome @ (FileOutcome::Skipped(reason)
+                | FileOutcome::Deleted(reason)
+                | FileOutcome::Failed(reason)) => {
+                    format!(
+                        "{}: {}",
+                        outcome.name(),
+                        reason.lines().next().unwrap_or_default()
+                    )
+                }
+            },
+            format_size(file.input_size),
+            file.output_size.map(format_size).unwrap_or_default(),
+            file.saved_percent()
+                .map(|saved| format!("{saved:.0}%"))
+                .unwrap_or_default(),
+        ]
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let escape = |cell: &str| cell.replace('|', "\\|");
+        let mut md = String::from("# jiffy report\n\n");
+        if let Some(line) = self.sample_line() {
+            writeln!(md, "**Sample:** {line}\n").unwrap();
+        }
+        md += "| File | Result | Original | Encoded | Saved |\n";
+        md += "|---|---|---:|---:|---:|\n";
+        for file in &self.files {
+            let cells = Self::cells(file).map(|cell| escape(&cell));
+            writeln!(md, "| {} |", cells.join(" | ")).unwrap();
+        }
+        let rollup = self.directory_rollup();
+        if !rollup.is_empty() {
+            md += "\n## By directory\n\n";
+            md += "| Directory | Results | Original | Encoded | Saved |\n";
+            md += "|---|---|---:|---:|---:|\n";
+            for (dir, group) in &rollup {
+                let cells = Self::group_cells(dir, group).map(|cell| escape(&cell));
+                writeln!(md, "| {} |", cells.join(" | ")).unwrap();
+            }
+        }
+        let usage_rows = self.usage_rows();
+        if !usage_rows.is_empty() {
+            md += "\n## Resource usage\n\n";
+            md += "| File | CPU time | Peak memory |\n";
+            md += "|---|---:|---:|\n";
+            for cells in usage_rows {
+                writeln!(md, "| {} |", cells.map(|cell| escape(&cell)).join(" | ")).unwrap();
+            }
+        }
+        writeln!(md, "\n**Total:** {}", self.totals_line()).unwrap();
+        if let Some(line) = self.usage_line() {
+            writeln!(md, "\n**Resources:** {line}").unwrap();
+        }
+        if let Some(line) = self.sync_line() {
+            writeln!(md, "\n**Sync:** {line}").unwrap();
+        }
+        if let Some(line) = self.fallback_line() {
+            writeln!(md, "\n**Fallback:** {line}").unwrap();
+        }
+        if let Some(line) = self.probe_line() {
+            writeln!(md, "\n**Probe:** {line}").unwrap();
+        }
+        if !self.warnings.is_empty() {
+            md += "\n## Warnings\n\n";
+            for (line, files) in self.warnings.report_items() {
+                writeln!(md, "- {}", escape(&line)).unwrap();
+                for file in files {
+                    writeln!(md, "  - {}", escape(&file)).unwrap();
+                }
+            }
+        }
+        md
+    }
+
+    /// Get the report as a standalone HTML page. The space saved is drawn as a bar in each row.
+    pub fn to_html(&self) -> String {
+        let escape = |cell: &str| {
+            cell.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        };
+        let write_row = |html: &mut String, cells: [String; 5], saved: Option<f64>| {
+            *html += "<tr>";
+            for cell in cells {
+                write!(html, "<td>{}</td>", escape(&cell)).unwrap();
+            }
+            let width = saved.unwrap_or_default().clamp(0.0, 100.0);
+            writeln!(
+                html,
+                "<td><div class=\"bar\" style=\"width: {width:.0}px\"></div></td></tr>"
+            )
+            .unwrap();
+        };
+        let mut html = String::from(concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>jiffy report</title>\n",
+            "<style>table { border-collapse: collapse; } td, th { padding: 2px 8px; text-align: left; }\n",
+            ".bar { background: #4a4; height: 1em; }</style>\n</head>\n<body>\n<h1>jiffy report</h1>\n",
+        ));
+        if let Some(line) = self.sample_line() {
+            writeln!(html, "<p><b>Sample:</b> {}</p>", escape(&line)).unwrap();
+        }
+        html += "<table>\n<tr><th>File</th><th>Result</th><th>Original</th><th>Encoded</th><th>Saved</th><th></th></tr>\n";
+        for file in &self.files {
+            write_row(&mut html, Self::cells(file), file.saved_percent());
+        }
+        html += "</table>\n";
+        let rollup = self.directory_rollup();
+        if !rollup.is_empty() {
+            html += "<h2>By directory</h2>\n<table>\n";
+            html += "<tr><th>Directory</th><th>Results</th><th>Original</th><th>Encoded</th><th>Saved</th><th></th></tr>\n";
+            for (dir, group) in &rollup {
+                write_row(
+                    &mut html,
+                    Self::group_cells(dir, group),
+                    group.saved_percent(),
+                );
+            }
+            html += "</table>\n";
+        }
+        let usage_rows = self.usage_rows();
+        if !usage_rows.is_empty() {
+            html += "<h2>Resource usage</h2>\n<table>\n<tr><th>File</th><th>CPU time</th><th>Peak memory</th></tr>\n";
+            for cells in usage_rows {
+                html += "<tr>";
+                for cell in cells {
+                    write!(html, "<td>{}</td>", escape(&cell)).unwrap();
+                }
+                html += "</tr>\n";
+            }
+            html += "</table>\n";
+        }
+        writeln!(html, "<p><b>Total:</b> {}</p>", escape(&self.totals_line())).unwrap();
+        if let Some(line) = self.usage_line() {
+            writeln!(html, "<p><b>Resources:</b> {}</p>", escape(&line)).unwrap();
+        }
+        if let Some(line) = self.sync_line() {
+            writeln!(html, "<p><b>Sync:</b> {}</p>", escape(&line)).unwrap();
+        }
+        if let Some(line) = self.fallback_line() {
+            writeln!(html, "<p><b>Fallback:</b> {}</p>", escape(&line)).unwrap();
+        }
+        if let Some(line) = self.probe_line() {
+            writeln!(html, "<p><b>Probe:</b> {}</p>", escape(&line)).unwrap();
+        }
+        if !self.warnings.is_empty() {
+            html += "<h2>Warnings</h2>\n<ul>\n";
+            for (line, files) in self.warnings.report_items() {
+                if files.is_empty() {
+                    writeln!(html, "<li>{}</li>", escape(&line)).unwrap();
+                    continue;
+                }
+                writeln!(
+                    html,
+                    "<li><details><summary>{}</summary><ul>",
+                    escape(&line)
+                )
+                .unwrap();
+                for file in files {
+                    writeln!(html, "<li>{}</li>", escape(&file)).unwrap();
+                }
+                html += "</ul></details></li>\n";
+            }
+            html += "</ul>\n";
+        }
+        html += "</body>\n</html>\n";
+        html
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Md => self.to_markdown(),
+            ReportFormat::Html => self.to_html(),
+        }
+    }
+}
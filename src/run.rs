@@ -0,0 +1,63 @@
+//! The entry point for a whole run, for the command line and for programs that embed jiffy.
+
+use anyhow::anyhow;
+#[allow(unused_imports)]
+use log::*;
+use tokio_util::sync::CancellationToken;
+
+use crate::{get_output_dir, Cli, Encoder, JiffyResult, RunReport, Subcommands};
+
+/// Do everything the command line would for these options, and return what happened. The
+/// report isn't printed, even with `--report-format`, so the caller can show it as it likes.
+/// Pruning, listing, the audio report and `--diff` return an empty report. The `slot` subcommand isn't
+/// supported, since it runs another command instead; use `Encoder::run_in_global_slot` for that.
+pub async fn run(cli: Cli) -> JiffyResult<RunReport> {
+    run_until_cancelled(cli, CancellationToken::new()).await
+}
+
+/// Warn if the video root looks like an output directory, which is usually a mistake. Returns
+/// whether it warned, so the command line can give the user a moment to stop the run.
+pub fn warn_if_output_dir(cli: &Cli) -> bool {
+    let Ok(video_root) = cli.video_root.canonicalize() else {
+        return false;
+    };
+    let is_output_dir = video_root
+        .components()
+        .next_back()
+        .expect("Cannot get components of encode path")
+        .as_os_str()
+        == get_output_dir(cli);
+    if is_output_dir {
+        warn!(
+            "The video directory is named {:?}. Did you mean to encode the parent directory?",
+            cli.output_dir
+        );
+    }
+    is_output_dir
+}
+
+/// Like `run`, but when `cancel` is cancelled, no more encodes are started and the running ones
+/// are stopped. The report of a cancelled run is still returned, with `cancelled` set.
+pub async fn run_until_cancelled(cli: Cli, cancel: CancellationToken) -> JiffyResult<RunReport> {
+    // With --input-url, the videos are fetched into the video root:
+    for video_root in cli.get_video_roots() {
+        if !video_root.exists() && (cli.input_url.is_none() || video_root != cli.video_root) {
+            return Err(anyhow!("Video root does not exist: {video_root:?}").into());
+        }
+    }
+
+    let diff = cli.diff;
+    let command = cli.command.clone();
+    let encoder = Encoder::new(cli)?;
+    match command {
+        Some(Subcommands::Prune(opts)) => encoder.prune_outputs(opts.trash).await?,
+        Some(Subcommands::List(opts)) => encoder.list_videos(opts.format).await?,
+        Some(Subcommands::AudioReport(opts)) => encoder.audio_report(&opts).await?,
+        Some(Subcommands::Slot(_)) => {
+            return Err(anyhow!("The slot subcommand can't be used in a run").into());
+        }
+        None if diff => encoder.diff_outputs().await?,
+        None => return encoder.run_encodes(cancel).await,
+    }
+    Ok(RunReport::default())
+}
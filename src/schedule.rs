@@ -0,0 +1,400 @@
+//! Waiting for other ffmpeg processes, so the number of running encodes stays within `--jobs`,
+//! or within the `--global-jobs` budget shared with other jiffy instances, and waiting for
+//! `--min-free-space`.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    time::Duration,
+};
+
+#[allow(unused_imports)]
+use log::*;
+use tokio::{select, sync::watch, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+#[allow(unused_imports)]
+use crate::{_debug, _error, _info, _log, _trace, _warn};
+use crate::{format_size, Encoder, InputFile, JiffyError, JiffyResult};
+
+/// How often to check for a free `--global-jobs` slot.
+const SLOT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often `--slow-start` counts the other encodes again.
+const SLOW_START_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often to check the free space again while waiting for `--min-free-space`.
+const FREE_SPACE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(crate) enum EncodingDone {
+    EncodingDone,
+    WaitTaskDone,
+}
+
+pub(crate) struct EncodingErr(pub(crate) PathBuf, pub(crate) String);
+
+impl Encoder {
+    /// With `--slow-start`, hold a job slot until few enough encodes are running elsewhere. Job 0
+    /// waits until at most JOBS-1 other encodes are running, so the total will be right, job 1
+    /// until at most JOBS-2 are, and so on. The wait ends as soon as this run has no encodes
+    /// left to start, since then there's nothing to hold back.
+    pub(crate) async fn wait_for_ffmpeg(
+        &self,
+        job_id: usize,
+        cancel: &CancellationToken,
+    ) -> Result<EncodingDone, EncodingErr> {
+        let mut queue_state = self.queue_state.subscribe();
+        let jobs = self.cli.get_jobs().expect("Jobs should be set by now");
+        let allowed = jobs - 1 - job_id;
+        loop {
+            if queue_state.borrow_and_update().waiting == 0 {
+                trace!("No encodes are left to start, so job {job_id} won't wait anymore");
+                break;
+            }
+            if cancel.is_cancelled() {
+                trace!("The run was cancelled, so job {job_id} won't wait anymore");
+                break;
+            }
+            let others = count_other_encodes();
+            debug!("{others} other encodes are running, and job {job_id} can start with {allowed}");
+            if others <= allowed {
+                break;
+            }
+            select! {
+                _ = sleep(SLOW_START_POLL_INTERVAL) => {}
+                _ = queue_state.changed() => {}
+                _ = cancel.cancelled() => {}
+            }
+        }
+        Ok(EncodingDone::WaitTaskDone)
+    }
+}
+
+/// What this run's queue is doing, for the tasks waiting in `--slow-start`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct QueueState {
+    /// The encodes running now.
+    pub running: usize,
+    /// The encodes not started yet.
+    pub waiting: usize,
+}
+
+/// Sends the queue state to the tasks that wait on it.
+pub(crate) struct QueueWatch(watch::Sender<QueueState>);
+
+impl Default for QueueWatch {
+    fn default() -> Self {
+        Self(watch::Sender::new(QueueState::default()))
+    }
+}
+
+impl QueueWatch {
+    pub(crate) fn publish(&self, state: QueueState) {
+        self.0
+            .send_if_modified(|current| std::mem::replace(current, state) != state);
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<QueueState> {
+        self.0.subscribe()
+    }
+}
+
+/// The directory where `--slow-start` instances share how many encodes they're running.
+pub fn slow_start_dir() -> PathBuf {
+    std::env::temp_dir().join("jiffy-slow-start")
+}
+
+/// This instance's file in the `--slow-start` directory, holding the number of encodes it's
+/// running. Other instances count these, which covers the moments between one of its encodes
+/// ending and the next starting, when there's no ffmpeg process to see. It's removed when this
+/// is dropped.
+#[derive(Debug)]
+pub struct SlowStartCounter {
+    path: PathBuf,
+}
+
+impl SlowStartCounter {
+    pub fn new(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            path: dir.join(std::process::id().to_string()),
+        })
+    }
+
+    pub fn publish(&self, running: usize) -> std::io::Result<()> {
+        std::fs::write(&self.path, running.to_string())
+    }
+}
+
+impl Drop for SlowStartCounter {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Add up the encodes that other `--slow-start` instances are running. The files of instances
+/// that are gone are removed.
+pub fn read_slow_start_counts(dir: &Path, is_alive: impl Fn(u32) -> bool) -> usize {
+    let Ok(entries) = dir.read_dir() else {
+        return 0;
+    };
+    let own_pid = std::process::id();
+    let mut total = 0;
+    for entry in entries.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == own_pid {
+            continue;
+        }
+        if !is_alive(pid) {
+            let _ = std::fs::remove_file(entry.path());
+            continue;
+        }
+        total += std::fs::read_to_string(entry.path())
+            .ok()
+            .and_then(|count| count.trim().parse::<usize>().ok())
+            .unwrap_or_default();
+    }
+    total
+}
+
+/// Count the encodes running outside this process: the ffmpeg processes that aren't its
+/// children, or the encodes other `--slow-start` instances say they're running, if that's more.
+fn count_other_encodes() -> usize {
+    let this_process = std::process::id();
+    let sysinfo = sysinfo::System::new_all();
+    let ffmpegs = sysinfo
+        .processes_by_exact_name("ffmpeg".as_ref())
+        .filter(|process| {
+            let mut process = *process;
+            // Skip the ffmpegs started by this process:
+            while let Some(parent_pid) = process.parent() {
+                if parent_pid.as_u32() == this_process {
+                    return false;
+                }
+                match sysinfo.process(parent_pid) {
+                    Some(parent) => process = parent,
+                    None => break,
+                }
+            }
+            true
+        })
+        .count();
+    let published = read_slow_start_counts(&slow_start_dir(), |pid| {
+        sysinfo.process(sysinfo::Pid::from_u32(pid)).is_some()
+    });
+    ffmpegs.max(published)
+}
+
+/// The tasks waiting to be started, in order. A task can wait for other tasks to finish before
+/// it starts, like an encode that writes the same output as an earlier one. A task may only wait
+/// for tasks queued before it, so there is always a task that can start once the running ones
+/// finish.
+pub struct JobQueue<T> {
+    waiting: VecDeque<(usize, T, Vec<usize>)>,
+    finished: HashSet<usize>,
+}
+
+impl<T> Default for JobQueue<T> {
+    fn default() -> Self {
+        Self {
+            waiting: VecDeque::new(),
+            finished: HashSet::new(),
+        }
+    }
+}
+
+impl<T> JobQueue<T> {
+    /// Queue a task that starts after the tasks in `after` have finished.
+    pub fn push_back(&mut self, id: usize, task: T, after: Vec<usize>) {
+        self.waiting.push_back((id, task, after));
+    }
+
+    /// Queue a task to start before the others.
+    pub fn push_front(&mut self, id: usize, task: T) {
+        self.waiting.push_front((id, task, Vec::new()));
+    }
+
+    /// Take the first task that can start now, if any.
+    pub fn pop_ready(&mut self) -> Option<(usize, T)> {
+        let position = self
+            .waiting
+            .iter()
+            .position(|(_, _, after)| after.iter().all(|id| self.finished.contains(id)))?;
+        let (id, task, _) = self.waiting.remove(position)?;
+        Some((id, task))
+    }
+
+    /// Record that a task finished, so the tasks waiting for it can start.
+    pub fn finish(&mut self, id: usize) {
+        self.finished.insert(id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+/// The directory that holds the `--global-jobs` lock files. Every jiffy instance (and container) that
+/// shares the budget must see the same directory, so set `TMPDIR` the same way for all of them.
+pub fn global_slot_dir() -> PathBuf {
+    std::env::temp_dir().join("jiffy-slots")
+}
+
+/// One of the `--global-jobs` slots. A slot is an exclusive lock on one of the numbered files in the
+/// slot directory, so it's released when this is dropped, or if the process holding it dies.
+#[derive(Debug)]
+pub struct GlobalSlot {
+    _file: File,
+    pub index: usize,
+}
+
+impl GlobalSlot {
+    /// Take a free slot if there is one.
+    pub fn try_acquire(dir: &Path, slots: usize) -> JiffyResult<Option<Self>> {
+        std::fs::create_dir_all(dir)?;
+        for index in 0..slots {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(dir.join(format!("slot-{index}.lock")))?;
+            match file.try_lock() {
+                Ok(()) => return Ok(Some(Self { _file: file, index })),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait until a slot is free and take it.
+    pub async fn acquire(
+        dir: &Path,
+        slots: usize,
+        cancel: &CancellationToken,
+    ) -> JiffyResult<Self> {
+        let mut logged = false;
+        loop {
+            if let Some(slot) = Self::try_acquire(dir, slots)? {
+                trace!("Took global slot {}", slot.index);
+                return Ok(slot);
+            }
+            if cancel.is_cancelled() {
+                return Err(JiffyError::Cancelled);
+            }
+            if !logged {
+                debug!("All {slots} global slots are taken. Waiting for one to be free");
+                logged = true;
+            }
+            sleep_unless_cancelled(SLOT_POLL_INTERVAL, cancel).await;
+        }
+    }
+}
+
+impl Encoder {
+    /// Run a command, such as a manual ffmpeg run, while holding a `--global-jobs` slot. Returns its exit code.
+    pub async fn run_in_global_slot(&self, command: &[String]) -> JiffyResult<i32> {
+        let Some(slots) = self.cli.global_jobs else {
+            return Err(anyhow::anyhow!("The slot command needs --global-jobs").into());
+        };
+        let slots = slots.get();
+        let (program, args) = command.split_first().expect("clap requires a command");
+        let _slot =
+            GlobalSlot::acquire(&global_slot_dir(), slots, &CancellationToken::new()).await?;
+        let status = tokio::process::Command::new(program)
+            .args(args)
+            .status()
+            .await?;
+        Ok(status.code().unwrap_or(1))
+    }
+}
+
+/// The free space on the filesystem holding `path`, given the mount points and their free space.
+/// The path's filesystem is the one with the deepest mount point above it.
+pub fn free_space_at(path: &Path, mounts: &[(PathBuf, u64)]) -> Option<u64> {
+    mounts
+        .iter()
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|&(_, free)| free)
+}
+
+/// The free space on the filesystem that `path` is on, or would be on once it's created. None if
+/// it can't be found.
+pub fn get_free_space(path: &Path) -> Option<u64> {
+    let path = std::path::absolute(path).ok()?;
+    // The output directory may not be created yet:
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())?
+        .canonicalize()
+        .ok()?;
+    let disks = sysinfo::Disks::new_with_refreshed_list();
+    let mounts: Vec<_> = disks
+        .list()
+        .iter()
+        .map(|disk| (disk.mount_point().to_owned(), disk.available_space()))
+        .collect();
+    free_space_at(&existing, &mounts)
+}
+
+impl Encoder {
+    /// With `--min-free-space`, wait until the filesystem the output goes on has that much space
+    /// free, so a full disk doesn't cut encodes short. The wait is warned about once.
+    pub(crate) async fn wait_for_free_space(
+        &self,
+        input: &InputFile,
+        output_path: &Path,
+        warning_tx: &Sender<(PathBuf, String)>,
+        cancel: &CancellationToken,
+    ) -> JiffyResult<()> {
+        let Some(min_free_space) = self.cli.min_free_space else {
+            return Ok(());
+        };
+        let mut warned = false;
+        loop {
+            let Some(free_space) = get_free_space(output_path) else {
+                _debug!(
+                    input,
+                    "Could not find the free space for {output_path:?}, so not waiting for it"
+                );
+                return Ok(());
+            };
+            if free_space >= min_free_space {
+                return Ok(());
+            }
+            if cancel.is_cancelled() {
+                return Err(JiffyError::Cancelled);
+            }
+            if !warned {
+                let msg = format!(
+                    "Only {} is free for the output, less than --min-free-space {}. Waiting for more space",
+                    format_size(free_space),
+                    format_size(min_free_space)
+                );
+                _warn!(input, "{:?}: {}", input.path, msg);
+                warning_tx.send((input.path.to_owned(), msg)).unwrap();
+                warned = true;
+            }
+            sleep_unless_cancelled(FREE_SPACE_POLL_INTERVAL, cancel).await;
+        }
+    }
+}
+
+/// Sleep, but wake up early if the run is cancelled.
+pub(crate) async fn sleep_unless_cancelled(duration: Duration, cancel: &CancellationToken) {
+    select! {
+        _ = sleep(duration) => {}
+        _ = cancel.cancelled() => {}
+    }
+}
@@ -0,0 +1,190 @@
+use std::{collections::BTreeSet, ffi::OsString};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use crate::StreamInfo;
+
+/// A stream that will be copied or encoded into the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedStream {
+    /// The index of the stream in the input file.
+    pub input_index: u32,
+    pub codec_type: String,
+    /// The disposition flags to set on the output stream.
+    pub dispositions: BTreeSet<String>,
+}
+
+/// The plan for which input streams go into the output, in what order, and with which flags.
+///
+/// The output streams keep the relative order of the input streams and their disposition flags,
+/// so the output is the same from run to run no matter which streams are dropped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamMap {
+    pub streams: Vec<MappedStream>,
+}
+
+impl StreamMap {
+    /// Plan the output streams, keeping those that `keep` returns true for.
+    pub fn plan<F>(streams: &[StreamInfo], keep: F) -> Self
+    where
+        F: Fn(&StreamInfo) -> bool,
+    {
+        let mut sorted: Vec<_> = streams.iter().collect();
+        sorted.sort_by_key(|stream| stream.index);
+
+        let mut mapped: Vec<_> = sorted
+            .iter()
+            .filter(|stream| keep(stream))
+            .map(|stream| MappedStream {
+                input_index: stream.index,
+                codec_type: stream.codec_type.clone(),
+                dispositions: stream.dispositions.clone(),
+            })
+            .collect();
+
+        // If the default stream of a type was dropped, the first kept stream of that type
+        // becomes the default, so players don't pick an arbitrary one:
+        let had_default: BTreeSet<_> = sorted
+            .iter()
+            .filter(|stream| stream.has_disposition("default"))
+            .map(|stream| stream.codec_type.as_str())
+            .collect();
+        for codec_type in had_default {
+            let kept_default = mapped.iter().any(|stream| {
+                stream.codec_type == codec_type && stream.dispositions.contains("default")
+            });
+            if !kept_default {
+                if let Some(first) = mapped
+                    .iter_mut()
+                    .find(|stream| stream.codec_type == codec_type)
+                {
+                    first.dispositions.insert("default".to_string());
+                }
+            }
+        }
+
+        Self { streams: mapped }
+    }
+
+    /// Get the `-map` and `-disposition` ffmpeg args for this plan.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        for stream in &self.streams {
+            args.push("-map".into());
+            args.push(format!("0:{}", stream.input_index).into());
+        }
+        for (output_index, stream) in self.streams.iter().enumerate() {
+            let dispositions = if stream.dispositions.is_empty() {
+                "0".to_string()
+            } else {
+                stream
+                    .dispositions
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join("+")
+            };
+            args.push(format!("-disposition:{output_index}").into());
+            args.push(dispositions.into());
+        }
+        args
+    }
+}
+
+/// Check whether a stream's language tag is one of `languages`.
+pub fn is_language(stream: &StreamInfo, languages: &[String]) -> bool {
+    stream.language.as_ref().is_some_and(|language| {
+        languages
+            .iter()
+            .any(|wanted| wanted.eq_ignore_ascii_case(language))
+    })
+}
+
+/// Check whether an audio stream's language is one of `languages`. If no audio stream has one of
+/// those languages, every audio stream passes, so the output isn't left without audio.
+pub fn has_wanted_language(
+    stream: &StreamInfo,
+    streams: &[StreamInfo],
+    languages: &[String],
+) -> bool {
+    languages.is_empty()
+        || is_language(stream, languages)
+        || !streams
+            .iter()
+            .any(|stream| stream.codec_type == "audio" && is_language(stream, languages))
+}
+
+/// Check whether an audio stream is a commentary track, by its title or its "comment" disposition.
+pub fn is_commentary(stream: &StreamInfo) -> bool {
+    let title_re = Regex::new(r"(?i)\bcommentary\b|\bdirector").unwrap();
+    stream.has_disposition("comment")
+        || stream
+            .title
+            .as_ref()
+            .is_some_and(|title| title_re.is_match(title))
+}
+
+/// Which subtitle stream to burn into the video, given with `--burn-sub`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubtitleSelector {
+    /// The index of the stream among all streams in the file, as shown by ffprobe.
+    pub index: Option<u32>,
+    pub language: Option<String>,
+    /// Only pick a stream flagged as forced, which usually has just the foreign language lines.
+    pub forced: bool,
+    /// Pick a stream flagged as forced if there is one, or else a full one.
+    pub prefer_forced: bool,
+}
+
+impl SubtitleSelector {
+    /// Parse a selector like "lang=eng", "index=2", "forced" or "lang=eng,forced".
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut selector = Self::default();
+        for part in input.split(',').map(str::trim) {
+            match part.split_once('=') {
+                Some(("lang", language)) if !language.is_empty() => selector.language = Some(language.to_owned()),
+                Some(("index", index)) => {
+                    selector.index = Some(index.parse().with_context(|| format!("Invalid stream index: {index:?}"))?)
+                }
+                None if part == "forced" => selector.forced = true,
+                _ => bail!("Subtitle selector must be lang=<language>, index=<stream index> or forced, not {part:?}"),
+            }
+        }
+        if selector.index.is_some() && (selector.language.is_some() || selector.forced) {
+            bail!("A subtitle stream index can't be combined with other selectors");
+        }
+        Ok(selector)
+    }
+
+    /// The selector for burning subtitles without `--burn-sub`. A forced stream is preferred, since
+    /// it's meant to be shown even to viewers who don't want the full subtitles.
+    pub fn automatic() -> Self {
+        Self {
+            prefer_forced: true,
+            ..Default::default()
+        }
+    }
+
+    /// Pick the subtitle stream to burn in. Unless a forced stream was asked for, a full subtitle
+    /// stream is preferred over a forced one, since forced subtitles only cover part of the dialog.
+    pub fn select<'a>(&self, streams: &'a [StreamInfo]) -> Option<&'a StreamInfo> {
+        let mut candidates = streams.iter().filter(|stream| {
+            stream.codec_type == "subtitle"
+                && self.index.is_none_or(|index| stream.index == index)
+                && self
+                    .language
+                    .as_ref()
+                    .is_none_or(|language| is_language(stream, std::slice::from_ref(language)))
+        });
+        if self.forced {
+            return candidates.find(|stream| stream.has_disposition("forced"));
+        }
+        let candidates: Vec<_> = candidates.collect();
+        candidates
+            .iter()
+            .find(|stream| stream.has_disposition("forced") == self.prefer_forced)
+            .or(candidates.first())
+            .copied()
+    }
+}
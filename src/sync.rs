@@ -0,0 +1,158 @@
+//! Copying finished outputs to other storage with rclone or rsync, for `--sync-to`. Only
+//! finished outputs are copied, never the partial files being written.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+#[allow(unused_imports)]
+use log::*;
+use tokio::sync::Semaphore;
+
+#[allow(unused_imports)]
+use crate::{_debug, _error, _info, _log, _trace, _warn};
+use crate::{get_output_dir, Encoder, InputFile};
+
+/// The program that copies outputs for `--sync-to`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncTool {
+    #[default]
+    Rclone,
+    Rsync,
+}
+
+impl SyncTool {
+    pub fn program(&self) -> &'static str {
+        match self {
+            SyncTool::Rclone => "rclone",
+            SyncTool::Rsync => "rsync",
+        }
+    }
+
+    /// Get the arguments to copy a file to the target, at the same path within the target as it
+    /// has within the output directory.
+    pub fn get_args(&self, target: &str, output_dir: &Path, file: &Path) -> Result<Vec<OsString>> {
+        let relative_path = pathdiff::diff_paths(file, output_dir)
+            .context("Could not get the output path within the output directory")?;
+        Ok(match self {
+            SyncTool::Rclone => {
+                let relative_path = relative_path
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                // "remote:" is the root of the remote, and needs no separator:
+                let separator = if target.ends_with([':', '/']) {
+                    ""
+                } else {
+                    "/"
+                };
+                os_args![
+                    "copyto",
+                    file,
+                    format!("{target}{separator}{relative_path}")
+                ]
+            }
+            SyncTool::Rsync => {
+                // With --relative, the part of the path after "/./" is recreated in the target:
+                os_args![
+                    "--archive",
+                    "--partial",
+                    "--relative",
+                    output_dir.join(".").join(relative_path),
+                    target
+                ]
+            }
+        })
+    }
+}
+
+/// How copying one file for `--sync-to` turned out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncResult {
+    pub file: PathBuf,
+    /// Why the copy failed, if it did.
+    pub error: Option<String>,
+}
+
+impl Encoder {
+    /// The limit on copies running at once for `--sync-to`, if it was given.
+    pub(crate) fn new_sync_slots(cli: &crate::Cli) -> Option<Arc<Semaphore>> {
+        cli.sync_to
+            .as_ref()
+            .map(|_| Arc::new(Semaphore::new(cli.sync_jobs as usize)))
+    }
+
+    /// Start copying finished files to the `--sync-to` target in the background. The copies are
+    /// waited for at the end of the run.
+    pub(crate) fn start_sync(&self, input: &InputFile, files: Vec<PathBuf>) {
+        let (Some(target), Some(sync_slots)) = (self.cli.sync_to.clone(), self.sync_slots.clone())
+        else {
+            return;
+        };
+        let tool = self.cli.sync_tool;
+        // Each video root has its own output directory, so the paths are relative to the input's:
+        let output_dir = get_output_dir(input.get_cli());
+        _debug!(input, "Queueing {} files to sync to {target}", files.len());
+        let handle = tokio::spawn(async move {
+            let mut results = Vec::new();
+            for file in files {
+                let _slot = sync_slots
+                    .acquire()
+                    .await
+                    .expect("The sync semaphore is never closed");
+                let error = match sync_file(tool, &target, &output_dir, &file).await {
+                    Ok(()) => None,
+                    Err(err) => Some(format!("{err:#}")),
+                };
+                results.push(SyncResult { file, error });
+            }
+            results
+        });
+        self.syncs.lock().unwrap().push(handle);
+    }
+
+    /// Wait for the copies started by `start_sync`. If the run was cancelled, they're stopped
+    /// instead.
+    pub(crate) async fn finish_syncs(&self, cancelled: bool) -> Vec<SyncResult> {
+        let handles = std::mem::take(&mut *self.syncs.lock().unwrap());
+        if !handles.is_empty() && !cancelled {
+            log::info!("Waiting for the outputs to finish syncing");
+        }
+        let mut results = Vec::new();
+        for handle in handles {
+            if cancelled {
+                handle.abort();
+            }
+            if let Ok(handle_results) = handle.await {
+                results.extend(handle_results);
+            }
+        }
+        results
+    }
+}
+
+async fn sync_file(tool: SyncTool, target: &str, output_dir: &Path, file: &Path) -> Result<()> {
+    let args = tool.get_args(target, output_dir, file)?;
+    log::info!("Syncing {file:?} to {target}");
+    let output = tokio::process::Command::new(tool.program())
+        .args(&args)
+        // If the run is cancelled, the copy is stopped too:
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Could not run {}", tool.program()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "{} failed: {}",
+            tool.program(),
+            stderr.lines().last().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Move a file to the user's trash, following the freedesktop.org trash specification. This is
+/// only supported on Linux and similar systems. Returns where the file is in the trash.
+pub fn move_to_trash(path: &Path) -> Result<PathBuf> {
+    if cfg!(any(windows, target_os = "macos")) {
+        bail!("Moving files to the trash is not supported on this platform");
+    }
+
+    let path = path
+        .canonicalize()
+        .context(format!("Could not find the file to trash: {path:?}"))?;
+    let trash_dir = get_trash_dir()?;
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    fs::create_dir_all(&files_dir)?;
+    fs::create_dir_all(&info_dir)?;
+
+    let name = path
+        .file_name()
+        .context("Cannot trash a path without a file name")?
+        .to_string_lossy()
+        .to_string();
+    // Find a name that's not used in the trash yet. Creating the info file claims the name:
+    let mut counter = 1;
+    let (trash_name, mut info_file) = loop {
+        let trash_name = if counter == 1 {
+            name.clone()
+        } else {
+            format!("{name}.{counter}")
+        };
+        let info_path = info_dir.join(format!("{trash_name}.trashinfo"));
+        if !files_dir.join(&trash_name).exists() {
+            if let Ok(file) = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&info_path)
+            {
+                break (trash_name, file);
+            }
+        }
+        counter += 1;
+    };
+
+    let deletion_date = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S");
+    write!(
+        info_file,
+        "[Trash Info]\nPath={}\nDeletionDate={deletion_date}\n",
+        percent_encode_path(&path)
+    )?;
+
+    let trashed_path = files_dir.join(&trash_name);
+    if let Err(err) = fs::rename(&path, &trashed_path) {
+        fs::remove_file(info_dir.join(format!("{trash_name}.trashinfo")))?;
+        bail!("Could not move {path:?} to the trash (is it on a different filesystem?): {err}");
+    }
+    Ok(trashed_path)
+}
+
+fn get_trash_dir() -> Result<PathBuf> {
+    if let Some(data_home) = std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(data_home).join("Trash"));
+    }
+    let home = std::env::var_os("HOME").context("Cannot find the trash: HOME is not set")?;
+    Ok(PathBuf::from(home).join(".local/share/Trash"))
+}
+
+/// Percent-encode a path for a .trashinfo file, leaving the separators.
+fn percent_encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded += &format!("%{byte:02X}"),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,87 @@
+//! Measuring the CPU time and memory each encode's ffmpeg uses, for `--resource-stats`.
+
+use std::time::{Duration, Instant};
+
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// How often the ffmpeg process is measured.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What an encode's ffmpeg used.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub cpu_seconds: f64,
+    /// The largest resident memory seen, in bytes.
+    pub peak_memory: u64,
+}
+
+impl ResourceUsage {
+    /// Combine the usage of two runs of ffmpeg, like a retry at another CRF: their CPU times
+    /// add up, and the peak is the larger one.
+    pub fn add(&mut self, other: ResourceUsage) {
+        self.cpu_seconds += other.cpu_seconds;
+        self.peak_memory = self.peak_memory.max(other.peak_memory);
+    }
+}
+
+/// Measures one process while it runs. The CPU time adds up the CPU use between measurements,
+/// so it's close but not exact.
+pub struct UsageSampler {
+    system: System,
+    pid: Pid,
+    last_sample: Instant,
+    usage: ResourceUsage,
+}
+
+impl UsageSampler {
+    pub fn new(pid: u32) -> Self {
+        let mut sampler = Self {
+            system: System::new(),
+            pid: Pid::from_u32(pid),
+            last_sample: Instant::now(),
+            usage: ResourceUsage::default(),
+        };
+        sampler.refresh();
+        sampler
+    }
+
+    fn refresh(&mut self) {
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[self.pid]),
+            true,
+            ProcessRefreshKind::new().with_cpu().with_memory(),
+        );
+    }
+
+    /// Measure the process, unless it was measured recently.
+    pub fn sample(&mut self) {
+        let elapsed = self.last_sample.elapsed();
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+        self.last_sample = Instant::now();
+        self.refresh();
+        if let Some(process) = self.system.process(self.pid) {
+            // The CPU use is a percentage of one core, averaged since the last refresh:
+            self.usage.cpu_seconds += process.cpu_usage() as f64 / 100.0 * elapsed.as_secs_f64();
+            self.usage.peak_memory = self.usage.peak_memory.max(process.memory());
+        }
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        self.usage
+    }
+}
+
+/// Format a number of seconds like "1h 02m 03s", "4m 05s" or "6s".
+pub fn format_cpu_time(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m {seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
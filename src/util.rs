@@ -0,0 +1,541 @@
+//! Small helpers for parsing arguments and dealing with paths.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::*;
+use regex::Regex;
+
+pub fn parse_size(input: &str) -> Result<u64> {
+    let msg = "Size string must be a number with optional K, M, G suffix";
+    let input = input.to_lowercase();
+    let captures = Regex::new(r"^(\.\d+|\d+(?:\.\d*)?)([bkmgt])?$")?
+        .captures(&input)
+        .context(msg)?;
+    let n = captures
+        .get(1)
+        .context(msg)?
+        .as_str()
+        .parse::<f64>()
+        .context(msg)?;
+    let suffix = captures.get(2).map_or("m", |m| m.as_str());
+    let factor = match suffix {
+        "b" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1u64 << 40,
+        _ => bail!(msg),
+    };
+    Ok((factor as f64 * n) as u64)
+}
+
+/// Parse a bitrate like "128k" or "10M" into bits per second. The suffixes are decimal, as with
+/// ffmpeg. If there is no suffix, it's taken to mean bits per second.
+pub fn parse_bitrate(input: &str) -> Result<u64> {
+    let msg = "Bitrate must be a number with optional k, M, G suffix";
+    let input = input.to_lowercase();
+    let captures = Regex::new(r"^(\.\d+|\d+(?:\.\d*)?)([kmg])?$")?
+        .captures(&input)
+        .context(msg)?;
+    let n = captures
+        .get(1)
+        .context(msg)?
+        .as_str()
+        .parse::<f64>()
+        .context(msg)?;
+    let factor = match captures.get(2).map(|m| m.as_str()) {
+        None => 1,
+        Some("k") => 1_000,
+        Some("m") => 1_000_000,
+        Some("g") => 1_000_000_000,
+        _ => bail!(msg),
+    };
+    Ok((factor as f64 * n) as u64)
+}
+
+/// Format a size in bytes for people, like "1.5 GiB".
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Parse a fraction like "5%" or "0.05". It must be more than 0, and at most 1.
+pub fn parse_fraction(input: &str) -> Result<f64> {
+    let msg = "The fraction must be a percentage like 5% or a number like 0.05, more than 0 and at most 100%";
+    let input = input.trim();
+    let fraction = match input.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().context(msg)? / 100.0,
+        None => input.parse::<f64>().context(msg)?,
+    };
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        bail!(msg);
+    }
+    Ok(fraction)
+}
+
+/// Parse a speed factor like "2x", "1.5" or "0.5x".
+pub fn parse_speed(input: &str) -> Result<f64> {
+    let msg = "Speed must be a number with an optional 'x' suffix, between 0.1 and 100";
+    let speed = input
+        .trim()
+        .trim_end_matches(['x', 'X'])
+        .parse::<f64>()
+        .context(msg)?;
+    if !(0.1..=100.0).contains(&speed) {
+        bail!(msg);
+    }
+    Ok(speed)
+}
+
+/// Parse a time in a video, in seconds, like "90", "1:30", "1:02:03" or "30.5".
+pub fn parse_timestamp(input: &str) -> Result<f64> {
+    let msg = "The time must be in seconds or like [hours:]minutes:seconds, for example 90, 1:30 or 1:02:03.5";
+    let mut seconds = 0.0;
+    let fields: Vec<_> = input.trim().split(':').collect();
+    if fields.len() > 3 {
+        bail!(msg);
+    }
+    for (i, field) in fields.iter().enumerate() {
+        // Only the seconds can have a fraction:
+        let value = if i + 1 == fields.len() {
+            field.parse::<f64>().context(msg)?
+        } else {
+            field.parse::<u32>().context(msg)?.into()
+        };
+        if !value.is_finite() || value < 0.0 || (i > 0 && value >= 60.0) {
+            bail!(msg);
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Ok(seconds)
+}
+
+/// Parse a `--since` time: either a date like "2024-03-01" or "2024-03-01 18:30" in local time,
+/// or a duration before now like "7d", "12h", "30m" or "2w".
+pub fn parse_since(input: &str) -> Result<std::time::SystemTime> {
+    let msg = "The time must be a date like 2024-03-01 or \"2024-03-01 18:30\", or a duration like 7d, 12h, 30m or 2w";
+    let input = input.trim();
+    if let Some(captures) = Regex::new(r"^(\d+)([smhdw])$")?.captures(input) {
+        let n = captures[1].parse::<u64>().context(msg)?;
+        let unit_seconds = match &captures[2] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => 7 * 24 * 60 * 60,
+        };
+        let duration = std::time::Duration::from_secs(n.checked_mul(unit_seconds).context(msg)?);
+        return std::time::SystemTime::now()
+            .checked_sub(duration)
+            .context(msg);
+    }
+
+    let datetime = chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN))
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M"))
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S"))
+        .context(msg)?;
+    let datetime = datetime
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .context(msg)?;
+    Ok(datetime.into())
+}
+
+pub(crate) fn get_mtime(path: &Path) -> Result<std::time::SystemTime> {
+    Ok(path.metadata()?.modified()?)
+}
+
+pub(crate) fn get_file_size(output_fname: &Path) -> Result<u64> {
+    let md = output_fname.metadata()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        return Ok(md.size());
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        return Ok(md.file_size());
+    }
+}
+
+/// Check whether another process has the file open for writing. This is only detectable on Windows and Linux.
+pub(crate) fn is_open_for_writing(path: &Path) -> Result<bool> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_SHARE_READ: u32 = 1;
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        // Opening without write sharing fails if another process already has the file open for writing:
+        return match std::fs::OpenOptions::new()
+            .read(true)
+            .share_mode(FILE_SHARE_READ)
+            .open(path)
+        {
+            Ok(_) => Ok(false),
+            Err(err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(true),
+            Err(err) => Err(err.into()),
+        };
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let path = path.canonicalize()?;
+        // Processes we can't inspect, or that exit during the search, are skipped:
+        for process in std::fs::read_dir("/proc")?.flatten() {
+            let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+                continue;
+            };
+            for fd in fds.flatten() {
+                if std::fs::read_link(fd.path()).is_ok_and(|target| target == path) {
+                    let fdinfo = process.path().join("fdinfo").join(fd.file_name());
+                    if std::fs::read_to_string(fdinfo)
+                        .is_ok_and(|fdinfo| fdinfo_is_writable(&fdinfo))
+                    {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        let _ = path;
+        Ok(false)
+    }
+}
+
+/// Check the open flags in the contents of a Linux `/proc/<pid>/fdinfo/<fd>` file for write access.
+pub fn fdinfo_is_writable(fdinfo: &str) -> bool {
+    const O_ACCMODE: u32 = 0o3;
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| flags & O_ACCMODE != 0)
+}
+
+// From Cargo: https://github.com/rust-lang/cargo/blob/7b7af3077bff8d60b7f124189bc9de227d3063a9/crates/cargo-util/src/paths.rs#L84
+/// Normalize a path, removing things like `.` and `..`.
+///
+/// CAUTION: This does not resolve symlinks
+pub fn normalize_path(path: &Path) -> PathBuf {
+    use std::path::Component;
+
+    let mut components = path.components().peekable();
+    let mut ret = if let Some(c @ Component::Prefix(..)) = components.peek().cloned() {
+        components.next();
+        PathBuf::from(c.as_os_str())
+    } else {
+        PathBuf::new()
+    };
+
+    for component in components {
+        match component {
+            Component::Prefix(..) => unreachable!(),
+            Component::RootDir => {
+                ret.push(component.as_os_str());
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                ret.pop();
+            }
+            Component::Normal(c) => {
+                ret.push(c);
+            }
+        }
+    }
+    ret
+}
+
+/// Check whether two paths are the same file. If they can't both be resolved, the paths match if
+/// one ends with all the components of the other.
+pub fn is_same_file<P>(pattern: P, matchable_path: P) -> bool
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let pattern: &Path = pattern.as_ref();
+    let matchable_path: &Path = matchable_path.as_ref();
+
+    debug!("Comparing paths: {:?} and {:?}", pattern, matchable_path);
+
+    if let Ok(canonical_pattern) = std::fs::canonicalize(pattern) {
+        if let Ok(canonical_path) = std::fs::canonicalize(matchable_path) {
+            debug!(
+                "Paths' canonical forms are equal: {}",
+                canonical_path == canonical_pattern
+            );
+            if canonical_path == canonical_pattern {
+                return true;
+            }
+        } else {
+            trace!("Could not canonicalize: {:?}", matchable_path);
+        }
+    } else {
+        trace!("Could not canonicalize: {:?}", pattern);
+    }
+
+    let pattern_comps = pattern.components();
+    let path_comps = matchable_path.components();
+    if pattern_comps.clone().count() > path_comps.clone().count() {
+        debug!(
+            "Not equal because component counts differ: {:?} and {:?}",
+            pattern, matchable_path
+        );
+        return false;
+    }
+
+    let are_match = pattern_comps
+        .rev()
+        .zip(path_comps.rev())
+        .all(|(a, b)| a == b);
+    debug!(
+        "Files are a match?: {} ({:?} and {:?})",
+        are_match, pattern, matchable_path
+    );
+    return are_match;
+}
+
+/// Hash a file with SHA-256, reading it in pieces so large videos aren't loaded into memory.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let bytes_read = file.read(&mut buf)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Write "<path>.sha256" next to a file, in the format `sha256sum --check` reads.
+pub fn write_checksum_file(path: &Path) -> Result<PathBuf> {
+    let hash = sha256_file(path)?;
+    let file_name = path
+        .file_name()
+        .context("The path has no file name")?
+        .to_string_lossy();
+    let checksum_path = get_checksum_path(path);
+    std::fs::write(&checksum_path, format!("{hash}  {file_name}\n"))?;
+    Ok(checksum_path)
+}
+
+/// The path of the checksum file for this file: "<path>.sha256".
+pub fn get_checksum_path(path: &Path) -> PathBuf {
+    let mut checksum_path = path.as_os_str().to_owned();
+    checksum_path.push(".sha256");
+    PathBuf::from(checksum_path)
+}
+
+/// Read the last lines of an ffmpeg log, where the reason for a failure usually is.
+pub(crate) fn read_log_tail(log_path: &Path) -> String {
+    const TAIL_LINES: usize = 10;
+    let Ok(log) = std::fs::read_to_string(log_path) else {
+        return String::new();
+    };
+    let lines: Vec<_> = log.lines().collect();
+    lines[lines.len().saturating_sub(TAIL_LINES)..].join("\n")
+}
+
+/// The signal that killed a process, if any. Only Unix has signals.
+pub(crate) fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        std::os::unix::process::ExitStatusExt::signal(status)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// Check whether ffmpeg stopped because it was interrupted, for example by Ctrl-C in the
+/// terminal, rather than because the encode failed. ffmpeg catches SIGINT and SIGTERM and exits
+/// with an error after logging "received signal", unless it's killed outright.
+pub fn is_interrupted_exit(exit_code: Option<i32>, signal: Option<i32>, log_tail: &str) -> bool {
+    // SIGHUP, SIGINT and SIGTERM:
+    const STOP_SIGNALS: [i32; 3] = [1, 2, 15];
+    // STATUS_CONTROL_C_EXIT, when a Windows console program is stopped by Ctrl-C:
+    const CONTROL_C_EXIT: i32 = 0xC000013Au32 as i32;
+    signal.is_some_and(|signal| STOP_SIGNALS.contains(&signal))
+        || exit_code == Some(CONTROL_C_EXIT)
+        || (exit_code == Some(255) && log_tail.contains("received signal"))
+}
+
+/// Whether a filesystem tells apart names that differ only in case. macOS and Windows usually
+/// don't, and neither do SMB shares.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseSensitivity {
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseSensitivity {
+    /// Find out how the filesystem of `dir`, or of its nearest existing parent, compares names,
+    /// by making a file and looking for it with another case. If that fails, guess from the OS.
+    pub fn detect(dir: &Path) -> Self {
+        let Some(existing_dir) = dir.ancestors().find(|dir| dir.is_dir()) else {
+            return Self::default_for_os();
+        };
+        match tempfile::Builder::new()
+            .prefix("jiffy-case-test-")
+            .tempfile_in(existing_dir)
+        {
+            Ok(file) => {
+                let name = file
+                    .path()
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_uppercase();
+                if existing_dir.join(name).exists() {
+                    Self::Insensitive
+                } else {
+                    Self::Sensitive
+                }
+            }
+            Err(err) => {
+                log::debug!("Could not check whether {existing_dir:?} is case sensitive: {err}");
+                Self::default_for_os()
+            }
+        }
+    }
+
+    fn default_for_os() -> Self {
+        if cfg!(any(windows, target_os = "macos")) {
+            Self::Insensitive
+        } else {
+            Self::Sensitive
+        }
+    }
+
+    /// A key that is the same for two paths exactly when this filesystem sees them as the same
+    /// file.
+    pub fn path_key(&self, path: &Path) -> String {
+        match self {
+            Self::Sensitive => path.to_string_lossy().into_owned(),
+            Self::Insensitive => path.to_string_lossy().to_lowercase(),
+        }
+    }
+}
+
+/// Find the paths that name the same file as an earlier path on this filesystem, though they're
+/// spelled differently, like "Show/E01.mkv" and "show/e01.mkv" on a case-insensitive one.
+/// Returns the index of each such path with the index of the first path it collides with. Paths
+/// that are exactly the same aren't collisions.
+pub fn find_case_collisions(paths: &[PathBuf], case: CaseSensitivity) -> Vec<(usize, usize)> {
+    let mut first_by_key = std::collections::HashMap::new();
+    let mut collisions = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        let first = *first_by_key.entry(case.path_key(path)).or_insert(i);
+        if paths[first] != *path {
+            collisions.push((i, first));
+        }
+    }
+    collisions
+}
+
+/// Find the groups of paths that name the same file on this filesystem, like "show-crf22.mkv"
+/// twice, or "Show/E01.mkv" and "show/e01.mkv" on a case-insensitive one. Each group has the
+/// indexes of its paths, in order.
+pub fn find_output_collisions(paths: &[PathBuf], case: CaseSensitivity) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_by_key = std::collections::HashMap::new();
+    for (i, path) in paths.iter().enumerate() {
+        let group = *group_by_key.entry(case.path_key(path)).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// The lowercase extension of an input, without the dot, like "avi". It's empty if there is none.
+pub fn get_original_ext(path: &Path) -> String {
+    path.extension().map_or(String::new(), |extension| {
+        extension.to_string_lossy().to_lowercase()
+    })
+}
+
+/// Get the suffixes that tell apart the outputs of inputs that would collide: their extensions,
+/// like "avi", with a number added for inputs that have the same extension. Inputs without an
+/// extension get just a number. The numbers follow the order of the paths, so they're the same
+/// whatever order the inputs are queued in.
+pub fn get_collision_suffixes(inputs: &[&Path]) -> Vec<String> {
+    let extensions: Vec<_> = inputs.iter().map(|input| get_original_ext(input)).collect();
+    inputs
+        .iter()
+        .zip(&extensions)
+        .map(|(input, extension)| {
+            let same_extension: Vec<_> = inputs
+                .iter()
+                .zip(&extensions)
+                .filter(|(_, other)| *other == extension)
+                .map(|(other, _)| other)
+                .collect();
+            if same_extension.len() == 1 && !extension.is_empty() {
+                return extension.clone();
+            }
+            let number = same_extension
+                .iter()
+                .filter(|other| **other < input)
+                .count()
+                + 1;
+            if extension.is_empty() {
+                number.to_string()
+            } else {
+                format!("{extension}-{number}")
+            }
+        })
+        .collect()
+}
+
+/// Identifies a file however it's reached: through a symlink, a hard link, or video roots that
+/// overlap. On Unix, it's the device and inode; elsewhere, the real path.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FileKey {
+    Inode { device: u64, inode: u64 },
+    RealPath(PathBuf),
+}
+
+/// Get the key of the file at this path, following symlinks.
+pub fn get_file_key(path: &Path) -> std::io::Result<FileKey> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let md = path.metadata()?;
+        Ok(FileKey::Inode {
+            device: md.dev(),
+            inode: md.ino(),
+        })
+    }
+    #[cfg(not(unix))]
+    Ok(FileKey::RealPath(path.canonicalize()?))
+}
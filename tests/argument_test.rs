@@ -57,6 +57,41 @@ fn test_crf() {
     assert_eq!(args.crf, Some(26));
 }
 
+#[test]
+fn test_crf_map() {
+    let args = &Cli::parse_from(["prog_name", "--crf-map", "720=20, 2160=26,1080p=22"]);
+    let crf_map = args.crf_map.as_ref().unwrap();
+    assert_eq!(crf_map, &CrfMap(vec![(2160, 26), (1080, 22), (720, 20)]));
+    assert_eq!(crf_map.get_crf(3840, 2160), 26);
+    assert_eq!(crf_map.get_crf(1920, 1080), 22);
+    // Letterboxed and portrait videos:
+    assert_eq!(crf_map.get_crf(1920, 800), 22);
+    assert_eq!(crf_map.get_crf(1080, 1920), 22);
+    assert_eq!(crf_map.get_crf(1280, 720), 20);
+    assert_eq!(crf_map.get_crf(640, 480), 20);
+
+    assert!(Cli::try_parse_from(["prog_name", "--crf-map", "1080"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--crf-map", ""]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--crf-map", "1080=22", "--crf", "20"]).is_err());
+}
+
+#[test]
+fn test_crf_offsets() {
+    let offsets = Cli::parse_from(["prog_name"]).get_crf_offsets();
+    assert_eq!(offsets.get_offset("hevc", 10), -2);
+    assert_eq!(offsets.get_offset("hevc", 8), -1);
+    assert_eq!(offsets.get_offset("h264", 8), 0);
+
+    let args = Cli::parse_from(["prog_name", "--crf-offsets", "h264:10=-1, HEVC=-3"]);
+    let offsets = args.get_crf_offsets();
+    assert_eq!(offsets.get_offset("h264", 10), -1);
+    assert_eq!(offsets.get_offset("h264", 8), 0);
+    assert_eq!(offsets.get_offset("hevc", 10), -3);
+
+    assert!(Cli::try_parse_from(["prog_name", "--crf-offsets", "hevc:ten=-1"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--crf-offsets", "hevc=-1", "--crf", "20"]).is_err());
+}
+
 #[test]
 fn test_preset() -> Result<()> {
     let args = &Cli::parse_from(["prog_name"]);
@@ -108,3 +143,512 @@ fn test_extra_flags() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_speed() -> Result<()> {
+    let args = &Cli::parse_from(["prog_name", "--speed", "2x"]);
+    assert_eq!(args.speed, Some(2.0));
+    assert_eq!(args.get_speed_str(), "2x");
+    let args = &Cli::parse_from(["prog_name", "--speed=1.5"]);
+    assert_eq!(args.speed, Some(1.5));
+
+    assert!(Cli::try_parse_from(["prog_name", "--speed", "0"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--speed", "fast"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--speed", "2x", "--copy-streams"]).is_err());
+
+    assert_eq!(get_atempo_filters(1.5), ["atempo=1.5"]);
+    assert_eq!(
+        get_atempo_filters(8.0),
+        ["atempo=2", "atempo=2", "atempo=2"]
+    );
+    assert_eq!(get_atempo_filters(0.3), ["atempo=0.5", "atempo=0.6"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_maxrate() {
+    let args = &Cli::parse_from(["prog_name"]);
+    assert_eq!(args.maxrate, None);
+    assert_eq!(args.bufsize, None);
+
+    let args = &Cli::parse_from(["prog_name", "--for-tv"]);
+    assert_eq!(args.maxrate, Some(10_000_000));
+    assert_eq!(args.bufsize, None);
+
+    // The buffer follows a lower max rate:
+    let args = &Cli::parse_from(["prog_name", "--for-tv", "--maxrate", "4M"]);
+    assert_eq!(args.maxrate, Some(4_000_000));
+    assert_eq!(args.bufsize, None);
+
+    let args = &Cli::parse_from(["prog_name", "--av1", "--maxrate", "4M"]);
+    assert_eq!(args.maxrate, Some(4_000_000));
+    assert_eq!(args.bufsize, None);
+
+    assert!(Cli::try_parse_from(["prog_name", "--bufsize", "4M"]).is_err());
+}
+
+#[test]
+fn test_tune() -> Result<()> {
+    let args = &Cli::parse_from(["prog_name"]);
+    assert_eq!(args.get_tune()?, None);
+
+    let args = &Cli::parse_from(["prog_name", "--tune", "grain"]);
+    assert_eq!(args.get_tune()?, Some("grain"));
+
+    let args = &Cli::parse_from(["prog_name", "--reference", "--tune", "film"]);
+    assert_eq!(args.get_tune()?, Some("film"));
+
+    let args = &Cli::parse_from(["prog_name", "--tune", "film"]);
+    assert!(args.get_tune().is_err());
+
+    let args = &Cli::parse_from(["prog_name", "--av1", "--tune", "grain"]);
+    assert!(args.get_tune().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_grain_synth() {
+    let args = &Cli::parse_from(["prog_name", "--av1", "--grain-synth", "8"]);
+    assert_eq!(args.grain_synth, Some(8));
+
+    assert!(Cli::try_parse_from(["prog_name", "--grain-synth", "8"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--av1", "--grain-synth", "51"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--av1", "--grain-prefilter"]).is_err());
+}
+
+#[test]
+fn test_max_fps() {
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--max-fps", "30"]).max_fps,
+        Some(30)
+    );
+    assert!(Cli::try_parse_from(["prog_name", "--max-fps", "0"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--max-fps", "30", "--copy-streams"]).is_err());
+}
+
+#[test]
+fn test_denoise() {
+    let args = &Cli::parse_from(["prog_name", "--denoise", "medium"]);
+    assert_eq!(args.denoise, Some(Denoise::Medium));
+    assert_eq!(Denoise::Heavy.filter(), "nlmeans=s=3:p=7:r=9");
+
+    assert!(Cli::try_parse_from(["prog_name", "--denoise", "extreme"]).is_err());
+    assert!(Cli::try_parse_from([
+        "prog_name",
+        "--av1",
+        "--grain-synth",
+        "8",
+        "--grain-prefilter",
+        "--denoise",
+        "light"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_prune_command() {
+    let args = &Cli::parse_from(["prog_name", "prune"]);
+    assert!(matches!(
+        args.command,
+        Some(Subcommands::Prune(PruneOpts { trash: false }))
+    ));
+    assert_eq!(args.video_root, std::path::PathBuf::from("."));
+
+    let args = &Cli::parse_from(["prog_name", "/videos", "prune", "--trash"]);
+    assert!(matches!(
+        args.command,
+        Some(Subcommands::Prune(PruneOpts { trash: true }))
+    ));
+    assert_eq!(args.video_root, std::path::PathBuf::from("/videos"));
+
+    let args = &Cli::parse_from(["prog_name", "/videos"]);
+    assert!(args.command.is_none());
+}
+
+#[test]
+fn test_too_large_retries() {
+    let args = &Cli::parse_from(["prog_name", "--expected-size", "60"]);
+    assert_eq!(args.projected_size_policy, TooLargePolicy::Ignore);
+    assert_eq!(args.crf_retry_step, 2);
+
+    let args = &Cli::parse_from([
+        "prog_name",
+        "--expected-size",
+        "60",
+        "--projected-size-policy",
+        "retry",
+    ]);
+    assert_eq!(args.projected_size_policy, TooLargePolicy::Retry);
+    assert!(Cli::try_parse_from(["prog_name", "--projected-size-policy", "skip"]).is_err());
+
+    let args = &Cli::parse_from([
+        "prog_name",
+        "--expected-size",
+        "60",
+        "--delete-too-large",
+        "--retry-too-large",
+    ]);
+    assert!(args.retry_too_large);
+    assert!(
+        Cli::try_parse_from(["prog_name", "--expected-size", "60", "--retry-too-large"]).is_err()
+    );
+}
+
+#[test]
+fn test_audio_lang() {
+    let args = &Cli::parse_from(["prog_name", "--audio-lang", "eng,jpn"]);
+    assert_eq!(args.audio_lang, ["eng", "jpn"]);
+    let args = &Cli::parse_from(["prog_name"]);
+    assert!(args.audio_lang.is_empty());
+}
+
+#[test]
+fn test_seed() {
+    use rand::Rng;
+
+    let args = &Cli::parse_from(["prog_name", "--seed", "42"]);
+    assert_eq!(args.seed, Some(42));
+    let first: Vec<u32> = (0..4).map(|_| args.get_rng().gen()).collect();
+    let second: Vec<u32> = (0..4).map(|_| args.get_rng().gen()).collect();
+    assert_eq!(first, second);
+    assert!(Cli::try_parse_from(["prog_name", "--seed", "-1"]).is_err());
+}
+
+#[test]
+fn test_audio_bitrate() {
+    assert_eq!(Cli::parse_from(["prog_name"]).get_audio_bitrate(), 128_000);
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--for-tv"]).get_audio_bitrate(),
+        192_000
+    );
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--audio-bitrate", "160k"]).get_audio_bitrate(),
+        160_000
+    );
+    assert!(Cli::try_parse_from(["prog_name", "--audio-bitrate", "loud"]).is_err());
+}
+
+#[test]
+fn test_trim() {
+    let args = &Cli::parse_from(["prog_name", "--start", "0:30", "--end", "25:00"]);
+    let trim = args.get_trim().unwrap();
+    assert_eq!(
+        trim,
+        Trim {
+            start: Some(30.0),
+            end: Some(1500.0)
+        }
+    );
+    assert_eq!(trim.get_input_args(true), ["-ss", "30"]);
+    assert_eq!(trim.get_output_args(true, 1.0), ["-t", "1470"]);
+    assert!(trim.get_input_args(false).is_empty());
+    assert_eq!(
+        trim.get_output_args(false, 1.0),
+        ["-ss", "30", "-t", "1470"]
+    );
+    assert_eq!(trim.output_start(true, 1.0), 0.0);
+    assert_eq!(trim.trimmed_duration(3600.0), 1470.0);
+    assert_eq!(trim.trimmed_duration(600.0), 570.0);
+
+    // With --speed, the output's times are on the sped up timeline, but the input's aren't:
+    let args = &Cli::parse_from([
+        "prog_name",
+        "--start",
+        "0:30",
+        "--end",
+        "25:00",
+        "--speed",
+        "2",
+    ]);
+    let trim = args.get_trim().unwrap();
+    assert_eq!(trim.get_input_args(true), ["-ss", "30"]);
+    assert_eq!(trim.get_output_args(true, 2.0), ["-t", "735"]);
+    assert_eq!(trim.get_output_args(false, 2.0), ["-ss", "15", "-t", "735"]);
+    assert_eq!(trim.output_start(false, 2.0), 15.0);
+
+    let args = &Cli::parse_from(["prog_name", "--start", "1:00", "--duration", "5:00"]);
+    assert_eq!(args.get_trim().unwrap().end, Some(360.0));
+    let args = &Cli::parse_from(["prog_name"]);
+    assert!(!args.get_trim().unwrap().is_trimmed());
+
+    let args = &Cli::parse_from(["prog_name", "--start", "5:00", "--end", "1:00"]);
+    assert!(args.get_trim().is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--end", "5:00", "--duration", "1:00"]).is_err());
+}
+
+#[test]
+fn test_skip_if_codec() {
+    assert!(Cli::parse_from(["prog_name"]).get_skip_codecs().is_empty());
+    let args = &Cli::parse_from(["prog_name", "--skip-if-codec=h265,vp9"]);
+    assert_eq!(args.get_skip_codecs(), ["hevc", "vp9"]);
+    // The list must be given with "=", so the video root isn't taken for a codec:
+    let args = &Cli::parse_from(["prog_name", "--skip-if-codec", "/videos"]);
+    assert_eq!(args.get_skip_codecs(), ["hevc", "av1"]);
+    assert_eq!(args.video_root, std::path::Path::new("/videos"));
+    // Without a list, it depends on the codec being encoded to:
+    let args = &Cli::parse_from(["prog_name", "--skip-if-codec"]);
+    assert_eq!(args.get_skip_codecs(), ["hevc", "av1"]);
+    let args = &Cli::parse_from(["prog_name", "--av1", "--skip-if-codec"]);
+    assert_eq!(args.get_skip_codecs(), ["av1"]);
+    let args = &Cli::parse_from(["prog_name", "--for-tv", "--skip-if-codec"]);
+    assert!(args.get_skip_codecs().is_empty());
+}
+
+#[test]
+fn test_minimum_bitrate() {
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--minimum-bitrate", "1500k"]).minimum_bitrate,
+        Some(1_500_000)
+    );
+    assert_eq!(Cli::parse_from(["prog_name"]).minimum_bitrate, None);
+    assert!(Cli::try_parse_from(["prog_name", "--minimum-bitrate", "low"]).is_err());
+}
+
+#[test]
+fn test_duration_filters() {
+    let args = &Cli::parse_from([
+        "prog_name",
+        "--min-duration",
+        "30",
+        "--max-duration",
+        "10:00",
+    ]);
+    assert_eq!(args.min_duration, Some(30.0));
+    assert_eq!(args.max_duration, Some(600.0));
+    assert!(Cli::try_parse_from(["prog_name", "--min-duration", "short"]).is_err());
+}
+
+#[test]
+fn test_mtime_filters() {
+    let args = &Cli::parse_from([
+        "prog_name",
+        "--newer-than",
+        "2024-03-01",
+        "--older-than",
+        "1h",
+    ]);
+    assert_eq!(args.since, Some(parse_since("2024-03-01").unwrap()));
+    assert!(args.older_than.unwrap() < std::time::SystemTime::now());
+    assert!(Cli::try_parse_from(["prog_name", "--older-than", "soon"]).is_err());
+}
+
+#[test]
+fn test_max_depth() {
+    assert_eq!(Cli::parse_from(["prog_name"]).get_max_depth(), None);
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--max-depth", "2"]).get_max_depth(),
+        Some(2)
+    );
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--no-recurse"]).get_max_depth(),
+        Some(0)
+    );
+    assert!(Cli::try_parse_from(["prog_name", "--no-recurse", "--max-depth", "2"]).is_err());
+}
+
+#[test]
+fn test_follow_symlinks() {
+    assert!(Cli::parse_from(["prog_name"]).get_follow_symlinks());
+    assert!(!Cli::parse_from(["prog_name", "--no-follow-symlinks"]).get_follow_symlinks());
+    // The last one given wins:
+    assert!(
+        Cli::parse_from(["prog_name", "--no-follow-symlinks", "--follow-symlinks"])
+            .get_follow_symlinks()
+    );
+}
+
+#[test]
+fn test_eco() {
+    let args = Cli::parse_from(["prog_name", "--eco"]);
+    assert_eq!(args.preset, "fast");
+    assert!(args.get_slow_start());
+    // --global-jobs limits the jobs instead:
+    assert!(!Cli::parse_from(["prog_name", "--eco", "--global-jobs", "2"]).get_slow_start());
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--eco", "--preset", "medium"]).preset,
+        "medium"
+    );
+    assert_eq!(Cli::parse_from(["prog_name", "--eco", "--av1"]).preset, "5");
+}
+
+#[test]
+fn test_extensions() {
+    let default_re = Cli::parse_from(["prog_name"])
+        .get_video_extensions_regex()
+        .unwrap();
+    assert!(default_re.is_match("mkv"));
+    assert!(!default_re.is_match("ts"));
+    // The default list used to match any extension containing one of these:
+    assert!(!default_re.is_match("mp4x"));
+    assert!(!default_re.is_match("xflv"));
+
+    let re = Cli::parse_from(["prog_name", "--extensions", "ts,.M2TS"])
+        .get_video_extensions_regex()
+        .unwrap();
+    assert!(re.is_match("ts"));
+    assert!(re.is_match("m2ts"));
+    assert!(!re.is_match("mkv"));
+}
+
+#[test]
+fn test_mezzanine() {
+    let args = Cli::parse_from(["prog_name", "--mezzanine", "prores"]);
+    assert_eq!(args.get_video_codec(), Codec::ProRes);
+    assert!(!args.get_video_codec().uses_crf());
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--mezzanine", "dnxhr"]).get_video_codec(),
+        Codec::Dnxhr
+    );
+    assert_eq!(
+        Cli::parse_from(["prog_name", "--mezzanine", "dnxhr"]).get_default_naming_format(),
+        "{basename}-dnxhr"
+    );
+    assert!(Cli::try_parse_from(["prog_name", "--mezzanine", "prores", "--av1"]).is_err());
+    assert!(Cli::try_parse_from(["prog_name", "--mezzanine", "prores", "--crf", "20"]).is_err());
+}
+
+#[test]
+fn test_lossless() {
+    let args = Cli::parse_from(["prog_name", "--lossless"]);
+    assert_eq!(args.get_video_codec(), Codec::H265);
+    assert!(args.keeps_source_picture());
+    assert!(args.test_opts.copy_audio);
+    assert_eq!(args.get_default_naming_format(), "{basename}-lossless");
+    assert!(lossless_size_warning(&args, 1_000_000).is_some());
+    assert!(Cli::try_parse_from(["prog_name", "--lossless", "--crf", "20"]).is_err());
+
+    let args = Cli::parse_from(["prog_name", "--ffv1"]);
+    assert!(args.lossless);
+    assert_eq!(args.get_video_codec(), Codec::Ffv1);
+    assert_eq!(args.get_default_naming_format(), "{basename}-ffv1");
+
+    let args = Cli::parse_from(["prog_name", "--near-lossless"]);
+    assert_eq!(args.get_video_codec(), Codec::H265);
+    assert!(args.keeps_source_picture());
+    assert!(!args.test_opts.copy_audio);
+    assert!(Cli::try_parse_from(["prog_name", "--near-lossless", "--lossless"]).is_err());
+    // Filters that change the picture would defeat the point of a lossless archive:
+    for mode in ["--lossless", "--ffv1", "--near-lossless"] {
+        assert!(Cli::try_parse_from(["prog_name", mode, "--autocrop"]).is_err());
+        assert!(Cli::try_parse_from(["prog_name", mode, "--speed", "2"]).is_err());
+        assert!(Cli::try_parse_from(["prog_name", mode, "--denoise", "light"]).is_err());
+    }
+    assert!(lossless_size_warning(&Cli::parse_from(["prog_name"]), 1_000_000).is_none());
+}
+
+#[test]
+fn test_list_subcommand() {
+    let args = Cli::parse_from([
+        "prog_name",
+        "--minimum-size",
+        "100M",
+        "/videos",
+        "list",
+        "--format",
+        "csv",
+    ]);
+    assert!(matches!(
+        args.command,
+        Some(Subcommands::List(ListOpts {
+            format: ListFormat::Csv
+        }))
+    ));
+    assert!(matches!(
+        Cli::parse_from(["prog_name", "list"]).command,
+        Some(Subcommands::List(ListOpts {
+            format: ListFormat::Paths
+        }))
+    ));
+}
+
+#[test]
+fn test_audio_copy_threshold() {
+    let threshold = |args: &[&str]| AudioCopyPolicy::new(&Cli::parse_from(args)).threshold;
+    assert_eq!(
+        threshold(&["prog_name"]),
+        AudioCopyThreshold::Total(200_000)
+    );
+    assert_eq!(
+        threshold(&["prog_name", "--audio-bitrate", "256k"]),
+        AudioCopyThreshold::Total(400_000)
+    );
+    assert_eq!(
+        threshold(&["prog_name", "--audio-copy-threshold", "96k/ch"]),
+        AudioCopyThreshold::PerChannel(96_000)
+    );
+    let args = Cli::parse_from([
+        "prog_name",
+        "--audio-copy-threshold",
+        "320k",
+        "audio-report",
+        "--no-loudness",
+    ]);
+    assert_eq!(
+        AudioCopyPolicy::new(&args).threshold,
+        AudioCopyThreshold::Total(320_000)
+    );
+    assert!(matches!(
+        args.command,
+        Some(Subcommands::AudioReport(AudioReportOpts {
+            no_loudness: true
+        }))
+    ));
+}
+
+#[test]
+fn test_print_config() {
+    use clap::CommandFactory;
+    let matches = Cli::command().get_matches_from([
+        "prog_name",
+        "--crf",
+        "22",
+        "-vv",
+        "--print-config",
+        "videos",
+    ]);
+    let config = get_effective_config(&matches);
+    let entry = |name: &str| {
+        config
+            .iter()
+            .find(|entry| entry.name == name)
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(entry("--crf").value.as_deref(), Some("22"));
+    assert_eq!(entry("--crf").source, ConfigSource::Flag);
+    assert_eq!(entry("--verbose").value.as_deref(), Some("2"));
+    assert_eq!(entry("--verbose").source, ConfigSource::Flag);
+    assert_eq!(entry("--preset").source, ConfigSource::Default);
+    assert_eq!(entry("--preset").value.as_deref(), Some("slow"));
+    assert!(config
+        .iter()
+        .all(|entry| entry.name != "--print-config" && entry.name != "--help"));
+
+    let rendered = render_config(&config);
+    assert!(rendered
+        .lines()
+        .any(|line| line.starts_with("--crf ") && line.ends_with("(flag)")));
+
+    // The defaults worked out when encoding are shown, and the subcommand's options:
+    let matches =
+        Cli::command().get_matches_from(["prog_name", "--eco", "list", "--format", "json"]);
+    let config = get_effective_config(&matches);
+    let entry = |name: &str| {
+        config
+            .iter()
+            .find(|entry| entry.name == name)
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(entry("--jobs").value.as_deref(), Some("1"));
+    assert_eq!(entry("--jobs").source, ConfigSource::Default);
+    assert_eq!(entry("--audio-bitrate").value.as_deref(), Some("128000"));
+    assert_eq!(
+        entry("--crf").value.as_deref(),
+        Some("(chosen for each video)")
+    );
+    assert_eq!(entry("list --format").value.as_deref(), Some("json"));
+    assert_eq!(entry("list --format").source, ConfigSource::Flag);
+}
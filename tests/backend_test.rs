@@ -0,0 +1,59 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use jiffy::*;
+
+#[test]
+fn test_output_url() {
+    let url = OutputUrl::parse("s3://media/videos/encoded/").unwrap();
+    assert_eq!(url.bucket, "media");
+    assert_eq!(url.prefix, "videos/encoded");
+    assert_eq!(
+        url.object_key(Path::new("Show/S01/E01-crf22.mkv")),
+        "videos/encoded/Show/S01/E01-crf22.mkv"
+    );
+
+    let url = OutputUrl::parse("s3://media").unwrap();
+    assert_eq!(url.prefix, "");
+    assert_eq!(url.object_key(Path::new("E01-crf22.mkv")), "E01-crf22.mkv");
+
+    assert!(OutputUrl::parse("s3:///videos").is_err());
+    assert!(OutputUrl::parse("gs://media/videos").is_err());
+    assert!(Cli::try_parse_from([
+        "prog_name",
+        "--output-url",
+        "s3://media",
+        "--sync-to",
+        "remote:"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_input_url() {
+    let url = InputUrl::parse("s3://camera/uploads/").unwrap();
+    assert_eq!(
+        url,
+        InputUrl::S3 {
+            bucket: "camera".into(),
+            prefix: "uploads".into()
+        }
+    );
+    assert_eq!(
+        url.local_path("uploads/2024/clip.mp4"),
+        Some(PathBuf::from("2024/clip.mp4"))
+    );
+    assert_eq!(url.local_path("uploads/encoded/clip-crf22.mp4"), None);
+    assert_eq!(url.local_path("uploads/../clip.mp4"), None);
+    assert_eq!(url.local_path("other/clip.mp4"), None);
+
+    let url = InputUrl::parse("https://example.com/dav/uploads/").unwrap();
+    assert_eq!(
+        url,
+        InputUrl::WebDav("https://example.com/dav/uploads".into())
+    );
+    assert_eq!(url.local_path("clip.mp4"), Some(PathBuf::from("clip.mp4")));
+
+    assert!(InputUrl::parse("sftp://host/uploads").is_err());
+    assert!(InputUrl::parse("uploads").is_err());
+}
@@ -0,0 +1,64 @@
+use jiffy::*;
+
+#[test]
+fn test_parse_auto_chapters() {
+    assert_eq!(AutoChapters::parse("scene").unwrap(), AutoChapters::Scene);
+    assert_eq!(
+        AutoChapters::parse("10").unwrap(),
+        AutoChapters::Interval(10.0)
+    );
+    assert_eq!(
+        AutoChapters::parse("2.5").unwrap(),
+        AutoChapters::Interval(2.5)
+    );
+    assert!(AutoChapters::parse("0").is_err());
+    assert!(AutoChapters::parse("-5").is_err());
+    assert!(AutoChapters::parse("scenes").is_err());
+}
+
+#[test]
+fn test_interval_chapters() {
+    assert_eq!(
+        interval_chapters(25.0 * 60.0, 10.0),
+        vec![0.0, 600.0, 1200.0]
+    );
+    // A video that ends at a chapter boundary doesn't get an empty chapter:
+    assert_eq!(interval_chapters(20.0 * 60.0, 10.0), vec![0.0, 600.0]);
+    // A short video still gets one chapter:
+    assert_eq!(interval_chapters(30.0, 10.0), vec![0.0]);
+}
+
+#[test]
+fn test_scene_chapters() {
+    let output = "\
+[Parsed_showinfo_2 @ 0x55d0] n:   0 pts:  12800 pts_time:50      duration:    512 fmt:yuv420p
+[Parsed_showinfo_2 @ 0x55d0] n:   1 pts: 102400 pts_time:400     duration:    512 fmt:yuv420p
+[Parsed_showinfo_2 @ 0x55d0] n:   2 pts: 115200 pts_time:450.5   duration:    512 fmt:yuv420p
+frame= 2 fps=0.0 q=-0.0 size=N/A time=00:07:30.50 bitrate=N/A speed= 900x
+[Parsed_showinfo_2 @ 0x55d0] n:   3 pts: 179200 pts_time:700     duration:    512 fmt:yuv420p
+[Parsed_showinfo_2 @ 0x55d0] n:   4 pts: 230400 pts_time:900     duration:    512 fmt:yuv420p
+";
+    let scene_times = parse_scene_times(output);
+    assert_eq!(scene_times, vec![50.0, 400.0, 450.5, 700.0, 900.0]);
+    // Cuts too soon after a chapter start or too near the end are skipped:
+    assert_eq!(scene_chapters(&scene_times, 960.0), vec![0.0, 400.0, 700.0]);
+}
+
+#[test]
+fn test_ffmetadata() {
+    assert_eq!(
+        to_ffmetadata(&[0.0, 600.0], 750.25),
+        ";FFMETADATA1
+[CHAPTER]
+TIMEBASE=1/1000
+START=0
+END=600000
+title=Chapter 1
+[CHAPTER]
+TIMEBASE=1/1000
+START=600000
+END=750250
+title=Chapter 2
+"
+    );
+}
@@ -0,0 +1,43 @@
+use clap::Parser;
+use jiffy::*;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn test_cancelled_run() {
+    let output_dir = tempfile::tempdir().unwrap();
+    let cli = Cli::parse_from([
+        "prog_name",
+        "--noop",
+        "--no-log",
+        "--output-dir",
+        output_dir.path().to_str().unwrap(),
+        "test",
+    ]);
+    let encoder = Encoder::new(cli).unwrap();
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    assert!(matches!(
+        encoder.encode_videos(cancel).await,
+        Err(JiffyError::Cancelled)
+    ));
+}
+
+#[tokio::test]
+async fn test_run_returns_report() {
+    let output_dir = tempfile::tempdir().unwrap();
+    let cli = Cli::parse_from([
+        "prog_name",
+        "--noop",
+        "--no-log",
+        "--output-dir",
+        output_dir.path().to_str().unwrap(),
+        "test",
+    ]);
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let report = run_until_cancelled(cli, cancel).await.unwrap();
+    assert!(report.cancelled);
+
+    let cli = Cli::parse_from(["prog_name", "--no-log", "does-not-exist"]);
+    assert!(run(cli).await.is_err());
+}
@@ -1,5 +1,7 @@
+use chrono::TimeZone;
 use clap::Parser;
 use jiffy::*;
+use regex::Regex;
 use std::{
     path::{Path, PathBuf},
     sync::Arc,
@@ -205,16 +207,22 @@ async fn test_output_fname() {
 }
 
 #[test]
-#[should_panic(expected = "Could not build glob pattern")]
 fn test_include_bad_glob() {
-    Encoder::get_matcher_from_globs(".", &vec!["a -!｜：([]).mp4".to_string()], true);
+    let err =
+        Encoder::get_matcher_from_globs(".", &vec!["a -!｜：([]).mp4".to_string()], true, false)
+            .unwrap_err();
+    assert!(
+        err.to_string().contains("Could not build glob pattern"),
+        "{err}"
+    );
 }
 
 #[test]
 fn test_include_bad_glob_okay_if_exists() {
     let path = "b -!｜：([]).mp4".to_string();
     std::fs::File::create(&path).expect("Could not create test file");
-    let matcher = Encoder::get_matcher_from_globs(".", &vec![path.clone()], true)
+    let matcher = Encoder::get_matcher_from_globs(".", &vec![path.clone()], true, false)
+        .unwrap()
         .expect("Could not create matcher");
 
     // make sure it is matched:
@@ -227,9 +235,554 @@ fn test_include_bad_glob_okay_if_exists2() {
     let path = r"test/special characters: ()[]'.mp4".to_string();
     assert!(PathBuf::from(&path).exists(), "Expected preexisting test file was not found: {path}");
 
-    let matcher = Encoder::get_matcher_from_globs(".", &vec![path.clone()], true)
+    let matcher = Encoder::get_matcher_from_globs(".", &vec![path.clone()], true, false)
+        .unwrap()
         .expect("Could not create matcher");
 
     // make sure it is matched:
     assert!(Encoder::default().is_match(&matcher, &path));
 }
+
+#[tokio::test]
+async fn test_speed_and_text_template() {
+    let args = Arc::new(Cli::parse_from([
+        "prog_name",
+        "--speed",
+        "2x",
+        "--no-log",
+        "a/b",
+    ]));
+    let input = InputFile::new(Path::new("a/b/vid.en.MP4"), args)
+        .await
+        .unwrap();
+    assert_paths_eq!(
+        input.get_output_path(None).unwrap(),
+        "a/b/encoded/vid.en-crf22-2x.mp4"
+    );
+    assert_eq!(
+        input
+            .fill_text_template("{basename} crf{crf} {speed}")
+            .unwrap(),
+        "vid.en crf22 2x"
+    );
+}
+
+#[test]
+fn test_editions() {
+    let (title, edition) = split_edition("Blade Runner (1982) Director's Cut");
+    assert_eq!(title, "bladerunner1982");
+    assert_eq!(edition.as_deref(), Some("Director's Cut"));
+    assert_eq!(
+        split_edition("Blade Runner (1982) {edition-Final Cut}").0,
+        "bladerunner1982"
+    );
+    assert_eq!(
+        split_edition("Aliens.1986.Special.Edition.1080p")
+            .1
+            .as_deref(),
+        Some("Special.Edition")
+    );
+    assert_eq!(split_edition("Movie").1, None);
+
+    let paths: Vec<PathBuf> = [
+        "/m/Blade Runner (1982).mkv",
+        "/m/Blade Runner (1982) Director's Cut.mkv",
+        "/m/Blade Runner (1982) {edition-Final Cut}.mkv",
+        "/m/Aliens (1986).mkv",
+        "/m/other/Blade Runner (1982).mkv",
+        "/tv/e01.mkv",
+        "/tv/e01.mp4",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .collect();
+    assert_eq!(find_editions(&paths), [vec![0, 1, 2]]);
+}
+
+#[test]
+fn test_choose_sample() {
+    use rand::SeedableRng;
+    let items: Vec<u32> = (0..40).collect();
+    let sample = choose_sample(
+        items.clone(),
+        0.1,
+        &mut rand::rngs::StdRng::seed_from_u64(7),
+    );
+    assert_eq!(sample.len(), 4);
+    assert!(
+        sample.windows(2).all(|pair| pair[0] < pair[1]),
+        "{sample:?}"
+    );
+    // The same seed chooses the same sample:
+    assert_eq!(
+        sample,
+        choose_sample(
+            items.clone(),
+            0.1,
+            &mut rand::rngs::StdRng::seed_from_u64(7)
+        )
+    );
+
+    assert_eq!(
+        choose_sample(
+            items.clone(),
+            0.001,
+            &mut rand::rngs::StdRng::seed_from_u64(7)
+        )
+        .len(),
+        1
+    );
+    assert_eq!(
+        choose_sample(
+            items.clone(),
+            1.0,
+            &mut rand::rngs::StdRng::seed_from_u64(7)
+        ),
+        items
+    );
+    assert!(choose_sample(
+        Vec::<u32>::new(),
+        0.5,
+        &mut rand::rngs::StdRng::seed_from_u64(7)
+    )
+    .is_empty());
+}
+
+#[test]
+fn test_naming_format_to_regex() {
+    let re = naming_format_to_regex("{basename}-{preset}-crf{crf}").unwrap();
+    let captures = re.captures("season 1/vid.en-5-crf24").unwrap();
+    assert_eq!(&captures["basename"], "season 1/vid.en");
+    assert!(re.captures("vid.en-crf24").is_none());
+
+    let re = naming_format_to_regex("{basename}-{original_ext}-crf{crf}").unwrap();
+    assert_eq!(
+        &re.captures("a/show-avi-crf22").unwrap()["basename"],
+        "a/show"
+    );
+    let re = naming_format_to_regex("{basename} (crf{crf}) {speed}").unwrap();
+    assert_eq!(&re.captures("vid (crf22) 1.5x").unwrap()["basename"], "vid");
+}
+
+#[test]
+fn test_log_name() {
+    let now = chrono::Local
+        .with_ymd_and_hms(2024, 3, 9, 14, 5, 0)
+        .unwrap();
+    let relpath = Path::new("season 1/vid.mkv");
+    assert_eq!(
+        InputFile::fill_log_template(DEFAULT_LOG_NAME, relpath, &now),
+        Path::new("season 1/vid.mkv.log")
+    );
+    assert_eq!(
+        InputFile::fill_log_template("logs/{date}/{basename}.{time}.log", relpath, &now),
+        Path::new("logs/2024-03-09/season 1/vid.140500.log")
+    );
+
+    let re = log_name_to_regex(DEFAULT_LOG_NAME).unwrap();
+    assert_eq!(
+        &re.captures("season 1/vid.mkv.log").unwrap()["relpath"],
+        "season 1/vid.mkv"
+    );
+    let re = log_name_to_regex("logs/{date}/{basename}.{time}.log").unwrap();
+    let captures = re
+        .captures("logs/2024-03-09/season 1/vid.140500.log")
+        .unwrap();
+    assert_eq!(&captures["basename"], "season 1/vid");
+    assert!(captures.name("relpath").is_none());
+    assert!(re.captures("logs/today/season 1/vid.140500.log").is_none());
+}
+
+#[tokio::test]
+async fn test_log_is_named_when_encode_starts() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = dir.path().to_str().unwrap();
+    let args = Arc::new(Cli::parse_from([
+        "prog_name",
+        "--crf",
+        "22",
+        "--log-name",
+        "{basename}.{time}.log",
+        video_root,
+    ]));
+    let mut input = InputFile::new(&dir.path().join("vid.mkv"), args)
+        .await
+        .unwrap();
+    let found_log = input.log_path.clone().unwrap();
+    std::fs::create_dir_all(found_log.parent().unwrap()).unwrap();
+    std::fs::write(&found_log, "found\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    input.start_log().unwrap();
+    // The log is named by the start of the encode, and has what was logged before it:
+    let encode_log = input.log_path.clone().unwrap();
+    assert_ne!(encode_log, found_log);
+    assert!(!found_log.exists());
+    assert_eq!(std::fs::read_to_string(&encode_log).unwrap(), "found\n");
+}
+
+#[test]
+fn test_external_subtitle_names() {
+    let video = Path::new("/a/Movie.mkv");
+    let subtitle = |name: &str| ExternalSubtitle::from_name(video, &Path::new("/a").join(name));
+
+    let plain = subtitle("movie.srt").unwrap();
+    assert_eq!(plain.language, None);
+    assert!(plain.dispositions.is_empty());
+    assert_eq!(
+        subtitle("Movie.en.srt").unwrap().language.as_deref(),
+        Some("eng")
+    );
+    assert_eq!(
+        subtitle("Movie.jpn.ass").unwrap().language.as_deref(),
+        Some("jpn")
+    );
+    let forced = subtitle("Movie.en.forced.srt").unwrap();
+    assert_eq!(forced.language.as_deref(), Some("eng"));
+    assert_eq!(forced.dispositions, ["forced"]);
+    assert_eq!(
+        subtitle("Movie.sdh.srt").unwrap().dispositions,
+        ["hearing_impaired"]
+    );
+
+    assert_eq!(subtitle("Movie 2.srt"), None);
+    assert_eq!(subtitle("Movie.en.sub"), None);
+    assert_eq!(subtitle("Movie.mkv"), None);
+}
+
+#[test]
+fn test_sub_dir_subtitle_names() {
+    let video = Path::new("/a/Show.S01E02.720p.mkv");
+    let subtitle = |name: &str, only_video| {
+        ExternalSubtitle::from_sub_dir(video, &Path::new("/a/Subs").join(name), only_video)
+    };
+
+    let loose = subtitle("show s01e02 720p English.srt", false).unwrap();
+    assert_eq!(loose.language.as_deref(), Some("eng"));
+    assert_eq!(
+        subtitle("Show.S01E02.720p.fr.forced.ass", false)
+            .unwrap()
+            .dispositions,
+        ["forced"]
+    );
+    assert_eq!(subtitle("Show.S01E03.720p.en.srt", false), None);
+    assert_eq!(
+        subtitle("Show_S01E02_720p_en.srt", false)
+            .unwrap()
+            .language
+            .as_deref(),
+        Some("eng")
+    );
+    // The video's name must be followed by another word, not more of the same one:
+    assert_eq!(subtitle("Show.S01E02.720pX.en.srt", false), None);
+    assert_eq!(subtitle("Old Show.S01E02.720p.en.srt", false), None);
+    // Unknown words aren't taken as languages:
+    assert_eq!(
+        subtitle("Show S01E02 720p WEB.srt", false)
+            .unwrap()
+            .language,
+        None
+    );
+
+    // In a directory for just this video, any subtitle name matches:
+    let numbered = subtitle("Show.S01E02.720p/2_English.srt", true).unwrap();
+    assert_eq!(numbered.language.as_deref(), Some("eng"));
+    assert_eq!(
+        subtitle("3_Japanese.srt", true)
+            .unwrap()
+            .language
+            .as_deref(),
+        Some("jpn")
+    );
+    assert_eq!(subtitle("3_Japanese.srt", false), None);
+    assert_eq!(subtitle("cover.jpg", true), None);
+}
+
+#[test]
+fn test_external_subtitle_args() {
+    let subtitles = [
+        ExternalSubtitle {
+            path: "/a/movie.en.srt".into(),
+            language: Some("eng".to_string()),
+            dispositions: vec![],
+        },
+        ExternalSubtitle {
+            path: "/a/movie.srt".into(),
+            language: None,
+            dispositions: vec!["forced".to_string()],
+        },
+    ];
+    let args: Vec<_> = get_external_subtitle_args(&subtitles, 3)
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(
+        args,
+        [
+            "-map",
+            "1:0",
+            "-metadata:s:3",
+            "language=eng",
+            "-disposition:3",
+            "0",
+            "-map",
+            "2:0",
+            "-disposition:4",
+            "forced",
+        ]
+    );
+}
+
+#[test]
+fn test_parse_file_list() {
+    let list = "./Show/S01E01.mkv\r\n\n./Show/S01E02.mkv\n  \n./Show/S01E01.mkv\n/videos/with space .mp4\n";
+    assert_eq!(
+        parse_file_list(list),
+        [
+            PathBuf::from("./Show/S01E01.mkv"),
+            PathBuf::from("./Show/S01E02.mkv"),
+            PathBuf::from("/videos/with space .mp4"),
+        ]
+    );
+}
+
+#[test]
+fn test_order_paths() {
+    use rand::SeedableRng;
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<PathBuf> = [("a.mkv", 30), ("b.mkv", 10), ("c.mkv", 20)]
+        .iter()
+        .map(|(name, size)| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, vec![0; *size]).unwrap();
+            path
+        })
+        .collect();
+    let names = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+
+    let mut ordered = paths.clone();
+    order_paths(&mut ordered, QueueOrder::Natural, &mut rng);
+    assert_eq!(names(&ordered), ["a.mkv", "b.mkv", "c.mkv"]);
+    order_paths(&mut ordered, QueueOrder::SizeDesc, &mut rng);
+    assert_eq!(names(&ordered), ["a.mkv", "c.mkv", "b.mkv"]);
+    order_paths(&mut ordered, QueueOrder::SizeAsc, &mut rng);
+    assert_eq!(names(&ordered), ["b.mkv", "c.mkv", "a.mkv"]);
+
+    // The same seed gives the same order:
+    let mut first: Vec<_> = (0..20).map(|i| PathBuf::from(format!("{i}.mkv"))).collect();
+    let mut second = first.clone();
+    order_paths(
+        &mut first,
+        QueueOrder::Random,
+        &mut rand::rngs::StdRng::seed_from_u64(7),
+    );
+    order_paths(
+        &mut second,
+        QueueOrder::Random,
+        &mut rand::rngs::StdRng::seed_from_u64(7),
+    );
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_regex_matches_path() {
+    let regexes = vec![Regex::new("E0[5-9]|E1[0-2]").unwrap()];
+    assert!(regex_matches_path(
+        &regexes,
+        Path::new("Show/Show S01E07.mkv")
+    ));
+    assert!(regex_matches_path(
+        &regexes,
+        Path::new("Show/Show S01E12.mkv")
+    ));
+    assert!(!regex_matches_path(
+        &regexes,
+        Path::new("Show/Show S01E13.mkv")
+    ));
+    assert!(!regex_matches_path(&[], Path::new("Show/Show S01E07.mkv")));
+    // The separators are always "/":
+    let regexes = vec![Regex::new("^Show/").unwrap()];
+    assert!(regex_matches_path(
+        &regexes,
+        &Path::new("Show").join("Show S01E01.mkv")
+    ));
+}
+
+#[test]
+fn test_ignore_case_globs() {
+    let globs = vec!["**/*.mkv".to_string()];
+    let matcher = Encoder::get_matcher_from_globs(".", &globs, true, false)
+        .unwrap()
+        .unwrap();
+    assert!(Encoder::default().is_match(&matcher, "Show/episode.mkv"));
+    assert!(!Encoder::default().is_match(&matcher, "Show/EPISODE.MKV"));
+    let matcher = Encoder::get_matcher_from_globs(".", &globs, true, true)
+        .unwrap()
+        .unwrap();
+    assert!(Encoder::default().is_match(&matcher, "Show/EPISODE.MKV"));
+}
+
+#[test]
+fn test_pattern_files() {
+    assert_eq!(
+        parse_pattern_file("# Extras\n**/*Sample*\r\n\n  # indented comment\n*S01*/*E01*\n"),
+        ["**/*Sample*", "*S01*/*E01*"]
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let list = dir.path().join("excludes.txt");
+    std::fs::write(&list, "**/*Sample*\n**/*Trailer*\n").unwrap();
+    let mut cli = Cli::parse_from([
+        "prog_name",
+        "--exclude",
+        "*.avi",
+        "--exclude-from",
+        list.to_str().unwrap(),
+    ]);
+    cli.load_pattern_files().unwrap();
+    assert_eq!(cli.exclude, ["*.avi", "**/*Sample*", "**/*Trailer*"]);
+
+    let mut cli = Cli::parse_from([
+        "prog_name",
+        "--include-from",
+        dir.path().join("missing").to_str().unwrap(),
+    ]);
+    assert!(cli.load_pattern_files().is_err());
+
+    // A typo in a glob is reported with its line, instead of failing later:
+    std::fs::write(&list, "# Extras\n**/*Sample*\n[Season 1\n").unwrap();
+    let mut cli = Cli::parse_from(["prog_name", "--exclude-from", list.to_str().unwrap()]);
+    let err = cli.load_pattern_files().unwrap_err().to_string();
+    assert!(err.contains("line 3 of"), "{err}");
+}
+
+#[tokio::test]
+async fn test_more_video_roots() {
+    let args = Cli::parse_from(["prog_name", "--x265", "--no-log", "/a", "/mnt/b"]);
+    assert_eq!(
+        args.get_video_roots(),
+        [PathBuf::from("/a"), PathBuf::from("/mnt/b")]
+    );
+    // Each root's outputs go in its own output directory:
+    let root_args = Arc::new(args.for_video_root(Path::new("/mnt/b")));
+    assert!(root_args.more_video_roots.is_empty());
+    let input = InputFile::new(Path::new("/mnt/b/show/vid.flv"), root_args)
+        .await
+        .unwrap();
+    assert_paths_eq!(
+        input.get_output_path(None).unwrap(),
+        "/mnt/b/encoded/show/vid-crf22.mkv"
+    );
+
+    // With --output-dir, they share it:
+    let args = Cli::parse_from([
+        "prog_name",
+        "--x265",
+        "--no-log",
+        "--output-dir",
+        "/out",
+        "/a",
+        "/mnt/b",
+    ]);
+    let input = InputFile::new(
+        Path::new("/mnt/b/show/vid.flv"),
+        Arc::new(args.for_video_root(Path::new("/mnt/b"))),
+    )
+    .await
+    .unwrap();
+    assert_paths_eq!(
+        input.get_output_path(None).unwrap(),
+        "/out/show/vid-crf22.mkv"
+    );
+}
+
+#[test]
+fn test_skip_markers() {
+    let dir = tempfile::tempdir().unwrap();
+    let video = dir.path().join("movie.mkv");
+    assert_eq!(
+        get_skip_marker_path(&video),
+        dir.path().join("movie.mkv.jiffyskip")
+    );
+    assert_eq!(read_skip_marker(&video), None);
+    std::fs::write(get_skip_marker_path(&video), "").unwrap();
+    assert_eq!(read_skip_marker(&video).as_deref(), Some(""));
+    std::fs::write(
+        get_skip_marker_path(&video),
+        "Already a remux\nof the disc\n",
+    )
+    .unwrap();
+    assert_eq!(
+        read_skip_marker(&video).as_deref(),
+        Some("Already a remux of the disc")
+    );
+}
+
+#[tokio::test]
+async fn test_flatten() {
+    let args = Arc::new(Cli::parse_from([
+        "prog_name",
+        "--flatten",
+        "--no-log",
+        "a/b",
+    ]));
+    let first = InputFile::new(Path::new("a/b/Show/Season 1/pilot.mkv"), args.clone())
+        .await
+        .unwrap();
+    let second = InputFile::new(Path::new("a/b/Other Show/pilot.mkv"), args)
+        .await
+        .unwrap();
+    let hash = get_template_hash(Path::new("Show/Season 1/pilot.mkv"));
+    assert_eq!(hash.len(), 8);
+    assert_paths_eq!(
+        first.get_output_path(None).unwrap(),
+        format!("a/b/encoded/pilot-{hash}-crf22.mkv")
+    );
+    assert_ne!(
+        first.get_output_path(None).unwrap(),
+        second.get_output_path(None).unwrap()
+    );
+    assert_paths_eq!(
+        first
+            .get_output_path(Some("{dir} - {basename}".to_string()))
+            .unwrap(),
+        "a/b/encoded/Show - Season 1 - pilot.mkv"
+    );
+    let re = naming_format_to_regex("{hash}-{basename}-{hash}-crf{crf}").unwrap();
+    assert_eq!(
+        &re.captures(&format!("{hash}-pilot-{hash}-crf22")).unwrap()["hash"],
+        hash
+    );
+}
+
+#[tokio::test]
+async fn test_strict_probe() {
+    // The video doesn't exist, so it can't be probed:
+    let args = Arc::new(Cli::parse_from(["prog_name"]));
+    let input = InputFile::new(Path::new("a/b/missing.mkv"), args)
+        .await
+        .unwrap();
+    assert!(!input.probe_errors().is_empty());
+    assert_eq!(input.crf, 22);
+
+    let args = Arc::new(Cli::parse_from(["prog_name", "--strict-probe"]));
+    let result = InputFile::new(Path::new("a/b/missing.mkv"), args).await;
+    assert!(matches!(result, Err(JiffyError::ProbeFailed(_))));
+
+    // With --crf, nothing needs to be probed:
+    let args = Arc::new(Cli::parse_from([
+        "prog_name",
+        "--strict-probe",
+        "--crf",
+        "20",
+    ]));
+    let input = InputFile::new(Path::new("a/b/missing.mkv"), args)
+        .await
+        .unwrap();
+    assert!(input.probe_errors().is_empty());
+}
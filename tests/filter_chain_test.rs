@@ -0,0 +1,123 @@
+use std::ffi::OsString;
+
+use jiffy::*;
+
+#[test]
+fn test_filter_stage_order() {
+    // Added in the order the options happen to be handled:
+    let mut vf = FilterChain::default();
+    vf.add(FilterStage::Subtitles, "subtitles=subs.ass");
+    vf.add(FilterStage::Scale, "scale=-2:720");
+    vf.add(FilterStage::Format, "format=yuv420p10le");
+    vf.add(FilterStage::Tonemap, "tonemap=hable");
+    vf.add(FilterStage::Crop, "crop=1920:800:0:140");
+    vf.add(FilterStage::Deinterlace, "bwdif");
+    vf.add(FilterStage::UserExtras, "hflip");
+    assert_eq!(
+        vf.to_arg(),
+        Some(OsString::from(
+            "bwdif, crop=1920:800:0:140, scale=-2:720, tonemap=hable, format=yuv420p10le, hflip, subtitles=subs.ass"
+        ))
+    );
+}
+
+#[test]
+fn test_filter_chain_around_scaling() {
+    let mut vf = FilterChain::default();
+    vf.add(FilterStage::Overlay, "drawtext=text=x");
+    vf.add(FilterStage::Timing, "setpts=PTS/2");
+    vf.add(FilterStage::Timing, "fps=30");
+    vf.add(FilterStage::Scale, "scale=-2:1080");
+    vf.add(FilterStage::Denoise, "hqdn3d");
+    vf.add(FilterStage::Stabilize, "vidstabtransform");
+    let filters: Vec<_> = vf
+        .filters()
+        .into_iter()
+        .map(|filter| filter.to_str().unwrap())
+        .collect();
+    // Filters in the same stage keep their order:
+    assert_eq!(
+        filters,
+        [
+            "vidstabtransform",
+            "hqdn3d",
+            "scale=-2:1080",
+            "setpts=PTS/2",
+            "fps=30",
+            "drawtext=text=x"
+        ]
+    );
+}
+
+#[test]
+fn test_subtitles_before_speed() {
+    let mut vf = FilterChain::default();
+    vf.add(FilterStage::Timing, "setpts=PTS/2");
+    vf.add(FilterStage::Subtitles, "subtitles=subs.ass");
+    vf.add(FilterStage::UserExtras, "hflip");
+    assert_eq!(
+        vf.to_arg(),
+        Some(OsString::from("hflip, subtitles=subs.ass, setpts=PTS/2"))
+    );
+}
+
+#[test]
+fn test_empty_filter_chain() {
+    let vf = FilterChain::default();
+    assert!(vf.is_empty());
+    assert_eq!(vf.to_arg(), None);
+}
+
+#[test]
+fn test_hwdecode_download() {
+    let hwdecode = HwDecode::Vaapi;
+    let mut vf = FilterChain::default();
+    vf.add(FilterStage::Scale, "scale=-2:720");
+    vf.add(FilterStage::Deinterlace, "bwdif");
+    vf.add(
+        FilterStage::Download,
+        hwdecode.download_filter(Some("yuv420p10le")).unwrap(),
+    );
+    assert_eq!(
+        vf.to_arg(),
+        Some(OsString::from(
+            "hwdownload, format=p010le, bwdif, scale=-2:720"
+        ))
+    );
+    assert_eq!(
+        HwDecode::Nvdec.download_filter(Some("yuv420p")),
+        Some("hwdownload, format=nv12")
+    );
+    // ffmpeg copies the frames itself when it picks the decoder:
+    assert_eq!(HwDecode::Auto.download_filter(Some("yuv420p")), None);
+    assert!(HwDecode::Auto.can_decode(Some("yuv444p12le")));
+    // Other layouts aren't known, so those videos are decoded in software:
+    for pix_fmt in [
+        Some("yuv422p10le"),
+        Some("yuv444p"),
+        Some("yuv420p12le"),
+        None,
+    ] {
+        assert_eq!(hwdecode.download_filter(pix_fmt), None);
+        assert!(!hwdecode.can_decode(pix_fmt));
+        assert!(HwDecode::None.can_decode(pix_fmt));
+    }
+
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+    assert_eq!(
+        hwdecode.input_args(Some("/dev/dri/renderD128")),
+        args(&[
+            "-hwaccel",
+            "vaapi",
+            "-hwaccel_output_format",
+            "vaapi",
+            "-hwaccel_device",
+            "/dev/dri/renderD128"
+        ])
+    );
+    assert_eq!(
+        HwDecode::Nvdec.input_args(None),
+        args(&["-hwaccel", "cuda", "-hwaccel_output_format", "cuda"])
+    );
+    assert!(HwDecode::None.input_args(Some("0")).is_empty());
+}
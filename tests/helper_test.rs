@@ -1,16 +1,5 @@
 use jiffy::*;
 
-#[test]
-fn test_size_str_to_int() {
-    assert_eq!(parse_size("1024b").unwrap(), 1024);
-    assert_eq!(parse_size("1k").unwrap(), 1024);
-    assert_eq!(parse_size("1.5M").unwrap(), (1024.0 * 1024.0 * 1.5) as u64);
-    assert_eq!(parse_size("1.5").unwrap(), (1024.0 * 1024.0 * 1.5) as u64);
-    assert_eq!(parse_size(".5").unwrap(), (1024.0 * 1024.0 * 0.5) as u64);
-    assert_eq!(parse_size("2g").unwrap(), (1024.0 * 1024.0 * 1024.0 * 2.0) as u64);
-    assert_eq!(parse_size("0.002T").unwrap(), (1024.0 * 1024.0 * 1024.0 * 1024.0 * 0.002) as u64);
-}
-
 #[test]
 fn test_minimum_size_input() {
     fn input_too_small_wrapper(size: u64, size_str: &str) -> bool {
@@ -19,3 +8,331 @@ fn test_minimum_size_input() {
     assert!(input_too_small_wrapper((2.5 * 1024.0 * 1024.0) as u64 - 1, "2.5M"));
     assert!(!input_too_small_wrapper((2.5 * 1024.0 * 1024.0) as u64, "2.5M"));
 }
+
+#[test]
+fn test_maximum_size_input() {
+    assert!(input_too_large(20 * (1 << 30) + 1, &Some("20G".to_string())).unwrap());
+    assert!(!input_too_large(20 * (1 << 30), &Some("20G".to_string())).unwrap());
+    assert!(!input_too_large(u64::MAX, &None).unwrap());
+    assert!(input_too_large(1, &Some("huge".to_string())).is_err());
+}
+
+#[test]
+fn test_external_command() {
+    let command = ExternalCommand::parse("nice -n 19 '/opt/my ffmpeg/ffmpeg'".as_ref()).unwrap();
+    assert_eq!(command.program, "nice");
+    assert_eq!(command.args, ["-n", "19", "/opt/my ffmpeg/ffmpeg"]);
+    assert_eq!(command.to_string(), "nice -n 19 /opt/my ffmpeg/ffmpeg");
+
+    let command = ExternalCommand::parse("/usr/bin/ffmpeg".as_ref()).unwrap();
+    assert_eq!(command.program, "/usr/bin/ffmpeg");
+    assert!(command.args.is_empty());
+
+    // An existing path is not split, even with spaces:
+    let path = "test/special characters: ()[]'.mp4";
+    let command = ExternalCommand::parse(path.as_ref()).unwrap();
+    assert_eq!(command.program, path);
+
+    assert!(ExternalCommand::parse("ffmpeg 'unclosed".as_ref()).is_err());
+    assert!(ExternalCommand::parse(" ".as_ref()).is_err());
+}
+
+#[test]
+fn test_error_kinds() {
+    let err = JiffyError::from(anyhow::Error::from(JiffyError::OutputExists(
+        "out.mkv".into(),
+    )));
+    assert!(
+        matches!(err, JiffyError::OutputExists(path) if path == std::path::Path::new("out.mkv"))
+    );
+
+    // An io::Error from anyhow keeps its context:
+    let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+    let err = JiffyError::from(anyhow::Error::from(io_err).context("Could not read the list"));
+    assert!(matches!(err, JiffyError::Other(_)));
+    assert_eq!(format!("{err:#}"), "Could not read the list: missing");
+    assert!(matches!(
+        JiffyError::from(std::io::Error::other("missing")),
+        JiffyError::Io(_)
+    ));
+
+    let err = JiffyError::from(anyhow::anyhow!("something else"));
+    assert!(matches!(err, JiffyError::Other(_)));
+    assert_eq!(err.to_string(), "something else");
+
+    let err = JiffyError::EncodeFailed {
+        path: "in.mkv".into(),
+        exit_code: Some(1),
+        stderr_tail: "Unknown encoder".to_string(),
+    };
+    assert_eq!(
+        err.to_string(),
+        "ffmpeg failed to encode \"in.mkv\" (exit code 1):\nUnknown encoder"
+    );
+}
+
+#[test]
+fn test_global_slots() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = GlobalSlot::try_acquire(dir.path(), 2).unwrap().unwrap();
+    let second = GlobalSlot::try_acquire(dir.path(), 2).unwrap().unwrap();
+    assert_eq!((first.index, second.index), (0, 1));
+    assert!(GlobalSlot::try_acquire(dir.path(), 2).unwrap().is_none());
+    // An instance with a bigger budget can still start:
+    assert_eq!(
+        GlobalSlot::try_acquire(dir.path(), 3)
+            .unwrap()
+            .unwrap()
+            .index,
+        2
+    );
+
+    drop(first);
+    assert_eq!(
+        GlobalSlot::try_acquire(dir.path(), 2)
+            .unwrap()
+            .unwrap()
+            .index,
+        0
+    );
+}
+
+#[test]
+fn test_job_queue() {
+    let mut queue = JobQueue::default();
+    queue.push_back(0, "1080p", vec![]);
+    queue.push_back(1, "4k", vec![0]);
+    queue.push_back(2, "other", vec![]);
+    queue.push_front(3, "wait");
+    assert_eq!(queue.pop_ready(), Some((3, "wait")));
+    assert_eq!(queue.pop_ready(), Some((0, "1080p")));
+    // The 4K encode waits for the 1080p one, so the next task starts first:
+    assert_eq!(queue.pop_ready(), Some((2, "other")));
+    assert_eq!(queue.pop_ready(), None);
+    assert_eq!(queue.len(), 1);
+    queue.finish(0);
+    assert_eq!(queue.pop_ready(), Some((1, "4k")));
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_slow_start_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("1001"), "2").unwrap();
+    std::fs::write(dir.path().join("1002"), "3").unwrap();
+    // A file from an instance that's gone:
+    std::fs::write(dir.path().join("1003"), "4").unwrap();
+    let counter = SlowStartCounter::new(dir.path()).unwrap();
+    counter.publish(5).unwrap();
+
+    // This instance's own encodes aren't counted:
+    assert_eq!(read_slow_start_counts(dir.path(), |pid| pid != 1003), 5);
+    assert!(!dir.path().join("1003").exists());
+    drop(counter);
+    assert!(!dir.path().join(std::process::id().to_string()).exists());
+}
+
+#[test]
+fn test_low_priority_command() {
+    let command = ExternalCommand::parse("ffmpeg -hide_banner".as_ref())
+        .unwrap()
+        .with_low_priority();
+    // The wrappers are only used if they're installed, but ffmpeg always comes last:
+    assert!(
+        command
+            .args
+            .ends_with(&["ffmpeg".into(), "-hide_banner".into()]),
+        "{command}"
+    );
+    if command.program == "nice" {
+        assert_eq!(command.args[..2], ["-n", "19"]);
+    }
+}
+
+#[test]
+fn test_find_case_collisions() {
+    let paths: Vec<std::path::PathBuf> = [
+        "Show/E01-crf22.mkv",
+        "show/e01-crf22.mkv",
+        "Show/E02-crf22.mkv",
+        "Show/E01-crf22.mkv",
+    ]
+    .iter()
+    .map(std::path::PathBuf::from)
+    .collect();
+    assert_eq!(
+        find_case_collisions(&paths, CaseSensitivity::Insensitive),
+        [(1, 0)]
+    );
+    assert_eq!(find_case_collisions(&paths, CaseSensitivity::Sensitive), []);
+    let dir = tempfile::tempdir().unwrap();
+    // Whatever the filesystem is, it's found without leaving files behind:
+    CaseSensitivity::detect(&dir.path().join("not/made/yet"));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_output_collisions() {
+    use std::path::{Path, PathBuf};
+    let outputs: Vec<PathBuf> = [
+        "show-crf22.mkv",
+        "other-crf22.mkv",
+        "show-crf22.mkv",
+        "Show-crf22.mkv",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .collect();
+    assert_eq!(
+        find_output_collisions(&outputs, CaseSensitivity::Sensitive),
+        [vec![0, 2]]
+    );
+    assert_eq!(
+        find_output_collisions(&outputs, CaseSensitivity::Insensitive),
+        [vec![0, 2, 3]]
+    );
+
+    let inputs = [Path::new("show.mkv"), Path::new("show.AVI")];
+    assert_eq!(get_collision_suffixes(&inputs), ["mkv", "avi"]);
+    // Inputs with the same extension are numbered by path, whatever the queue order is:
+    let inputs = [
+        Path::new("b/show.mkv"),
+        Path::new("a/show.mkv"),
+        Path::new("show.avi"),
+        Path::new("show"),
+    ];
+    assert_eq!(
+        get_collision_suffixes(&inputs),
+        ["mkv-2", "mkv-1", "avi", "1"]
+    );
+    let inputs = [Path::new("b/show"), Path::new("a/show")];
+    assert_eq!(get_collision_suffixes(&inputs), ["2", "1"]);
+}
+
+#[test]
+fn test_container_compatibility() {
+    assert_eq!(
+        Container::from_path(std::path::Path::new("a/b-crf22.MP4")),
+        Some(Container::Mp4)
+    );
+    assert_eq!(
+        Container::from_path(std::path::Path::new("a/b-crf22.avi")),
+        None
+    );
+    assert!(!Container::Mp4.can_hold("audio", "dts"));
+    assert!(!Container::Mp4.can_hold("audio", "truehd"));
+    assert!(Container::Mp4.can_hold("audio", "eac3"));
+    assert!(!Container::Mp4.can_hold("subtitle", "ass"));
+    assert!(Container::Mp4.can_hold("video", "vp9"));
+    assert!(!Container::Mp4.can_hold("video", "vc1"));
+    assert!(Container::Mov.can_hold("audio", "pcm_s24le"));
+    assert!(!Container::Mov.can_hold("subtitle", "subrip"));
+    assert!(Container::Mov.can_hold("subtitle", "mov_text"));
+    assert!(Container::Mkv.can_hold("audio", "dts"));
+    assert_eq!(Container::Mov.to_string(), "mov");
+}
+
+#[test]
+fn test_move_source() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = dir.path().join("videos");
+    let source = video_root.join("shows/episode.mkv");
+    let destination =
+        get_moved_source_path(&dir.path().join("done"), &video_root, &source).unwrap();
+    assert_eq!(destination, dir.path().join("done/shows/episode.mkv"));
+    assert!(get_moved_source_path(
+        &dir.path().join("done"),
+        &video_root,
+        &dir.path().join("other.mkv")
+    )
+    .is_err());
+
+    std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+    std::fs::write(&source, "video").unwrap();
+    move_file(&source, &destination).unwrap();
+    assert!(!source.exists());
+    assert_eq!(std::fs::read_to_string(&destination).unwrap(), "video");
+
+    // An existing file is never replaced:
+    std::fs::write(&source, "another video").unwrap();
+    assert!(move_file(&source, &destination).is_err());
+    assert!(source.exists());
+}
+
+#[test]
+fn test_audio_copy_policy() {
+    assert_eq!(
+        AudioCopyThreshold::parse("200k").unwrap(),
+        AudioCopyThreshold::Total(200_000)
+    );
+    assert_eq!(
+        AudioCopyThreshold::parse("64k/ch").unwrap(),
+        AudioCopyThreshold::PerChannel(64_000)
+    );
+    assert_eq!(
+        AudioCopyThreshold::parse("64k/channel").unwrap(),
+        AudioCopyThreshold::PerChannel(64_000)
+    );
+    assert!(AudioCopyThreshold::parse("64k/ear").is_err());
+
+    let efficient = AudioCopyPolicy {
+        policy: AudioPolicy::CopyIfEfficient,
+        threshold: AudioCopyThreshold::PerChannel(64_000),
+    };
+    // A 5.1 stream gets six times the threshold, and unknown channel counts are taken as stereo:
+    assert!(efficient.should_copy(Some(384.0), Some(6)));
+    assert!(!efficient.should_copy(Some(192.0), Some(2)));
+    assert!(efficient.should_copy(Some(128.0), None));
+    assert!(!efficient.should_copy(None, Some(2)));
+
+    let always_copy = AudioCopyPolicy {
+        policy: AudioPolicy::AlwaysCopy,
+        ..efficient
+    };
+    assert!(always_copy.should_copy(None, None));
+    let always_encode = AudioCopyPolicy {
+        policy: AudioPolicy::AlwaysEncode,
+        ..efficient
+    };
+    assert!(!always_encode.should_copy(Some(32.0), Some(2)));
+}
+
+#[test]
+fn test_free_space_at() {
+    use std::path::{Path, PathBuf};
+    let mounts = [
+        (PathBuf::from("/"), 100),
+        (PathBuf::from("/mnt/media"), 5),
+        (PathBuf::from("/mnt/med"), 7),
+    ];
+    assert_eq!(
+        free_space_at(Path::new("/mnt/media/encoded/show"), &mounts),
+        Some(5)
+    );
+    // Mount points match whole path components:
+    assert_eq!(free_space_at(Path::new("/mnt/medium"), &mounts), Some(100));
+    assert_eq!(free_space_at(Path::new("relative"), &mounts), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_file_key_duplicates() {
+    let dir = tempfile::tempdir().unwrap();
+    let video = dir.path().join("movie.mkv");
+    std::fs::write(&video, "video").unwrap();
+    let symlink = dir.path().join("symlink.mkv");
+    std::os::unix::fs::symlink(&video, &symlink).unwrap();
+    let hard_link = dir.path().join("hard link.mkv");
+    std::fs::hard_link(&video, &hard_link).unwrap();
+    let other = dir.path().join("other.mkv");
+    std::fs::write(&other, "video").unwrap();
+
+    let key = get_file_key(&video).unwrap();
+    assert_eq!(get_file_key(&symlink).unwrap(), key);
+    assert_eq!(get_file_key(&hard_link).unwrap(), key);
+    assert_eq!(
+        get_file_key(&dir.path().join(".").join("movie.mkv")).unwrap(),
+        key
+    );
+    assert_ne!(get_file_key(&other).unwrap(), key);
+}
@@ -0,0 +1,82 @@
+//! Runs with a stand-in for an old ffmpeg, which is set for this test binary only.
+
+#![cfg(unix)]
+
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use clap::Parser;
+use jiffy::*;
+use tokio_util::sync::CancellationToken;
+
+/// Write an executable shell script.
+fn write_script(path: &Path, script: &str) {
+    std::fs::write(path, format!("#!/bin/sh\n{script}")).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// The ffmpeg says it's version 4.1, and its encode writes its arguments to the partial output,
+/// which is the last argument. Every video is 10 seconds long.
+#[tokio::test]
+async fn test_newer_av1_options_are_left_out() {
+    let dir = tempfile::tempdir().unwrap();
+    let bin = dir.path().join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    write_script(
+        &bin.join("ffmpeg"),
+        "for last; do :; done\ncase \"$last\" in\n-version) echo 'ffmpeg version 4.1.6 Copyright (c) 2000-2020';;\n-encoders) printf ' ------\\n V..... libaom-av1\\n A..... aac\\n';;\n*.part.*) printf '%s\\n' \"$@\" > \"$last\";;\nesac\n",
+    );
+    write_script(
+        &bin.join("ffprobe"),
+        "case \"$*\" in *format=duration*) printf 10;; esac\n",
+    );
+    std::env::set_var("FFMPEG", bin.join("ffmpeg"));
+    std::env::set_var("FFPROBE", bin.join("ffprobe"));
+
+    let video_root = dir.path().join("videos");
+    std::fs::create_dir(&video_root).unwrap();
+    std::fs::write(video_root.join("a.mkv"), vec![1; 10_000]).unwrap();
+    let cli = Cli::parse_from([
+        "prog_name",
+        "--no-log",
+        "--av1",
+        "--crf",
+        "30",
+        "--tune",
+        "ssim",
+        "--grain-synth",
+        "8",
+        video_root.to_str().unwrap(),
+    ]);
+    let report = Encoder::new(cli)
+        .unwrap()
+        .run_encodes(CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(report.files.len(), 1, "{:?}", report.files);
+    let args = std::fs::read_to_string(video_root.join("encoded/a-5-crf30.mkv")).unwrap();
+    assert!(!args.lines().any(|arg| arg == "-tune"), "{args}");
+    assert!(
+        !args.lines().any(|arg| arg == "-denoise-noise-level"),
+        "{args}"
+    );
+    // Each left out option is warned about:
+    let warnings: Vec<_> = report
+        .warnings
+        .groups
+        .iter()
+        .flat_map(|group| &group.warnings)
+        .map(|(_, msg)| msg.as_str())
+        .collect();
+    assert!(
+        warnings
+            .iter()
+            .any(|msg| msg.starts_with("Ignoring --tune")),
+        "{warnings:?}"
+    );
+    assert!(
+        warnings
+            .iter()
+            .any(|msg| msg.starts_with("Ignoring --grain-synth")),
+        "{warnings:?}"
+    );
+}
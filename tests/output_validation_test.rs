@@ -0,0 +1,169 @@
+use clap::Parser;
+use jiffy::*;
+
+#[test]
+fn test_default_size_checks() {
+    let policy = OutputPolicy::new(&Cli::parse_from(["prog_name", "--expected-size", "60"]));
+    assert_eq!(policy.check_size(1000, 299), SizeVerdict::Broken);
+    assert_eq!(policy.check_size(100_000, 50_000), SizeVerdict::Ok);
+    assert_eq!(
+        policy.check_size(100_000, 61_000),
+        SizeVerdict::LargerThanExpected(61)
+    );
+    assert_eq!(
+        policy.check_size(100_000, 19_000),
+        SizeVerdict::SuspiciouslySmall(19)
+    );
+    assert_eq!(policy.check_size(100_000, 20_000), SizeVerdict::Ok);
+
+    let policy = OutputPolicy::new(&Cli::parse_from(["prog_name"]));
+    assert_eq!(policy.check_size(100_000, 1000), SizeVerdict::Ok);
+    assert_eq!(policy.check_size(100_000, 100_000), SizeVerdict::Ok);
+    assert_eq!(
+        policy.check_size(100_000, 200_000),
+        SizeVerdict::LargerThanExpected(200)
+    );
+}
+
+#[test]
+fn test_configured_size_checks() {
+    let policy = OutputPolicy::new(&Cli::parse_from([
+        "prog_name",
+        "--expected-size",
+        "60",
+        "--min-output-size",
+        "1k",
+        "--suspect-small-ratio",
+        "0",
+    ]));
+    assert_eq!(policy.check_size(100_000, 1000), SizeVerdict::Broken);
+    assert_eq!(policy.check_size(100_000, 2000), SizeVerdict::Ok);
+
+    let policy = OutputPolicy::new(&Cli::parse_from([
+        "prog_name",
+        "--expected-size",
+        "60",
+        "--suspect-small-ratio",
+        "20",
+    ]));
+    assert_eq!(policy.check_size(100_000, 4000), SizeVerdict::Ok);
+    assert_eq!(
+        policy.check_size(100_000, 2000),
+        SizeVerdict::SuspiciouslySmall(2)
+    );
+}
+
+fn policy(args: &[&str]) -> OutputPolicy {
+    let mut all_args = vec!["prog_name", "--expected-size", "60"];
+    all_args.extend(args);
+    OutputPolicy::new(&Cli::parse_from(all_args))
+}
+
+#[test]
+fn test_judge_output() {
+    let warn_only = policy(&[]);
+    assert_eq!(warn_only.judge_output(100_000, 50_000), OutputVerdict::Keep);
+    assert!(matches!(
+        warn_only.judge_output(100_000, 100),
+        OutputVerdict::Delete(_)
+    ));
+    assert!(matches!(
+        warn_only.judge_output(100_000, 70_000),
+        OutputVerdict::Warn(_)
+    ));
+    assert!(matches!(
+        warn_only.judge_output(100_000, 10_000),
+        OutputVerdict::Warn(_)
+    ));
+
+    let delete = policy(&["--delete-too-large"]);
+    assert!(matches!(
+        delete.judge_output(100_000, 70_000),
+        OutputVerdict::Delete(_)
+    ));
+    assert!(matches!(
+        delete.judge_output(100_000, 10_000),
+        OutputVerdict::Warn(_)
+    ));
+
+    let retry = policy(&["--delete-too-large", "--retry-too-large"]);
+    assert!(matches!(
+        retry.judge_output(100_000, 70_000),
+        OutputVerdict::Retry(_)
+    ));
+    // A broken output is deleted, not retried with a higher CRF:
+    assert!(matches!(
+        retry.judge_output(100_000, 100),
+        OutputVerdict::Delete(_)
+    ));
+}
+
+#[test]
+fn test_judge_partial() {
+    assert_eq!(
+        policy(&[]).judge_partial(100_000, 200_000),
+        OutputVerdict::Keep
+    );
+
+    let delete = policy(&["--delete-too-large"]);
+    assert_eq!(delete.judge_partial(100_000, 62_000), OutputVerdict::Keep);
+    assert!(matches!(
+        delete.judge_partial(100_000, 62_001),
+        OutputVerdict::Delete(_)
+    ));
+
+    let retry = policy(&["--delete-too-large", "--retry-too-large"]);
+    assert!(matches!(
+        retry.judge_partial(100_000, 62_001),
+        OutputVerdict::Retry(_)
+    ));
+}
+
+#[test]
+fn test_judge_projection() {
+    let ignore = policy(&[]);
+    assert!(!ignore.wants_projection());
+    assert_eq!(
+        ignore.judge_projection(100_000, 200_000),
+        OutputVerdict::Keep
+    );
+
+    let skip = policy(&["--projected-size-policy", "skip"]);
+    assert!(skip.wants_projection());
+    assert_eq!(skip.judge_projection(100_000, 70_000), OutputVerdict::Keep);
+    assert!(matches!(
+        skip.judge_projection(100_000, 70_001),
+        OutputVerdict::Delete(_)
+    ));
+
+    let retry = policy(&["--projected-size-policy", "retry"]);
+    assert!(matches!(
+        retry.judge_projection(100_000, 70_001),
+        OutputVerdict::Retry(_)
+    ));
+}
+
+#[test]
+fn test_length_matches() {
+    assert!(length_matches(60.0, 61.5));
+    assert!(!length_matches(60.0, 63.0));
+    // Long videos get a fraction of their length:
+    assert!(length_matches(3600.0, 3630.0));
+    assert!(!length_matches(3600.0, 3000.0));
+}
+
+#[test]
+fn test_lossless_size_checks() {
+    // Lossless and mezzanine outputs are expected to grow, so they're never too large:
+    for args in [
+        &["--lossless"][..],
+        &["--near-lossless"],
+        &["--mezzanine", "prores"],
+    ] {
+        let mut all_args = vec!["prog_name", "--delete-too-large"];
+        all_args.extend(args);
+        let policy = OutputPolicy::new(&Cli::parse_from(all_args));
+        assert_eq!(policy.judge_output(100_000, 300_000), OutputVerdict::Keep);
+        assert_eq!(policy.judge_partial(100_000, 300_000), OutputVerdict::Keep);
+    }
+}
@@ -0,0 +1,365 @@
+use jiffy::*;
+
+const AUDIO_STREAMS: &str = "[STREAM]
+index=1
+codec_name=ac3
+channels=6
+TAG:language=eng
+TAG:title=Surround 5.1
+[/STREAM]
+[STREAM]
+index=2
+codec_name=aac
+channels=2
+[/STREAM]
+";
+
+#[test]
+fn test_parse_ffprobe_sections() {
+    let sections = parse_ffprobe_sections(AUDIO_STREAMS);
+    assert_eq!(sections.len(), 2);
+    assert_eq!(sections[0]["codec_name"], "ac3");
+    assert_eq!(sections[0]["TAG:title"], "Surround 5.1");
+    assert_eq!(sections[1]["index"], "2");
+    assert!(!sections[1].contains_key("TAG:language"));
+}
+
+#[test]
+fn test_channel_layout_name() {
+    assert_eq!(get_channel_layout_name(2), "2.0");
+    assert_eq!(get_channel_layout_name(6), "5.1");
+    assert_eq!(get_channel_layout_name(4), "4ch");
+}
+
+#[test]
+fn test_parse_encoders() {
+    let output = "Encoders:
+ V..... = Video
+ A..... = Audio
+ .....D = Supports direct rendering method 1
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC (codec h264)
+ V....D libx265              libx265 H.265 / HEVC (codec hevc)
+ A....D aac                  AAC (Advanced Audio Coding)
+";
+    let encoders = parse_encoders(output);
+    assert_eq!(encoders.len(), 3);
+    assert!(encoders.contains("libx265"));
+    assert!(encoders.contains("aac"));
+    assert!(!encoders.contains("libaom-av1"));
+    assert!(!encoders.contains("="));
+}
+
+#[test]
+fn test_ffmpeg_version() {
+    let version = FfmpegVersion::parse("ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023");
+    assert_eq!(version, Some(FfmpegVersion { major: 6, minor: 1 }));
+    let version = FfmpegVersion::parse("ffmpeg version n4.4 Copyright (c) 2000-2021");
+    assert_eq!(version, Some(FfmpegVersion { major: 4, minor: 4 }));
+    assert_eq!(
+        FfmpegVersion::parse("ffmpeg version N-112233-gabcdef"),
+        None
+    );
+    assert!(
+        FfmpegVersion {
+            major: 4,
+            minor: 10
+        } > FfmpegVersion { major: 4, minor: 4 }
+    );
+    assert!(FfmpegVersion { major: 5, minor: 0 } > FfmpegVersion { major: 4, minor: 4 });
+}
+
+#[test]
+fn test_bitrate_for_channels() {
+    assert_eq!(get_bitrate_for_channels(128_000, 1), 64_000);
+    assert_eq!(get_bitrate_for_channels(128_000, 2), 128_000);
+    assert_eq!(get_bitrate_for_channels(128_000, 6), 256_000);
+    assert_eq!(get_bitrate_for_channels(128_000, 8), 320_000);
+}
+
+#[test]
+fn test_progress_projection() {
+    let mut progress = Progress::default();
+    for line in "frame=100\nout_time_us=N/A\ntotal_size=1000\nprogress=continue\n".lines() {
+        progress.update(line);
+    }
+    assert_eq!(progress.out_time, 0.0);
+    assert_eq!(progress.projected_size(600.0), None);
+
+    progress.update("out_time_us=120000000");
+    progress.update("total_size=2000000");
+    // Less than a quarter of a 10 minute video:
+    assert_eq!(progress.projected_size(600.0), None);
+    progress.update("out_time_us=150000000");
+    assert_eq!(progress.projected_size(600.0), Some(8_000_000));
+    // For long videos, three minutes is enough:
+    progress.update("out_time_us=180000000");
+    assert_eq!(progress.projected_size(3600.0), Some(40_000_000));
+
+    progress.update("progress=end");
+    assert!(progress.finished);
+}
+
+#[test]
+fn test_parse_loudness() {
+    let output = r#"[Parsed_loudnorm_0 @ 0x5581c0b3e2c0]
+{
+	"input_i" : "-27.61",
+	"input_tp" : "-4.47",
+	"input_lra" : "18.06",
+	"input_thresh" : "-39.20",
+	"output_i" : "-16.58",
+	"output_tp" : "-1.50",
+	"output_lra" : "14.78",
+	"output_thresh" : "-27.71",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.58"
+}
+"#;
+    let loudness = Loudness::parse(output).unwrap();
+    assert_eq!(loudness.input_i, -27.61);
+    assert_eq!(loudness.target_offset, 0.58);
+    assert_eq!(
+        loudness.to_filter(),
+        "loudnorm=I=-16:TP=-1.5:LRA=11:measured_I=-27.61:measured_TP=-4.47:measured_LRA=18.06:measured_thresh=-39.2:offset=0.58:linear=true"
+    );
+
+    assert!(Loudness::parse(&output.replace("-27.61", "-inf")).is_err());
+    assert!(Loudness::parse("").is_err());
+}
+
+#[test]
+fn test_crop_rect() {
+    let output = "\
+[Parsed_cropdetect_0 @ 0x55] x1:0 x2:1919 y1:138 y2:941 w:1920 h:800 x:0 y:140 pts:1 t:0.04 crop=1920:800:0:140
+[Parsed_cropdetect_0 @ 0x55] x1:0 x2:1919 y1:0 y2:1079 w:1920 h:1080 x:0 y:0 pts:2 t:0.08 crop=1920:1080:0:0
+[Parsed_cropdetect_0 @ 0x55] x1:0 x2:1919 y1:138 y2:941 w:1920 h:800 x:0 y:140 pts:3 t:0.12 crop=1920:800:0:140
+";
+    let rect = CropRect::parse(output).unwrap();
+    assert_eq!(
+        rect,
+        CropRect {
+            width: 1920,
+            height: 800,
+            x: 0,
+            y: 140
+        }
+    );
+    assert_eq!(rect.to_filter(), "crop=1920:800:0:140");
+    assert!(rect.crops(1920, 1080));
+    assert!(!CropRect {
+        width: 1920,
+        height: 1080,
+        x: 0,
+        y: 0
+    }
+    .crops(1920, 1080));
+    assert_eq!(CropRect::parse("no crop here"), None);
+
+    // A darker sample shouldn't cut off picture that other samples show:
+    let dark = CropRect {
+        width: 1800,
+        height: 700,
+        x: 60,
+        y: 191,
+    };
+    assert_eq!(CropRect::union(&[rect, dark]), Some(rect));
+    let shifted = CropRect {
+        width: 1920,
+        height: 800,
+        x: 0,
+        y: 141,
+    };
+    assert_eq!(
+        CropRect::union(&[rect, shifted]),
+        Some(CropRect {
+            width: 1920,
+            height: 800,
+            x: 0,
+            y: 140
+        })
+    );
+    assert_eq!(CropRect::union(&[]), None);
+}
+
+#[test]
+fn test_parse_packet_sizes() {
+    let sizes = parse_packet_sizes("1,417\n2,300\n1,418\n\n2,12,side_data\n");
+    assert_eq!(sizes.into_iter().collect::<Vec<_>>(), [(1, 835), (2, 312)]);
+}
+
+#[test]
+fn test_parse_pixel_formats() {
+    let output = "Encoder libx265 [libx265 H.265 / HEVC]:
+    General capabilities: dr1 delay threads
+    Threading capabilities: other
+    Supported pixel formats: yuv420p yuvj420p yuv422p yuv420p10le yuv422p10le gray
+libx265 AVOptions:
+  -crf               <float>      E..V....... set the x265 crf (from -1 to FLT_MAX) (default -1)
+";
+    let formats = parse_pixel_formats(output);
+    assert_eq!(formats.len(), 6);
+    assert!(formats.contains(&"yuv420p10le".to_string()));
+    assert!(parse_pixel_formats("Codec 'x' is not recognized by FFmpeg.").is_empty());
+}
+
+#[test]
+fn test_lossless_audio() {
+    let audio = |codec: &str, profile: Option<&str>| StreamInfo {
+        codec_type: "audio".to_string(),
+        codec: Some(codec.to_string()),
+        profile: profile.map(str::to_string),
+        ..Default::default()
+    };
+    assert!(audio("flac", None).is_lossless_audio());
+    assert!(audio("truehd", None).is_lossless_audio());
+    assert!(audio("pcm_s24le", None).is_lossless_audio());
+    assert!(audio("dts", Some("DTS-HD MA")).is_lossless_audio());
+    assert!(!audio("dts", Some("DTS")).is_lossless_audio());
+    assert!(!audio("aac", Some("LC")).is_lossless_audio());
+}
+
+#[test]
+fn test_text_subtitles() {
+    let subtitle = |codec: &str| StreamInfo {
+        codec_type: "subtitle".to_string(),
+        codec: Some(codec.to_string()),
+        ..Default::default()
+    };
+    assert!(subtitle("subrip").is_text_subtitle());
+    assert!(subtitle("ass").is_text_subtitle());
+    assert!(!subtitle("hdmv_pgs_subtitle").is_text_subtitle());
+    assert!(!subtitle("dvd_subtitle").is_text_subtitle());
+}
+
+#[test]
+fn test_hdr_streams() {
+    let sections = parse_ffprobe_sections(
+        "[STREAM]
+index=0
+codec_type=video
+codec_name=hevc
+color_transfer=smpte2084
+[/STREAM]
+[STREAM]
+index=1
+codec_type=video
+codec_name=h264
+color_transfer=unknown
+[/STREAM]",
+    );
+    let streams = sections
+        .iter()
+        .map(|section| StreamInfo::from_section(section).unwrap())
+        .collect::<Vec<_>>();
+    assert!(streams[0].is_hdr());
+    assert_eq!(streams[1].color_transfer, None);
+    assert!(!streams[1].is_hdr());
+
+    let hlg = StreamInfo {
+        codec_type: "video".to_string(),
+        color_transfer: Some("arib-std-b67".to_string()),
+        ..Default::default()
+    };
+    assert!(hlg.is_hdr());
+    let sdr = StreamInfo {
+        codec_type: "video".to_string(),
+        color_transfer: Some("bt709".to_string()),
+        ..Default::default()
+    };
+    assert!(!sdr.is_hdr());
+}
+
+#[test]
+fn test_color_args() {
+    let sections = parse_ffprobe_sections(
+        "[STREAM]
+index=0
+codec_type=video
+codec_name=mpeg2video
+color_range=tv
+color_space=smpte170m
+color_transfer=unknown
+color_primaries=bt470bg
+[/STREAM]",
+    );
+    let stream = StreamInfo::from_section(&sections[0]).unwrap();
+    assert_eq!(stream.color_space.as_deref(), Some("smpte170m"));
+    assert_eq!(stream.color_transfer, None);
+    assert_eq!(
+        get_color_args(&stream),
+        [
+            "-color_primaries",
+            "bt470bg",
+            "-colorspace",
+            "smpte170m",
+            "-color_range",
+            "tv"
+        ]
+    );
+    assert!(get_color_args(&StreamInfo::default()).is_empty());
+}
+
+#[test]
+fn test_frame_rate() {
+    assert_eq!(parse_frame_rate("25/1"), Some(25.0));
+    assert!((parse_frame_rate("30000/1001").unwrap() - 29.97).abs() < 0.01);
+    assert_eq!(parse_frame_rate("60"), Some(60.0));
+    assert_eq!(parse_frame_rate("0/0"), None);
+    assert_eq!(parse_frame_rate("N/A"), None);
+
+    let sections = parse_ffprobe_sections(
+        "[STREAM]
+index=0
+codec_type=video
+avg_frame_rate=0/0
+r_frame_rate=60/1
+[/STREAM]",
+    );
+    assert_eq!(
+        StreamInfo::from_section(&sections[0]).unwrap().frame_rate,
+        Some(60.0)
+    );
+}
+
+#[test]
+fn test_bit_depth() {
+    let video = |pix_fmt: &str| StreamInfo {
+        codec_type: "video".to_string(),
+        pix_fmt: Some(pix_fmt.to_string()),
+        ..Default::default()
+    };
+    assert_eq!(video("yuv420p").bit_depth(), Some(8));
+    assert_eq!(video("yuv420p10le").bit_depth(), Some(10));
+    assert_eq!(video("yuv444p12be").bit_depth(), Some(12));
+    assert_eq!(StreamInfo::default().bit_depth(), None);
+}
+
+#[test]
+fn test_jiffy_signature() {
+    let signature = get_jiffy_signature(&Codec::H265, 22);
+    assert_eq!(
+        signature,
+        format!("jiffy:{}:h265:crf22", env!("CARGO_PKG_VERSION"))
+    );
+    assert_eq!(
+        get_jiffy_signature(&Codec::Copy, 22),
+        format!("jiffy:{}:copy", env!("CARGO_PKG_VERSION"))
+    );
+
+    let mkv_tags = format!("TAG:ENCODER=Lavf60.16.100\nTAG:JIFFY={signature}\n");
+    assert_eq!(parse_jiffy_signature(&mkv_tags), Some(signature.clone()));
+    let mp4_tags = format!("TAG:major_brand=isom\nTAG:jiffy={signature}\n");
+    assert_eq!(parse_jiffy_signature(&mp4_tags), Some(signature.clone()));
+    // Outputs of earlier versions have it in the comment:
+    let old_tags = format!("TAG:comment={signature}\n");
+    assert_eq!(parse_jiffy_signature(&old_tags), Some(signature));
+    assert_eq!(
+        parse_jiffy_signature("TAG:comment=Ripped by someone\n"),
+        None
+    );
+    assert_eq!(
+        parse_jiffy_signature("TAG:title=jiffy:0.1.0:h265:crf22\n"),
+        None
+    );
+}
@@ -0,0 +1,378 @@
+use std::path::PathBuf;
+
+use jiffy::*;
+
+fn warning(path: &str, msg: &str) -> (PathBuf, String) {
+    (PathBuf::from(path), msg.to_string())
+}
+
+#[test]
+fn test_warning_kind() {
+    assert_eq!(
+        warning_kind(r#"The output file already exists: "/a/encoded/b \"1\".mkv""#),
+        "The output file already exists"
+    );
+    assert_eq!(
+        warning_kind("Output file was larger than expected at 95%: \"/a/encoded/b.mkv\""),
+        "Output file was larger than expected at …%"
+    );
+    assert_eq!(
+        warning_kind("Error adding subtitles: bad file\n\nCaused by:\n    oops"),
+        "Error adding subtitles: bad file"
+    );
+}
+
+#[test]
+fn test_summary_coalesces() {
+    let mut warnings: Vec<_> = (0..5)
+        .map(|i| {
+            warning(
+                &format!("/a/{i}.mkv"),
+                &format!("The output file already exists: \"/a/encoded/{i}.mkv\""),
+            )
+        })
+        .collect();
+    warnings.insert(
+        2,
+        warning("/a/x.mkv", "No subtitle stream matches --burn-sub"),
+    );
+    let summary = WarningSummary::new(warnings);
+    assert_eq!(summary.groups.len(), 2);
+    assert_eq!(
+        summary.summary_lines(),
+        [
+            "The output file already exists (5 files, list with -v)",
+            "/a/x.mkv: No subtitle stream matches --burn-sub",
+        ]
+    );
+
+    // A few of a kind are listed one by one:
+    let summary = WarningSummary::new(
+        (0..2).map(|i| warning(&format!("/a/{i}.mkv"), "Deleting 0 byte output file")),
+    );
+    assert_eq!(
+        summary.summary_lines(),
+        [
+            "/a/0.mkv: Deleting 0 byte output file",
+            "/a/1.mkv: Deleting 0 byte output file"
+        ]
+    );
+    assert!(WarningSummary::new([]).is_empty());
+}
+
+#[test]
+fn test_report_lists_coalesced_warnings() {
+    let mut report = sample_report();
+    report.warnings = WarningSummary::new((0..5).map(|i| {
+        warning(
+            &format!("/a/{i}.mkv"),
+            &format!("The output file already exists: \"/a/encoded/{i}.mkv\""),
+        )
+    }));
+    let md = report.to_markdown();
+    assert!(
+        md.contains("- The output file already exists (5 files)\n  - /a/0.mkv: The output file already exists: \"/a/encoded/0.mkv\"\n"),
+        "{md}"
+    );
+    assert!(md.contains("  - /a/4.mkv: "), "{md}");
+    let html = report.to_html();
+    assert!(
+        html.contains("<li><details><summary>The output file already exists (5 files)</summary><ul>\n<li>/a/0.mkv: "),
+        "{html}"
+    );
+    assert!(html.contains("</ul></details></li>\n</ul>"), "{html}");
+}
+
+#[test]
+fn test_failure_causes() {
+    assert_eq!(
+        FailureCause::detect("ffmpeg failed to encode \"/a/b.mkv\" (exit code 1):\n[out#0] Error: No space left on device"),
+        Some(FailureCause::DiskFull)
+    );
+    assert_eq!(
+        FailureCause::detect("Could not open \"/a/encoded/b.log\"\n\nCaused by:\n    Permission denied (os error 13)"),
+        Some(FailureCause::PermissionDenied)
+    );
+    assert_eq!(
+        FailureCause::detect("Unknown encoder 'libaom-av1'"),
+        Some(FailureCause::MissingEncoder)
+    );
+    assert_eq!(
+        FailureCause::detect("Stream map '0:s:0' matches no streams."),
+        Some(FailureCause::Mapping)
+    );
+    assert_eq!(
+        FailureCause::detect("The output file already exists: \"/a/b.mkv\""),
+        None
+    );
+
+    let summary = WarningSummary::new([
+        warning("/a/1.mkv", "ffmpeg failed to encode \"/a/1.mkv\" (exit code 1):\nNo space left on device"),
+        warning("/a/2.mkv", "ffmpeg failed to encode \"/a/2.mkv\" (exit code 1):\nav_interleaved_write_frame(): No space left on device"),
+    ]);
+    assert_eq!(summary.groups.len(), 1);
+    assert_eq!(summary.groups[0].cause, Some(FailureCause::DiskFull));
+    let lines = summary.summary_lines();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        lines[0],
+        "The disk is full. Free some space, or use --output-dir to write to another disk."
+    );
+}
+
+fn sample_report() -> RunReport {
+    RunReport {
+        files: vec![
+            FileResult {
+                input: "show/e01.mkv".into(),
+                input_size: 1 << 30,
+                output_size: Some(1 << 28),
+                crf: 24,
+                outcome: FileOutcome::Encoded,
+                usage: Some(ResourceUsage {
+                    cpu_seconds: 3723.4,
+                    peak_memory: 3 << 29,
+                }),
+                software_fallback: false,
+            },
+            FileResult {
+                input: "show/e02 <b>.mkv".into(),
+                input_size: 1 << 20,
+                output_size: None,
+                crf: 24,
+                outcome: FileOutcome::Skipped(
+                    "The output file already exists: \"e02.mkv\"".to_string(),
+                ),
+                usage: None,
+                software_fallback: false,
+            },
+        ],
+        warnings: WarningSummary::new([warning("show/e03.mkv", "Deleting 0 byte output file")]),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_markdown_report() {
+    let md = sample_report().render(ReportFormat::Md);
+    assert!(
+        md.contains("| show/e01.mkv | Encoded at CRF 24 | 1.0 GiB | 256.0 MiB | 75% |"),
+        "{md}"
+    );
+    assert!(
+        md.contains("| Skipped: The output file already exists: \"e02.mkv\" | 1.0 MiB |  |  |"),
+        "{md}"
+    );
+    assert!(md.contains("**Total:** 1 encoded, 1 skipped, 0 deleted, 0 failed. 1.0 GiB to 256.0 MiB (75% saved)."), "{md}");
+    assert!(
+        md.contains("- show/e03.mkv: Deleting 0 byte output file"),
+        "{md}"
+    );
+    assert!(
+        md.contains("| show/e01.mkv | 1h 02m 03s | 1.5 GiB |"),
+        "{md}"
+    );
+    assert!(
+        md.contains("**Resources:** ffmpeg used 1h 02m 03s of CPU time for 1 files."),
+        "{md}"
+    );
+}
+
+#[test]
+fn test_software_fallback_report() {
+    assert_eq!(sample_report().fallback_line(), None);
+    let mut report = sample_report();
+    report.files[0].software_fallback = true;
+    let md = report.render(ReportFormat::Md);
+    assert!(
+        md.contains("| show/e01.mkv | Encoded at CRF 24, decoded in software |"),
+        "{md}"
+    );
+    assert!(
+        md.contains("**Fallback:** 1 files failed with hardware decoding"),
+        "{md}"
+    );
+}
+
+#[test]
+fn test_html_report() {
+    let html = sample_report().render(ReportFormat::Html);
+    assert!(html.contains("<td>show/e02 &lt;b&gt;.mkv</td>"), "{html}");
+    assert!(html.contains("style=\"width: 75px\""), "{html}");
+    assert!(html.contains("<h2>By directory</h2>"), "{html}");
+    assert!(html.ends_with("</html>\n"));
+}
+
+#[test]
+fn test_directory_rollup() {
+    let file = |input: &str, output_size: Option<u64>, outcome: FileOutcome| FileResult {
+        input: input.into(),
+        input_size: 1 << 30,
+        output_size,
+        crf: 22,
+        outcome,
+        usage: None,
+        software_fallback: false,
+    };
+    let report = RunReport {
+        files: vec![
+            file("Show/Season 1/e01.mkv", Some(1 << 28), FileOutcome::Encoded),
+            file(
+                "Show/Season 1/e02.mkv",
+                None,
+                FileOutcome::Failed("ffmpeg failed".to_string()),
+            ),
+            file("Show/Season 2/e01.mkv", Some(1 << 29), FileOutcome::Encoded),
+            file("Show/extras/a/b.mkv", Some(1 << 29), FileOutcome::Encoded),
+            file("movie.mkv", Some(1 << 29), FileOutcome::Encoded),
+        ],
+        ..Default::default()
+    };
+    let rollup = report.directory_rollup();
+    let dirs = rollup
+        .iter()
+        .map(|(dir, _)| dir.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        dirs,
+        ["Show", "Show/Season 1", "Show/Season 2", "Show/extras"]
+    );
+    assert_eq!(rollup[0].1.counts(), [3, 0, 0, 1]);
+    assert_eq!(rollup[1].1.saved_percent(), Some(75.0));
+
+    let md = report.to_markdown();
+    assert!(
+        md.contains("| Show/Season 1 | 1 encoded, 1 failed | 1.0 GiB | 256.0 MiB | 75% |"),
+        "{md}"
+    );
+    assert!(
+        md.contains("| Show | 3 encoded, 1 failed | 3.0 GiB | 1.2 GiB | 58% |"),
+        "{md}"
+    );
+}
+
+#[test]
+fn test_sample_report() {
+    let mut report = sample_report();
+    assert_eq!(report.sample_line(), None);
+    report.sample = Some(SampleInfo {
+        fraction: 0.05,
+        sampled: 2,
+        matched: 40,
+        matched_size: 40 << 30,
+    });
+    let md = report.to_markdown();
+    assert!(
+        md.contains("**Sample:** This was a random sample of 2 of the 40 matched files (5%). At the same rate, all of them (40.0 GiB) would be encoded to about 10.0 GiB."),
+        "{md}"
+    );
+    assert!(report
+        .to_html()
+        .contains("<p><b>Sample:</b> This was a random sample"));
+}
+
+#[test]
+fn test_list_formats() {
+    let videos = [
+        ListedVideo {
+            path: "show/e01, \"pilot\".mkv".to_string(),
+            size: 1024,
+            duration: Some(1320.5),
+            codec: Some("h264".to_string()),
+            width: Some(1920),
+            height: Some(1080),
+            bitrate: Some(5_000_000),
+        },
+        ListedVideo {
+            path: "show/e02.mkv".to_string(),
+            size: 2048,
+            ..Default::default()
+        },
+    ];
+    assert_eq!(
+        render_list(&videos, ListFormat::Paths),
+        "show/e01, \"pilot\".mkv\nshow/e02.mkv\n"
+    );
+    let json = render_list(&videos, ListFormat::Json);
+    assert!(json.contains(r#"{"path": "show/e01, \"pilot\".mkv", "size": 1024, "duration": 1320.5, "codec": "h264", "width": 1920, "height": 1080, "bitrate": 5000000}"#), "{json}");
+    assert!(
+        json.contains(r#""duration": null, "codec": null"#),
+        "{json}"
+    );
+    let csv = render_list(&videos, ListFormat::Csv);
+    assert_eq!(
+        csv,
+        "path,size,duration,codec,width,height,bitrate\n\"show/e01, \"\"pilot\"\".mkv\",1024,1320.5,h264,1920,1080,5000000\nshow/e02.mkv,2048,,,,,\n"
+    );
+    assert_eq!(render_list(&[], ListFormat::Json), "[]\n");
+}
+
+#[test]
+fn test_audio_actions_for_streams() {
+    assert_eq!(
+        AudioActions::All(AudioAction::Drop).for_streams(2),
+        vec![AudioAction::Drop; 2]
+    );
+    assert_eq!(AudioActions::All(AudioAction::Copy).for_streams(0), vec![]);
+    let aac = AudioAction::Reencode(Some("aac".to_string()));
+    assert_eq!(
+        AudioActions::PerStream(vec![AudioAction::Copy, aac.clone()]).for_streams(2),
+        vec![AudioAction::Copy, aac]
+    );
+    assert_eq!(
+        AudioActions::default().for_streams(1),
+        vec![AudioAction::Reencode(None)]
+    );
+}
+
+#[test]
+fn test_render_audio_report() {
+    let files = [AudioFileReport {
+        path: "show.mkv".to_string(),
+        size: 1024 * 1024,
+        loudness: Some(-23.04),
+        streams: vec![
+            AudioStreamReport {
+                index: 1,
+                codec: Some("ac3".to_string()),
+                channels: Some(6),
+                bitrate: Some(448.0),
+                action: AudioAction::Reencode(Some("aac".to_string())),
+            },
+            AudioStreamReport {
+                index: 2,
+                codec: Some("aac".to_string()),
+                channels: Some(2),
+                bitrate: None,
+                action: AudioAction::Copy,
+            },
+        ],
+    }];
+    let copy_policy = AudioCopyPolicy {
+        policy: AudioPolicy::CopyIfEfficient,
+        threshold: AudioCopyThreshold::Total(200_000),
+    };
+    let report = render_audio_report(&files, &copy_policy);
+    assert!(report.contains(", -23.0 LUFS\n"));
+    assert!(report.contains("  Stream 1: ac3, 6 channels, 448 kb/s: reencode as aac\n"));
+    assert!(report.contains("  Stream 2: aac, 2 channels, ? kb/s: copy\n"));
+    assert!(report.ends_with("1 audio streams would be copied, 1 reencoded and 0 dropped. Streams of up to 200 kb/s are copied.\n"));
+}
+
+#[test]
+fn test_probe_report() {
+    let mut report = sample_report();
+    assert_eq!(report.probe_line(), None);
+    report.probe_fallbacks = 3;
+    assert!(report
+        .to_markdown()
+        .contains("**Probe:** 3 files could not be probed, so their CRF was chosen"));
+    report.probe_skipped = 2;
+    assert_eq!(
+        report.probe_line().unwrap(),
+        "5 files could not be probed: 2 were skipped, and the CRF of 3 was chosen without knowing their dimensions."
+    );
+    assert!(report
+        .to_html()
+        .contains("<p><b>Probe:</b> 5 files could not be probed"));
+}
@@ -0,0 +1,363 @@
+//! Whole runs with stand-ins for ffmpeg and ffprobe, which are set for this test binary only.
+
+#![cfg(unix)]
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use clap::Parser;
+use jiffy::*;
+use tokio_util::sync::CancellationToken;
+
+/// Write an executable shell script.
+fn write_script(path: &Path, script: &str) {
+    std::fs::write(path, format!("#!/bin/sh\n{script}")).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Set up the stand-ins once for all the tests. The ffmpeg has the needed encoders, and its
+/// encode writes 4000 bytes to the partial output, which is the last argument, and its arguments
+/// to a file named like the output with the ".args" extension. Its stabilization pass writes the
+/// motion file, and fails for videos with "shaky" in their names. Every video is 10
+/// seconds long, except the ones with "short" in their names, which are 1 second long. The ones
+/// with "titled" in their names have a titled and an untitled audio stream. Each ffmpeg run adds
+/// a line with its arguments to a file named like the input with ".calls" added.
+fn use_stub_tools() {
+    static BIN: OnceLock<PathBuf> = OnceLock::new();
+    BIN.get_or_init(|| {
+        let bin = tempfile::tempdir().unwrap().into_path();
+        write_script(
+            &bin.join("ffmpeg"),
+            "prev=\nfor arg; do [ \"$prev\" = -i ] && echo \"$*\" >> \"$arg.calls\"; prev=$arg; done\nfor last; do :; done\ncase \"$last\" in\n-encoders) printf ' ------\\n V..... libx265\\n A..... aac\\n';;\n*.part.*) head -c 4000 /dev/zero > \"$last\"; printf '%s\\n' \"$@\" > \"${last%%.part.*}.args\";;\n-) for arg; do case \"$arg\" in vidstabdetect*) : > \"${arg##*result=}\";; esac; done\ncase \"$*\" in *shaky*) exit 1;; esac;;\nesac\n",
+        );
+        write_script(&bin.join("ffprobe"), "case \"$*\" in *stream=index*titled*) printf '[STREAM]\\nindex=0\\ncodec_type=audio\\ncodec_name=ac3\\nchannels=2\\nTAG:title=Commentary\\n[/STREAM]\\n[STREAM]\\nindex=1\\ncodec_type=audio\\ncodec_name=ac3\\nchannels=2\\n[/STREAM]\\n';; *short*format=duration*|*format=duration*short*) printf 1;; *format=duration*) printf 10;; esac\n");
+        std::env::set_var("FFMPEG", bin.join("ffmpeg"));
+        std::env::set_var("FFPROBE", bin.join("ffprobe"));
+        bin
+    });
+}
+
+/// Make a video root with a 10 KB video in it.
+fn make_video_root(dir: &Path) -> PathBuf {
+    let video_root = dir.join("videos");
+    std::fs::create_dir_all(&video_root).unwrap();
+    std::fs::write(video_root.join("a.mkv"), vec![1; 10_000]).unwrap();
+    video_root
+}
+
+/// Read the arguments that the stand-in ffmpeg encoded an output with.
+fn read_ffmpeg_args(output: &Path) -> Vec<String> {
+    let args = std::fs::read_to_string(output.with_extension("args")).unwrap();
+    args.lines().map(str::to_string).collect()
+}
+
+/// Whether `args` has these arguments one after another.
+fn has_args(args: &[String], expected: &[&str]) -> bool {
+    args.windows(expected.len())
+        .any(|window| window == expected)
+}
+
+async fn run_with(args: &[&str]) -> RunReport {
+    use_stub_tools();
+    let mut all_args = vec!["prog_name", "--no-log", "--crf", "22"];
+    all_args.extend(args);
+    Encoder::new(Cli::parse_from(all_args))
+        .unwrap()
+        .run_encodes(CancellationToken::new())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_moved_source_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    let moved_to = dir.path().join("moved");
+    let report = run_with(&[
+        "--move-source-to",
+        moved_to.to_str().unwrap(),
+        video_root.to_str().unwrap(),
+    ])
+    .await;
+    assert!(moved_to.join("a.mkv").exists());
+    assert!(!video_root.join("a.mkv").exists());
+    // The source's size is read before it's moved away:
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(report.files[0].outcome, FileOutcome::Encoded);
+    assert_eq!(report.files[0].input_size, 10_000);
+    assert_eq!(report.files[0].output_size, Some(4000));
+}
+
+#[tokio::test]
+async fn test_existing_output_is_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    let output = video_root.join("encoded/a-crf22.mkv");
+    std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+    std::fs::write(&output, "earlier output").unwrap();
+    let report = run_with(&[video_root.to_str().unwrap()]).await;
+    assert_eq!(report.files.len(), 1);
+    assert!(
+        matches!(&report.files[0].outcome, FileOutcome::Skipped(reason) if reason.contains("already exists"))
+    );
+    assert_eq!(report.files[0].output_size, None);
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "earlier output");
+}
+
+#[tokio::test]
+async fn test_prune_flattened_orphans() {
+    use_stub_tools();
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = dir.path().join("videos");
+    std::fs::create_dir_all(video_root.join("Show")).unwrap();
+    std::fs::write(video_root.join("Show/pilot.mkv"), vec![1; 10_000]).unwrap();
+    let output_dir = video_root.join("encoded");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let kept = output_dir.join(format!(
+        "pilot-{}-crf22.mkv",
+        get_template_hash(Path::new("Show/pilot.mkv"))
+    ));
+    let orphan = output_dir.join(format!(
+        "pilot-{}-crf22.mkv",
+        get_template_hash(Path::new("Old Show/pilot.mkv"))
+    ));
+    std::fs::write(&kept, "output").unwrap();
+    std::fs::write(&orphan, "output").unwrap();
+
+    let cli = Cli::parse_from([
+        "prog_name",
+        "--no-log",
+        "--flatten",
+        video_root.to_str().unwrap(),
+        "prune",
+    ]);
+    Encoder::new(cli)
+        .unwrap()
+        .prune_outputs(false)
+        .await
+        .unwrap();
+    // The outputs are told apart by the hash of their source's path:
+    assert!(kept.exists());
+    assert!(!orphan.exists());
+}
+
+#[tokio::test]
+async fn test_linked_copies_are_encoded_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    std::os::unix::fs::symlink(video_root.join("a.mkv"), video_root.join("b.mkv")).unwrap();
+    std::fs::hard_link(video_root.join("a.mkv"), video_root.join("c.mkv")).unwrap();
+    // A symlink back to the video root must not be followed forever:
+    std::os::unix::fs::symlink(&video_root, video_root.join("loop")).unwrap();
+    let report = run_with(&[video_root.to_str().unwrap()]).await;
+    assert_eq!(report.files.len(), 1, "{:?}", report.files);
+    assert_eq!(report.files[0].outcome, FileOutcome::Encoded);
+}
+
+#[tokio::test]
+async fn test_broken_symlink_is_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    std::os::unix::fs::symlink(dir.path().join("gone.mkv"), video_root.join("b.mkv")).unwrap();
+    let report = run_with(&[video_root.to_str().unwrap()]).await;
+    assert_eq!(report.files.len(), 1, "{:?}", report.files);
+    assert_eq!(report.files[0].outcome, FileOutcome::Encoded);
+}
+
+#[tokio::test]
+async fn test_sample_is_chosen_after_duration_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    for i in 0..9 {
+        std::fs::write(video_root.join(format!("short{i}.mkv")), vec![1; 10_000]).unwrap();
+    }
+    let report = run_with(&[
+        "--sample-fraction",
+        "10%",
+        "--min-duration",
+        "5",
+        video_root.to_str().unwrap(),
+    ])
+    .await;
+    // Only the long video is long enough, so it's the whole sample:
+    assert_eq!(report.sample.as_ref().unwrap().matched, 1);
+    assert_eq!(report.files.len(), 1, "{:?}", report.files);
+    assert!(report.files[0].input.ends_with("a.mkv"));
+}
+
+#[tokio::test]
+async fn test_strip_metadata_and_set_title() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    run_with(&[
+        "--strip-metadata",
+        "--set-title",
+        "Clip {basename}",
+        video_root.to_str().unwrap(),
+    ])
+    .await;
+    let args = read_ffmpeg_args(&video_root.join("encoded/a-crf22.mkv"));
+    assert!(has_args(&args, &["-map_metadata", "-1"]), "{args:?}");
+    assert!(!has_args(&args, &["-map_metadata", "0"]), "{args:?}");
+    assert!(has_args(&args, &["-metadata", "comment="]), "{args:?}");
+    assert!(has_args(&args, &["-metadata", "title=Clip a"]), "{args:?}");
+
+    // Without them, the source's metadata is kept:
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    run_with(&[video_root.to_str().unwrap()]).await;
+    let args = read_ffmpeg_args(&video_root.join("encoded/a-crf22.mkv"));
+    assert!(has_args(&args, &["-map_metadata", "0"]), "{args:?}");
+    assert!(
+        !args.iter().any(|arg| arg.starts_with("title=")),
+        "{args:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_stabilize_motion_file_is_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    std::fs::write(video_root.join("shaky.mkv"), vec![1; 10_000]).unwrap();
+    let report = run_with(&["--stabilize", video_root.to_str().unwrap()]).await;
+    assert_eq!(report.files.len(), 2, "{:?}", report.files);
+    assert_eq!(report.files[0].outcome, FileOutcome::Encoded);
+    assert!(matches!(report.files[1].outcome, FileOutcome::Failed(_)));
+    // The motion file is kept next to the part file while encoding:
+    let output_dir = video_root.join("encoded");
+    let args = read_ffmpeg_args(&output_dir.join("a-crf22.mkv"));
+    let motion_file = output_dir.join("a-crf22.part.trf");
+    let transform = format!("vidstabtransform=input={}", motion_file.to_str().unwrap());
+    assert!(
+        args.iter().any(|arg| arg.starts_with(&transform)),
+        "{args:?}"
+    );
+    // The detection pass doesn't read the terminal:
+    let calls = std::fs::read_to_string(video_root.join("a.mkv.calls")).unwrap();
+    assert!(
+        calls
+            .lines()
+            .any(|call| call.contains("vidstabdetect") && call.starts_with("-nostdin -nostats")),
+        "{calls}"
+    );
+    // It's removed after the encode, and after a failed detection pass:
+    assert!(!motion_file.exists());
+    assert!(!output_dir.join("shaky-crf22.part.trf").exists());
+}
+
+#[tokio::test]
+async fn test_audio_title_is_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = dir.path().join("videos");
+    std::fs::create_dir_all(&video_root).unwrap();
+    std::fs::write(video_root.join("titled.mkv"), vec![1; 10_000]).unwrap();
+    run_with(&["--skip-bitrate-check", video_root.to_str().unwrap()]).await;
+    let args = read_ffmpeg_args(&video_root.join("encoded/titled-crf22.mkv"));
+    // Only the stream without a title gets one:
+    assert!(
+        !has_args(&args, &["-metadata:s:a:0", "title=AAC 2.0"]),
+        "{args:?}"
+    );
+    assert!(
+        has_args(&args, &["-metadata:s:a:1", "title=AAC 2.0"]),
+        "{args:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_prune_shared_output_dir() {
+    use_stub_tools();
+    let dir = tempfile::tempdir().unwrap();
+    let first_root = dir.path().join("first");
+    let second_root = dir.path().join("second");
+    std::fs::create_dir_all(&first_root).unwrap();
+    std::fs::create_dir_all(&second_root).unwrap();
+    std::fs::write(first_root.join("a.mkv"), vec![1; 10_000]).unwrap();
+    std::fs::write(second_root.join("b.mkv"), vec![1; 10_000]).unwrap();
+    let output_dir = dir.path().join("outputs");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    for name in ["a-crf22.mkv", "b-crf22.mkv", "gone-crf22.mkv"] {
+        std::fs::write(output_dir.join(name), "output").unwrap();
+    }
+
+    let cli = Cli::parse_from([
+        "prog_name",
+        "--no-log",
+        "--output-dir",
+        output_dir.to_str().unwrap(),
+        first_root.to_str().unwrap(),
+        second_root.to_str().unwrap(),
+        "prune",
+    ]);
+    Encoder::new(cli)
+        .unwrap()
+        .prune_outputs(false)
+        .await
+        .unwrap();
+    // The sources are looked for in every video root that shares the output directory:
+    assert!(output_dir.join("a-crf22.mkv").exists());
+    assert!(output_dir.join("b-crf22.mkv").exists());
+    assert!(!output_dir.join("gone-crf22.mkv").exists());
+}
+
+#[tokio::test]
+async fn test_diff_prune_with_noop() {
+    use_stub_tools();
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = make_video_root(dir.path());
+    let orphan = video_root.join("encoded/gone-crf22.mkv");
+    std::fs::create_dir_all(orphan.parent().unwrap()).unwrap();
+    std::fs::write(&orphan, "output").unwrap();
+
+    let diff = |noop: bool| {
+        let mut args = vec!["prog_name", "--no-log", "--diff", "--prune"];
+        if noop {
+            args.push("--noop");
+        }
+        args.push(video_root.to_str().unwrap());
+        Encoder::new(Cli::parse_from(args)).unwrap()
+    };
+    diff(true).diff_outputs().await.unwrap();
+    assert!(orphan.exists());
+    diff(false).diff_outputs().await.unwrap();
+    assert!(!orphan.exists());
+}
+
+/// Encode a video that's smaller than `--minimum-size` with these arguments, and check that it's
+/// skipped without running ffmpeg on it.
+async fn assert_small_video_not_decoded(args: &[&str]) {
+    let dir = tempfile::tempdir().unwrap();
+    let video_root = dir.path().join("videos");
+    std::fs::create_dir_all(&video_root).unwrap();
+    // It has audio, for the passes that decode that:
+    std::fs::write(video_root.join("titled.mkv"), vec![1; 10_000]).unwrap();
+    let mut all_args = vec!["--minimum-size", "1M"];
+    all_args.extend(args);
+    all_args.push(video_root.to_str().unwrap());
+    let report = run_with(&all_args).await;
+    assert_eq!(report.files.len(), 1, "{:?}", report.files);
+    assert!(
+        matches!(&report.files[0].outcome, FileOutcome::Skipped(reason) if reason.contains("small")),
+        "{:?}",
+        report.files[0].outcome
+    );
+    assert!(!video_root.join("titled.mkv.calls").exists());
+}
+
+#[tokio::test]
+async fn test_small_video_is_not_cropped() {
+    assert_small_video_not_decoded(&["--autocrop"]).await;
+}
+
+#[tokio::test]
+async fn test_small_video_has_no_scene_detection() {
+    assert_small_video_not_decoded(&["--auto-chapters", "scene"]).await;
+}
+
+#[tokio::test]
+async fn test_small_video_loudness_is_not_measured() {
+    assert_small_video_not_decoded(&["--normalize-audio"]).await;
+}
@@ -0,0 +1,243 @@
+use std::ffi::OsString;
+
+use jiffy::*;
+
+fn stream(index: u32, codec_type: &str, dispositions: &[&str]) -> StreamInfo {
+    StreamInfo {
+        index,
+        codec_type: codec_type.to_string(),
+        dispositions: dispositions.iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+fn args(strs: &[&str]) -> Vec<OsString> {
+    strs.iter().map(OsString::from).collect()
+}
+
+#[test]
+fn test_stream_info_from_probe() {
+    let sections = parse_ffprobe_sections(
+        "[STREAM]
+index=2
+codec_type=subtitle
+codec_name=subrip
+DISPOSITION:default=0
+DISPOSITION:forced=1
+TAG:language=eng
+[/STREAM]",
+    );
+    let info = StreamInfo::from_section(&sections[0]).unwrap();
+    assert_eq!(info, {
+        let mut expected = stream(2, "subtitle", &["forced"]);
+        expected.codec = Some("subrip".to_string());
+        expected.language = Some("eng".to_string());
+        expected
+    });
+}
+
+#[test]
+fn test_plan_keeps_all_in_order() {
+    // Probe results in a different order must give the same plan:
+    let streams = [
+        stream(2, "subtitle", &["forced"]),
+        stream(0, "video", &["default"]),
+        stream(1, "audio", &["default", "original"]),
+    ];
+    let plan = StreamMap::plan(&streams, |_| true);
+    assert_eq!(
+        plan.to_args(),
+        args(&[
+            "-map",
+            "0:0",
+            "-map",
+            "0:1",
+            "-map",
+            "0:2",
+            "-disposition:0",
+            "default",
+            "-disposition:1",
+            "default+original",
+            "-disposition:2",
+            "forced",
+        ])
+    );
+}
+
+#[test]
+fn test_plan_moves_default_when_dropped() {
+    let streams = [
+        stream(0, "video", &["default"]),
+        stream(1, "audio", &["default"]),
+        stream(2, "audio", &[]),
+        stream(3, "audio", &[]),
+    ];
+    let plan = StreamMap::plan(&streams, |s| s.index != 1);
+    assert_eq!(
+        plan.to_args(),
+        args(&[
+            "-map",
+            "0:0",
+            "-map",
+            "0:2",
+            "-map",
+            "0:3",
+            "-disposition:0",
+            "default",
+            "-disposition:1",
+            "default",
+            "-disposition:2",
+            "0",
+        ])
+    );
+}
+
+#[test]
+fn test_plan_no_default_added_if_source_had_none() {
+    let streams = [stream(0, "video", &[]), stream(1, "subtitle", &[])];
+    let plan = StreamMap::plan(&streams, |_| true);
+    assert!(plan.streams.iter().all(|s| s.dispositions.is_empty()));
+}
+
+#[test]
+fn test_audio_languages() {
+    let audio = |index, language: Option<&str>| StreamInfo {
+        language: language.map(str::to_string),
+        ..stream(index, "audio", &[])
+    };
+    let streams = vec![
+        audio(1, Some("eng")),
+        audio(2, Some("JPN")),
+        audio(3, Some("fre")),
+        audio(4, None),
+    ];
+    let languages = vec!["eng".to_string(), "jpn".to_string()];
+    let kept: Vec<_> = streams
+        .iter()
+        .filter(|stream| has_wanted_language(stream, &streams, &languages))
+        .map(|stream| stream.index)
+        .collect();
+    assert_eq!(kept, [1, 2]);
+
+    // Nothing matches, so everything is kept:
+    let languages = vec!["ger".to_string()];
+    assert!(streams
+        .iter()
+        .all(|stream| has_wanted_language(stream, &streams, &languages)));
+    assert!(streams
+        .iter()
+        .all(|stream| has_wanted_language(stream, &streams, &[])));
+}
+
+#[test]
+fn test_commentary() {
+    let audio = |title: Option<&str>, dispositions: &[&str]| StreamInfo {
+        title: title.map(str::to_string),
+        ..stream(1, "audio", dispositions)
+    };
+    assert!(is_commentary(&audio(Some("Commentary with the cast"), &[])));
+    assert!(is_commentary(&audio(Some("Director's Commentary"), &[])));
+    assert!(is_commentary(&audio(Some("Stereo"), &["comment"])));
+    assert!(!is_commentary(&audio(Some("Surround 5.1"), &["default"])));
+    assert!(!is_commentary(&audio(None, &[])));
+}
+
+#[test]
+fn test_subtitle_selector() {
+    let sub = |index, language: &str, dispositions: &[&str]| StreamInfo {
+        language: Some(language.to_string()),
+        ..stream(index, "subtitle", dispositions)
+    };
+    let streams = vec![
+        stream(0, "video", &[]),
+        sub(1, "eng", &["forced"]),
+        sub(2, "eng", &[]),
+        sub(3, "jpn", &[]),
+    ];
+    let selected = |selector: &str| {
+        SubtitleSelector::parse(selector)
+            .unwrap()
+            .select(&streams)
+            .map(|stream| stream.index)
+    };
+    assert_eq!(
+        SubtitleSelector::default()
+            .select(&streams)
+            .map(|stream| stream.index),
+        Some(2)
+    );
+    assert_eq!(selected("lang=eng"), Some(2));
+    assert_eq!(selected("lang=ENG,forced"), Some(1));
+    assert_eq!(selected("forced"), Some(1));
+    assert_eq!(selected("lang=jpn"), Some(3));
+    assert_eq!(selected("lang=jpn,forced"), None);
+    assert_eq!(selected("lang=ger"), None);
+    assert_eq!(selected("index=1"), Some(1));
+    // Stream 0 isn't a subtitle:
+    assert_eq!(selected("index=0"), None);
+
+    // A forced stream is used if it's the only one:
+    assert_eq!(
+        SubtitleSelector::default()
+            .select(&streams[..2])
+            .map(|stream| stream.index),
+        Some(1)
+    );
+    // Without a choice, a forced stream is burned in if there is one:
+    assert_eq!(
+        SubtitleSelector::automatic()
+            .select(&streams)
+            .map(|stream| stream.index),
+        Some(1)
+    );
+    assert_eq!(
+        SubtitleSelector::automatic()
+            .select(&streams[2..])
+            .map(|stream| stream.index),
+        Some(2)
+    );
+
+    assert!(SubtitleSelector::parse("index=2,lang=eng").is_err());
+    assert!(SubtitleSelector::parse("lang=").is_err());
+    assert!(SubtitleSelector::parse("eng").is_err());
+    assert!(SubtitleSelector::parse("index=two").is_err());
+}
+
+#[test]
+fn test_mp4_subtitles() {
+    let sub = |codec: &str| StreamInfo {
+        codec: Some(codec.to_string()),
+        ..stream(2, "subtitle", &[])
+    };
+    assert!(SubHandling::Convert.keeps_in_mp4(&sub("subrip")));
+    assert!(SubHandling::Convert.keeps_in_mp4(&sub("ass")));
+    assert!(!SubHandling::Convert.keeps_in_mp4(&sub("hdmv_pgs_subtitle")));
+    assert!(!SubHandling::Drop.keeps_in_mp4(&sub("subrip")));
+    assert!(!SubHandling::Burn.keeps_in_mp4(&sub("subrip")));
+    assert!(SubHandling::Drop.keeps_in_mp4(&stream(1, "audio", &[])));
+}
+
+#[test]
+fn test_plan_without_subtitles() {
+    // When the subtitles are dropped with -sn, they aren't mapped, so the flags of the streams
+    // after them stay on the right output streams:
+    let streams = [
+        stream(0, "video", &["default"]),
+        stream(1, "subtitle", &["forced"]),
+        stream(2, "audio", &["default"]),
+    ];
+    let plan = StreamMap::plan(&streams, |stream| stream.codec_type != "subtitle");
+    assert_eq!(
+        plan.to_args(),
+        args(&[
+            "-map",
+            "0:0",
+            "-map",
+            "0:2",
+            "-disposition:0",
+            "default",
+            "-disposition:1",
+            "default"
+        ])
+    );
+}
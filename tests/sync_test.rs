@@ -0,0 +1,58 @@
+use std::{ffi::OsString, path::Path};
+
+use jiffy::*;
+
+#[test]
+fn test_sync_args() {
+    let output_dir = Path::new("/videos/encoded");
+    let file = Path::new("/videos/encoded/Show/S01/E01-crf22.mkv");
+    let args = SyncTool::Rclone
+        .get_args("remote:backup", output_dir, file)
+        .unwrap();
+    assert_eq!(
+        args,
+        [
+            OsString::from("copyto"),
+            file.into(),
+            "remote:backup/Show/S01/E01-crf22.mkv".into()
+        ]
+    );
+    let args = SyncTool::Rclone
+        .get_args("remote:", output_dir, file)
+        .unwrap();
+    assert_eq!(args[2], "remote:Show/S01/E01-crf22.mkv");
+
+    let args = SyncTool::Rsync
+        .get_args("host:/backup", output_dir, file)
+        .unwrap();
+    assert_eq!(
+        args,
+        [
+            "--archive",
+            "--partial",
+            "--relative",
+            "/videos/encoded/./Show/S01/E01-crf22.mkv",
+            "host:/backup"
+        ]
+    );
+}
+
+#[test]
+fn test_sync_report() {
+    let mut report = RunReport::default();
+    assert_eq!(report.sync_line(), None);
+    report.syncs = vec![
+        SyncResult {
+            file: "a.mkv".into(),
+            error: None,
+        },
+        SyncResult {
+            file: "b.mkv".into(),
+            error: Some("rclone failed: quota exceeded".into()),
+        },
+    ];
+    assert_eq!(report.sync_line().unwrap(), "1 of 2 files were synced.");
+    assert!(report
+        .to_markdown()
+        .contains("**Sync:** 1 of 2 files were synced."));
+}
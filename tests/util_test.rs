@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+
+use jiffy::*;
+
+#[test]
+fn test_size_str_to_int() {
+    assert_eq!(parse_size("1024b").unwrap(), 1024);
+    assert_eq!(parse_size("1k").unwrap(), 1024);
+    assert_eq!(parse_size("1.5M").unwrap(), (1024.0 * 1024.0 * 1.5) as u64);
+    assert_eq!(parse_size("1.5").unwrap(), (1024.0 * 1024.0 * 1.5) as u64);
+    assert_eq!(parse_size(".5").unwrap(), (1024.0 * 1024.0 * 0.5) as u64);
+    assert_eq!(
+        parse_size("2g").unwrap(),
+        (1024.0 * 1024.0 * 1024.0 * 2.0) as u64
+    );
+    assert_eq!(
+        parse_size("0.002T").unwrap(),
+        (1024.0 * 1024.0 * 1024.0 * 1024.0 * 0.002) as u64
+    );
+}
+
+#[test]
+fn test_parse_bitrate() {
+    assert_eq!(parse_bitrate("128k").unwrap(), 128_000);
+    assert_eq!(parse_bitrate("10M").unwrap(), 10_000_000);
+    assert_eq!(parse_bitrate("1.5m").unwrap(), 1_500_000);
+    assert_eq!(parse_bitrate("96000").unwrap(), 96_000);
+    assert!(parse_bitrate("fast").is_err());
+    assert!(parse_bitrate("10MB").is_err());
+}
+
+#[test]
+fn test_parse_speed() {
+    assert_eq!(parse_speed("2x").unwrap(), 2.0);
+    assert_eq!(parse_speed("1.5").unwrap(), 1.5);
+    assert!(parse_speed("0.01x").is_err());
+    assert!(parse_speed("fast").is_err());
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp("90").unwrap(), 90.0);
+    assert_eq!(parse_timestamp("30.5").unwrap(), 30.5);
+    assert_eq!(parse_timestamp("1:30").unwrap(), 90.0);
+    assert_eq!(parse_timestamp("1:02:03.5").unwrap(), 3723.5);
+    assert!(parse_timestamp("1:60").is_err());
+    assert!(parse_timestamp("1.5:00").is_err());
+    assert!(parse_timestamp("1:2:3:4").is_err());
+    assert!(parse_timestamp("-5").is_err());
+}
+
+#[test]
+fn test_parse_fraction() {
+    assert_eq!(parse_fraction("5%").unwrap(), 0.05);
+    assert_eq!(parse_fraction("0.25").unwrap(), 0.25);
+    assert_eq!(parse_fraction("100%").unwrap(), 1.0);
+    assert!(parse_fraction("0%").is_err());
+    assert!(parse_fraction("150%").is_err());
+    assert!(parse_fraction("some").is_err());
+}
+
+#[test]
+fn test_parse_since() {
+    let now = std::time::SystemTime::now();
+    let week_ago = parse_since("7d").unwrap();
+    let elapsed = now.duration_since(week_ago).unwrap().as_secs();
+    assert!((7 * 24 * 60 * 60 - 5..=7 * 24 * 60 * 60 + 5).contains(&elapsed));
+    assert!(parse_since("2w").unwrap() < week_ago);
+
+    let date = chrono::DateTime::<chrono::Local>::from(parse_since("2024-03-01").unwrap());
+    assert_eq!(
+        date.format("%Y-%m-%d %H:%M").to_string(),
+        "2024-03-01 00:00"
+    );
+    let date = chrono::DateTime::<chrono::Local>::from(parse_since("2024-03-01 18:30").unwrap());
+    assert_eq!(
+        date.format("%Y-%m-%d %H:%M").to_string(),
+        "2024-03-01 18:30"
+    );
+
+    assert!(parse_since("7 days").is_err());
+    assert!(parse_since("yesterday").is_err());
+}
+
+#[test]
+fn test_normalize_path() {
+    assert_eq!(
+        normalize_path(Path::new("a/./b/../c")),
+        PathBuf::from("a/c")
+    );
+    assert_eq!(
+        normalize_path(Path::new("/videos/encoded/..")),
+        PathBuf::from("/videos")
+    );
+}
+
+#[test]
+fn test_is_same_file() {
+    // Paths that don't exist are compared by their last components:
+    assert!(is_same_file("show/e01.mkv", "/videos/show/e01.mkv"));
+    assert!(!is_same_file("show/e02.mkv", "/videos/show/e01.mkv"));
+    assert!(!is_same_file("/videos/show/e01.mkv", "show/e01.mkv"));
+}
+
+#[test]
+fn test_format_size() {
+    assert_eq!(format_size(300), "300 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(3 << 30), "3.0 GiB");
+}
+
+#[test]
+fn test_fdinfo_is_writable() {
+    assert!(!fdinfo_is_writable(
+        "pos:\t0\nflags:\t0100000\nmnt_id:\t29\n"
+    ));
+    assert!(fdinfo_is_writable(
+        "pos:\t0\nflags:\t0100001\nmnt_id:\t29\n"
+    ));
+    assert!(fdinfo_is_writable(
+        "pos:\t4096\nflags:\t02100002\nmnt_id:\t29\n"
+    ));
+    assert!(!fdinfo_is_writable(""));
+}
+
+#[test]
+fn test_is_interrupted_exit() {
+    // Killed by SIGINT or SIGTERM:
+    assert!(is_interrupted_exit(None, Some(2), ""));
+    assert!(is_interrupted_exit(None, Some(15), ""));
+    assert!(!is_interrupted_exit(None, Some(9), ""));
+    // ffmpeg caught the signal and exited:
+    assert!(is_interrupted_exit(
+        Some(255),
+        None,
+        "[out#0/matroska] Exiting normally, received signal 2."
+    ));
+    assert!(!is_interrupted_exit(Some(255), None, "Conversion failed!"));
+    assert!(!is_interrupted_exit(
+        Some(1),
+        None,
+        "Error opening output files: Invalid argument"
+    ));
+}
+
+#[test]
+fn test_write_checksum_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("video.mkv");
+    std::fs::write(&path, "abc").unwrap();
+    let checksum_path = write_checksum_file(&path).unwrap();
+    assert_eq!(checksum_path, dir.path().join("video.mkv.sha256"));
+    assert_eq!(
+        std::fs::read_to_string(checksum_path).unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  video.mkv\n"
+    );
+}